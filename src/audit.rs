@@ -0,0 +1,331 @@
+// The audit trail: a line of JSON for every upload and deletion, kept apart from the logs and
+// their rotation so operators can answer abuse reports about files hosted long ago
+
+use actix_web::{HttpMessage, HttpRequest};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::config::AuditConfig;
+use crate::storage::{OutboxEntry, UploadRecord};
+use crate::upload::UploadData;
+
+// How often entries past the retention are dropped
+pub(crate) const AUDIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AuditAction {
+    Upload,
+    Delete,
+}
+
+impl AuditAction {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Upload => "upload",
+            AuditAction::Delete => "delete",
+        }
+    }
+}
+
+// Where an upload or deletion came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AuditSource {
+    Http,
+    // Files sent to the bot, and its /delete admin command
+    Bot,
+    // Uploads deleted once their TTL was up
+    Expiry,
+    // Files hosted by a program embedding the server through Uploader
+    Library,
+}
+
+impl AuditSource {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuditSource::Http => "http",
+            AuditSource::Bot => "bot",
+            AuditSource::Expiry => "expiry",
+            AuditSource::Library => "library",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) action: AuditAction,
+    pub(crate) source: AuditSource,
+    // Whether the file was hosted or deleted
+    pub(crate) success: bool,
+    // Status the server answered with, for requests over HTTP
+    #[serde(default)]
+    pub(crate) status: Option<u16>,
+    #[serde(default)]
+    pub(crate) client_ip: Option<String>,
+    // Name of the API key
+    #[serde(default)]
+    pub(crate) api_key: Option<String>,
+    #[serde(default)]
+    pub(crate) request_id: Option<String>,
+    // Who sent the file to the bot or told it to delete one
+    #[serde(default)]
+    pub(crate) telegram_user_id: Option<u64>,
+    #[serde(default)]
+    pub(crate) upload_id: Option<Uuid>,
+    // Hex-encoded SHA-256 of the file
+    #[serde(default)]
+    pub(crate) content_hash: Option<String>,
+    #[serde(default)]
+    pub(crate) error: Option<String>,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(action: AuditAction, source: AuditSource, success: bool) -> AuditEntry {
+        AuditEntry {
+            timestamp: Utc::now(),
+            action,
+            source,
+            success,
+            status: None,
+            client_ip: None,
+            api_key: None,
+            request_id: None,
+            telegram_user_id: None,
+            upload_id: None,
+            content_hash: None,
+            error: None,
+        }
+    }
+
+    // An entry about an upload in the registry
+    pub(crate) fn of(action: AuditAction, source: AuditSource, record: &UploadRecord) -> AuditEntry {
+        AuditEntry {
+            upload_id: Some(record.id),
+            content_hash: record.content_hash.clone(),
+            ..AuditEntry::new(action, source, true)
+        }
+    }
+}
+
+// A file a request hosted, deleted or failed to, noted by its handler for audit_middleware,
+// which adds what it knows about the request
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditedFile {
+    // Set when it isn't what the request did, as for files replaced by an upload
+    pub(crate) action: Option<AuditAction>,
+    pub(crate) upload_id: Option<Uuid>,
+    pub(crate) content_hash: Option<String>,
+    pub(crate) error: Option<String>,
+}
+
+impl AuditedFile {
+    pub(crate) fn of(record: &UploadRecord) -> AuditedFile {
+        AuditedFile {
+            action: None,
+            upload_id: Some(record.id),
+            content_hash: record.content_hash.clone(),
+            error: None,
+        }
+    }
+
+    pub(crate) fn queued(entry: &OutboxEntry) -> AuditedFile {
+        AuditedFile {
+            action: None,
+            upload_id: Some(entry.id),
+            content_hash: Some(entry.content_hash.clone()),
+            error: None,
+        }
+    }
+}
+
+// The files noted for a request so far
+pub(crate) struct AuditedFiles(pub(crate) Vec<AuditedFile>);
+
+// Why a request failed, as the client was told
+pub(crate) struct AuditedError(pub(crate) String);
+
+pub(crate) fn note_audited(req: &HttpRequest, file: AuditedFile) {
+    let mut extensions = req.extensions_mut();
+    match extensions.get_mut::<AuditedFiles>() {
+        Some(files) => files.0.push(file),
+        None => {
+            extensions.insert(AuditedFiles(vec![file]));
+        }
+    }
+}
+
+// The audit trail as a JSON Lines file that only ever gets appended to, except when entries
+// past the retention are dropped
+pub(crate) struct AuditLog {
+    pub(crate) path: PathBuf,
+    pub(crate) retention: Option<chrono::Duration>,
+    // Held while writing, so lines don't interleave and pruning doesn't lose any
+    pub(crate) file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub(crate) fn open(config: &AuditConfig) -> std::io::Result<AuditLog> {
+        if let Some(parent) = config.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let audit_log = AuditLog {
+            path: config.path.clone(),
+            retention: i64::try_from(config.retention_days).ok().filter(|days| *days > 0).and_then(chrono::Duration::try_days),
+            file: Mutex::new(AuditLog::append_to(&config.path)?),
+        };
+        audit_log.prune()?;
+        Ok(audit_log)
+    }
+
+    pub(crate) fn append_to(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    // Failing to write the audit trail doesn't fail the upload or deletion it is about
+    pub(crate) fn record(&self, entry: &AuditEntry) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit entry {:?}: {:?}", entry, e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(&line).and_then(|()| file.flush()) {
+            error!("Failed to write audit entry {:?}: {:?}", entry, e);
+        }
+    }
+
+    // The log as it is now: every line written so far, which record() only ever writes whole
+    // while holding the lock. Read without the lock, so nothing waits on it being parsed, and
+    // from the file as opened, which pruning replacing it doesn't change.
+    pub(crate) fn snapshot(&self) -> std::io::Result<(impl BufRead, u64)> {
+        let (log, length) = {
+            let _file = self.file.lock().unwrap();
+            let log = File::open(&self.path)?;
+            let length = log.metadata()?.len();
+            (log, length)
+        };
+        Ok((BufReader::new(log.take(length)), length))
+    }
+
+    // Entries in the order they were written, skipping lines that aren't entries
+    pub(crate) fn entries(&self, mut filter: impl FnMut(&AuditEntry) -> bool) -> std::io::Result<Vec<AuditEntry>> {
+        let (log, _) = self.snapshot()?;
+        let mut entries = Vec::new();
+        for (number, line) in log.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) if filter(&entry) => entries.push(entry),
+                Ok(_) => {}
+                Err(e) => warn!("Skipped line {} of audit log {:?}: {}", number + 1, self.path, e),
+            }
+        }
+        Ok(entries)
+    }
+
+    // Drop the entries past the retention, returning how many there were. The snapshot is
+    // filtered without the lock; only the entries recorded meanwhile are copied over with it held.
+    pub(crate) fn prune(&self) -> std::io::Result<usize> {
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let Some(cutoff) = Utc::now().checked_sub_signed(retention) else {
+            return Ok(0);
+        };
+        let (log, length) = self.snapshot()?;
+        let mut kept = Vec::new();
+        let mut dropped = 0;
+        for line in log.lines() {
+            let line = line?;
+            // Lines that don't parse are kept, they may still mean something to someone
+            match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) if entry.timestamp < cutoff => dropped += 1,
+                _ if line.trim().is_empty() => {}
+                _ => {
+                    kept.extend_from_slice(line.as_bytes());
+                    kept.push(b'\n');
+                }
+            }
+        }
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(&kept)?;
+        let mut file = self.file.lock().unwrap();
+        let mut recorded = File::open(&self.path)?;
+        recorded.seek(SeekFrom::Start(length))?;
+        std::io::copy(&mut recorded, &mut temp)?;
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+        *file = AuditLog::append_to(&self.path)?;
+        info!("Dropped {} audit entries older than {} days", dropped, retention.num_days());
+        Ok(dropped)
+    }
+}
+
+// Drop entries past the retention every AUDIT_PRUNE_INTERVAL
+pub(crate) async fn run_audit_pruning(data: actix_web::web::Data<UploadData>) {
+    let mut interval = tokio::time::interval(AUDIT_PRUNE_INTERVAL);
+    // The first tick is immediate, and opening the log pruned it already
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(audit) = &data.audit else {
+            return;
+        };
+        let path = audit.path.clone();
+        let pruner = data.clone();
+        let pruned = tokio::task::spawn_blocking(move || pruner.audit.as_ref().map_or(Ok(0), |audit| audit.prune())).await;
+        match pruned {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Failed to prune audit log {:?}: {:?}", path, e),
+            Err(e) => error!("Failed to prune audit log {:?}: {:?}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pruning_drops_old_entries_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AuditConfig { path: dir.path().join("audit.jsonl"), retention_days: 30 };
+        let audit = AuditLog::open(&config).unwrap();
+        let old = AuditEntry {
+            timestamp: Utc::now() - chrono::Duration::days(31),
+            ..AuditEntry::new(AuditAction::Upload, AuditSource::Http, true)
+        };
+        audit.record(&old);
+        audit.record(&AuditEntry::new(AuditAction::Upload, AuditSource::Bot, true));
+
+        // A snapshot only has what was recorded before it
+        let (snapshot, _) = audit.snapshot().unwrap();
+        audit.record(&AuditEntry::new(AuditAction::Delete, AuditSource::Http, true));
+        assert_eq!(snapshot.lines().count(), 2);
+
+        // Entries are still recorded to the log that pruning swapped in
+        assert_eq!(audit.prune().unwrap(), 1);
+        audit.record(&AuditEntry::new(AuditAction::Delete, AuditSource::Expiry, true));
+
+        let sources: Vec<&str> = audit.entries(|_| true).unwrap().iter().map(|entry| entry.source.as_str()).collect();
+        assert_eq!(sources, ["bot", "http", "expiry"]);
+    }
+}
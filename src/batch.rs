@@ -0,0 +1,345 @@
+// ZIP batch uploads: the images of an archive are extracted to the temp directory and hosted one
+// by one, within limits that keep zip bombs from filling the disk
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Component, Path};
+use tracing::{debug, error};
+use uuid::Uuid;
+use crate::config::ZipUploadConfig;
+use crate::error::Error;
+use crate::image::is_image;
+use crate::storage::{remove_temp_file, to_hex, QuotaReservation, SavedFile};
+use crate::upload::is_type_allowed;
+
+// An entry of an uploaded archive, extracted or turned away on its own
+pub(crate) struct ZipEntry {
+    // Path of the entry inside the archive
+    pub(crate) name: String,
+    pub(crate) extracted: Result<SavedFile, Error>,
+}
+
+// Extract the images of an archive next to it, counting them against the temp dir quota as they
+// come out. Entries that don't pass are reported as failed, while an archive over the entry count
+// or size limit, or one that doesn't fit in the quota, is rejected as a whole.
+pub(crate) fn extract_zip(
+    zip_path: &Path,
+    temp_dir: &Path,
+    limits: &ZipUploadConfig,
+    allowed_types: &[String],
+    reservation: &mut QuotaReservation<'_>,
+) -> Result<Vec<ZipEntry>, Error> {
+    let file = File::open(zip_path).map_err(|e| {
+        error!("Failed to open archive {:?}: {:?}", zip_path, e);
+        Error::Internal("Failed to read the archive".to_string())
+    })?;
+    let mut archive =
+        zip::ZipArchive::new(file).map_err(|e| Error::InvalidRequest(format!("The upload isn't a valid ZIP archive: {}", e)))?;
+    if archive.len() > limits.max_entries {
+        return Err(Error::InvalidRequest(format!("The archive has more than {} entries", limits.max_entries)));
+    }
+
+    let mut entries = Vec::new();
+    let mut remaining = limits.max_total_bytes;
+    for index in 0..archive.len() {
+        let mut entry = match archive.by_index(index) {
+            Ok(entry) => entry,
+            Err(e) => {
+                discard(&entries);
+                return Err(Error::InvalidRequest(format!("Failed to read entry {} of the archive: {}", index, e)));
+            }
+        };
+        let name = entry.name().to_string();
+        if entry.is_dir() || is_junk(&name) {
+            continue;
+        }
+
+        let rejected = |error: Error| ZipEntry { name: name.clone(), extracted: Err(error) };
+        let Some(path) = entry.enclosed_name().filter(|path| path.components().all(|part| matches!(part, Component::Normal(_)))) else {
+            entries.push(rejected(Error::InvalidRequest("The entry's path leads out of the archive".to_string())));
+            continue;
+        };
+        if path.components().count() > limits.max_depth {
+            entries.push(rejected(Error::InvalidRequest(format!("The entry is nested deeper than {} levels", limits.max_depth))));
+            continue;
+        }
+        let file_name = sanitize_filename::sanitize(path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default());
+        if !is_type_allowed(allowed_types, &file_name, None) {
+            entries.push(rejected(Error::UnsupportedMediaType("File type is not allowed".to_string())));
+            continue;
+        }
+
+        // The sizes in the archive can lie; count what actually comes out
+        let id = Uuid::new_v4();
+        let file_path = temp_dir.join(format!("{}_{}", id, file_name));
+        match write_entry(&mut entry, &file_path, remaining, reservation) {
+            Ok(Ok((written, content_hash))) => {
+                remaining -= written;
+                // Only images are hosted, whatever the entry's name says
+                if !is_image(&file_path).unwrap_or(false) {
+                    remove_temp_file(&file_path);
+                    entries.push(rejected(Error::UnsupportedMediaType("Only images are extracted from archives".to_string())));
+                    continue;
+                }
+                debug!("Extracted {:?} from the archive to {:?}", name, file_path);
+                let content_type = mime_guess::from_path(&file_name).first().map(|mime| mime.to_string());
+                let saved = SavedFile {
+                    id,
+                    file_path: file_path.to_string_lossy().into_owned(),
+                    content_hash,
+                    fields: HashMap::new(),
+                    file_name,
+                    content_type,
+                };
+                entries.push(ZipEntry { name, extracted: Ok(saved) });
+            }
+            Ok(Err(e)) => {
+                if matches!(e, Error::StorageFull) {
+                    error!("Rejected archive, its entries exceed the temp directory quota");
+                }
+                discard(&entries);
+                return Err(e);
+            }
+            Err(e) => {
+                error!("Failed to extract {:?} from the archive: {:?}", name, e);
+                remove_temp_file(&file_path);
+                entries.push(rejected(Error::InvalidRequest(format!("Failed to extract the entry: {}", e))));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Copy an entry to a file, returning its size and hex SHA-256. The file is removed again once it
+// goes over the limit or the quota refuses to grow, which is returned as the inner error.
+pub(crate) fn write_entry(
+    entry: &mut impl Read,
+    file_path: &Path,
+    limit: u64,
+    reservation: &mut QuotaReservation<'_>,
+) -> std::io::Result<Result<(u64, String), Error>> {
+    let mut file = File::create(file_path)?;
+    let mut hasher = Sha256::new();
+    let mut written = 0u64;
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let read = entry.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        written += read as u64;
+        let refused = if written > limit {
+            Some(Error::PayloadTooLarge)
+        } else if !reservation.grow(read as u64) {
+            Some(Error::StorageFull)
+        } else {
+            None
+        };
+        if let Some(e) = refused {
+            drop(file);
+            remove_temp_file(file_path);
+            return Ok(Err(e));
+        }
+        hasher.update(&chunk[..read]);
+        file.write_all(&chunk[..read])?;
+    }
+    Ok(Ok((written, to_hex(&hasher.finalize()))))
+}
+
+// Files archivers add on their own, like macOS resource forks and .DS_Store
+pub(crate) fn is_junk(name: &str) -> bool {
+    name.starts_with("__MACOSX/") || name.rsplit('/').next().is_some_and(|file_name| file_name.starts_with('.'))
+}
+
+// Remove what was extracted before the archive as a whole was turned away
+pub(crate) fn discard(entries: &[ZipEntry]) {
+    for saved in entries.iter().filter_map(|entry| entry.extracted.as_ref().ok()) {
+        remove_temp_file(Path::new(&saved.file_path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TempQuota;
+    use zip::write::SimpleFileOptions;
+
+    fn limits(max_entries: usize, max_depth: usize, max_total_bytes: u64) -> ZipUploadConfig {
+        ZipUploadConfig { max_entries, max_depth, max_total_bytes }
+    }
+
+    // Write an archive of the given entries, where a name ending in / is a directory
+    fn archive(dir: &Path, entries: &[(&str, &[u8])], method: zip::CompressionMethod) -> std::path::PathBuf {
+        let path = dir.join("upload.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(method);
+        for (name, contents) in entries {
+            if name.ends_with('/') {
+                writer.add_directory(*name, options).unwrap();
+            } else {
+                writer.start_file(*name, options).unwrap();
+                writer.write_all(contents).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn extract(entries: &[(&str, &[u8])], limits: &ZipUploadConfig) -> (tempfile::TempDir, Result<Vec<ZipEntry>, Error>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive(dir.path(), entries, zip::CompressionMethod::Stored);
+        let quota = TempQuota::new(None);
+        let extracted = extract_zip(&path, dir.path(), limits, &[], &mut quota.reserve());
+        (dir, extracted)
+    }
+
+    // A PNG padded with trailing bytes to the given size, still sniffed as an image
+    fn png(size: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::DynamicImage::new_rgb8(1, 1).write_to(&mut std::io::Cursor::new(&mut bytes), image::ImageFormat::Png).unwrap();
+        assert!(bytes.len() <= size);
+        bytes.resize(size, 0);
+        bytes
+    }
+
+    // Files in the temp directory other than the archive
+    fn extracted_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().filter(|entry| entry.as_ref().unwrap().file_name() != "upload.zip").count()
+    }
+
+    #[test]
+    fn extracts_files_and_skips_junk() {
+        let (first, second) = (png(100), png(120));
+        let (dir, extracted) = extract(
+            &[("a.png", &first), ("pics/", b""), ("pics/b.png", &second), ("__MACOSX/._a.png", b"fork"), (".DS_Store", b"")],
+            &ZipUploadConfig::default(),
+        );
+        let entries = extracted.unwrap();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["a.png", "pics/b.png"]);
+        let saved = entries[1].extracted.as_ref().unwrap();
+        assert_eq!(saved.file_name, "b.png");
+        assert!(saved.file_path.starts_with(&*dir.path().to_string_lossy()));
+        assert!(Path::new(&saved.file_path).file_name().unwrap().to_string_lossy().ends_with("_b.png"));
+        assert_eq!(std::fs::read(&saved.file_path).unwrap(), second);
+        assert_eq!(saved.content_hash, to_hex(&Sha256::digest(&second)));
+    }
+
+    #[test]
+    fn only_extracts_images() {
+        let image = png(100);
+        let (dir, extracted) = extract(&[("a.png", &image), ("notes.png", b"not an image"), ("run.sh", b"#!/bin/sh")], &ZipUploadConfig::default());
+        let entries = extracted.unwrap();
+        assert!(entries[0].extracted.is_ok());
+        for entry in &entries[1..] {
+            assert!(matches!(entry.extracted, Err(Error::UnsupportedMediaType(_))), "{} was extracted", entry.name);
+        }
+        assert_eq!(extracted_files(dir.path()), 1);
+    }
+
+    #[test]
+    fn stops_once_the_quota_is_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (png(600), png(600));
+        let path = archive(dir.path(), &[("a.png", &first), ("b.png", &second)], zip::CompressionMethod::Stored);
+        let quota = TempQuota::new(Some(1000));
+        let mut reservation = quota.reserve();
+        let extracted = extract_zip(&path, dir.path(), &ZipUploadConfig::default(), &[], &mut reservation);
+        assert!(matches!(extracted, Err(Error::StorageFull)));
+        assert_eq!(extracted_files(dir.path()), 0);
+        drop(reservation);
+        assert_eq!(quota.spooled.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        let quota = TempQuota::new(Some(1200));
+        let mut reservation = quota.reserve();
+        let entries = extract_zip(&path, dir.path(), &ZipUploadConfig::default(), &[], &mut reservation).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(reservation.bytes, 1200);
+    }
+
+    #[test]
+    fn rejects_archives_with_too_many_entries() {
+        let image = png(100);
+        let (dir, extracted) = extract(&[("a.png", &image), ("b/", b""), ("b/c.png", &image)], &limits(2, 4, 1024));
+        assert!(matches!(extracted, Err(Error::InvalidRequest(_))));
+        assert_eq!(extracted_files(dir.path()), 0);
+
+        let (_dir, extracted) = extract(&[("a.png", &image), ("b/", b""), ("b/c.png", &image)], &limits(3, 4, 1024));
+        assert_eq!(extracted.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_entries_nested_too_deep() {
+        let image = png(100);
+        let (_dir, extracted) = extract(&[("a/b.png", &image), ("a/b/c.png", &image)], &limits(10, 2, 1024));
+        let entries = extracted.unwrap();
+        assert!(entries[0].extracted.is_ok());
+        assert_eq!(entries[1].name, "a/b/c.png");
+        assert!(matches!(entries[1].extracted, Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn rejects_entries_leading_out_of_the_archive() {
+        let entries: &[(&str, &[u8])] = &[("../evil.png", b"1"), ("a/../../evil.png", b"2"), ("/etc/evil.png", b"3")];
+        let (dir, extracted) = extract(entries, &limits(10, 4, 1024));
+        let entries = extracted.unwrap();
+        assert_eq!(entries.len(), 3);
+        for entry in &entries {
+            assert!(matches!(entry.extracted, Err(Error::InvalidRequest(_))), "{} was extracted", entry.name);
+        }
+        assert_eq!(extracted_files(dir.path()), 0);
+        assert!(!dir.path().parent().unwrap().join("evil.png").exists());
+    }
+
+    #[test]
+    fn rejects_archives_over_the_total_size() {
+        let (dir, extracted) = extract(&[("a.png", &png(600)), ("b.png", &png(600))], &limits(10, 4, 1000));
+        assert!(matches!(extracted, Err(Error::PayloadTooLarge)));
+        // What was extracted before the limit was hit is removed again
+        assert_eq!(extracted_files(dir.path()), 0);
+
+        let (_dir, extracted) = extract(&[("a.png", &png(500)), ("b.png", &png(500))], &limits(10, 4, 1000));
+        assert_eq!(extracted.unwrap().iter().filter(|entry| entry.extracted.is_ok()).count(), 2);
+    }
+
+    #[test]
+    fn counts_what_comes_out_rather_than_the_declared_size() {
+        // A bomb claiming to hold 10 bytes that inflates to 100 KB
+        let dir = tempfile::tempdir().unwrap();
+        let path = archive(dir.path(), &[("bomb.png", &[0; 100 * 1024])], zip::CompressionMethod::Deflated);
+        let mut bytes = std::fs::read(&path).unwrap();
+        lie_about_sizes(&mut bytes, 10);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let quota = TempQuota::new(None);
+        let extracted = extract_zip(&path, dir.path(), &limits(10, 4, 1000), &[], &mut quota.reserve());
+        assert!(matches!(extracted, Err(Error::PayloadTooLarge)));
+        assert_eq!(extracted_files(dir.path()), 0);
+    }
+
+    #[test]
+    fn write_entry_stops_at_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entry");
+        let quota = TempQuota::new(None);
+        let mut reservation = quota.reserve();
+        assert_eq!(write_entry(&mut &[1u8; 100][..], &path, 100, &mut reservation).unwrap().unwrap().0, 100);
+        assert_eq!(std::fs::read(&path).unwrap().len(), 100);
+        assert!(matches!(write_entry(&mut &[1u8; 101][..], &path, 100, &mut reservation).unwrap(), Err(Error::PayloadTooLarge)));
+        assert!(!path.exists());
+    }
+
+    // Overwrite the uncompressed size in every local header and central directory entry
+    fn lie_about_sizes(bytes: &mut [u8], size: u32) {
+        let size = size.to_le_bytes();
+        for offset in 0..bytes.len().saturating_sub(4) {
+            match &bytes[offset..offset + 4] {
+                b"PK\x03\x04" => bytes[offset + 22..offset + 26].copy_from_slice(&size),
+                b"PK\x01\x02" => bytes[offset + 24..offset + 28].copy_from_slice(&size),
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,320 @@
+// The bot side: re-hosting files sent in private chats and answering admin commands
+
+use actix_web::web;
+use chrono::Utc;
+use std::path::Path;
+use teloxide::prelude::*;
+use teloxide::types::ReplyParameters;
+use teloxide::utils::command::BotCommands;
+use uuid::Uuid;
+use tracing::{debug, error, info, warn, Instrument};
+use crate::audit::{AuditAction, AuditEntry, AuditSource};
+use crate::config::InboundConfig;
+use crate::error::Error;
+use crate::telegram::{TelegramUploader, UploadMode};
+use crate::storage::{save_bytes, SavedFile, remove_temp_file};
+use crate::upload::{Destination, HostedFile, UploadData, WebhookPayload, host_file, is_type_allowed, notify_webhooks, remove_upload};
+use crate::metrics::InFlight;
+
+// What the dispatcher answering messages needs besides the upload data
+#[derive(Debug, Clone)]
+pub(crate) struct BotSettings {
+    // Who may upload by private message, None if nobody may
+    pub(crate) inbound: Option<InboundConfig>,
+    pub(crate) admin_commands: bool,
+    // Links in replies start with this
+    pub(crate) base_url: String,
+}
+
+// Commands admins of the target chats can send
+#[derive(Debug, Clone, BotCommands)]
+#[command(rename_rule = "lowercase")]
+pub(crate) enum AdminCommand {
+    #[command(description = "show upload statistics")]
+    Stats,
+    #[command(description = "list the latest uploads")]
+    Recent,
+    #[command(description = "delete an upload by its ID")]
+    Delete(String),
+}
+
+// Uploads listed by /recent
+pub(crate) const RECENT_UPLOADS: usize = 10;
+
+// Answer messages to the primary bot: files sent in private chats and admin commands in the target chats
+pub(crate) async fn run_bot(data: web::Data<UploadData>, settings: BotSettings) {
+    let in_target_chat = |message: Message, data: web::Data<UploadData>, settings: BotSettings| {
+        settings.admin_commands && data.chat_ids.contains(&message.chat.id)
+    };
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .branch(
+                    dptree::filter(|message: Message, settings: BotSettings| {
+                        settings.inbound.is_some() && message.chat.is_private()
+                    })
+                    .endpoint(handle_private_message),
+                )
+                .branch(dptree::filter(in_target_chat).filter_command::<AdminCommand>().endpoint(handle_admin_command)),
+        )
+        .branch(
+            Update::filter_channel_post()
+                .filter(in_target_chat)
+                .filter_command::<AdminCommand>()
+                .endpoint(handle_admin_command),
+        );
+
+    let Some(bot) = data.bots.primary().bot().cloned() else {
+        warn!("The Telegram client can't receive messages, not listening for any");
+        return;
+    };
+    info!("Listening for messages to the bot");
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![data, settings])
+        .default_handler(|_| async {})
+        .build()
+        .dispatch()
+        .await;
+}
+
+pub(crate) async fn handle_private_message(
+    bot: Bot,
+    message: Message,
+    data: web::Data<UploadData>,
+    settings: BotSettings,
+) -> ResponseResult<()> {
+    let user_id = message.from.as_ref().map(|user| user.id.0);
+    let span = tracing::info_span!("inbound_upload", user_id);
+    let reply = host_message(&message, &data, &settings, user_id).instrument(span).await;
+    bot.send_message(message.chat.id, reply).reply_parameters(ReplyParameters::new(message.id)).await?;
+    Ok(())
+}
+
+// Channel posts and anonymous admins are sent as the chat itself; everyone else is looked up
+pub(crate) async fn is_chat_admin(bot: &Bot, message: &Message) -> bool {
+    if message.sender_chat.as_ref().is_some_and(|sender| sender.id == message.chat.id) {
+        return true;
+    }
+    let Some(user) = &message.from else {
+        return false;
+    };
+    match bot.get_chat_member(message.chat.id, user.id).await {
+        Ok(member) => member.is_privileged(),
+        Err(e) => {
+            error!("Failed to look up member {} of chat {}: {:?}", user.id, message.chat.id, e);
+            false
+        }
+    }
+}
+
+pub(crate) async fn handle_admin_command(
+    bot: Bot,
+    message: Message,
+    command: AdminCommand,
+    data: web::Data<UploadData>,
+    settings: BotSettings,
+) -> ResponseResult<()> {
+    if !is_chat_admin(&bot, &message).await {
+        debug!("Ignored {:?} from a non-admin in chat {}", command, message.chat.id);
+        return Ok(());
+    }
+
+    let span = tracing::info_span!("admin_command", command = ?command, chat_id = message.chat.id.0);
+    let user_id = message.from.as_ref().map(|user| user.id.0);
+    let reply = admin_command_reply(command, &data, &settings, user_id).instrument(span).await;
+    bot.send_message(message.chat.id, reply).reply_parameters(ReplyParameters::new(message.id)).await?;
+    Ok(())
+}
+
+pub(crate) async fn admin_command_reply(
+    command: AdminCommand,
+    data: &UploadData,
+    settings: &BotSettings,
+    user_id: Option<u64>,
+) -> String {
+    match command {
+        AdminCommand::Stats => {
+            let records = data.registry.records();
+            let day_ago = Utc::now() - chrono::Duration::days(1);
+            let last_day = records.iter().filter(|record| record.uploaded_at > day_ago).count();
+            let bytes: u64 = records.iter().filter_map(|record| record.size).sum();
+            let pending = data.outbox.as_ref().map_or(0, |outbox| outbox.pending().len());
+            let circuit = if data.circuit_breaker.check().is_err() { "open" } else { "closed" };
+            format!(
+                "Uploads: {}\nIn the last 24 hours: {}\nTotal size: {:.1} MB\nWaiting in the outbox: {}\nTelegram circuit: {}",
+                records.len(),
+                last_day,
+                bytes as f64 / (1024.0 * 1024.0),
+                pending,
+                circuit
+            )
+        }
+        AdminCommand::Recent => {
+            let records = data.registry.records();
+            let upload_settings = data.settings();
+            if records.is_empty() {
+                return "No uploads yet.".to_string();
+            }
+            records
+                .iter()
+                .take(RECENT_UPLOADS)
+                .map(|record| {
+                    let uploaded_at = record.uploaded_at.format("%Y-%m-%d %H:%M");
+                    format!("{} {}", uploaded_at, upload_settings.file_url(&settings.base_url, &record.id))
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        AdminCommand::Delete(id) => {
+            let Some(record) = Uuid::parse_str(id.trim()).ok().and_then(|id| data.registry.get(&id)) else {
+                return "Upload not found. Usage: /delete <id>".to_string();
+            };
+            let mut entry = AuditEntry {
+                telegram_user_id: user_id,
+                ..AuditEntry::of(AuditAction::Delete, AuditSource::Bot, &record)
+            };
+            let reply = match remove_upload(data, &record).await {
+                Ok(()) => {
+                    info!("Deleted upload {} by admin command", record.id);
+                    format!("Deleted upload {}", record.id)
+                }
+                Err(e) => {
+                    error!("Failed to remove upload {} from the registry: {:?}", record.id, e);
+                    (entry.success, entry.error) = (false, Some(e.to_string()));
+                    format!("Failed to delete upload: {}", e)
+                }
+            };
+            data.audit(entry);
+            reply
+        }
+    }
+}
+
+// Re-host the photo or file in a message, returning the text to reply with
+pub(crate) async fn host_message(
+    message: &Message,
+    data: &UploadData,
+    settings: &BotSettings,
+    user_id: Option<u64>,
+) -> String {
+    let allowed_users = settings.inbound.as_ref().map(|inbound| inbound.allowed_users.as_slice()).unwrap_or_default();
+    if !allowed_users.is_empty() && !user_id.is_some_and(|user_id| allowed_users.contains(&user_id)) {
+        info!("Ignored upload from user {:?}, who isn't allowed to upload", user_id);
+        return "You are not allowed to upload files here.".to_string();
+    }
+
+    // Photos come in several sizes, the last one is the original
+    let (file, file_name, content_type) = if let Some(photo) = message.photo().and_then(|sizes| sizes.last()) {
+        (&photo.file, "photo.jpg".to_string(), Some("image/jpeg".to_string()))
+    } else if let Some(document) = message.document() {
+        let file_name = sanitize_filename::sanitize(document.file_name.as_deref().unwrap_or("file"));
+        let content_type = mime_guess::from_path(&file_name)
+            .first()
+            .or_else(|| document.mime_type.clone())
+            .map(|mime| mime.to_string());
+        (&document.file, file_name, content_type)
+    } else {
+        return "Send me a photo or a file to get a link to it.".to_string();
+    };
+
+    data.metrics.uploads_total.inc();
+    let _in_flight = InFlight::new(&data.metrics.uploads_in_flight);
+
+    let declared = content_type.as_deref().and_then(|content_type| content_type.parse().ok());
+    let upload_settings = data.settings();
+    if !is_type_allowed(&upload_settings.allowed_types, &file_name, declared.as_ref()) {
+        error!("Rejected file with disallowed type: {:?} ({:?})", file_name, content_type);
+        data.metrics.upload_failed("save");
+        return "This file type is not allowed.".to_string();
+    }
+
+    let circuit_open = data.circuit_breaker.check().is_err();
+    if circuit_open && data.outbox.is_none() {
+        data.metrics.upload_failed("circuit_open");
+        return "Telegram is currently unavailable, try again later.".to_string();
+    }
+
+    let mut reservation = data.temp_quota.reserve();
+    if !reservation.grow(file.size as u64) {
+        error!("Rejected upload of {} bytes, temp directory quota exceeded", file.size);
+        data.metrics.upload_failed("quota");
+        return "The server is out of space, try again later.".to_string();
+    }
+
+    let saved = match download_to_temp(data.bots.primary(), &file.id, &file_name, content_type, data).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            error!("Failed to download file sent to the bot: {:?}", e);
+            data.metrics.upload_failed("save");
+            return format!("Failed to download file: {}", e);
+        }
+    };
+    reservation.hold(saved.id);
+    let path = Path::new(&saved.file_path);
+
+    // The caption of the message goes along with the upload
+    let mut options = upload_settings.send_options.clone();
+    options.caption = message.caption().map(str::to_string).or(options.caption);
+    if let Some(entities) = message.caption_entities() {
+        (options.parse_mode, options.caption_entities) = (None, Some(entities.to_vec()));
+    }
+    options.mode = if message.photo().is_some() {
+        UploadMode::Photo
+    } else {
+        match options.mode.resolve(path, saved.content_type.as_deref()) {
+            Ok(mode) => mode,
+            Err(e) => {
+                error!("Rejected upload of {:?} as {:?}: {}", saved.content_type, options.mode, e);
+                data.metrics.upload_failed("invalid");
+                remove_temp_file(path);
+                notify_webhooks(data, &WebhookPayload::failed(saved.id, Some(&saved.file_name), &e));
+                return e;
+            }
+        }
+    };
+    options.file_name = Some(saved.file_name.clone());
+
+    let hosted = host_file(data, &saved, &options, &Destination::default(), circuit_open).await;
+    data.audit(AuditEntry {
+        telegram_user_id: user_id,
+        upload_id: match &hosted {
+            Ok(HostedFile::Sent { record, .. }) => Some(record.id),
+            Ok(HostedFile::Queued { entry, .. }) => Some(entry.id),
+            Err(_) => None,
+        },
+        content_hash: Some(saved.content_hash.clone()),
+        error: hosted.as_ref().err().map(Error::to_string),
+        ..AuditEntry::new(AuditAction::Upload, AuditSource::Bot, hosted.is_ok())
+    });
+    match hosted {
+        // Links to the proxy: Telegram's file URL carries the bot token
+        Ok(HostedFile::Sent { record, .. }) => {
+            let url = upload_settings.file_url(&settings.base_url, &record.id);
+            info!("Hosted file sent to the bot as upload {}", record.id);
+            let deletion_url = format!("{}/delete/{}/{}", settings.base_url, record.id, record.deletion_token);
+            format!("{}\n\nDelete it again: {}", url, deletion_url)
+        }
+        Ok(HostedFile::Queued { entry, .. }) => format!(
+            "Telegram is busy, the file will be at {} once it has been delivered.",
+            upload_settings.file_url(&settings.base_url, &entry.id)
+        ),
+        Err(e) => e.to_string(),
+    }
+}
+
+// Fetch a file someone sent to the bot into the temp dir, like a multipart upload would be saved
+pub(crate) async fn download_to_temp(
+    telegram: &dyn TelegramUploader,
+    file_id: &str,
+    file_name: &str,
+    content_type: Option<String>,
+    data: &UploadData,
+) -> Result<SavedFile, Error> {
+    let file_path = data.metrics.time_telegram("get_file", telegram.get_file(file_id)).await?;
+    let bytes = data.metrics.time_telegram("download_file", telegram.download_file(&file_path)).await?;
+
+    save_bytes(&data.temp_dir, &bytes, file_name, content_type).await.map_err(|e| {
+        error!("Failed to save file sent to the bot: {:?}", e);
+        Error::Internal("Failed to save file".to_string())
+    })
+}
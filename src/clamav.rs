@@ -0,0 +1,152 @@
+// Virus scanning of uploads through clamd's INSTREAM command
+
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{error, info, warn};
+use crate::config::ClamavConfig;
+use crate::error::Error;
+use crate::metrics::Metrics;
+
+// Bytes sent to clamd per INSTREAM chunk
+pub(crate) const CLAMAV_CHUNK_SIZE: usize = 64 * 1024;
+
+// What clamd found in a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ScanResult {
+    Clean,
+    // With the name of the signature that matched
+    Infected(String),
+}
+
+// Scan a file before it is hosted, rejecting it when clamd finds something. A clamd that
+// can't be reached rejects the upload too, unless fail_open is set.
+pub(crate) async fn scan_upload(config: &ClamavConfig, metrics: &Metrics, file_path: &Path) -> Result<(), Error> {
+    let timeout = Duration::from_secs(config.timeout_secs.max(1));
+    let result = match tokio::time::timeout(timeout, scan_file(&config.address, file_path)).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "clamd didn't answer in time")),
+    };
+
+    match result {
+        Ok(ScanResult::Clean) => {
+            metrics.clamav_scans.with_label_values(&["clean"]).inc();
+            info!("Scanned {:?}: clean", file_path);
+            Ok(())
+        }
+        Ok(ScanResult::Infected(signature)) => {
+            metrics.clamav_scans.with_label_values(&["infected"]).inc();
+            warn!("Scanned {:?}: infected with {}", file_path, signature);
+            Err(Error::Infected(signature))
+        }
+        Err(e) => {
+            metrics.clamav_scans.with_label_values(&["error"]).inc();
+            if config.fail_open {
+                warn!("Failed to scan {:?}, hosting it unscanned: {:?}", file_path, e);
+                return Ok(());
+            }
+            error!("Failed to scan {:?}: {:?}", file_path, e);
+            Err(Error::Internal("Virus scanning is unavailable".to_string()))
+        }
+    }
+}
+
+// Stream a file to clamd, at host:port or at unix:/path/to/clamd.sock
+pub(crate) async fn scan_file(address: &str, file_path: &Path) -> std::io::Result<ScanResult> {
+    let file = tokio::fs::File::open(file_path).await?;
+    if let Some(socket_path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return instream(tokio::net::UnixStream::connect(socket_path).await?, file).await;
+        #[cfg(not(unix))]
+        return Err(std::io::Error::other(format!("Unix sockets aren't supported here: {}", socket_path)));
+    }
+    instream(tokio::net::TcpStream::connect(address).await?, file).await
+}
+
+// The INSTREAM exchange: length-prefixed chunks ended by an empty one, answered with a single
+// line like "stream: OK" or "stream: Eicar-Signature FOUND"
+pub(crate) async fn instream(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    mut file: impl AsyncRead + Unpin,
+) -> std::io::Result<ScanResult> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut chunk = vec![0; CLAMAV_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk).await?;
+        stream.write_all(&(read as u32).to_be_bytes()).await?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&chunk[..read]).await?;
+    }
+    stream.flush().await?;
+
+    // The reply ends with a NUL, or with the connection
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    while stream.read(&mut byte).await? == 1 && byte[0] != 0 {
+        reply.push(byte[0]);
+    }
+    let reply = String::from_utf8_lossy(&reply);
+    let reply = reply.trim_end_matches(['\0', '\n']).trim_start_matches("stream:").trim();
+    if reply == "OK" {
+        Ok(ScanResult::Clean)
+    } else if let Some(signature) = reply.strip_suffix(" FOUND") {
+        Ok(ScanResult::Infected(signature.to_string()))
+    } else {
+        Err(std::io::Error::other(format!("Unexpected answer from clamd: {}", reply)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Play clamd on the other end of a stream: take an INSTREAM scan and answer it
+    async fn clamd(mut stream: tokio::io::DuplexStream, reply: &'static [u8]) -> (Vec<u8>, Vec<usize>) {
+        let mut command = [0; 10];
+        stream.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+        let (mut received, mut chunks) = (Vec::new(), Vec::new());
+        loop {
+            let mut length = [0; 4];
+            stream.read_exact(&mut length).await.unwrap();
+            let length = u32::from_be_bytes(length) as usize;
+            if length == 0 {
+                break;
+            }
+            let mut chunk = vec![0; length];
+            stream.read_exact(&mut chunk).await.unwrap();
+            received.extend_from_slice(&chunk);
+            chunks.push(length);
+        }
+        stream.write_all(reply).await.unwrap();
+        (received, chunks)
+    }
+
+    #[actix_web::test]
+    async fn streams_files_in_length_prefixed_chunks() {
+        let file: Vec<u8> = (0..150 * 1024).map(|i| i as u8).collect();
+        let (client, server) = tokio::io::duplex(8 * 1024);
+        let clamd = tokio::spawn(clamd(server, b"stream: OK\0"));
+        assert_eq!(instream(client, file.as_slice()).await.unwrap(), ScanResult::Clean);
+        let (received, chunks) = clamd.await.unwrap();
+        assert_eq!(chunks, [CLAMAV_CHUNK_SIZE, CLAMAV_CHUNK_SIZE, 150 * 1024 - 2 * CLAMAV_CHUNK_SIZE]);
+        assert_eq!(received, file);
+    }
+
+    #[actix_web::test]
+    async fn reads_what_clamd_found() {
+        let scan = |reply: &'static [u8]| async move {
+            let (client, server) = tokio::io::duplex(8 * 1024);
+            let clamd = tokio::spawn(clamd(server, reply));
+            let result = instream(client, &b"X5O!P%@AP"[..]).await;
+            clamd.await.unwrap();
+            result
+        };
+        assert_eq!(scan(b"stream: Eicar-Signature FOUND\0").await.unwrap(), ScanResult::Infected("Eicar-Signature".to_string()));
+        // Without the NUL, the reply ends with the connection
+        assert_eq!(scan(b"stream: OK\n").await.unwrap(), ScanResult::Clean);
+        assert!(scan(b"INSTREAM size limit exceeded. ERROR\0").await.is_err());
+    }
+}
@@ -0,0 +1,197 @@
+// The upload subcommand: a client for a running server, so scripts can host files without
+// putting together multipart requests themselves
+
+use reqwest::header::RETRY_AFTER;
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub(crate) const UPLOAD_USAGE: &str = "\
+Usage: anarchic-image-hosting-bot upload [options] <files...>
+
+Options:
+  --server <url>     Base URL of the server (or ANARCHIC_SERVER)
+  --key <key>        API key to upload with (or ANARCHIC_API_KEY)
+  --as <mode>        Send the files as photo, document, video or auto
+  --ttl <seconds>    Delete the uploads after this long
+  --retries <n>      Times to retry a failed upload (default 3)
+  --json             Print the server's answer for each file as a line of JSON
+  -h, --help         Show this help";
+
+// Longest wait between retries, whatever the server asks for
+pub(crate) const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// What the upload subcommand was asked to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UploadArgs {
+    pub(crate) server: String,
+    pub(crate) key: Option<String>,
+    pub(crate) upload_mode: Option<String>,
+    pub(crate) ttl: Option<u64>,
+    pub(crate) retries: u32,
+    pub(crate) json: bool,
+    pub(crate) files: Vec<String>,
+}
+
+impl UploadArgs {
+    // None when only the help was asked for
+    pub(crate) fn parse(args: &[String]) -> Result<Option<UploadArgs>, String> {
+        let mut server = std::env::var("ANARCHIC_SERVER").ok();
+        let mut key = std::env::var("ANARCHIC_API_KEY").ok().filter(|key| !key.is_empty());
+        let mut upload_mode = None;
+        let mut ttl = None;
+        let mut retries = 3;
+        let mut json = false;
+        let mut files = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().cloned().ok_or_else(|| format!("{} needs a value", name));
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--server" => server = Some(value(arg)?),
+                "--key" => key = Some(value(arg)?),
+                "--as" => upload_mode = Some(value(arg)?),
+                "--ttl" => ttl = Some(value(arg)?.parse().map_err(|_| "--ttl must be a number of seconds".to_string())?),
+                "--retries" => retries = value(arg)?.parse().map_err(|_| "--retries must be a number".to_string())?,
+                "--json" => json = true,
+                "--" => files.extend(args.by_ref().cloned()),
+                option if option.starts_with('-') && option != "-" => return Err(format!("Unknown option {}", option)),
+                file => files.push(file.to_string()),
+            }
+        }
+
+        let server = server.ok_or("--server is required")?.trim_end_matches('/').to_string();
+        if files.is_empty() {
+            return Err("No files to upload".to_string());
+        }
+        Ok(Some(UploadArgs { server, key, upload_mode, ttl, retries, json, files }))
+    }
+}
+
+// Run the subcommand, returning the exit code: 0 when every file was hosted, 1 when some
+// weren't and 2 for invalid arguments
+pub async fn upload_command(args: &[String]) -> i32 {
+    let args = match UploadArgs::parse(args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", UPLOAD_USAGE);
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, UPLOAD_USAGE);
+            return 2;
+        }
+    };
+
+    let client = match reqwest::Client::builder().build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to set up the HTTP client: {}", e);
+            return 1;
+        }
+    };
+    let mut failed = 0;
+    for file in &args.files {
+        match upload_with_retries(&client, &args, Path::new(file)).await {
+            Ok(mut response) if args.json => {
+                response["file"] = file.as_str().into();
+                println!("{}", response);
+            }
+            Ok(response) => println!("{}", response["url"].as_str().unwrap_or_default()),
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", file, e);
+                if args.json {
+                    println!("{}", serde_json::json!({ "file": file, "error": e }));
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+// Upload a file, trying again while the server or the connection to it fails. Every attempt
+// carries the same Idempotency-Key, so one that got through only counts once.
+pub(crate) async fn upload_with_retries(
+    client: &reqwest::Client,
+    args: &UploadArgs,
+    file_path: &Path,
+) -> Result<serde_json::Value, String> {
+    let bytes = tokio::fs::read(file_path).await.map_err(|e| format!("Failed to read the file: {}", e))?;
+    let file_name = file_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    let mut query = vec![("format", "json".to_string())];
+    if let Some(upload_mode) = &args.upload_mode {
+        query.push(("as", upload_mode.clone()));
+    }
+    if let Some(ttl) = args.ttl {
+        query.push(("ttl", ttl.to_string()));
+    }
+
+    let mut attempt = 0;
+    loop {
+        let form = Form::new().part("file", Part::bytes(bytes.clone()).file_name(file_name.clone()));
+        let mut request =
+            client.post(format!("{}/upload", args.server)).query(&query).header("Idempotency-Key", &idempotency_key).multipart(form);
+        if let Some(key) = &args.key {
+            request = request.bearer_auth(key);
+        }
+
+        let (error, retry_after) = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                return response.json().await.map_err(|e| format!("The server's answer isn't JSON: {}", e));
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .map(Duration::from_secs);
+                // Errors come as JSON, except those actix-web answers before the upload handler runs
+                let body = response.text().await.unwrap_or_default();
+                let message = match serde_json::from_str::<serde_json::Value>(&body) {
+                    Ok(body) => body["message"].as_str().unwrap_or_default().to_string(),
+                    Err(_) => body.trim().to_string(),
+                };
+                let message = if message.is_empty() { status.to_string() } else { format!("{} ({})", message, status) };
+                if !is_retryable(status) {
+                    return Err(message);
+                }
+                (message, retry_after)
+            }
+            Err(e) => (format!("Failed to reach the server: {}", e), None),
+        };
+
+        if attempt >= args.retries {
+            return Err(error);
+        }
+        let delay = retry_after.unwrap_or(Duration::from_secs(1 << attempt.min(6))).min(MAX_RETRY_DELAY);
+        eprintln!("{}: {}, retrying in {}s", file_path.display(), error, delay.as_secs());
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Failures that may go away on their own: timeouts, rate limits, an unreachable Telegram, a busy
+// server or a full temp directory, and an upload with the same Idempotency-Key still in progress
+pub(crate) fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::CONFLICT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+            | StatusCode::INSUFFICIENT_STORAGE
+    )
+}
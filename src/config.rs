@@ -0,0 +1,895 @@
+// Configuration, read from a JSON5 file
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+#[allow(clippy::single_component_path_imports)]
+use json5;
+use crate::telegram::UploadMode;
+use crate::image::{default_convert_timeout_secs, default_jpeg_quality, default_max_dimension};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub(crate) telegram_bot_token: String,
+    // Further bots to spread uploads over
+    #[serde(default)]
+    pub(crate) telegram_bot_tokens: Vec<String>,
+    // Bot API server to talk to instead of api.telegram.org, e.g. a self-hosted one
+    #[serde(default)]
+    pub(crate) api_url: Option<url::Url>,
+    // Single chat to send uploads to, used when chat_ids is empty
+    #[serde(default, alias = "chat")]
+    pub(crate) chat_id: Option<ChatRef>,
+    // Chats to send uploads to, in order of preference
+    #[serde(default)]
+    pub(crate) chat_ids: Vec<ChatRef>,
+    #[serde(default)]
+    pub(crate) chat_mode: ChatMode,
+    // Forum topic to post uploads into
+    #[serde(default)]
+    pub(crate) message_thread_id: Option<i32>,
+    // Kind of message uploads are sent as, unless they ask for another with ?as=
+    #[serde(default)]
+    pub(crate) upload_mode: UploadMode,
+    // Post uploads silently
+    #[serde(default)]
+    pub(crate) disable_notification: bool,
+    // Keep uploads from being forwarded or saved from the chat
+    #[serde(default)]
+    pub(crate) protect_content: bool,
+    // Pin every upload in the chat
+    #[serde(default)]
+    pub(crate) pin_uploads: bool,
+    // Seconds after which uploads are deleted unless they ask otherwise with ?ttl=, 0 keeps them
+    #[serde(default)]
+    pub(crate) default_ttl_secs: u64,
+    // Clients allowed to upload; anyone may upload when empty
+    #[serde(default)]
+    pub(crate) api_keys: Vec<ApiKeyConfig>,
+    pub(crate) max_concurrent_uploads: usize,
+    // What happens to uploads waiting for one of the max_concurrent_uploads slots
+    #[serde(default)]
+    pub(crate) upload_queue: UploadQueueConfig,
+    pub(crate) host: String,
+    pub(crate) port: String,
+    // Tuning of the HTTP server itself
+    #[serde(default)]
+    pub(crate) http_server: HttpServerConfig,
+    // Base URL clients reach this server at, used for links back to it
+    #[serde(default)]
+    pub(crate) public_url: Option<String>,
+    // Reverse proxies (addresses or CIDR ranges) whose X-Forwarded-For and Forwarded headers
+    // are believed when working out the client's address
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<String>,
+    // Networks allowed to use the server at all
+    #[serde(default)]
+    pub(crate) ip_filter: IpFilterConfig,
+    // Directory where uploads are stored until they have been sent to Telegram
+    #[serde(default = "default_temp_dir")]
+    pub(crate) temp_dir: PathBuf,
+    // MIME types (e.g. "image/png", "video/*") or extensions (e.g. ".jpg") accepted for upload.
+    // An empty list accepts every file.
+    #[serde(default)]
+    pub(crate) allowed_types: Vec<String>,
+    // Rotate pixels according to the EXIF orientation tag before upload
+    #[serde(default)]
+    pub(crate) auto_orient: bool,
+    // Remove EXIF/GPS metadata from JPEGs and PNGs before they are sent to Telegram
+    #[serde(default)]
+    pub(crate) strip_exif: bool,
+    // Longest side, in pixels, of photos; larger ones are scaled down and recompressed
+    #[serde(default = "default_max_dimension")]
+    pub(crate) max_dimension: u32,
+    // JPEG quality (1-100) used whenever an image is re-encoded
+    #[serde(default = "default_jpeg_quality")]
+    pub(crate) jpeg_quality: u8,
+    // External command decoding HEIC/AVIF into PNG, with {input} and {output} placeholders
+    #[serde(default)]
+    pub(crate) convert_command: Option<Vec<String>>,
+    // Seconds convert_command may run before it is killed
+    #[serde(default = "default_convert_timeout_secs")]
+    pub(crate) convert_timeout_secs: u64,
+    // Overlay stamped onto every image before upload
+    #[serde(default)]
+    pub(crate) watermark: Option<WatermarkConfig>,
+    // External classifier every image is scored by before it is posted
+    #[serde(default)]
+    pub(crate) moderation: Option<ModerationConfig>,
+    // clamd every upload is scanned by before it is posted
+    #[serde(default)]
+    pub(crate) clamav: Option<ClamavConfig>,
+    // Directory where a thumbnail of every upload is stored, served at /t/{id}
+    #[serde(default)]
+    pub(crate) thumbnail_dir: Option<PathBuf>,
+    // Directory where the original of every upload is kept, in YYYY/MM/DD subfolders
+    #[serde(default)]
+    pub(crate) archive_dir: Option<PathBuf>,
+    // Longest side of generated thumbnails, in pixels
+    #[serde(default = "default_thumbnail_size")]
+    pub(crate) thumbnail_size: u32,
+    // JSON file recording every finished upload
+    #[serde(default = "default_registry_path")]
+    pub(crate) registry_path: PathBuf,
+    // Return the existing URL when the same bytes are uploaded again
+    #[serde(default = "default_deduplicate")]
+    pub(crate) deduplicate: bool,
+    // Make /readyz call Telegram on every probe instead of only validating the token once
+    #[serde(default)]
+    pub(crate) readiness_check_telegram: bool,
+    // Number of resized variants kept in memory by the file proxy
+    #[serde(default = "default_resize_cache_size")]
+    pub(crate) resize_cache_size: usize,
+    // How long browsers and CDNs may cache served files, in seconds
+    #[serde(default = "default_cache_max_age_secs")]
+    pub(crate) cache_max_age_secs: u64,
+    // How long uploads are remembered by their Idempotency-Key header, in seconds; 0 turns it off
+    #[serde(default = "default_idempotency_window_secs")]
+    pub(crate) idempotency_window_secs: u64,
+    // Log levels, format and optional log file
+    #[serde(default)]
+    pub(crate) log: LogConfig,
+    // Export traces over OTLP/HTTP, e.g. to Jaeger
+    #[serde(default)]
+    pub(crate) otel: Option<OtelConfig>,
+    // HTTP access log, written separately from the application logs
+    #[serde(default)]
+    pub(crate) access_log: Option<AccessLogConfig>,
+    // Retrying Telegram calls that hit rate limits or transient errors
+    #[serde(default)]
+    pub(crate) retry: RetryConfig,
+    // Fast-failing uploads while Telegram is down
+    #[serde(default)]
+    pub(crate) circuit_breaker: CircuitBreakerConfig,
+    // Keep uploads that Telegram couldn't take and deliver them later
+    #[serde(default)]
+    pub(crate) outbox: Option<OutboxConfig>,
+    // Deleting temp files left behind by crashes and aborted uploads
+    #[serde(default)]
+    pub(crate) temp_cleanup: TempCleanupConfig,
+    // Rate limits on serving files through /f/{id}
+    #[serde(default)]
+    pub(crate) bandwidth: BandwidthConfig,
+    // Most bytes uploads in progress may spool to temp_dir at once
+    #[serde(default)]
+    pub(crate) temp_dir_quota: Option<u64>,
+    // Limits on archives uploaded to /upload/zip
+    #[serde(default)]
+    pub(crate) zip_upload: ZipUploadConfig,
+    // Files the proxy fetched from Telegram, kept on local disk
+    #[serde(default)]
+    pub(crate) proxy_cache: Option<ProxyCacheConfig>,
+    // Record of every upload and deletion, exported at /admin/audit
+    #[serde(default)]
+    pub(crate) audit: Option<AuditConfig>,
+    // Endpoints told about finished and failed uploads
+    #[serde(default)]
+    pub(crate) webhooks: Vec<WebhookConfig>,
+    // Re-hosting photos and files sent to the bot in a private chat
+    #[serde(default)]
+    pub(crate) inbound: Option<InboundConfig>,
+    // Answer /stats, /recent and /delete from admins of the target chats
+    #[serde(default)]
+    pub(crate) admin_commands: bool,
+    // HTML page of recent uploads at /gallery
+    #[serde(default)]
+    pub(crate) gallery: GalleryConfig,
+    // Atom feed of the latest uploads at /feed.xml
+    #[serde(default)]
+    pub(crate) feed: Option<FeedConfig>,
+    // Only serve /f/{id} to links signed with this, or to clients with an API key
+    #[serde(default)]
+    pub(crate) signed_urls: Option<SignedUrlConfig>,
+    // Swagger UI for the OpenAPI document at /openapi.json, served at /docs/
+    #[serde(default)]
+    pub(crate) swagger_ui: bool,
+    // PUT, GET and DELETE files by path under /dav/, for WebDAV clients
+    #[serde(default)]
+    pub(crate) webdav: bool,
+    // PutObject, GetObject and DeleteObject under /s3/, for tools that speak S3
+    #[serde(default)]
+    pub(crate) s3: bool,
+}
+
+#[derive(Clone, Deserialize)]
+pub(crate) struct SignedUrlConfig {
+    // Key the links are signed with; changing it invalidates every link handed out
+    pub(crate) secret: String,
+    // How long handed out links keep working
+    #[serde(default = "default_signed_url_expiry_secs")]
+    pub(crate) expiry_secs: u64,
+}
+
+pub(crate) fn default_signed_url_expiry_secs() -> u64 {
+    24 * 60 * 60
+}
+
+// Keep the secret out of the logs
+impl std::fmt::Debug for SignedUrlConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignedUrlConfig").field("expiry_secs", &self.expiry_secs).finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub(crate) struct FeedConfig {
+    // Has to be passed as ?token= to read the feed
+    pub(crate) token: String,
+    #[serde(default = "default_feed_title")]
+    pub(crate) title: String,
+    // Uploads listed in the feed
+    #[serde(default = "default_feed_entries")]
+    pub(crate) entries: usize,
+    // Name of the API key whose uploads are listed, or everyone's when unset
+    #[serde(default)]
+    pub(crate) tenant: Option<String>,
+}
+
+pub(crate) fn default_feed_title() -> String {
+    "anarchic-image-hosting-bot uploads".to_string()
+}
+
+pub(crate) fn default_feed_entries() -> usize {
+    50
+}
+
+// Keep the token out of the logs
+impl std::fmt::Debug for FeedConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FeedConfig")
+            .field("title", &self.title)
+            .field("entries", &self.entries)
+            .field("tenant", &self.tenant)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct GalleryConfig {
+    pub(crate) enabled: bool,
+    // Uploads shown per page
+    pub(crate) page_size: usize,
+}
+
+impl Default for GalleryConfig {
+    fn default() -> GalleryConfig {
+        GalleryConfig { enabled: false, page_size: 24 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct InboundConfig {
+    // Telegram user IDs that may upload this way, everyone if empty
+    #[serde(default)]
+    pub(crate) allowed_users: Vec<u64>,
+}
+
+// Upload events a webhook can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum WebhookEvent {
+    #[serde(rename = "upload.succeeded")]
+    UploadSucceeded,
+    #[serde(rename = "upload.failed")]
+    UploadFailed,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ModerationConfig {
+    // Classifier the image is POSTed to, answering with JSON like {"score": 0.12}
+    #[serde(default)]
+    pub(crate) url: Option<url::Url>,
+    // Or a local command run with {input} replaced by the path of the image, printing the same JSON
+    #[serde(default)]
+    pub(crate) command: Option<Vec<String>>,
+    // Uploads scoring above this are rejected
+    #[serde(default = "default_block_above")]
+    pub(crate) block_above: f64,
+    // Uploads scoring above this are hosted, but flagged in the registry
+    #[serde(default)]
+    pub(crate) flag_above: Option<f64>,
+    #[serde(default = "default_moderation_timeout_secs")]
+    pub(crate) timeout_secs: u64,
+    // Host uploads unchecked when the classifier fails, instead of rejecting them
+    #[serde(default)]
+    pub(crate) fail_open: bool,
+}
+
+pub(crate) fn default_block_above() -> f64 {
+    0.8
+}
+
+pub(crate) fn default_moderation_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ClamavConfig {
+    // host:port of clamd's TCP socket, or unix:/path/to/clamd.sock
+    pub(crate) address: String,
+    #[serde(default = "default_clamav_timeout_secs")]
+    pub(crate) timeout_secs: u64,
+    // Host uploads unscanned when clamd fails, instead of rejecting them
+    #[serde(default)]
+    pub(crate) fail_open: bool,
+}
+
+pub(crate) fn default_clamav_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Clone, Deserialize)]
+pub(crate) struct WebhookConfig {
+    pub(crate) url: url::Url,
+    // Key for the HMAC-SHA256 signature in X-Webhook-Signature, unsigned if unset
+    #[serde(default)]
+    pub(crate) secret: Option<String>,
+    // Events to send, all of them if empty
+    #[serde(default)]
+    pub(crate) events: Vec<WebhookEvent>,
+}
+
+impl WebhookConfig {
+    pub(crate) fn subscribes(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+// Keep the secret out of the logs
+impl std::fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookConfig")
+            .field("url", &self.url.as_str())
+            .field("signed", &self.secret.is_some())
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ProxyCacheConfig {
+    pub(crate) directory: PathBuf,
+    // Size in bytes the cached files may take up together
+    #[serde(default = "default_proxy_cache_max_bytes")]
+    pub(crate) max_bytes: u64,
+}
+
+pub(crate) fn default_proxy_cache_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct AuditConfig {
+    // JSON Lines file the entries are appended to
+    pub(crate) path: PathBuf,
+    // Entries older than this many days are dropped, 0 keeps them forever
+    #[serde(default = "default_audit_retention_days")]
+    pub(crate) retention_days: u64,
+}
+
+pub(crate) fn default_audit_retention_days() -> u64 {
+    90
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct TempCleanupConfig {
+    // Temp files not modified for this long are deleted, 0 disables the cleanup
+    pub(crate) max_age_secs: u64,
+    // How often the temp dir is checked
+    pub(crate) interval_secs: u64,
+}
+
+impl Default for TempCleanupConfig {
+    fn default() -> TempCleanupConfig {
+        TempCleanupConfig { max_age_secs: 3600, interval_secs: 600 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct ZipUploadConfig {
+    // Entries an archive may have, directories included
+    pub(crate) max_entries: usize,
+    // Directories an entry may be nested in, plus one for the file itself
+    pub(crate) max_depth: usize,
+    // Bytes all entries may extract to together
+    pub(crate) max_total_bytes: u64,
+}
+
+impl Default for ZipUploadConfig {
+    fn default() -> ZipUploadConfig {
+        ZipUploadConfig { max_entries: 100, max_depth: 4, max_total_bytes: 200 * 1024 * 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct IpFilterConfig {
+    // Addresses or CIDR ranges let in; everyone when empty
+    pub(crate) allow: Vec<String>,
+    // Addresses or CIDR ranges turned away, even when they're allowed too
+    pub(crate) deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct HttpServerConfig {
+    // Worker threads, 0 for one per CPU core
+    pub(crate) workers: usize,
+    // Seconds a client has to send the request headers, 0 for no limit
+    pub(crate) client_request_timeout_secs: u64,
+    // Seconds an idle connection is kept open for another request, 0 to close it after each one
+    pub(crate) keep_alive_secs: u64,
+    // Connections each worker handles at once; more wait to be accepted
+    pub(crate) max_connections: usize,
+    // Seconds an upload may go without sending any of its body, 0 for no limit
+    pub(crate) payload_read_timeout_secs: u64,
+}
+
+// The defaults are actix-web's own
+impl Default for HttpServerConfig {
+    fn default() -> HttpServerConfig {
+        HttpServerConfig {
+            workers: 0,
+            client_request_timeout_secs: 5,
+            keep_alive_secs: 5,
+            max_connections: 25_000,
+            payload_read_timeout_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct UploadQueueConfig {
+    // Uploads allowed to wait for a slot at once; more are turned away, 0 for no limit
+    pub(crate) max_waiting: usize,
+    // Seconds an upload may wait for a slot before it is turned away, 0 for no limit
+    pub(crate) max_wait_secs: u64,
+    // Retry-After sent with uploads that were turned away
+    pub(crate) retry_after_secs: u64,
+}
+
+impl Default for UploadQueueConfig {
+    fn default() -> UploadQueueConfig {
+        UploadQueueConfig { max_waiting: 0, max_wait_secs: 0, retry_after_secs: 5 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct BandwidthConfig {
+    // Bytes per second a single response is sent at, 0 for no limit
+    pub(crate) per_connection_bytes_per_sec: u64,
+    // Bytes per second all responses together are sent at, 0 for no limit
+    pub(crate) global_bytes_per_sec: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct OutboxConfig {
+    pub(crate) directory: PathBuf,
+    // How often queued uploads are sent again
+    #[serde(default = "default_outbox_retry_interval_secs")]
+    pub(crate) retry_interval_secs: u64,
+    // Deliveries tried before an upload is given up, 0 keeps trying for as long as it takes
+    #[serde(default)]
+    pub(crate) max_attempts: u32,
+}
+
+pub(crate) fn default_outbox_retry_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct CircuitBreakerConfig {
+    // Consecutive failed uploads that open the circuit, 0 disables it
+    pub(crate) failure_threshold: u32,
+    // How long the circuit stays open before an upload is let through to probe Telegram
+    pub(crate) cooldown_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> CircuitBreakerConfig {
+        CircuitBreakerConfig { failure_threshold: 5, cooldown_secs: 30 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct RetryConfig {
+    // Retries per call, 0 disables retrying
+    pub(crate) max_retries: u32,
+    // Backoff before the first retry, doubled for every further one
+    pub(crate) initial_delay_ms: u64,
+    // Upper bound for a single backoff
+    pub(crate) max_delay_ms: u64,
+    // Total time a call may spend waiting between retries
+    pub(crate) max_total_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig { max_retries: 3, initial_delay_ms: 500, max_delay_ms: 10_000, max_total_delay_ms: 30_000 }
+    }
+}
+
+impl RetryConfig {
+    pub(crate) fn budget(&self) -> Duration {
+        Duration::from_millis(self.max_total_delay_ms)
+    }
+}
+
+// Line format of the access log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccessLogFormat {
+    // NCSA common log format
+    Common,
+    // Common log format plus referer and user agent
+    #[default]
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct AccessLogConfig {
+    #[serde(default)]
+    pub(crate) format: AccessLogFormat,
+    // Rotating files to write to, stdout if unset
+    #[serde(default)]
+    pub(crate) file: Option<LogFileConfig>,
+}
+
+pub(crate) fn default_otel_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_string()
+}
+
+pub(crate) fn default_otel_service_name() -> String {
+    "anarchic-image-hosting-bot".to_string()
+}
+
+pub(crate) fn default_otel_sample_ratio() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct OtelConfig {
+    // OTLP/HTTP traces endpoint of the collector
+    #[serde(default = "default_otel_endpoint")]
+    pub(crate) endpoint: String,
+    #[serde(default = "default_otel_service_name")]
+    pub(crate) service_name: String,
+    // Fraction of requests traced, between 0 and 1
+    #[serde(default = "default_otel_sample_ratio")]
+    pub(crate) sample_ratio: f64,
+}
+
+// Output format of log lines
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    // Human-readable lines
+    #[default]
+    Pretty,
+    // One JSON object per line, for log shippers
+    Json,
+}
+
+// How often the log file is rotated
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+pub(crate) fn default_log_level() -> String {
+    "info".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct LogConfig {
+    // Default level for everything not listed in `modules`
+    #[serde(default = "default_log_level")]
+    pub(crate) level: String,
+    // Per-module levels, e.g. { "actix_server": "warn" }
+    #[serde(default)]
+    pub(crate) modules: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) format: LogFormat,
+    // Also write logs to rotating files
+    #[serde(default)]
+    pub(crate) file: Option<LogFileConfig>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: default_log_level(),
+            modules: HashMap::new(),
+            format: LogFormat::default(),
+            file: None,
+        }
+    }
+}
+
+pub(crate) fn default_log_file_prefix() -> String {
+    "anarchic-image-hosting-bot.log".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct LogFileConfig {
+    pub(crate) directory: PathBuf,
+    #[serde(default = "default_log_file_prefix")]
+    pub(crate) prefix: String,
+    #[serde(default)]
+    pub(crate) rotation: LogRotation,
+    // Number of rotated files to keep, all of them if unset
+    #[serde(default)]
+    pub(crate) max_files: Option<usize>,
+}
+
+pub(crate) fn default_temp_dir() -> PathBuf {
+    std::env::temp_dir().join("anarchic-image-hosting-bot")
+}
+
+pub(crate) fn default_registry_path() -> PathBuf {
+    PathBuf::from("uploads.json")
+}
+
+pub(crate) fn default_deduplicate() -> bool {
+    true
+}
+
+pub(crate) fn default_resize_cache_size() -> usize {
+    100
+}
+
+pub(crate) fn default_cache_max_age_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+pub(crate) fn default_idempotency_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+pub(crate) fn default_thumbnail_size() -> u32 {
+    320
+}
+
+// Where a watermark is placed on the image
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+pub(crate) fn default_watermark_position() -> WatermarkPosition {
+    WatermarkPosition::BottomRight
+}
+
+pub(crate) fn default_watermark_opacity() -> f32 {
+    0.5
+}
+
+pub(crate) fn default_watermark_margin() -> u32 {
+    16
+}
+
+pub(crate) fn default_watermark_font_size() -> f32 {
+    32.0
+}
+
+pub(crate) fn default_watermark_color() -> String {
+    "#ffffff".to_string()
+}
+
+// Watermark settings: either a PNG overlay (`image`) or a line of text (`text` + `font`)
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct WatermarkConfig {
+    #[serde(default)]
+    pub(crate) image: Option<String>,
+    // Width of the overlay as a fraction of the image width; the overlay's own size if unset
+    #[serde(default)]
+    pub(crate) scale: Option<f32>,
+    #[serde(default)]
+    pub(crate) text: Option<String>,
+    // TrueType/OpenType font used to render `text`
+    #[serde(default)]
+    pub(crate) font: Option<String>,
+    #[serde(default = "default_watermark_font_size")]
+    pub(crate) font_size: f32,
+    #[serde(default = "default_watermark_color")]
+    pub(crate) color: String,
+    #[serde(default = "default_watermark_position")]
+    pub(crate) position: WatermarkPosition,
+    #[serde(default = "default_watermark_opacity")]
+    pub(crate) opacity: f32,
+    // Distance from the image edges, in pixels
+    #[serde(default = "default_watermark_margin")]
+    pub(crate) margin: u32,
+}
+
+impl Config {
+    // Read configuration from a JSON5 file
+    pub fn load(config_file: impl AsRef<Path>) -> Result<Config, String> {
+        let file_content = std::fs::read_to_string(config_file).map_err(|e| format!("Failed to read config file: {:?}", e))?;
+        json5::from_str(&file_content).map_err(|e| format!("Failed to parse config: {}", e))
+    }
+
+    // URL this server is reachable at when there's no request to take it from
+    pub(crate) fn base_url(&self) -> String {
+        match &self.public_url {
+            Some(public_url) => public_url.trim_end_matches('/').to_string(),
+            None => format!("http://{}:{}", self.host, self.port),
+        }
+    }
+
+    // Chats uploads go to, from chat_ids or else chat_id
+    pub(crate) fn target_chats(&self) -> Vec<ChatRef> {
+        if self.chat_ids.is_empty() {
+            self.chat_id.iter().cloned().collect()
+        } else {
+            self.chat_ids.clone()
+        }
+    }
+}
+
+// A chat given by its numeric ID or a public @username
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ChatRef {
+    Id(i64),
+    Username(String),
+}
+
+impl std::str::FromStr for ChatRef {
+    type Err = String;
+
+    fn from_str(chat: &str) -> Result<ChatRef, String> {
+        let chat = chat.trim();
+        if chat.starts_with('@') {
+            return Ok(ChatRef::Username(chat.to_string()));
+        }
+        chat.parse().map(ChatRef::Id).map_err(|_| format!("Invalid chat: {:?}", chat))
+    }
+}
+
+// A client allowed to upload, identified by its key
+#[derive(Clone, Deserialize)]
+pub(crate) struct ApiKeyConfig {
+    // Name of the client, used in logs
+    pub(crate) name: String,
+    pub(crate) key: String,
+    // Whether the client may send uploads to a chat of its choosing
+    #[serde(default)]
+    pub(crate) allow_chat_override: bool,
+    // Whether the client may use the /admin endpoints
+    #[serde(default)]
+    pub(crate) admin: bool,
+    // Only accept requests signed with the key, never the key itself
+    #[serde(default)]
+    pub(crate) signed_only: bool,
+    // Chat the client's uploads go to instead of the configured ones
+    #[serde(default)]
+    pub(crate) chat_id: Option<ChatRef>,
+    // Forum topic in that chat, or in the configured ones
+    #[serde(default)]
+    pub(crate) message_thread_id: Option<i32>,
+}
+
+// Keep the key itself out of the logs
+impl std::fmt::Debug for ApiKeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeyConfig")
+            .field("name", &self.name)
+            .field("allow_chat_override", &self.allow_chat_override)
+            .field("admin", &self.admin)
+            .field("signed_only", &self.signed_only)
+            .field("chat_id", &self.chat_id)
+            .field("message_thread_id", &self.message_thread_id)
+            .finish()
+    }
+}
+
+// How uploads are spread over several chats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChatMode {
+    // Send to the first chat that accepts the upload
+    #[default]
+    Failover,
+    // Send to the first chat that accepts the upload, then copy it to all others
+    Mirror,
+}
+
+// Implement a custom Debug for Config to hide the telegram_bot_token
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")            
+            .field("telegram_bot_tokens", &self.telegram_bot_tokens.len())
+            .field("api_url", &self.api_url)
+            .field("chat_id", &self.chat_id)
+            .field("chat_ids", &self.chat_ids)
+            .field("chat_mode", &self.chat_mode)
+            .field("message_thread_id", &self.message_thread_id)
+            .field("upload_mode", &self.upload_mode)
+            .field("disable_notification", &self.disable_notification)
+            .field("protect_content", &self.protect_content)
+            .field("pin_uploads", &self.pin_uploads)
+            .field("default_ttl_secs", &self.default_ttl_secs)
+            .field("api_keys", &self.api_keys)
+            .field("max_concurrent_uploads", &self.max_concurrent_uploads)
+            .field("upload_queue", &self.upload_queue)
+            .field("http_server", &self.http_server)
+            .field("public_url", &self.public_url)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("ip_filter", &self.ip_filter)
+            .field("temp_dir", &self.temp_dir)
+            .field("allowed_types", &self.allowed_types)
+            .field("auto_orient", &self.auto_orient)
+            .field("strip_exif", &self.strip_exif)
+            .field("max_dimension", &self.max_dimension)
+            .field("jpeg_quality", &self.jpeg_quality)
+            .field("convert_command", &self.convert_command)
+            .field("convert_timeout_secs", &self.convert_timeout_secs)
+            .field("watermark", &self.watermark)
+            .field("moderation", &self.moderation)
+            .field("clamav", &self.clamav)
+            .field("thumbnail_dir", &self.thumbnail_dir)
+            .field("archive_dir", &self.archive_dir)
+            .field("thumbnail_size", &self.thumbnail_size)
+            .field("registry_path", &self.registry_path)
+            .field("deduplicate", &self.deduplicate)
+            .field("resize_cache_size", &self.resize_cache_size)
+            .field("cache_max_age_secs", &self.cache_max_age_secs)
+            .field("idempotency_window_secs", &self.idempotency_window_secs)
+            .field("readiness_check_telegram", &self.readiness_check_telegram)
+            .field("log", &self.log)
+            .field("otel", &self.otel)
+            .field("access_log", &self.access_log)
+            .field("retry", &self.retry)
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("outbox", &self.outbox)
+            .field("temp_cleanup", &self.temp_cleanup)
+            .field("bandwidth", &self.bandwidth)
+            .field("temp_dir_quota", &self.temp_dir_quota)
+            .field("zip_upload", &self.zip_upload)
+            .field("proxy_cache", &self.proxy_cache)
+            .field("audit", &self.audit)
+            .field("webhooks", &self.webhooks)
+            .field("inbound", &self.inbound)
+            .field("admin_commands", &self.admin_commands)
+            .field("gallery", &self.gallery)
+            .field("feed", &self.feed)
+            .field("signed_urls", &self.signed_urls)
+            .field("swagger_ui", &self.swagger_ui)
+            .field("webdav", &self.webdav)
+            .field("s3", &self.s3)
+            .finish()
+    }
+}
+
+// Where the binary reads its config from, at startup and when reloading it
+pub const CONFIG_FILE: &str = "anarchic-image-hosting-bot.json5";
+
+// An address or CIDR range, e.g. "10.0.0.1" or "10.0.0.0/8"
+pub(crate) fn parse_network(network: &str) -> Result<IpNet, String> {
+    let network = network.trim();
+    match network.parse::<IpAddr>() {
+        Ok(address) => Ok(IpNet::from(address)),
+        Err(_) => network.parse::<IpNet>().map_err(|_| format!("{:?} is not an IP address or CIDR range", network)),
+    }
+}
@@ -0,0 +1,202 @@
+// Errors as clients see them: an HTTP status, a stable machine-readable code and a message
+// that never carries more than a description of what went wrong
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, HttpResponseBuilder, ResponseError};
+use teloxide::RequestError;
+use crate::telegram::is_transient;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // No API key, or one that isn't configured
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    // The request clashes with one still being handled
+    Conflict(String),
+    // The Idempotency-Key was already used for an upload of something else
+    IdempotencyKeyReused,
+    InvalidRequest(String),
+    PayloadTooLarge,
+    // The client stopped sending the request body
+    RequestTimeout,
+    UnsupportedMediaType(String),
+    // Content moderation scored the upload above block_above
+    ContentBlocked,
+    // ClamAV found the signature named in the file
+    Infected(String),
+    // Telegram's flood control kicked in and outlasted the retries
+    TelegramRateLimited { retry_after: u64 },
+    // Telegram can't be reached or is failing; retry_after is known when the circuit is open
+    TelegramUnavailable { retry_after: Option<u64> },
+    // Telegram answered, but refused the request
+    TelegramRejected(String),
+    // The temp directory quota is used up
+    StorageFull,
+    // Too many uploads are waiting for a free upload slot
+    Overloaded { retry_after: u64 },
+    // Anything else; the details are logged where it happened, not sent to the client
+    Internal(String),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Error::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            Error::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::ContentBlocked | Error::Infected(_) | Error::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::TelegramRateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::TelegramUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::TelegramRejected(_) => StatusCode::BAD_GATEWAY,
+            Error::StorageFull => StatusCode::INSUFFICIENT_STORAGE,
+            Error::Overloaded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Code for clients to match on; these stay the same when messages are reworded
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::NotFound(_) => "not_found",
+            Error::Conflict(_) => "conflict",
+            Error::IdempotencyKeyReused => "idempotency_key_reused",
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::PayloadTooLarge => "payload_too_large",
+            Error::RequestTimeout => "request_timeout",
+            Error::UnsupportedMediaType(_) => "unsupported_media_type",
+            Error::ContentBlocked => "content_blocked",
+            Error::Infected(_) => "infected",
+            Error::TelegramRateLimited { .. } => "telegram_rate_limited",
+            Error::TelegramUnavailable { .. } => "telegram_unavailable",
+            Error::TelegramRejected(_) => "telegram_rejected",
+            Error::StorageFull => "storage_full",
+            Error::Overloaded { .. } => "overloaded",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+
+    // Seconds a client should wait before trying again, if that's known
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::TelegramRateLimited { retry_after } => Some(*retry_after),
+            Error::TelegramUnavailable { retry_after } => *retry_after,
+            Error::Overloaded { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    // Whether Telegram itself is in trouble, rather than refusing this particular request
+    pub(crate) fn is_transient(&self) -> bool {
+        matches!(self, Error::TelegramRateLimited { .. } | Error::TelegramUnavailable { .. })
+    }
+
+    // JSON body of the error, with the request ID to look it up in the logs
+    pub(crate) fn body(&self, request_id: &str) -> serde_json::Value {
+        let mut body = serde_json::json!({ "error": self.code(), "message": self.to_string(), "request_id": request_id });
+        if let Some(retry_after) = self.retry_after() {
+            body["retry_after"] = retry_after.into();
+        }
+        body
+    }
+
+    // Start a response with the status and headers of the error
+    pub(crate) fn builder(&self) -> HttpResponseBuilder {
+        let mut response = HttpResponse::build(self.status());
+        if let Some(retry_after) = self.retry_after() {
+            response.insert_header((header::RETRY_AFTER, retry_after.max(1)));
+        }
+        response
+    }
+
+    pub(crate) fn response(&self, request_id: &str) -> HttpResponse {
+        self.builder().json(self.body(request_id))
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::NotFound(message)
+            | Error::Conflict(message)
+            | Error::InvalidRequest(message)
+            | Error::UnsupportedMediaType(message)
+            | Error::Internal(message) => f.write_str(message),
+            Error::IdempotencyKeyReused => f.write_str("This Idempotency-Key was already used for a different upload"),
+            Error::PayloadTooLarge => f.write_str("The upload is too large"),
+            Error::RequestTimeout => f.write_str("Timed out waiting for the upload"),
+            Error::ContentBlocked => f.write_str("The upload was blocked by content moderation"),
+            Error::Infected(signature) => write!(f, "The file is infected with {}", signature),
+            Error::TelegramRateLimited { retry_after } => {
+                write!(f, "Telegram is rate limiting uploads, try again in {} seconds", retry_after)
+            }
+            Error::TelegramUnavailable { .. } => f.write_str("Telegram is currently unavailable, try again later"),
+            Error::TelegramRejected(message) => write!(f, "Telegram rejected the upload: {}", message),
+            Error::StorageFull => f.write_str("Temp directory quota exceeded"),
+            Error::Overloaded { retry_after } => {
+                write!(f, "Too many uploads are waiting, try again in {} seconds", retry_after)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RequestError> for Error {
+    fn from(error: RequestError) -> Error {
+        match error {
+            RequestError::RetryAfter(seconds) => Error::TelegramRateLimited { retry_after: seconds.seconds().into() },
+            ref error if is_transient(error) => Error::TelegramUnavailable { retry_after: None },
+            RequestError::Api(error) => Error::TelegramRejected(error.to_string()),
+            RequestError::MigrateToChatId(chat_id) => {
+                Error::TelegramRejected(format!("The chat was upgraded to a supergroup with ID {}", chat_id))
+            }
+            RequestError::Network(_) | RequestError::InvalidJson { .. } => Error::TelegramUnavailable { retry_after: None },
+            RequestError::Io(_) => Error::Internal("Failed to read or write the file exchanged with Telegram".to_string()),
+        }
+    }
+}
+
+impl From<actix_web::error::PayloadError> for Error {
+    fn from(error: actix_web::error::PayloadError) -> Error {
+        match error {
+            actix_web::error::PayloadError::Io(e) => match e.kind() {
+                std::io::ErrorKind::TimedOut => Error::RequestTimeout,
+                // From a signed request whose body isn't the one signed
+                std::io::ErrorKind::PermissionDenied => Error::Unauthorized(e.to_string()),
+                _ => Error::InvalidRequest(format!("Failed to read the request body: {}", e)),
+            },
+            actix_web::error::PayloadError::Overflow => Error::PayloadTooLarge,
+            error => Error::InvalidRequest(format!("Failed to read the request body: {}", error)),
+        }
+    }
+}
+
+impl From<actix_multipart::MultipartError> for Error {
+    fn from(error: actix_multipart::MultipartError) -> Error {
+        use actix_web::error::PayloadError;
+        // Timeouts and tampered signed bodies are reported as such, not as a broken form
+        let kind = |error: &PayloadError| match error {
+            PayloadError::Io(e) => Some(e.kind()),
+            _ => None,
+        };
+        match error {
+            actix_multipart::MultipartError::Payload(error)
+                if matches!(kind(&error), Some(std::io::ErrorKind::TimedOut | std::io::ErrorKind::PermissionDenied)) =>
+            {
+                error.into()
+            }
+            error if error.status_code() == StatusCode::PAYLOAD_TOO_LARGE => Error::PayloadTooLarge,
+            error => Error::InvalidRequest(format!("Invalid multipart form: {}", error)),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// An in-memory stand-in for Telegram, so the upload pipeline runs without a bot token or chat
+
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use teloxide::types::{ChatId, MessageId};
+use teloxide::{ApiError, RequestError};
+use crate::telegram::{Media, PhotoVariant, PostedMedia, SendOptions, TelegramUploader, UploadMode};
+
+// A message posted to a FakeTelegram
+#[derive(Debug, Clone)]
+pub struct FakeMessage {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+    pub file_id: String,
+    pub sent_as: UploadMode,
+    pub caption: Option<String>,
+    pub pinned: bool,
+    pub deleted: bool,
+}
+
+// TelegramUploader keeping every posted file and message in memory. Hand it to
+// ServerBuilder::telegram and look at what was posted through messages() and file().
+#[derive(Default)]
+pub struct FakeTelegram {
+    state: Mutex<FakeState>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    // Contents of every stored file by file ID
+    files: HashMap<String, Vec<u8>>,
+    messages: Vec<FakeMessage>,
+    next_id: i32,
+    unavailable: bool,
+}
+
+impl FakeTelegram {
+    pub fn new() -> FakeTelegram {
+        FakeTelegram::default()
+    }
+
+    // Every message posted so far, oldest first
+    pub fn messages(&self) -> Vec<FakeMessage> {
+        self.state.lock().unwrap().messages.clone()
+    }
+
+    // While unavailable, sending files fails as it does when a gateway in front of Telegram is down
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.state.lock().unwrap().unavailable = unavailable;
+    }
+
+    // Contents of a stored file
+    pub fn file(&self, file_id: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(file_id).cloned()
+    }
+
+    fn message(&self, chat_id: ChatId, message_id: MessageId, update: impl FnOnce(&mut FakeMessage)) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let message = state
+            .messages
+            .iter_mut()
+            .find(|message| message.chat_id == chat_id && message.message_id == message_id && !message.deleted);
+        message.map(update).is_some()
+    }
+}
+
+impl TelegramUploader for FakeTelegram {
+    fn send_media<'a>(
+        &'a self,
+        chat_id: ChatId,
+        media: Media<'a>,
+        options: &'a SendOptions,
+    ) -> BoxFuture<'a, Result<Option<PostedMedia>, RequestError>> {
+        Box::pin(async move {
+            if self.state.lock().unwrap().unavailable {
+                return Err(RequestError::Api(ApiError::Unknown("Bad Gateway".to_string())));
+            }
+            let (file_id, dimensions) = match media {
+                Media::File(path) => {
+                    let bytes = tokio::fs::read(path).await.map_err(RequestError::Io)?;
+                    if let Some(progress) = &options.progress {
+                        progress.total.store(bytes.len() as u64, Ordering::Relaxed);
+                        progress.sent.store(bytes.len() as u64, Ordering::Relaxed);
+                    }
+                    let dimensions = image::load_from_memory(&bytes).ok().map(|image| (image.width(), image.height()));
+                    let mut state = self.state.lock().unwrap();
+                    state.next_id += 1;
+                    let file_id = format!("fake-file-{}", state.next_id);
+                    state.files.insert(file_id.clone(), bytes);
+                    (file_id, dimensions)
+                }
+                Media::FileId(file_id) => {
+                    let state = self.state.lock().unwrap();
+                    let bytes = state.files.get(file_id).ok_or(RequestError::Api(ApiError::WrongFileId))?;
+                    let dimensions = image::load_from_memory(bytes).ok().map(|image| (image.width(), image.height()));
+                    (file_id.to_string(), dimensions)
+                }
+            };
+
+            let mut state = self.state.lock().unwrap();
+            let file_size = state.files[&file_id].len() as u32;
+            let file_unique_id = format!("{}-unique", file_id);
+
+            // Only images can be sent as photos, and a single size of them is kept here
+            let photo_sizes = match options.mode {
+                UploadMode::Photo | UploadMode::Auto => {
+                    let Some((width, height)) = dimensions else {
+                        return Err(RequestError::Api(ApiError::Unknown("Bad Request: IMAGE_PROCESS_FAILED".to_string())));
+                    };
+                    let file_unique_id = file_unique_id.clone();
+                    vec![PhotoVariant { file_id: file_id.clone(), file_unique_id, width, height, file_size }]
+                }
+                UploadMode::Document | UploadMode::Video => Vec::new(),
+            };
+
+            state.next_id += 1;
+            let message_id = MessageId(state.next_id);
+            state.messages.push(FakeMessage {
+                chat_id,
+                message_id,
+                file_id: file_id.clone(),
+                sent_as: options.mode,
+                caption: options.caption.clone(),
+                pinned: false,
+                deleted: false,
+            });
+            Ok(Some(PostedMedia {
+                chat_id,
+                message_id,
+                file_id,
+                file_unique_id,
+                photo_sizes,
+            }))
+        })
+    }
+
+    fn get_file<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<String, RequestError>> {
+        Box::pin(async move {
+            match self.state.lock().unwrap().files.contains_key(file_id) {
+                true => Ok(format!("files/{}", file_id)),
+                false => Err(RequestError::Api(ApiError::WrongFileId)),
+            }
+        })
+    }
+
+    fn download_file<'a>(&'a self, file_path: &'a str) -> BoxFuture<'a, Result<Vec<u8>, RequestError>> {
+        Box::pin(async move {
+            let file_id = file_path.trim_start_matches("files/");
+            self.file(file_id).ok_or(RequestError::Api(ApiError::WrongFileId))
+        })
+    }
+
+    fn pin_message(&self, chat_id: ChatId, message_id: MessageId, _disable_notification: bool) -> BoxFuture<'_, Result<(), RequestError>> {
+        Box::pin(async move {
+            match self.message(chat_id, message_id, |message| message.pinned = true) {
+                true => Ok(()),
+                false => Err(RequestError::Api(ApiError::MessageIdInvalid)),
+            }
+        })
+    }
+
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> BoxFuture<'_, Result<(), RequestError>> {
+        Box::pin(async move {
+            match self.message(chat_id, message_id, |message| message.deleted = true) {
+                true => Ok(()),
+                false => Err(RequestError::Api(ApiError::MessageToDeleteNotFound)),
+            }
+        })
+    }
+
+    fn get_me(&self) -> BoxFuture<'_, Result<String, RequestError>> {
+        Box::pin(async { Ok("fake_bot".to_string()) })
+    }
+
+    fn resolve_username<'a>(&'a self, _username: &'a str) -> BoxFuture<'a, Result<ChatId, RequestError>> {
+        Box::pin(async { Err(RequestError::Api(ApiError::ChatNotFound)) })
+    }
+
+    fn file_url(&self, file_path: &str) -> String {
+        format!("https://telegram.invalid/{}", file_path)
+    }
+}
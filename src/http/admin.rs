@@ -0,0 +1,580 @@
+// Admin endpoints: metrics, stats, usage, the audit trail and the dashboard
+
+use actix_web::http::header;
+use actix_web::{get, post, route, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, NaiveDate, Utc};
+use prometheus::core::Collector;
+use prometheus::{HistogramVec, IntCounterVec, TextEncoder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use tracing::{error, info};
+use crate::config::Config;
+use crate::error::Error;
+use crate::telegram::UploadMode;
+use crate::storage::{Outbox, dir_size};
+use crate::upload::{Settings, UploadData, remove_upload};
+use crate::moderation::ModerationVerdict;
+use crate::audit::{note_audited, AuditedFile};
+use crate::http::{base_url, error_response, require_admin, with_query};
+
+#[utoipa::path(
+    tag = "operations",
+    responses((status = 200, description = "Prometheus metrics", content_type = "text/plain"))
+)]
+#[get("/metrics")]
+pub(crate) async fn serve_metrics(req: HttpRequest, data: web::Data<UploadData>) -> impl Responder {
+    match dir_size(&data.temp_dir) {
+        Ok(size) => data.metrics.temp_dir_bytes.set(size as i64),
+        Err(e) => error!("Failed to measure temp directory: {:?}", e),
+    }
+    if let Some(proxy_cache) = &data.proxy_cache {
+        data.metrics.proxy_cache_bytes.set(proxy_cache.size() as i64);
+    }
+    for pooled in &data.bots.bots {
+        let recent_calls = pooled.recent_calls() as i64;
+        data.metrics.bot_recent_calls.with_label_values(&[&pooled.id.to_string()]).set(recent_calls);
+    }
+
+    let mut output = String::new();
+    if let Err(e) = TextEncoder::new().encode_utf8(&data.metrics.registry.gather(), &mut output) {
+        error!("Failed to encode metrics: {:?}", e);
+        return error_response(&req, Error::Internal("Failed to encode metrics".to_string()));
+    }
+
+    HttpResponse::Ok().content_type(prometheus::TEXT_FORMAT).body(output)
+}
+
+// Longest history uploads_per_day covers
+pub(crate) const MAX_STATS_DAYS: u32 = 366;
+
+// Query parameters of the statistics endpoint
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct StatsQuery {
+    // Days covered by uploads_per_day
+    pub(crate) days: Option<u32>,
+}
+
+// Uploads that finished on one day
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct DailyUploads {
+    pub(crate) uploads: u64,
+    pub(crate) bytes: u64,
+}
+
+// Value of every series of a counter, by its only label
+pub(crate) fn counter_values(counter: &IntCounterVec) -> BTreeMap<String, u64> {
+    let mut values = BTreeMap::new();
+    for family in counter.collect() {
+        for metric in family.get_metric() {
+            let label = metric.get_label().first().map(|label| label.get_value().to_string()).unwrap_or_default();
+            values.insert(label, metric.get_counter().get_value() as u64);
+        }
+    }
+    values
+}
+
+// Average in milliseconds of every series of a histogram, by its only label
+pub(crate) fn histogram_averages(histogram: &HistogramVec) -> BTreeMap<String, f64> {
+    let mut averages = BTreeMap::new();
+    for family in histogram.collect() {
+        for metric in family.get_metric() {
+            let label = metric.get_label().first().map(|label| label.get_value().to_string()).unwrap_or_default();
+            let histogram = metric.get_histogram();
+            if histogram.get_sample_count() > 0 {
+                let average = histogram.get_sample_sum() / histogram.get_sample_count() as f64 * 1000.0;
+                averages.insert(label, average);
+            }
+        }
+    }
+    averages
+}
+
+// Totals for operators, from the registry and the metrics collected since the server started
+#[utoipa::path(
+    tag = "admin",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "Upload counts, errors, Telegram latency, concurrency and temp dir usage"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/admin/stats")]
+pub(crate) async fn admin_stats(req: HttpRequest, query: web::Query<StatsQuery>, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+    HttpResponse::Ok().json(collect_stats(&data, query.days.unwrap_or(30).clamp(1, MAX_STATS_DAYS)))
+}
+
+pub(crate) fn collect_stats(data: &UploadData, days: u32) -> serde_json::Value {
+    let records = data.registry.records();
+    let today = Utc::now().date_naive();
+    let first_day = today - chrono::Duration::days(days.saturating_sub(1) as i64);
+    let mut uploads_per_day = BTreeMap::new();
+    for day in first_day.iter_days().take_while(|day| *day <= today) {
+        uploads_per_day.insert(day.to_string(), DailyUploads::default());
+    }
+    for record in &records {
+        if let Some(daily) = uploads_per_day.get_mut(&record.uploaded_at.date_naive().to_string()) {
+            daily.uploads += 1;
+            daily.bytes += record.size.unwrap_or(0);
+        }
+    }
+
+    let temp_dir_bytes = dir_size(&data.temp_dir).unwrap_or_else(|e| {
+        error!("Failed to measure temp directory: {:?}", e);
+        0
+    });
+    let uploads_running = data.max_concurrent_uploads.saturating_sub(data.semaphore.available_permits());
+
+    serde_json::json!({
+        "uploads": {
+            "stored": records.len(),
+            "stored_bytes": records.iter().filter_map(|record| record.size).sum::<u64>(),
+            "received_since_start": data.metrics.uploads_total.get(),
+            "in_flight": data.metrics.uploads_in_flight.get(),
+            "pending_in_outbox": data.outbox.as_ref().map_or(0, |outbox| outbox.pending().len()),
+        },
+        "uploads_per_day": uploads_per_day,
+        "bytes_uploaded_since_start": data.metrics.bytes_uploaded.get(),
+        "errors_by_reason": counter_values(&data.metrics.uploads_failed),
+        "telegram": {
+            "average_latency_ms": histogram_averages(&data.metrics.telegram_latency),
+            "retries": counter_values(&data.metrics.telegram_retries),
+            "circuit_open": data.circuit_breaker.check().is_err(),
+        },
+        "semaphore": {
+            "running": uploads_running,
+            "waiting": data.metrics.uploads_waiting.get(),
+            "limit": data.max_concurrent_uploads,
+            "saturation": uploads_running as f64 / data.max_concurrent_uploads.max(1) as f64,
+        },
+        "temp_dir": {
+            "bytes": temp_dir_bytes,
+            "spooled_bytes": data.temp_quota.spooled.load(Ordering::Relaxed),
+            "quota": data.temp_quota.limit,
+        },
+    })
+}
+
+// Length of the periods usage is summed up over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UsagePeriod {
+    #[default]
+    Day,
+    Month,
+}
+
+// Shape of the usage report, picked with ?format=
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UsageFormat {
+    #[default]
+    Json,
+    // With a header line, for spreadsheets and billing scripts
+    Csv,
+}
+
+// Query parameters of the usage report
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct UsageQuery {
+    // Only the usage of the API key of this name
+    pub(crate) key: Option<String>,
+    // First and last day covered, as YYYY-MM-DD
+    pub(crate) from: Option<NaiveDate>,
+    pub(crate) to: Option<NaiveDate>,
+    pub(crate) period: Option<UsagePeriod>,
+    pub(crate) format: Option<UsageFormat>,
+}
+
+// Uploads one API key made in one period
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct UsageRow {
+    // Name of the API key, null for uploads made without one
+    pub(crate) key: Option<String>,
+    // The day (YYYY-MM-DD) or month (YYYY-MM)
+    pub(crate) period: String,
+    pub(crate) uploads: u64,
+    pub(crate) bytes: u64,
+}
+
+// A CSV field, quoted when it has to be
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Uploads and bytes per API key and day or month, counted as uploads were hosted, so deleted
+// uploads still count
+#[utoipa::path(
+    tag = "admin",
+    params(UsageQuery),
+    responses(
+        (status = 200, description = "Usage per API key and period, ordered by key and period", body = [UsageRow]),
+        (status = 200, description = "The same as CSV, with format=csv", content_type = "text/csv"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/admin/usage")]
+pub(crate) async fn admin_usage(req: HttpRequest, query: web::Query<UsageQuery>, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+
+    let period = query.period.unwrap_or_default();
+    let mut rows: Vec<UsageRow> = Vec::new();
+    let usage = data.registry.usage().into_iter().filter(|usage| {
+        query.key.as_ref().is_none_or(|key| usage.key.as_ref() == Some(key))
+            && query.from.is_none_or(|from| usage.date >= from)
+            && query.to.is_none_or(|to| usage.date <= to)
+    });
+    for usage in usage {
+        let label = match period {
+            UsagePeriod::Day => usage.date.format("%Y-%m-%d").to_string(),
+            UsagePeriod::Month => usage.date.format("%Y-%m").to_string(),
+        };
+        // Ordered by key and day, so days of the same month follow each other
+        match rows.last_mut() {
+            Some(row) if row.key == usage.key && row.period == label => {
+                row.uploads += usage.uploads;
+                row.bytes += usage.bytes;
+            }
+            _ => rows.push(UsageRow { key: usage.key, period: label, uploads: usage.uploads, bytes: usage.bytes }),
+        }
+    }
+
+    match query.format.unwrap_or_default() {
+        UsageFormat::Json => HttpResponse::Ok().json(rows),
+        UsageFormat::Csv => {
+            let mut csv = String::from("key,period,uploads,bytes\n");
+            for row in &rows {
+                let key = csv_field(row.key.as_deref().unwrap_or_default());
+                csv.push_str(&format!("{},{},{},{}\n", key, row.period, row.uploads, row.bytes));
+            }
+            HttpResponse::Ok()
+                .content_type("text/csv; charset=utf-8")
+                .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"usage.csv\""))
+                .body(csv)
+        }
+    }
+}
+
+// Shape of the audit export, picked with ?format=
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AuditFormat {
+    // An entry of JSON per line, as the audit trail is kept
+    #[default]
+    Jsonl,
+    Csv,
+}
+
+// Query parameters of the audit export
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct AuditQuery {
+    // Only entries about the upload with this ID
+    pub(crate) upload: Option<Uuid>,
+    // Only entries about files with this SHA-256, in hex
+    pub(crate) hash: Option<String>,
+    // Only entries of requests from this client address
+    pub(crate) ip: Option<String>,
+    // Only entries of requests made with the API key of this name
+    pub(crate) key: Option<String>,
+    // First and last day covered, as YYYY-MM-DD
+    pub(crate) from: Option<NaiveDate>,
+    pub(crate) to: Option<NaiveDate>,
+    pub(crate) format: Option<AuditFormat>,
+}
+
+// The audit trail of uploads and deletions, oldest first, for answering abuse reports
+#[utoipa::path(
+    tag = "admin",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Audit entries as JSON Lines, oldest first", content_type = "application/x-ndjson"),
+        (status = 200, description = "The same as CSV, with format=csv", content_type = "text/csv"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+        (status = 404, description = "The audit trail isn't enabled"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/admin/audit")]
+pub(crate) async fn admin_audit(req: HttpRequest, query: web::Query<AuditQuery>, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+    if data.audit.is_none() {
+        return error_response(&req, Error::NotFound("The audit trail isn't enabled".to_string()));
+    }
+
+    let query = query.into_inner();
+    let format = query.format.unwrap_or_default();
+    let hash = query.hash.map(|hash| hash.trim().to_ascii_lowercase());
+    let entries = tokio::task::spawn_blocking(move || {
+        data.audit.as_ref().map_or(Ok(Vec::new()), |audit| {
+            audit.entries(|entry| {
+                let date = entry.timestamp.date_naive();
+                query.upload.is_none_or(|id| entry.upload_id == Some(id))
+                    && hash.as_ref().is_none_or(|hash| entry.content_hash.as_ref() == Some(hash))
+                    && query.ip.as_ref().is_none_or(|ip| entry.client_ip.as_ref() == Some(ip))
+                    && query.key.as_ref().is_none_or(|key| entry.api_key.as_ref() == Some(key))
+                    && query.from.is_none_or(|from| date >= from)
+                    && query.to.is_none_or(|to| date <= to)
+            })
+        })
+    })
+    .await;
+    let entries = match entries {
+        Ok(Ok(entries)) => entries,
+        Ok(Err(e)) => {
+            error!("Failed to read the audit log: {:?}", e);
+            return error_response(&req, Error::Internal("Failed to read the audit log".to_string()));
+        }
+        Err(e) => {
+            error!("Failed to read the audit log: {:?}", e);
+            return error_response(&req, Error::Internal("Failed to read the audit log".to_string()));
+        }
+    };
+
+    let (content_type, file_name, body) = match format {
+        AuditFormat::Jsonl => {
+            let lines = entries.iter().filter_map(|entry| serde_json::to_string(entry).ok());
+            ("application/x-ndjson", "audit.jsonl", lines.map(|line| line + "\n").collect::<String>())
+        }
+        AuditFormat::Csv => {
+            let mut csv = String::from(
+                "timestamp,action,source,success,status,client_ip,api_key,request_id,telegram_user_id,upload_id,content_hash,error\n",
+            );
+            for entry in &entries {
+                let optional = |value: Option<String>| csv_field(&value.unwrap_or_default());
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                    entry.timestamp.to_rfc3339(),
+                    entry.action.as_str(),
+                    entry.source.as_str(),
+                    entry.success,
+                    optional(entry.status.map(|status| status.to_string())),
+                    optional(entry.client_ip.clone()),
+                    optional(entry.api_key.clone()),
+                    optional(entry.request_id.clone()),
+                    optional(entry.telegram_user_id.map(|user_id| user_id.to_string())),
+                    optional(entry.upload_id.map(|upload_id| upload_id.to_string())),
+                    optional(entry.content_hash.clone()),
+                    optional(entry.error.clone()),
+                ));
+            }
+            ("text/csv; charset=utf-8", "audit.csv", csv)
+        }
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)))
+        .body(body)
+}
+
+// Page showing live statistics, recent uploads and the outbox, built on the endpoints below
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "The admin dashboard", content_type = "text/html"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/admin")]
+pub(crate) async fn admin_dashboard(req: HttpRequest, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(include_str!("admin.html"))
+}
+
+// Query parameters of the admin upload list
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct AdminUploadsQuery {
+    pub(crate) limit: Option<usize>,
+    // Only list the uploads made with the API key of this name
+    pub(crate) tenant: Option<String>,
+}
+
+// An upload as listed on the dashboard
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct AdminUpload {
+    pub(crate) id: Uuid,
+    pub(crate) url: String,
+    // Thumbnail or resized proxy URL, for images only
+    pub(crate) preview_url: Option<String>,
+    pub(crate) uploaded_at: DateTime<Utc>,
+    pub(crate) size: Option<u64>,
+    pub(crate) sent_as: UploadMode,
+    #[schema(value_type = Option<Object>)]
+    pub(crate) moderation: Option<ModerationVerdict>,
+    // Name of the API key the upload was made with
+    pub(crate) tenant: Option<String>,
+}
+
+#[utoipa::path(
+    tag = "admin",
+    params(AdminUploadsQuery),
+    responses(
+        (status = 200, description = "The latest uploads, newest first", body = [AdminUpload]),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/admin/uploads")]
+pub(crate) async fn admin_uploads(req: HttpRequest, query: web::Query<AdminUploadsQuery>, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+
+    let base_url = base_url(&req, &data);
+    let settings = data.settings();
+    let uploads: Vec<AdminUpload> = data
+        .registry
+        .records()
+        .into_iter()
+        .filter(|record| query.tenant.is_none() || record.tenant == query.tenant)
+        .take(query.limit.unwrap_or(20))
+        .map(|record| {
+            let url = settings.file_url(&base_url, &record.id);
+            let preview_url = record.width.map(|_| match &data.thumbnail_dir {
+                Some(_) => settings.thumbnail_url(&base_url, &record.id),
+                None => with_query(&url, "w=128&h=128&fit=cover"),
+            });
+            AdminUpload {
+                id: record.id,
+                url,
+                preview_url,
+                uploaded_at: record.uploaded_at,
+                size: record.size,
+                sent_as: record.sent_as,
+                moderation: record.moderation,
+                tenant: record.tenant,
+            }
+        })
+        .collect();
+    HttpResponse::Ok().json(uploads)
+}
+
+// Entries in the outbox, including the ones Telegram rejected for good
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "Uploads waiting in the outbox or rejected by Telegram"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/admin/outbox")]
+pub(crate) async fn admin_outbox(req: HttpRequest, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+
+    let mut entries = data.outbox.as_ref().map(Outbox::entries).unwrap_or_default();
+    entries.sort_by_key(|entry| entry.queued_at);
+    let entries: Vec<serde_json::Value> = entries
+        .into_iter()
+        .map(|entry| {
+            serde_json::json!({
+                "id": entry.id,
+                "file_name": entry.options.file_name,
+                "queued_at": entry.queued_at,
+                "attempts": entry.attempts,
+                "last_error": entry.last_error,
+                "failed": entry.failed,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(entries)
+}
+
+// Delete an upload without its deletion token
+#[utoipa::path(
+    delete,
+    path = "/admin/uploads/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "ID of the upload")),
+    responses(
+        (status = 200, description = "Upload deleted"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+        (status = 404, description = "Upload not found"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[route("/admin/uploads/{id}", method = "DELETE")]
+pub(crate) async fn admin_delete_upload(req: HttpRequest, id: web::Path<String>, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+    let Some(record) = Uuid::parse_str(&id).ok().and_then(|id| data.registry.get(&id)) else {
+        return error_response(&req, Error::NotFound("Upload not found".to_string()));
+    };
+    note_audited(&req, AuditedFile::of(&record));
+
+    match remove_upload(&data, &record).await {
+        Ok(()) => {
+            info!("Deleted upload {} from the admin dashboard", record.id);
+            HttpResponse::Ok().body("Upload deleted")
+        }
+        Err(e) => {
+            error!("Failed to remove upload {} from the registry: {:?}", record.id, e);
+            error_response(&req, Error::Internal("Failed to delete upload".to_string()))
+        }
+    }
+}
+
+// Apply the options in the config file that can change without a restart
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "Config reloaded"),
+        (status = 400, description = "The config couldn't be read or is invalid"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 403, description = "The API key isn't an admin key"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[post("/admin/reload")]
+pub(crate) async fn admin_reload(req: HttpRequest, data: web::Data<UploadData>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &data) {
+        return response;
+    }
+
+    let Some(config_file) = &data.config_file else {
+        return error_response(&req, Error::InvalidRequest("The config wasn't loaded from a file".to_string()));
+    };
+    match Config::load(config_file).and_then(|config| Settings::from_config(&config)) {
+        Ok(settings) => {
+            *data.settings.write().unwrap() = Arc::new(settings);
+            info!("Reloaded config from {:?}", config_file);
+            HttpResponse::Ok().body("Config reloaded")
+        }
+        Err(e) => {
+            error!("Failed to reload config: {}", e);
+            error_response(&req, Error::InvalidRequest(e))
+        }
+    }
+}
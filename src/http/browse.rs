@@ -0,0 +1,695 @@
+// Getting at hosted files: the file proxy, thumbnails, QR codes, the gallery and the feed
+
+use actix_web::http::{header, StatusCode};
+use actix_web::body::{BoxBody, SizedStream};
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use image::codecs::png::PngEncoder;
+use image::{DynamicImage, Luma};
+use chrono::Utc;
+use qrcode::QrCode;
+use serde::Deserialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use tracing::{debug, error};
+use crate::error::Error;
+use crate::telegram::{FileRange, UploadMode, download_from_telegram, download_range_from_telegram};
+use crate::image::{MAX_RESIZE_DIMENSION, ProxiedFile, ResizeFit, ResizeKey, resize_image};
+use crate::storage::{UploadRecord, thumbnail_path};
+use crate::upload::{Settings, UploadData, url_signature};
+use crate::throttle::Bandwidth;
+use crate::http::{api_key, base_url, constant_time_eq, error_response, may_see, with_query, xml_escape};
+
+// Query parameters of a signed link
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct SignedLinkQuery {
+    // Unix time a signed link expires at
+    pub(crate) exp: Option<i64>,
+    // Signature of a signed link, needed when signed_urls is set
+    pub(crate) sig: Option<String>,
+}
+
+#[utoipa::path(
+    tag = "files",
+    params(("id" = Uuid, Path, description = "ID of the upload"), SignedLinkQuery),
+    responses(
+        (status = 200, description = "JPEG thumbnail of the upload", content_type = "image/jpeg"),
+        (status = 304, description = "The cached copy is still valid"),
+        (status = 403, description = "signed_urls is set and the link has no valid signature, or it expired"),
+        (status = 404, description = "Thumbnails are disabled or there is none for the upload"),
+    )
+)]
+#[get("/t/{id}")]
+pub(crate) async fn serve_thumbnail(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<SignedLinkQuery>,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    let Some(thumbnail_dir) = &data.thumbnail_dir else {
+        return error_response(&req, Error::NotFound("Thumbnails are disabled".to_string()));
+    };
+
+    // Only accept UUIDs so the ID can't be used to escape the thumbnail directory
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, Error::NotFound("Thumbnail not found".to_string()));
+    };
+    // Thumbnails are kept from clients without a key like the files they are of
+    if let Err(e) = check_signature(&req, &data.settings(), &id, query.exp, query.sig.as_deref()) {
+        return error_response(&req, e);
+    }
+
+    let path = thumbnail_path(thumbnail_dir, &id);
+    let read = std::fs::metadata(&path).and_then(|metadata| {
+        let validators = CacheValidators::for_thumbnail(&id, metadata.modified()?, data.cache_max_age_secs);
+        if validators.is_fresh(&req) {
+            return Ok((validators, None));
+        }
+        Ok((validators, Some(std::fs::read(&path)?)))
+    });
+
+    match read {
+        Ok((validators, None)) => validators.not_modified(),
+        Ok((validators, Some(bytes))) => validators.response(StatusCode::OK).content_type("image/jpeg").body(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            error_response(&req, Error::NotFound("Thumbnail not found".to_string()))
+        }
+        Err(e) => {
+            error!("Failed to read thumbnail {}: {:?}", id, e);
+            error_response(&req, Error::Internal("Failed to read thumbnail".to_string()))
+        }
+    }
+}
+
+// Image format of a rendered QR code
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QrFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
+// Query parameters of the QR code endpoint
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct QrQuery {
+    #[serde(default)]
+    pub(crate) format: QrFormat,
+    // Minimum width and height in pixels
+    pub(crate) size: Option<u32>,
+}
+
+// Default and largest size of a rendered QR code, in pixels
+pub(crate) const QR_DEFAULT_SIZE: u32 = 256;
+pub(crate) const QR_MAX_SIZE: u32 = 2048;
+
+// QR code linking to an upload's /f/{id} URL, so it can be opened on a phone
+#[utoipa::path(
+    tag = "files",
+    params(("id" = Uuid, Path, description = "ID of the upload"), QrQuery),
+    responses(
+        (status = 200, description = "QR code as PNG, or as SVG with ?format=svg", content_type = "image/png"),
+        (status = 401, description = "signed_urls is set and the request has no API key"),
+        (status = 404, description = "Upload not found"),
+    )
+)]
+#[get("/qr/{id}")]
+pub(crate) async fn qr_code(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<QrQuery>,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    let settings = data.settings();
+    // The code holds a freshly signed link, which only clients with a key may have made
+    if settings.signed_urls.is_some() && api_key(&req, &settings.api_keys).is_none() {
+        return error_response(&req, Error::Unauthorized("signed_urls is set, QR codes need an API key".to_string()));
+    }
+    let Some(record) = Uuid::parse_str(&id).ok().and_then(|id| data.registry.get(&id)) else {
+        return error_response(&req, Error::NotFound("Upload not found".to_string()));
+    };
+
+    let url = settings.file_url(&base_url(&req, &data), &record.id);
+    let code = match QrCode::new(url.as_bytes()) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Failed to encode QR code for upload {}: {:?}", record.id, e);
+            return error_response(&req, Error::Internal("Failed to encode QR code".to_string()));
+        }
+    };
+    let size = query.size.unwrap_or(QR_DEFAULT_SIZE).clamp(1, QR_MAX_SIZE);
+
+    match query.format {
+        QrFormat::Svg => {
+            let svg = code.render::<qrcode::render::svg::Color>().min_dimensions(size, size).build();
+            HttpResponse::Ok().content_type("image/svg+xml").body(svg)
+        }
+        QrFormat::Png => {
+            let image = DynamicImage::ImageLuma8(code.render::<Luma<u8>>().min_dimensions(size, size).build());
+            let mut png = Vec::new();
+            match image.write_with_encoder(PngEncoder::new(&mut png)) {
+                Ok(()) => HttpResponse::Ok().content_type("image/png").body(png),
+                Err(e) => {
+                    error!("Failed to render QR code for upload {}: {:?}", record.id, e);
+                    error_response(&req, Error::Internal("Failed to render QR code".to_string()))
+                }
+            }
+        }
+    }
+}
+
+// Query parameters of the gallery
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct GalleryQuery {
+    pub(crate) page: Option<usize>,
+}
+
+// Thumbnails of the latest uploads with links to them and their deletion URLs, newest first
+#[utoipa::path(
+    tag = "browse",
+    params(GalleryQuery),
+    responses(
+        (status = 200, description = "HTML page of recent uploads", content_type = "text/html"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 404, description = "The gallery is disabled"),
+    ),
+    security(("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/gallery")]
+pub(crate) async fn gallery(req: HttpRequest, query: web::Query<GalleryQuery>, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    if !settings.gallery.enabled {
+        return error_response(&req, Error::NotFound("The gallery is disabled".to_string()));
+    }
+    let Some(api_key) = api_key(&req, &settings.api_keys) else {
+        let mut response = error_response(&req, Error::Unauthorized("Missing or invalid API key".to_string()));
+        let challenge = header::HeaderValue::from_static("Basic realm=\"gallery\"");
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+        return response;
+    };
+
+    // Other tenants' uploads, and the deletion links of them, stay hidden
+    let records: Vec<UploadRecord> = data.registry.records().into_iter().filter(|record| may_see(api_key, record)).collect();
+    let page_size = settings.gallery.page_size.max(1);
+    let pages = records.len().div_ceil(page_size).max(1);
+    let page = query.page.unwrap_or(1).clamp(1, pages);
+    let base_url = base_url(&req, &data);
+
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Uploads</title><style>",
+        "body{font-family:sans-serif;margin:2em;background:#fafafa}",
+        ".grid{display:grid;grid-template-columns:repeat(auto-fill,minmax(200px,1fr));gap:1em}",
+        ".card{background:#fff;border:1px solid #ddd;padding:.5em;font-size:.85em}",
+        ".card img{width:100%;height:180px;object-fit:cover;display:block}",
+        ".card form{margin:0}",
+        ".file{height:180px;display:flex;align-items:center;justify-content:center;background:#eee}",
+        "nav{margin:1em 0}",
+        "</style></head><body>",
+    ));
+    html.push_str(&format!("<h1>Uploads</h1><p>{} uploads, page {} of {}</p><div class=\"grid\">", records.len(), page, pages));
+    for record in records.iter().skip((page - 1) * page_size).take(page_size) {
+        let url = settings.file_url(&base_url, &record.id);
+        // Only images have dimensions, everything else gets a placeholder
+        let preview = match (record.width, &data.thumbnail_dir) {
+            (Some(_), Some(_)) => format!(
+                "<img src=\"{}\" loading=\"lazy\" alt=\"\">",
+                xml_escape(&settings.thumbnail_url(&base_url, &record.id))
+            ),
+            (Some(_), None) => {
+                format!("<img src=\"{}\" loading=\"lazy\" alt=\"\">", xml_escape(&with_query(&url, "w=400&h=360&fit=cover")))
+            }
+            (None, _) => format!("<div class=\"file\">{}</div>", record.sent_as),
+        };
+        let size = record.size.map(|size| format!(", {:.1} KB", size as f64 / 1024.0)).unwrap_or_default();
+        // Deleting takes a POST, so following or prefetching links can't delete anything
+        html.push_str(&format!(
+            concat!(
+                "<div class=\"card\"><a href=\"{url}\">{preview}</a>",
+                "<div>{uploaded_at}{size}</div>",
+                "<form method=\"post\" action=\"{delete_url}\" onsubmit=\"return confirm('Delete this upload?')\">",
+                "<button type=\"submit\">Delete</button></form>",
+                "</div>"
+            ),
+            url = xml_escape(&url),
+            preview = preview,
+            uploaded_at = record.uploaded_at.format("%Y-%m-%d %H:%M"),
+            size = size,
+            delete_url = xml_escape(&format!("{}/delete/{}/{}", base_url, record.id, record.deletion_token)),
+        ));
+    }
+    html.push_str("</div><nav>");
+    if page > 1 {
+        html.push_str(&format!("<a href=\"?page={}\">&larr; Newer</a> ", page - 1));
+    }
+    if page < pages {
+        html.push_str(&format!("<a href=\"?page={}\">Older &rarr;</a>", page + 1));
+    }
+    html.push_str("</nav></body></html>");
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html)
+}
+
+// Query parameters of the feed
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct FeedQuery {
+    pub(crate) token: Option<String>,
+}
+
+// Atom feed of the latest uploads, each linking to its file through the proxy
+#[utoipa::path(
+    tag = "browse",
+    params(FeedQuery),
+    responses(
+        (status = 200, description = "Atom feed of the latest uploads", content_type = "application/atom+xml"),
+        (status = 401, description = "Missing or invalid feed token"),
+        (status = 404, description = "The feed is disabled"),
+    )
+)]
+#[get("/feed.xml")]
+pub(crate) async fn feed(req: HttpRequest, query: web::Query<FeedQuery>, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    let Some(feed) = &settings.feed else {
+        return error_response(&req, Error::NotFound("The feed is disabled".to_string()));
+    };
+    if !query.token.as_deref().is_some_and(|token| constant_time_eq(&feed.token, token)) {
+        return error_response(&req, Error::Unauthorized("Missing or invalid feed token".to_string()));
+    }
+
+    let records: Vec<UploadRecord> = data
+        .registry
+        .records()
+        .into_iter()
+        .filter(|record| feed.tenant.is_none() || record.tenant == feed.tenant)
+        .collect();
+    let raw_base_url = base_url(&req, &data);
+    let base_url = xml_escape(&raw_base_url);
+    let updated = records.first().map_or_else(Utc::now, |record| record.uploaded_at);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <title>{}</title>\n", xml_escape(&feed.title)));
+    xml.push_str(&format!("  <id>{}/feed.xml</id>\n", base_url));
+    xml.push_str(&format!("  <link rel=\"alternate\" href=\"{}/\"/>\n", base_url));
+    xml.push_str(&format!("  <updated>{}</updated>\n", updated.to_rfc3339()));
+    for record in records.iter().take(feed.entries) {
+        let url = xml_escape(&settings.file_url(&raw_base_url, &record.id));
+        // Telegram stores photos as JPEGs; other files keep a type this server doesn't remember
+        let content_type = match record.sent_as {
+            UploadMode::Photo => " type=\"image/jpeg\"",
+            _ => "",
+        };
+        let length = record.size.map(|size| format!(" length=\"{}\"", size)).unwrap_or_default();
+        let dimensions = match (record.width, record.height) {
+            (Some(width), Some(height)) => format!(" ({}x{})", width, height),
+            _ => String::new(),
+        };
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!(
+            "    <title>{} uploaded {}{}</title>\n",
+            record.sent_as,
+            record.uploaded_at.format("%Y-%m-%d %H:%M UTC"),
+            dimensions
+        ));
+        xml.push_str(&format!("    <id>urn:uuid:{}</id>\n", record.id));
+        xml.push_str(&format!("    <updated>{}</updated>\n", record.uploaded_at.to_rfc3339()));
+        xml.push_str(&format!("    <link rel=\"alternate\" href=\"{}\"/>\n", url));
+        xml.push_str(&format!("    <link rel=\"enclosure\" href=\"{}\"{}{}/>\n", url, content_type, length));
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+
+    HttpResponse::Ok().content_type("application/atom+xml; charset=utf-8").body(xml)
+}
+
+// Query parameters of the file proxy
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ProxyQuery {
+    pub(crate) w: Option<u32>,
+    pub(crate) h: Option<u32>,
+    pub(crate) fit: Option<ResizeFit>,
+    // Unix time a signed link expires at
+    pub(crate) exp: Option<i64>,
+    // Signature of a signed link, needed when signed_urls is set
+    pub(crate) sig: Option<String>,
+}
+
+// Validators sent with a served file so clients can revalidate their cached copy
+pub(crate) struct CacheValidators {
+    pub(crate) etag: header::EntityTag,
+    pub(crate) last_modified: header::HttpDate,
+    pub(crate) max_age: u64,
+}
+
+impl CacheValidators {
+    // An upload never changes, so its hash identifies it. Resized variants get their own tag.
+    pub(crate) fn for_upload(record: &UploadRecord, variant: Option<&ResizeKey>, max_age: u64) -> CacheValidators {
+        let mut tag = record.content_hash.clone().unwrap_or_else(|| record.file_unique_id.clone());
+        if let Some(key) = variant {
+            let dimension = |value: Option<u32>| value.map(|value| value.to_string()).unwrap_or_default();
+            tag.push_str(&format!("-{}x{}-{:?}", dimension(key.width), dimension(key.height), key.fit).to_lowercase());
+        }
+        // HTTP dates only have second precision
+        let uploaded_at = UNIX_EPOCH + Duration::from_secs(record.uploaded_at.timestamp().max(0) as u64);
+        CacheValidators {
+            etag: header::EntityTag::new_strong(tag),
+            last_modified: uploaded_at.into(),
+            max_age,
+        }
+    }
+
+    // Thumbnails are regenerated at most once, so their modification time identifies them
+    pub(crate) fn for_thumbnail(id: &Uuid, modified: SystemTime, max_age: u64) -> CacheValidators {
+        let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        CacheValidators {
+            etag: header::EntityTag::new_strong(format!("thumbnail-{}-{}", id, modified)),
+            last_modified: (UNIX_EPOCH + Duration::from_secs(modified)).into(),
+            max_age,
+        }
+    }
+
+    // Whether the client's copy is still current. If-Modified-Since only counts without If-None-Match.
+    pub(crate) fn is_fresh(&self, req: &HttpRequest) -> bool {
+        if let Some(if_none_match) = req.get_header::<header::IfNoneMatch>() {
+            return match if_none_match {
+                header::IfNoneMatch::Any => true,
+                header::IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(&self.etag)),
+            };
+        }
+        match req.get_header::<header::IfModifiedSince>() {
+            Some(header::IfModifiedSince(since)) => since >= self.last_modified,
+            None => false,
+        }
+    }
+
+    // Whether a Range header may be honored, following If-Range
+    pub(crate) fn allows_range(&self, req: &HttpRequest) -> bool {
+        match req.get_header::<header::IfRange>() {
+            Some(header::IfRange::EntityTag(tag)) => tag.strong_eq(&self.etag),
+            Some(header::IfRange::Date(date)) => date >= self.last_modified,
+            None => true,
+        }
+    }
+
+    pub(crate) fn response(&self, status: StatusCode) -> actix_web::HttpResponseBuilder {
+        let mut response = HttpResponse::build(status);
+        response
+            .insert_header(header::CacheControl(vec![
+                header::CacheDirective::Public,
+                header::CacheDirective::MaxAge(self.max_age.min(u32::MAX as u64) as u32),
+            ]))
+            .insert_header(header::ETag(self.etag.clone()))
+            .insert_header(header::LastModified(self.last_modified));
+        response
+    }
+
+    pub(crate) fn not_modified(&self) -> HttpResponse {
+        self.response(StatusCode::NOT_MODIFIED).finish()
+    }
+}
+
+// The byte range asked for with a single `Range: bytes=` header. Multiple ranges aren't
+// supported; those requests get the whole file.
+pub(crate) fn requested_range(req: &HttpRequest, validators: &CacheValidators) -> Option<header::ByteRangeSpec> {
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .filter(|_| validators.allows_range(req))
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.parse::<header::Range>().ok());
+    match range {
+        Some(header::Range::Bytes(specs)) if specs.len() == 1 => specs.into_iter().next(),
+        _ => None,
+    }
+}
+
+pub(crate) fn throttled_body(bytes: web::Bytes, bandwidth: &Bandwidth) -> BoxBody {
+    match bandwidth.is_limited() {
+        true => BoxBody::new(SizedStream::new(bytes.len() as u64, bandwidth.throttle(bytes))),
+        false => BoxBody::new(bytes),
+    }
+}
+
+// Serve a file, or the part of it asked for with a Range header
+pub(crate) fn serve_file(
+    req: &HttpRequest,
+    file: ProxiedFile,
+    validators: &CacheValidators,
+    bandwidth: &Bandwidth,
+) -> HttpResponse {
+    if validators.is_fresh(req) {
+        return validators.not_modified();
+    }
+
+    match requested_range(req, validators) {
+        Some(spec) => serve_range(file.content_type, FileRange::of(&file.bytes, &spec), validators, bandwidth),
+        None => validators
+            .response(StatusCode::OK)
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .content_type(file.content_type)
+            .body(throttled_body(file.bytes, bandwidth)),
+    }
+}
+
+pub(crate) fn serve_range(content_type: String, range: FileRange, validators: &CacheValidators, bandwidth: &Bandwidth) -> HttpResponse {
+    match range {
+        FileRange::Partial { body, start, end, length } => validators
+            .response(StatusCode::PARTIAL_CONTENT)
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length)))
+            .content_type(content_type)
+            .body(SizedStream::new(end - start + 1, bandwidth.throttle_stream(body))),
+        FileRange::Unsatisfiable { length } => HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", length)))
+            .finish(),
+    }
+}
+
+// With signed_urls, files are only served for a valid unexpired signature or an API key
+pub(crate) fn check_signature(
+    req: &HttpRequest,
+    settings: &Settings,
+    id: &Uuid,
+    expires: Option<i64>,
+    signature: Option<&str>,
+) -> Result<(), Error> {
+    let Some(signed_urls) = &settings.signed_urls else {
+        return Ok(());
+    };
+    if api_key(req, &settings.api_keys).is_some() {
+        return Ok(());
+    }
+    let (Some(expires), Some(signature)) = (expires, signature) else {
+        return Err(Error::Forbidden("This link needs a signature".to_string()));
+    };
+    if !constant_time_eq(&url_signature(&signed_urls.secret, id, expires), &signature.to_ascii_lowercase()) {
+        return Err(Error::Forbidden("Invalid signature".to_string()));
+    }
+    if expires < Utc::now().timestamp() {
+        return Err(Error::Forbidden("This link has expired".to_string()));
+    }
+    Ok(())
+}
+
+// Serve an uploaded file through this server, optionally resized with ?w=&h=&fit=contain|cover|fill
+#[utoipa::path(
+    tag = "files",
+    params(("id" = Uuid, Path, description = "ID of the upload"), ProxyQuery, ("Range" = Option<String>, Header, description = "A single byte range")),
+    responses(
+        (status = 200, description = "The uploaded file, resized if asked to"),
+        (status = 206, description = "The requested byte range of the file"),
+        (status = 304, description = "The cached copy is still valid"),
+        (status = 400, description = "Invalid resize parameters"),
+        (status = 403, description = "signed_urls is set and the link has no valid signature, or it expired"),
+        (status = 404, description = "Upload not found"),
+        (status = 416, description = "The byte range is outside the file"),
+        (status = 502, description = "The file couldn't be downloaded from Telegram"),
+    )
+)]
+#[get("/f/{id}")]
+pub(crate) async fn proxy_file(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<ProxyQuery>,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, Error::NotFound("Upload not found".to_string()));
+    };
+    // Checked before the lookup, so unsigned requests can't find out which uploads exist
+    if let Err(e) = check_signature(&req, &data.settings(), &id, query.exp, query.sig.as_deref()) {
+        return error_response(&req, e);
+    }
+    let Some(record) = data.registry.get(&id) else {
+        return error_response(&req, Error::NotFound("Upload not found".to_string()));
+    };
+
+    for dimension in [query.w, query.h].into_iter().flatten() {
+        if dimension == 0 || dimension > MAX_RESIZE_DIMENSION {
+            let message = format!("Width and height must be between 1 and {}", MAX_RESIZE_DIMENSION);
+            return error_response(&req, Error::InvalidRequest(message));
+        }
+    }
+
+    let resize_key = (query.w.is_some() || query.h.is_some()).then(|| ResizeKey {
+        id: record.id,
+        width: query.w,
+        height: query.h,
+        fit: query.fit.unwrap_or(ResizeFit::Contain),
+    });
+
+    // Revalidation doesn't need the file, so it never reaches Telegram
+    let validators = CacheValidators::for_upload(&record, resize_key.as_ref(), data.cache_max_age_secs);
+    if validators.is_fresh(&req) {
+        return validators.not_modified();
+    }
+
+    if let Some(key) = &resize_key {
+        if let Some(cached) = data.resize_cache.lock().unwrap().get(key).cloned() {
+            debug!("Serving resized variant of {} from cache", record.id);
+            return serve_file(&req, cached, &validators, &data.bandwidth);
+        }
+    }
+
+    // Without a cached copy, only the part asked for is downloaded from Telegram
+    if let (None, Some(spec)) = (&resize_key, requested_range(&req, &validators)) {
+        if let Some(cached) = cached_upload(&data, &record).await {
+            return serve_file(&req, cached, &validators, &data.bandwidth);
+        }
+        return match download_range_from_telegram(data.bots.get(record.bot_id), &record.file_id, &spec, &data.metrics).await {
+            Ok((content_type, range)) => serve_range(content_type, range, &validators, &data.bandwidth),
+            Err(e) => {
+                error!("Failed to download part of upload {} from Telegram: {:?}", record.id, e);
+                error_response(&req, e)
+            }
+        };
+    }
+
+    let file = match fetch_upload(&data, &record).await {
+        Ok(file) => file,
+        Err(e) => return error_response(&req, e),
+    };
+
+    let Some(key) = resize_key else {
+        return serve_file(&req, file, &validators, &data.bandwidth);
+    };
+
+    let quality = data.settings().image_options.jpeg_quality;
+    let resize_job_key = key.clone();
+    let span = tracing::Span::current();
+    let resized = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| resize_image(&file.bytes, &resize_job_key, quality))
+    })
+    .await;
+
+    match resized {
+        Ok(Ok(resized)) => {
+            data.resize_cache.lock().unwrap().put(key, resized.clone());
+            serve_file(&req, resized, &validators, &data.bandwidth)
+        }
+        Ok(Err(e)) => {
+            error!("Failed to resize upload {}: {:?}", record.id, e);
+            error_response(&req, Error::UnsupportedMediaType(format!("Failed to resize file: {}", e)))
+        }
+        Err(e) => {
+            error!("Failed to resize upload {}: {:?}", record.id, e);
+            error_response(&req, Error::Internal("Failed to resize file".to_string()))
+        }
+    }
+}
+
+// The file of an upload if the proxy cache has it
+pub(crate) async fn cached_upload(data: &UploadData, record: &UploadRecord) -> Option<ProxiedFile> {
+    let proxy_cache = data.proxy_cache.as_ref()?;
+    let cached = proxy_cache.get(&record.id).await;
+    let result = if cached.is_some() { "hit" } else { "miss" };
+    data.metrics.proxy_cache_lookups.with_label_values(&[result]).inc();
+    cached
+}
+
+// The file of an upload, from the proxy cache or else from Telegram
+pub(crate) async fn fetch_upload(data: &UploadData, record: &UploadRecord) -> Result<ProxiedFile, Error> {
+    if let Some(file) = cached_upload(data, record).await {
+        return Ok(file);
+    }
+
+    match download_from_telegram(data.bots.get(record.bot_id), &record.file_id, &data.metrics).await {
+        Ok(file) => {
+            if let Some(proxy_cache) = &data.proxy_cache {
+                proxy_cache.put(record.id, &file).await;
+            }
+            Ok(file)
+        }
+        Err(e) => {
+            error!("Failed to download upload {} from Telegram: {:?}", record.id, e);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn serve(range: &str, if_range: Option<&str>) -> HttpResponse {
+        let mut req = TestRequest::default().insert_header((header::RANGE, range));
+        if let Some(if_range) = if_range {
+            req = req.insert_header((header::IF_RANGE, if_range));
+        }
+        let validators = CacheValidators {
+            etag: header::EntityTag::new_strong("upload".to_string()),
+            last_modified: SystemTime::now().into(),
+            max_age: 60,
+        };
+        let file = ProxiedFile { content_type: "text/plain".to_string(), bytes: web::Bytes::from_static(b"0123456789") };
+        serve_file(&req.to_http_request(), file, &validators, &Bandwidth { per_connection: 0, global: None })
+    }
+
+    async fn body(response: HttpResponse) -> web::Bytes {
+        actix_web::body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    fn content_range(response: &HttpResponse) -> &str {
+        response.headers().get(header::CONTENT_RANGE).unwrap().to_str().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn serves_single_ranges() {
+        let response = serve("bytes=2-4", None);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(content_range(&response), "bytes 2-4/10");
+        assert_eq!(body(response).await, "234");
+
+        // The last bytes of the file, for a suffix
+        let response = serve("bytes=-3", None);
+        assert_eq!(content_range(&response), "bytes 7-9/10");
+        assert_eq!(body(response).await, "789");
+
+        // Ends past the end of the file are cut to it
+        let response = serve("bytes=8-100", None);
+        assert_eq!(content_range(&response), "bytes 8-9/10");
+        assert_eq!(body(response).await, "89");
+    }
+
+    #[actix_web::test]
+    async fn rejects_ranges_past_the_end() {
+        let response = serve("bytes=10-", None);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(content_range(&response), "bytes */10");
+    }
+
+    #[actix_web::test]
+    async fn serves_the_whole_file_for_other_ranges() {
+        for (range, if_range) in [("bytes=0-1,4-5", None), ("lines=1-2", None), ("bytes=2-4", Some("\"other\""))] {
+            let response = serve(range, if_range);
+            assert_eq!(response.status(), StatusCode::OK, "{}", range);
+            assert!(response.headers().get(header::CONTENT_RANGE).is_none());
+            assert_eq!(body(response).await, "0123456789");
+        }
+        assert_eq!(serve("bytes=2-4", Some("\"upload\"")).status(), StatusCode::PARTIAL_CONTENT);
+    }
+}
@@ -0,0 +1,268 @@
+// The WebDAV endpoint under /dav/: files put at a path, hosted like uploads
+
+use actix_web::http::{header, StatusCode};
+use actix_web::error::PayloadError;
+use actix_web::{route, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use std::collections::HashMap;
+use std::path::Path;
+use uuid::Uuid;
+use tracing::{error, info};
+use crate::config::ApiKeyConfig;
+use crate::error::Error;
+use crate::telegram::UploadMode;
+use crate::storage::{SavedFile, remove_temp_file};
+use crate::upload::{HostedFile, UploadData, host_file, is_type_allowed, remove_upload};
+use crate::metrics::InFlight;
+use crate::audit::{note_audited, AuditedFile};
+use crate::http::{ErrorBody, api_key, error_response};
+use crate::http::browse::{CacheValidators, fetch_upload, serve_file};
+use crate::http::upload::{admit_upload, read_timeout, send_defaults, upload_destination, write_field};
+
+// Methods the WebDAV endpoint answers to
+pub(crate) const DAV_METHODS: &str = "OPTIONS, GET, HEAD, PUT, DELETE";
+
+// A file path under /dav/, without empty, `.` or `..` segments. There are no collections, so
+// paths ending with a slash don't name anything.
+pub(crate) fn dav_path(path: &str) -> Option<String> {
+    let valid = !path.is_empty() && path.split('/').all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    valid.then(|| path.to_string())
+}
+
+// The API key of a WebDAV request, which clients send as the password of Basic auth
+pub(crate) fn dav_api_key<'a>(req: &HttpRequest, api_keys: &'a [ApiKeyConfig]) -> Result<Option<&'a ApiKeyConfig>, HttpResponse> {
+    match api_key(req, api_keys) {
+        Some(api_key) => Ok(Some(api_key)),
+        None if api_keys.is_empty() => Ok(None),
+        None => {
+            let mut response = error_response(req, Error::Unauthorized("Missing or invalid API key".to_string()));
+            let challenge = header::HeaderValue::from_static("Basic realm=\"dav\"");
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+            Err(response)
+        }
+    }
+}
+
+#[route("/dav/{path:.*}", method = "OPTIONS")]
+pub(crate) async fn dav_options() -> impl Responder {
+    HttpResponse::Ok().insert_header(("DAV", "1")).insert_header((header::ALLOW, DAV_METHODS)).finish()
+}
+
+#[utoipa::path(
+    put,
+    tag = "webdav",
+    path = "/dav/{path}",
+    params(("path" = String, Path, description = "Where to put the file")),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "The file"),
+    responses(
+        (status = 201, description = "The file was hosted, under the ID in X-Upload-Id"),
+        (status = 204, description = "The file replaced the one at the path"),
+        (status = 400, description = "The path names a collection or has . or .. segments", body = ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 415, description = "The file type is not allowed", body = ErrorBody),
+        (status = 503, description = "Telegram is unavailable, or too many uploads are waiting", body = ErrorBody),
+        (status = 507, description = "The temp directory is full", body = ErrorBody),
+    ),
+    security((), ("basic" = []))
+)]
+#[route("/dav/{path:.*}", method = "PUT")]
+pub(crate) async fn dav_put(
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: web::Payload,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    data.metrics.uploads_total.inc();
+    let _in_flight = InFlight::new(&data.metrics.uploads_in_flight);
+    let settings = data.settings();
+    let api_key = match dav_api_key(&req, &settings.api_keys) {
+        Ok(api_key) => api_key,
+        Err(response) => {
+            data.metrics.upload_failed("unauthorized");
+            return response;
+        }
+    };
+    let Some(dav_path) = dav_path(&path) else {
+        data.metrics.upload_failed("invalid");
+        let e = Error::InvalidRequest("The path must name a file, without empty, . or .. segments".to_string());
+        return error_response(&req, e);
+    };
+
+    let body = read_timeout(payload, data.payload_read_timeout);
+    match put_file(&req, &data, api_key, &dav_path, body).await {
+        Ok(put) => {
+            let status = if put.replaced { StatusCode::NO_CONTENT } else { StatusCode::CREATED };
+            HttpResponse::build(status).insert_header(("X-Upload-Id", put.id.to_string())).finish()
+        }
+        Err(e) => error_response(&req, e),
+    }
+}
+
+// A file put at a path through WebDAV or S3
+pub(crate) struct PutFile {
+    pub(crate) id: Uuid,
+    // Hex SHA-256 of the body
+    pub(crate) content_hash: String,
+    // Whether it took the place of an earlier upload at the path
+    pub(crate) replaced: bool,
+}
+
+// Host a request body at a path, replacing the upload that was there. WebDAV and S3 share
+// the paths, which are each API key's own.
+pub(crate) async fn put_file(
+    req: &HttpRequest,
+    data: &UploadData,
+    api_key: Option<&ApiKeyConfig>,
+    dav_path: &str,
+    body: impl futures_util::Stream<Item = Result<web::Bytes, PayloadError>>,
+) -> Result<PutFile, Error> {
+    let failed = |reason: &str, error: Error| {
+        data.metrics.upload_failed(reason);
+        error
+    };
+    let settings = data.settings();
+    let file_name = sanitize_filename::sanitize(dav_path.rsplit('/').next().unwrap_or_default());
+    let declared = req.mime_type().ok().flatten();
+    if !is_type_allowed(&settings.allowed_types, &file_name, declared.as_ref()) {
+        return Err(failed("save", Error::UnsupportedMediaType("File type is not allowed".to_string())));
+    }
+    let circuit_open = admit_upload(req, data).map_err(|(reason, e)| failed(reason, e))?;
+
+    let mut reservation = data.temp_quota.reserve();
+    let id = Uuid::new_v4();
+    reservation.hold(id);
+    let file_path = data.temp_dir.join(format!("{}_{}", id, file_name)).to_string_lossy().into_owned();
+    let content_hash = match write_field(&mut std::pin::pin!(body), &file_path, &mut reservation, None).await {
+        Ok(content_hash) => content_hash,
+        Err(e) => {
+            error!("Failed to save file: {:?}", e);
+            return Err(failed(if e == Error::StorageFull { "quota" } else { "save" }, e));
+        }
+    };
+    let content_type = mime_guess::from_path(&file_name).first().or(declared).map(|mime| mime.essence_str().to_string());
+    let saved = SavedFile { id, file_path, content_hash: content_hash.clone(), fields: HashMap::new(), file_name, content_type };
+
+    // Clients expect to get back what they put, which Telegram only keeps for documents
+    let mut options = send_defaults(&settings, api_key);
+    options.mode = UploadMode::Document;
+    options.file_name = Some(saved.file_name.clone());
+    let mut destination = match upload_destination(data, api_key, None).await {
+        Ok(destination) => destination,
+        Err((reason, e)) => {
+            remove_temp_file(Path::new(&saved.file_path));
+            return Err(failed(reason, e));
+        }
+    };
+    destination.dav_path = Some(dav_path.to_string());
+
+    // The upload there is replaced once this one reaches Telegram, see finish_upload
+    let replaced = data.registry.find_by_dav_path(dav_path, destination.tenant.as_deref()).is_some();
+    let hosted = match host_file(data, &saved, &options, &destination, circuit_open).await {
+        Ok(hosted) => hosted,
+        Err(e) => {
+            let error = Some(e.to_string());
+            note_audited(req, AuditedFile { content_hash: Some(content_hash), error, ..AuditedFile::default() });
+            return Err(e);
+        }
+    };
+    let id = match &hosted {
+        HostedFile::Sent { record, .. } => record.id,
+        HostedFile::Queued { entry, .. } => entry.id,
+    };
+    note_audited(
+        req,
+        match &hosted {
+            HostedFile::Sent { record, .. } => AuditedFile::of(record),
+            HostedFile::Queued { entry, .. } => AuditedFile::queued(entry),
+        },
+    );
+    info!("Hosted {:?} as upload {}", dav_path, id);
+    Ok(PutFile { id, content_hash, replaced })
+}
+
+#[utoipa::path(
+    get,
+    tag = "webdav",
+    path = "/dav/{path}",
+    params(("path" = String, Path, description = "Where the file was put")),
+    responses(
+        (status = 200, description = "The file, as it was put"),
+        (status = 304, description = "The cached copy is still valid"),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 404, description = "Nothing was put at the path, or it is still in the outbox", body = ErrorBody),
+    ),
+    security((), ("basic" = []))
+)]
+#[route("/dav/{path:.*}", method = "GET", method = "HEAD")]
+pub(crate) async fn dav_get(req: HttpRequest, path: web::Path<String>, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    let api_key = match dav_api_key(&req, &settings.api_keys) {
+        Ok(api_key) => api_key,
+        Err(response) => return response,
+    };
+    // Like /f/{id}, signed_urls keeps files from clients without a key
+    if settings.signed_urls.is_some() && api_key.is_none() {
+        return error_response(&req, Error::Unauthorized("Missing or invalid API key".to_string()));
+    }
+    let tenant = api_key.map(|api_key| api_key.name.as_str());
+    serve_put_file(&req, &data, tenant, &path).await.unwrap_or_else(|e| error_response(&req, e))
+}
+
+// Serve the file put at a path, typed after its name. HEAD requests get the same headers;
+// actix-web leaves out the body.
+pub(crate) async fn serve_put_file(req: &HttpRequest, data: &UploadData, tenant: Option<&str>, path: &str) -> Result<HttpResponse, Error> {
+    let record = dav_path(path).and_then(|path| data.registry.find_by_dav_path(&path, tenant));
+    let record = record.ok_or_else(|| Error::NotFound("Nothing was put at this path".to_string()))?;
+
+    let validators = CacheValidators::for_upload(&record, None, data.cache_max_age_secs);
+    if validators.is_fresh(req) {
+        return Ok(validators.not_modified());
+    }
+    let mut file = fetch_upload(data, &record).await?;
+    if let Some(content_type) = mime_guess::from_path(path).first() {
+        file.content_type = content_type.to_string();
+    }
+    Ok(serve_file(req, file, &validators, &data.bandwidth))
+}
+
+#[utoipa::path(
+    delete,
+    tag = "webdav",
+    path = "/dav/{path}",
+    params(("path" = String, Path, description = "Where the file was put")),
+    responses(
+        (status = 204, description = "The upload was deleted"),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 404, description = "Nothing was put at the path", body = ErrorBody),
+    ),
+    security((), ("basic" = []))
+)]
+#[route("/dav/{path:.*}", method = "DELETE")]
+pub(crate) async fn dav_delete(req: HttpRequest, path: web::Path<String>, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    let api_key = match dav_api_key(&req, &settings.api_keys) {
+        Ok(api_key) => api_key,
+        Err(response) => return response,
+    };
+    let tenant = api_key.map(|api_key| api_key.name.as_str());
+    match delete_put_file(&req, &data, tenant, &path).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => error_response(&req, e),
+    }
+}
+
+// Delete the upload put at a path
+pub(crate) async fn delete_put_file(req: &HttpRequest, data: &UploadData, tenant: Option<&str>, path: &str) -> Result<(), Error> {
+    let record = dav_path(path).and_then(|path| data.registry.find_by_dav_path(&path, tenant));
+    let record = record.ok_or_else(|| Error::NotFound("Nothing was put at this path".to_string()))?;
+    note_audited(req, AuditedFile::of(&record));
+    match remove_upload(data, &record).await {
+        Ok(()) => {
+            info!("Deleted upload {} at {:?}", record.id, path);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to remove upload {} from the registry: {:?}", record.id, e);
+            Err(Error::Internal("Failed to delete upload".to_string()))
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// Middleware every request goes through: request IDs, the IP filter, signatures, the audit
+// trail and the access log
+
+use actix_web::http::header;
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest};
+use chrono::Utc;
+use std::io::Write;
+use uuid::Uuid;
+use tracing::{error, info, Instrument};
+use tracing_appender::non_blocking::NonBlocking;
+use crate::config::{AccessLogFormat, ApiKeyConfig};
+use crate::error::Error;
+use crate::upload::UploadData;
+use crate::signing::{request_signature, verify_body, ReplayGuard, RequestSignature, SIGNATURE_MAX_AGE};
+use crate::audit::{AuditAction, AuditEntry, AuditSource, AuditedError, AuditedFiles};
+use crate::http::{SignedBy, api_key, client_ip, constant_time_eq, error_response};
+
+// Check the X-Signature of a request, returning the key it was signed with and the SHA-256 its
+// body must have
+pub(crate) fn check_signature_header(req: &HttpRequest, data: &UploadData) -> Result<(String, String), Error> {
+    verify_signature_header(req, &data.settings().api_keys, &data.replay_guard, Utc::now().timestamp())
+}
+
+// check_signature_header against the given keys, at the given time
+pub(crate) fn verify_signature_header(
+    req: &HttpRequest,
+    api_keys: &[ApiKeyConfig],
+    replay_guard: &ReplayGuard,
+    now: i64,
+) -> Result<(String, String), Error> {
+    let invalid = |message: &str| Error::Unauthorized(message.to_string());
+    let header = |name| req.headers().get(name).and_then(|value: &header::HeaderValue| value.to_str().ok());
+    let signature = header("X-Signature").ok_or_else(|| invalid("Invalid X-Signature"))?;
+    let signature = RequestSignature::parse(signature).map_err(Error::Unauthorized)?;
+    let body_sha256 = header("X-Content-Sha256")
+        .map(str::to_ascii_lowercase)
+        .filter(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| invalid("Signed requests need the hex SHA-256 of their body in X-Content-Sha256"))?;
+    if now.abs_diff(signature.timestamp) > SIGNATURE_MAX_AGE.as_secs() {
+        return Err(invalid("The request was signed too long ago, or the client's clock is off"));
+    }
+
+    let api_key = api_keys.iter().find(|api_key| api_key.name == signature.key).ok_or_else(|| invalid("Invalid signature"))?;
+    let target = req.uri().path_and_query().map_or(req.path(), |target| target.as_str());
+    let expected = request_signature(&api_key.key, req.method().as_str(), target, signature.timestamp, &body_sha256);
+    if !constant_time_eq(&expected, &signature.signature) {
+        return Err(invalid("Invalid signature"));
+    }
+    if !replay_guard.first_use(&signature.signature) {
+        return Err(invalid("This signed request was sent before"));
+    }
+    Ok((api_key.name.clone(), body_sha256))
+}
+
+// ID of a request, taken from the client's X-Request-Id header or generated
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+
+// Longest client-supplied request ID we accept
+pub(crate) const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// The ID assigned to a request by the request ID middleware
+pub(crate) fn request_id(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone())
+        .unwrap_or_default()
+}
+
+// Assign every request an ID, run it inside a tracing span carrying that ID
+// so all of its log lines can be found, and echo the ID in X-Request-Id
+pub(crate) async fn request_id_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH)
+        .filter(|value| value.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let client_ip = req.app_data::<web::Data<UploadData>>().and_then(|data| client_ip(req.request(), &data.settings().trusted_proxies));
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        client_ip = %client_ip.map(|address| address.to_string()).unwrap_or_default(),
+    );
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut response = next.call(req).instrument(span).await?;
+    if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(header::HeaderName::from_static("x-request-id"), value);
+    }
+    Ok(response)
+}
+
+// Turn away clients outside the networks of ip_filter before anything else is done for them
+pub(crate) async fn ip_filter_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(data) = req.app_data::<web::Data<UploadData>>() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let settings = data.settings();
+    if settings.ip_filter.is_empty() {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    match client_ip(req.request(), &settings.trusted_proxies) {
+        Some(address) if settings.ip_filter.allows(&address) => Ok(next.call(req).await?.map_into_left_body()),
+        address => {
+            info!("Turned away {} request to {} from {:?}", req.method(), req.path(), address);
+            let response = error_response(req.request(), Error::Forbidden("Your network may not use this server".to_string()));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+// Authenticate requests carrying an X-Signature, before their body is read. The body is checked
+// against the signed hash as it comes in.
+pub(crate) async fn signature_middleware(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let data = req.app_data::<web::Data<UploadData>>().cloned();
+    let (Some(data), true) = (data, req.headers().contains_key("X-Signature")) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    match check_signature_header(req.request(), &data) {
+        Ok((name, body_sha256)) => {
+            let length = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            let body = verify_body(req.take_payload(), body_sha256, length);
+            req.set_payload(Payload::Stream { payload: Box::pin(body) });
+            req.extensions_mut().insert(SignedBy(name));
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+        Err(e) => {
+            info!("Rejected signed {} request to {}: {}", req.method(), req.path(), e);
+            let response = error_response(req.request(), e);
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+// Sink for HTTP access log lines, separate from the application logs
+pub(crate) struct AccessLog {
+    pub(crate) format: AccessLogFormat,
+    pub(crate) writer: NonBlocking,
+}
+
+// The upload or deletion a request is about, going by the route it was matched to
+pub(crate) fn audit_action(req: &HttpRequest) -> Option<AuditAction> {
+    match (req.method().as_str(), req.match_pattern()?.as_str()) {
+        ("POST", "/upload" | "/upload/zip") | ("PUT", "/dav/{path:.*}" | "/s3/{bucket}/{key:.*}") => Some(AuditAction::Upload),
+        ("DELETE", "/dav/{path:.*}" | "/s3/{bucket}/{key:.*}" | "/admin/uploads/{id}") => Some(AuditAction::Delete),
+        ("GET" | "POST" | "DELETE", "/delete/{id}/{token}") => Some(AuditAction::Delete),
+        _ => None,
+    }
+}
+
+// Write the uploads and deletions a request made or tried to the audit trail, once the
+// response is ready. Requests turned away before their handler ran are written too, without
+// the files they were about.
+pub(crate) async fn audit_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(data) = req.app_data::<web::Data<UploadData>>().cloned().filter(|data| data.audit.is_some()) else {
+        return next.call(req).await;
+    };
+    let response = next.call(req).await?;
+    let request = response.request();
+    let Some(action) = audit_action(request) else {
+        return Ok(response);
+    };
+
+    let settings = data.settings();
+    let status = response.status();
+    let success = status.is_success() || status.is_redirection();
+    let files = request.extensions_mut().remove::<AuditedFiles>().map(|files| files.0);
+    let error = request.extensions().get::<AuditedError>().map(|error| error.0.clone());
+    let entry = AuditEntry {
+        status: Some(status.as_u16()),
+        client_ip: client_ip(request, &settings.trusted_proxies).map(|address| address.to_string()),
+        api_key: api_key(request, &settings.api_keys).map(|api_key| api_key.name.clone()),
+        request_id: request.extensions().get::<RequestId>().map(|request_id| request_id.0.clone()),
+        error: error.filter(|_| !success),
+        ..AuditEntry::new(action, AuditSource::Http, success)
+    };
+    match files {
+        Some(files) => {
+            for file in files {
+                data.audit(AuditEntry {
+                    action: file.action.unwrap_or(action),
+                    success: success && file.error.is_none(),
+                    upload_id: file.upload_id,
+                    content_hash: file.content_hash,
+                    error: file.error.or_else(|| entry.error.clone()),
+                    ..entry.clone()
+                });
+            }
+        }
+        None => data.audit(entry),
+    }
+    Ok(response)
+}
+
+// Write one access log line per request, after the response is ready
+pub(crate) async fn access_log_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(access_log) = req.app_data::<web::Data<AccessLog>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let started = std::time::Instant::now();
+    let timestamp = chrono::Local::now();
+    let client_ip = match req.app_data::<web::Data<UploadData>>() {
+        Some(data) => client_ip(req.request(), &data.settings().trusted_proxies),
+        None => req.peer_addr().map(|addr| addr.ip()),
+    };
+    let client_ip = client_ip.map(|address| address.to_string()).unwrap_or_else(|| "-".to_string());
+    let method = req.method().to_string();
+    let target = req.uri().path_and_query().map(|target| target.to_string()).unwrap_or_default();
+    let version = format!("{:?}", req.version());
+    let header = |name: header::HeaderName| {
+        req.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or("-").to_string()
+    };
+    let (referer, user_agent) = (header(header::REFERER), header(header::USER_AGENT));
+
+    let response = next.call(req).await?;
+
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let status = response.status().as_u16();
+    let bytes = match response.response().body().size() {
+        BodySize::Sized(size) => Some(size),
+        BodySize::None | BodySize::Stream => None,
+    };
+    let request_id = response.request().extensions().get::<RequestId>().map(|request_id| request_id.0.clone());
+
+    let line = match access_log.format {
+        AccessLogFormat::Common | AccessLogFormat::Combined => {
+            let mut line = format!(
+                "{} - - [{}] \"{} {} {}\" {} {}",
+                client_ip,
+                timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                method,
+                target,
+                version,
+                status,
+                bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+            );
+            if access_log.format == AccessLogFormat::Combined {
+                line.push_str(&format!(" \"{}\" \"{}\"", referer.replace('"', "\\\""), user_agent.replace('"', "\\\"")));
+            }
+            line.push_str(&format!(" {:.3}ms", latency_ms));
+            line
+        }
+        AccessLogFormat::Json => serde_json::json!({
+            "timestamp": timestamp.to_rfc3339(),
+            "client_ip": client_ip,
+            "method": method,
+            "path": target,
+            "version": version,
+            "status": status,
+            "bytes": bytes,
+            "latency_ms": latency_ms,
+            "referer": referer,
+            "user_agent": user_agent,
+            "request_id": request_id,
+        })
+        .to_string(),
+    };
+
+    let mut writer = access_log.writer.clone();
+    if let Err(e) = writeln!(writer, "{}", line) {
+        error!("Failed to write access log: {:?}", e);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    // SHA-256 of "hello"
+    const BODY_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const TIMESTAMP: i64 = 1_700_000_000;
+
+    fn api_keys() -> Vec<ApiKeyConfig> {
+        vec![serde_json::from_value(serde_json::json!({ "name": "ci", "key": "secret" })).unwrap()]
+    }
+
+    fn signed(signature: &str) -> HttpRequest {
+        TestRequest::post()
+            .uri("/upload?format=json")
+            .insert_header(("X-Signature", signature))
+            .insert_header(("X-Content-Sha256", BODY_SHA256))
+            .to_http_request()
+    }
+
+    fn signature_of(key: &str, secret: &str, timestamp: i64) -> String {
+        let signature = request_signature(secret, "POST", "/upload?format=json", timestamp, BODY_SHA256);
+        format!("key={}, timestamp={}, signature={}", key, timestamp, signature)
+    }
+
+    fn verify(req: &HttpRequest, now: i64) -> Result<(String, String), Error> {
+        verify_signature_header(req, &api_keys(), &ReplayGuard::default(), now)
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let req = signed(&signature_of("ci", "secret", TIMESTAMP));
+        let (name, body_sha256) = verify(&req, TIMESTAMP + 10).unwrap();
+        assert_eq!(name, "ci");
+        assert_eq!(body_sha256, BODY_SHA256);
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        assert!(verify(&signed(&signature_of("ci", "not the secret", TIMESTAMP)), TIMESTAMP).is_err());
+        assert!(verify(&signed(&signature_of("someone", "secret", TIMESTAMP)), TIMESTAMP).is_err());
+    }
+
+    #[test]
+    fn rejects_skewed_timestamps() {
+        let req = signed(&signature_of("ci", "secret", TIMESTAMP));
+        let max_age = SIGNATURE_MAX_AGE.as_secs() as i64;
+        assert!(verify(&req, TIMESTAMP + max_age).is_ok());
+        assert!(verify(&req, TIMESTAMP + max_age + 1).is_err());
+        assert!(verify(&req, TIMESTAMP - max_age - 1).is_err());
+    }
+
+    #[test]
+    fn rejects_replayed_signatures() {
+        let replay_guard = ReplayGuard::default();
+        let req = signed(&signature_of("ci", "secret", TIMESTAMP));
+        assert!(verify_signature_header(&req, &api_keys(), &replay_guard, TIMESTAMP).is_ok());
+        assert!(verify_signature_header(&req, &api_keys(), &replay_guard, TIMESTAMP).is_err());
+    }
+
+    #[test]
+    fn rejects_a_changed_target() {
+        let req = TestRequest::post()
+            .uri("/upload?format=txt")
+            .insert_header(("X-Signature", signature_of("ci", "secret", TIMESTAMP)))
+            .insert_header(("X-Content-Sha256", BODY_SHA256))
+            .to_http_request();
+        assert!(verify(&req, TIMESTAMP).is_err());
+    }
+
+    #[test]
+    fn needs_the_body_hash() {
+        let req = TestRequest::post()
+            .uri("/upload?format=json")
+            .insert_header(("X-Signature", signature_of("ci", "secret", TIMESTAMP)))
+            .to_http_request();
+        assert!(verify(&req, TIMESTAMP).is_err());
+    }
+}
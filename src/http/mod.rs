@@ -0,0 +1,525 @@
+// The HTTP API: uploads, the file proxy, admin endpoints, gallery, feed and probes
+
+mod admin;
+mod browse;
+mod dav;
+mod middleware;
+mod s3;
+mod upload;
+
+use actix_web::http::header;
+use actix_web::http::KeepAlive;
+use actix_web::middleware::from_fn;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{get, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder};
+use ipnet::IpNet;
+use base64::prelude::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+use uuid::Uuid;
+use tracing::info;
+use crate::config::{ApiKeyConfig, Config};
+use crate::error::Error;
+use crate::logging::rolling_appender;
+use crate::telegram::{PhotoVariant, UploadMode};
+use crate::storage::UploadRecord;
+use crate::upload::{contains_address, UploadData};
+use crate::systemd::{self, Listener};
+use crate::audit::AuditedError;
+pub(crate) use self::upload::UploadQuery;
+use self::admin::AdminUpload;
+use self::middleware::{AccessLog, access_log_middleware, audit_middleware, ip_filter_middleware, request_id, request_id_middleware, signature_middleware};
+use self::upload::{UploadForm, UploadInfo, UploadResponse, ZipManifest};
+
+// The API key a request was made with, from `Authorization: Bearer`, `X-Api-Key`, or as the
+// password of `Authorization: Basic` so browsers can prompt for it
+pub(crate) fn api_key<'a>(req: &HttpRequest, api_keys: &'a [ApiKeyConfig]) -> Option<&'a ApiKeyConfig> {
+    // Signed requests were checked by signature_middleware already
+    if let Some(SignedBy(name)) = req.extensions().get::<SignedBy>() {
+        return api_keys.iter().find(|api_key| &api_key.name == name);
+    }
+
+    let header = |name| req.headers().get(name).and_then(|value: &header::HeaderValue| value.to_str().ok());
+    let authorization = header(header::AUTHORIZATION);
+    let basic_password = authorization
+        .and_then(|authorization| authorization.strip_prefix("Basic "))
+        .and_then(|credentials| BASE64_STANDARD.decode(credentials.trim()).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| credentials.split_once(':').map(|(_, password)| password.to_string()));
+    let key = match basic_password {
+        Some(password) => password,
+        None => authorization
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .or_else(|| header(header::HeaderName::from_static("x-api-key")))?
+            .to_string(),
+    };
+    api_keys.iter().find(|api_key| !api_key.signed_only && constant_time_eq(&api_key.key, key.trim()))
+}
+
+// Name of the API key a request was signed with, once its X-Signature or SigV4 signature checked out
+#[derive(Debug, Clone)]
+pub(crate) struct SignedBy(pub(crate) String);
+
+// Address of the client behind a request. Forwarded (or else X-Forwarded-For) is only believed
+// when the peer is a trusted proxy, and then read from the right: the client is the last address
+// in it that isn't a trusted proxy too.
+pub(crate) fn client_ip(req: &HttpRequest, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let trusted = |address: &IpAddr| is_trusted_proxy(address, trusted_proxies);
+    if !trusted(&peer) {
+        return Some(peer);
+    }
+
+    let headers = req.headers();
+    let forwarded: Vec<&str> = match headers.get(header::FORWARDED).and_then(|value| value.to_str().ok()) {
+        Some(forwarded) => forwarded
+            .split(',')
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim().eq_ignore_ascii_case("for").then(|| value.trim().trim_matches('"'))
+                })
+            })
+            .collect(),
+        None => headers
+            .get_all(header::X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect(),
+    };
+
+    // Stop at anything that isn't an address (e.g. "unknown"), the hop before it vouched for nothing
+    let mut client = peer;
+    for address in forwarded.into_iter().rev() {
+        let Some(address) = parse_forwarded_address(address) else {
+            break;
+        };
+        client = address;
+        if !trusted(&address) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+pub(crate) fn is_trusted_proxy(address: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    contains_address(trusted_proxies, address)
+}
+
+// An address from Forwarded or X-Forwarded-For, which may come with a port ("[::1]:80", "1.2.3.4:80")
+pub(crate) fn parse_forwarded_address(address: &str) -> Option<IpAddr> {
+    if let Some(bracketed) = address.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    address.parse().ok().or_else(|| address.rsplit_once(':')?.0.parse::<Ipv4Addr>().ok().map(IpAddr::V4))
+}
+
+// Whether an upload is one a key may see: admin keys see them all, other keys only their own
+pub(crate) fn may_see(api_key: &ApiKeyConfig, record: &UploadRecord) -> bool {
+    api_key.admin || record.tenant.as_deref() == Some(api_key.name.as_str())
+}
+
+// Turn away requests to the /admin endpoints that weren't made with an admin key
+pub(crate) fn require_admin(req: &HttpRequest, data: &UploadData) -> Result<(), HttpResponse> {
+    match api_key(req, &data.settings().api_keys) {
+        Some(api_key) if api_key.admin => Ok(()),
+        Some(_) => Err(error_response(req, Error::Forbidden("This API key may not use the admin endpoints".to_string()))),
+        None => {
+            let mut response = error_response(req, Error::Unauthorized("Missing or invalid API key".to_string()));
+            let challenge = header::HeaderValue::from_static("Basic realm=\"admin\"");
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+            Err(response)
+        }
+    }
+}
+
+// Compare secrets without leaking how much of them matched through timing
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Escape text for use in XML content and attributes
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+// JSON error response for the endpoints that don't take a response format
+pub(crate) fn error_response(req: &HttpRequest, error: Error) -> HttpResponse {
+    req.extensions_mut().insert(AuditedError(error.to_string()));
+    error.response(&request_id(req))
+}
+
+// Add parameters to the query of a URL that may already have one
+pub(crate) fn with_query(url: &str, query: &str) -> String {
+    format!("{}{}{}", url, if url.contains('?') { '&' } else { '?' }, query)
+}
+
+// Base URL under which this server is reachable, from the config or the request's Host header
+pub(crate) fn base_url(req: &HttpRequest, data: &UploadData) -> String {
+    let settings = data.settings();
+    match &settings.public_url {
+        Some(public_url) => public_url.trim_end_matches('/').to_string(),
+        None => request_base_url(req, &settings.trusted_proxies),
+    }
+}
+
+// Forwarded, X-Forwarded-Host and X-Forwarded-Proto are only believed from a trusted proxy,
+// anyone else could point the links handed out at a host of their choosing
+pub(crate) fn request_base_url(req: &HttpRequest, trusted_proxies: &[IpNet]) -> String {
+    if req.peer_addr().is_some_and(|peer| is_trusted_proxy(&peer.ip(), trusted_proxies)) {
+        let connection = req.connection_info();
+        return format!("{}://{}", connection.scheme(), connection.host());
+    }
+    let scheme = if req.app_config().secure() { "https" } else { "http" };
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+        .unwrap_or_else(|| req.app_config().host());
+    format!("{}://{}", scheme, host)
+}
+
+// Body of error responses, only used to describe it in the OpenAPI document
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct ErrorBody {
+    // Machine-readable code, e.g. telegram_rate_limited or storage_full
+    pub(crate) error: String,
+    pub(crate) message: String,
+    pub(crate) request_id: String,
+    // Seconds to wait before trying again, when that's known
+    pub(crate) retry_after: Option<u64>,
+}
+
+// OpenAPI document describing every endpoint, served at /openapi.json
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "anarchic-image-hosting-bot"),
+    paths(
+        upload::upload,
+        upload::upload_zip,
+        upload::upload_exists,
+        dav::dav_put,
+        dav::dav_get,
+        dav::dav_delete,
+        s3::s3_put_object,
+        s3::s3_get_object,
+        s3::s3_delete_object,
+        upload::create_progress,
+        upload::progress_stream,
+        upload::pending_status,
+        upload::upload_info,
+        upload::delete_upload,
+        upload::sharex_config,
+        browse::proxy_file,
+        browse::serve_thumbnail,
+        browse::qr_code,
+        browse::gallery,
+        browse::feed,
+        admin::admin_dashboard,
+        admin::admin_stats,
+        admin::admin_usage,
+        admin::admin_audit,
+        admin::admin_uploads,
+        admin::admin_outbox,
+        admin::admin_delete_upload,
+        admin::admin_reload,
+        admin::serve_metrics,
+        healthz,
+        readyz,
+    ),
+    components(schemas(ErrorBody, UploadForm, UploadResponse, ZipManifest, UploadInfo, PhotoVariant, AdminUpload, UploadMode)),
+    modifiers(&ApiKeySchemes)
+)]
+pub(crate) struct ApiDoc;
+
+// The three ways a client can send its API key
+pub(crate) struct ApiKeySchemes;
+
+impl utoipa::Modify for ApiKeySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))));
+        components.add_security_scheme("basic", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Basic).build()));
+    }
+}
+
+#[get("/openapi.json")]
+pub(crate) async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+// Liveness probe: the process is up and serving requests
+#[utoipa::path(
+    tag = "operations",
+    responses((status = 200, description = "The server is up"))
+)]
+#[get("/healthz")]
+pub(crate) async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+// Check that a file can be created in the temp directory
+pub(crate) fn check_temp_dir_writable(temp_dir: &Path) -> std::io::Result<()> {
+    let probe_path = temp_dir.join(format!(".readyz-{}", Uuid::new_v4()));
+    std::fs::write(&probe_path, b"ok")?;
+    std::fs::remove_file(&probe_path)
+}
+
+// Readiness probe: the bot token is valid and the temp directory is writable,
+// plus a live Telegram round trip when readiness_check_telegram is enabled
+#[utoipa::path(
+    tag = "operations",
+    responses(
+        (status = 200, description = "Ready to accept uploads"),
+        (status = 503, description = "A readiness check failed"),
+    )
+)]
+#[get("/readyz")]
+pub(crate) async fn readyz(data: web::Data<UploadData>) -> impl Responder {
+    let mut checks = serde_json::Map::new();
+    let mut ready = true;
+
+    // The token only has to be validated once; until then every probe retries
+    let token_check = if data.bot_validated.load(Ordering::Relaxed) {
+        Ok(())
+    } else {
+        data.metrics.time_telegram("get_me", data.bots.primary().get_me()).await.map(|username| {
+            info!("Bot token validated for @{}", username);
+            data.bot_validated.store(true, Ordering::Relaxed);
+        })
+    };
+    match token_check {
+        Ok(()) => checks.insert("bot_token".to_string(), "ok".into()),
+        Err(e) => {
+            ready = false;
+            checks.insert("bot_token".to_string(), e.to_string().into())
+        }
+    };
+
+    match check_temp_dir_writable(&data.temp_dir) {
+        Ok(()) => checks.insert("temp_dir".to_string(), "ok".into()),
+        Err(e) => {
+            ready = false;
+            checks.insert("temp_dir".to_string(), e.to_string().into())
+        }
+    };
+
+    if data.readiness_check_telegram {
+        match data.metrics.time_telegram("get_me", data.bots.primary().get_me()).await {
+            Ok(_) => checks.insert("telegram".to_string(), "ok".into()),
+            Err(e) => {
+                ready = false;
+                checks.insert("telegram".to_string(), e.to_string().into())
+            }
+        };
+    }
+
+    let body = serde_json::json!({ "ready": ready, "checks": checks });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+// Every route and middleware, with the optional routes the config turns on
+pub(crate) fn app(
+    upload_data: web::Data<UploadData>,
+    access_log: Option<web::Data<AccessLog>>,
+    swagger_ui: bool,
+    webdav: bool,
+    s3: bool,
+) -> App<
+    impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>,
+> {
+    let mut app = App::new()
+        .wrap(from_fn(signature_middleware))
+        .wrap(from_fn(ip_filter_middleware))
+        .wrap(from_fn(audit_middleware))
+        .wrap(from_fn(request_id_middleware))
+        .wrap(from_fn(access_log_middleware))
+        .app_data(upload_data);
+    if let Some(access_log) = access_log {
+        app = app.app_data(access_log);
+    }
+    app
+        .service(upload::upload_zip)
+        .service(upload::upload)
+        .service(upload::upload_exists)
+        .service(upload::create_progress)
+        .service(upload::progress_stream)
+        .service(browse::serve_thumbnail)
+        .service(browse::proxy_file)
+        .service(upload::delete_upload)
+        .service(upload::sharex_config)
+        .service(browse::gallery)
+        .service(browse::feed)
+        .service(admin::admin_stats)
+        .service(admin::admin_usage)
+        .service(admin::admin_audit)
+        .service(admin::admin_dashboard)
+        .service(admin::admin_uploads)
+        .service(admin::admin_outbox)
+        .service(admin::admin_delete_upload)
+        .service(admin::admin_reload)
+        .service(browse::qr_code)
+        .service(admin::serve_metrics)
+        .service(healthz)
+        .service(readyz)
+        .service(upload::pending_status)
+        .service(upload::upload_info)
+        .service(openapi_json)
+        .configure(|cfg| {
+            if swagger_ui {
+                cfg.service(SwaggerUi::new("/docs/{_:.*}").config(utoipa_swagger_ui::Config::from("/openapi.json")));
+            }
+            if webdav {
+                cfg.service(dav::dav_options).service(dav::dav_put).service(dav::dav_get).service(dav::dav_delete);
+            }
+            if s3 {
+                cfg.service(s3::s3_put_object).service(s3::s3_get_object).service(s3::s3_delete_object);
+            }
+        })
+}
+
+// Serve the HTTP API on the host and port from the config until the server is stopped
+pub(crate) async fn serve(config: &Config, upload_data: web::Data<UploadData>) -> std::io::Result<()> {
+    let (access_log, _access_log_guard) = match &config.access_log {
+        Some(access_log) => {
+            let (writer, guard) = match &access_log.file {
+                Some(file) => tracing_appender::non_blocking(rolling_appender(file).map_err(std::io::Error::other)?),
+                None => tracing_appender::non_blocking(std::io::stdout()),
+            };
+            let access_log = AccessLog { format: access_log.format, writer };
+            (Some(web::Data::new(access_log)), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let swagger_ui = config.swagger_ui;
+    let webdav = config.webdav;
+    let s3 = config.s3;
+    let tuning = &config.http_server;
+
+    let bind_address = format!("{}:{}", config.host, config.port);
+    let server = HttpServer::new(move || app(upload_data.clone(), access_log.clone(), swagger_ui, webdav, s3))
+    .client_request_timeout(Duration::from_secs(tuning.client_request_timeout_secs))
+    .keep_alive(match tuning.keep_alive_secs {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    })
+    .max_connections(tuning.max_connections.max(1));
+    // actix-web starts a worker per CPU core unless told otherwise
+    let server = match tuning.workers {
+        0 => server,
+        workers => server.workers(workers),
+    };
+
+    // Under socket activation systemd holds the sockets, so they stay open across restarts
+    let listeners = systemd::inherited_listeners();
+    let mut server = if listeners.is_empty() { server.bind(&bind_address)? } else { server };
+    for listener in listeners {
+        server = match listener {
+            Listener::Tcp(listener) => server.listen(listener)?,
+            #[cfg(unix)]
+            Listener::Unix(listener) => server.listen_uds(listener)?,
+        };
+    }
+
+    let server = server.run();
+    systemd::notify("READY=1");
+    if let Some(interval) = systemd::watchdog_interval() {
+        info!("Pinging the systemd watchdog every {:?}", interval / 2);
+        tokio::spawn(systemd::run_watchdog(interval));
+    }
+    tokio::spawn(systemd::notify_stopping());
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn networks(networks: &[&str]) -> Vec<IpNet> {
+        networks.iter().map(|network| network.parse().unwrap()).collect()
+    }
+
+    fn from(peer: &str) -> TestRequest {
+        TestRequest::default().peer_addr(peer.parse().unwrap())
+    }
+
+    #[test]
+    fn client_ip_ignores_headers_from_untrusted_peers() {
+        let req = from("203.0.113.7:4000").insert_header((header::X_FORWARDED_FOR, "198.51.100.1")).to_http_request();
+        assert_eq!(client_ip(&req, &networks(&["10.0.0.0/8"])), Some("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_ip_stops_at_the_first_untrusted_hop() {
+        // The client claims to be 192.0.2.1, but only the hop 198.51.100.1 was added by a proxy
+        let req = from("10.0.0.1:4000")
+            .insert_header((header::X_FORWARDED_FOR, "192.0.2.1, 198.51.100.1, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &networks(&["10.0.0.0/8"])), Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_ip_trusts_ipv4_mapped_peers() {
+        let req = from("[::ffff:10.0.0.1]:4000").insert_header((header::X_FORWARDED_FOR, "198.51.100.1")).to_http_request();
+        assert_eq!(client_ip(&req, &networks(&["10.0.0.0/8"])), Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_ip_prefers_forwarded() {
+        let req = from("10.0.0.1:4000")
+            .insert_header((header::FORWARDED, "for=192.0.2.1, for=\"[2001:db8::1]:8080\";proto=https"))
+            .insert_header((header::X_FORWARDED_FOR, "198.51.100.1"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &networks(&["10.0.0.0/8"])), Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_ip_stops_at_unknown() {
+        let req = from("10.0.0.1:4000")
+            .insert_header((header::FORWARDED, "for=192.0.2.1, for=unknown, for=10.0.0.2"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &networks(&["10.0.0.0/8"])), Some("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_forwarded_addresses() {
+        assert_eq!(parse_forwarded_address("192.0.2.1"), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(parse_forwarded_address("192.0.2.1:8080"), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(parse_forwarded_address("2001:db8::1"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(parse_forwarded_address("[2001:db8::1]"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(parse_forwarded_address("[2001:db8::1]:8080"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(parse_forwarded_address("unknown"), None);
+        assert_eq!(parse_forwarded_address("_hidden"), None);
+        assert_eq!(parse_forwarded_address("[2001:db8::1"), None);
+    }
+
+    #[test]
+    fn base_url_only_follows_forwarded_host_from_trusted_proxies() {
+        let forwarded = |peer: &str| {
+            from(peer)
+                .insert_header((header::HOST, "internal:8080"))
+                .insert_header(("X-Forwarded-Host", "images.example.com"))
+                .insert_header(("X-Forwarded-Proto", "https"))
+                .to_http_request()
+        };
+        let trusted_proxies = networks(&["10.0.0.0/8"]);
+        assert_eq!(request_base_url(&forwarded("10.0.0.1:4000"), &trusted_proxies), "https://images.example.com");
+        assert_eq!(request_base_url(&forwarded("203.0.113.7:4000"), &trusted_proxies), "http://internal:8080");
+    }
+}
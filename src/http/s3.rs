@@ -0,0 +1,202 @@
+// The S3-compatible endpoint under /s3/, on top of the paths WebDAV puts files at
+
+use actix_web::http::header;
+use actix_web::{route, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use tracing::debug;
+use crate::config::ApiKeyConfig;
+use crate::error::Error;
+use crate::upload::UploadData;
+use crate::metrics::InFlight;
+use crate::signing::verify_body;
+use crate::audit::AuditedError;
+use crate::s3::{canonical_request, s3_error_xml, s3_status, sigv4_signature, SigV4Authorization, S3_MAX_CLOCK_SKEW, UNSIGNED_PAYLOAD};
+use crate::http::{SignedBy, api_key, constant_time_eq};
+use crate::http::dav::{dav_path, delete_put_file, put_file, serve_put_file};
+use crate::http::middleware::request_id;
+use crate::http::upload::read_timeout;
+
+// The API key of an S3 request. SigV4 signatures are made with the name of an API key as the
+// access key ID and the key itself as the secret access key; other clients can send the key as
+// they would to the rest of the API. Signed requests also give the SHA-256 their body must
+// have, unless they left it unsigned.
+pub(crate) fn s3_api_key<'a>(req: &HttpRequest, api_keys: &'a [ApiKeyConfig]) -> Result<(Option<&'a ApiKeyConfig>, Option<String>), Error> {
+    let header = |name| req.headers().get(name).and_then(|value: &header::HeaderValue| value.to_str().ok());
+    let Some(authorization) = header(header::AUTHORIZATION).and_then(SigV4Authorization::parse) else {
+        return match api_key(req, api_keys) {
+            Some(api_key) => Ok((Some(api_key), None)),
+            None if api_keys.is_empty() => Ok((None, None)),
+            None => Err(Error::Forbidden("Access Denied".to_string())),
+        };
+    };
+    let authorization = authorization.map_err(Error::InvalidRequest)?;
+    let api_key = api_keys
+        .iter()
+        .find(|api_key| api_key.name == authorization.access_key)
+        .ok_or_else(|| Error::Forbidden("The access key ID doesn't match an API key".to_string()))?;
+
+    let amz_date = header(header::HeaderName::from_static("x-amz-date")).ok_or_else(|| Error::InvalidRequest("Missing x-amz-date".to_string()))?;
+    let signed_at = chrono::NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ")
+        .map_err(|_| Error::InvalidRequest("Invalid x-amz-date".to_string()))?
+        .and_utc();
+    if (Utc::now() - signed_at).num_seconds().unsigned_abs() > S3_MAX_CLOCK_SKEW.as_secs() {
+        return Err(Error::Forbidden("The difference between the request time and the server's time is too large".to_string()));
+    }
+
+    let payload_hash = header(header::HeaderName::from_static("x-amz-content-sha256"))
+        .filter(|_| authorization.signed_headers.iter().any(|name| name == "x-amz-content-sha256"))
+        .ok_or_else(|| Error::InvalidRequest("Signed requests need a signed x-amz-content-sha256".to_string()))?;
+    if payload_hash.starts_with("STREAMING-") {
+        let message = "Chunked uploads aren't supported, sign the whole payload or send it as UNSIGNED-PAYLOAD";
+        return Err(Error::InvalidRequest(message.to_string()));
+    }
+    let body_sha256 = match payload_hash {
+        UNSIGNED_PAYLOAD => None,
+        hash if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => Some(hash.to_ascii_lowercase()),
+        _ => return Err(Error::InvalidRequest("Invalid x-amz-content-sha256".to_string())),
+    };
+
+    let canonical = canonical_request(req.method().as_str(), req.path(), req.query_string(), req.headers(), &authorization, payload_hash);
+    let expected = sigv4_signature(&api_key.key, amz_date, &authorization, &canonical);
+    if !constant_time_eq(&expected, &authorization.signature) {
+        debug!("Rejected S3 request signed as {:?}, canonical request: {:?}", authorization.access_key, canonical);
+        return Err(Error::Unauthorized("The request signature doesn't match the one calculated with the key".to_string()));
+    }
+    req.extensions_mut().insert(SignedBy(api_key.name.clone()));
+    Ok((Some(api_key), body_sha256))
+}
+
+// An error the way S3 reports it, in XML
+pub(crate) fn s3_error(req: &HttpRequest, error: Error) -> HttpResponse {
+    req.extensions_mut().insert(AuditedError(error.to_string()));
+    error
+        .builder()
+        .status(s3_status(&error))
+        .content_type("application/xml")
+        .body(s3_error_xml(&error, req.path(), &request_id(req)))
+}
+
+#[utoipa::path(
+    put,
+    tag = "s3",
+    path = "/s3/{bucket}/{key}",
+    params(
+        ("bucket" = String, Path, description = "First segment of the path the object is put at"),
+        ("key" = String, Path, description = "Rest of the path"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "The object"),
+    responses(
+        (status = 200, description = "The object was hosted; its ETag is the SHA-256 of the body"),
+        (status = 400, description = "Invalid signature headers or object key, or a chunked upload"),
+        (status = 403, description = "Missing credentials, or the signature doesn't match"),
+        (status = 503, description = "Telegram is unavailable, or too many uploads are waiting"),
+    ),
+    security((), ("bearer" = []), ("api_key" = []))
+)]
+#[route("/s3/{bucket}/{key:.*}", method = "PUT")]
+pub(crate) async fn s3_put_object(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    payload: web::Payload,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    data.metrics.uploads_total.inc();
+    let _in_flight = InFlight::new(&data.metrics.uploads_in_flight);
+    let settings = data.settings();
+    let (api_key, body_sha256) = match s3_api_key(&req, &settings.api_keys) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            data.metrics.upload_failed("unauthorized");
+            return s3_error(&req, e);
+        }
+    };
+    if req.headers().contains_key("x-amz-copy-source") {
+        data.metrics.upload_failed("invalid");
+        return s3_error(&req, Error::InvalidRequest("Copying objects isn't supported".to_string()));
+    }
+    let (bucket, key) = path.into_inner();
+    let Some(dav_path) = dav_path(&format!("{}/{}", bucket, key)) else {
+        data.metrics.upload_failed("invalid");
+        return s3_error(&req, Error::InvalidRequest("Object keys must be file paths, without empty, . or .. segments".to_string()));
+    };
+
+    let body = Box::pin(read_timeout(payload, data.payload_read_timeout));
+    let put = match body_sha256 {
+        Some(body_sha256) => {
+            let length = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            put_file(&req, &data, api_key, &dav_path, verify_body(body, body_sha256, length)).await
+        }
+        None => put_file(&req, &data, api_key, &dav_path, body).await,
+    };
+    match put {
+        Ok(put) => HttpResponse::Ok()
+            .insert_header((header::ETAG, format!("\"{}\"", put.content_hash)))
+            .insert_header(("X-Upload-Id", put.id.to_string()))
+            .finish(),
+        Err(e) => s3_error(&req, e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "s3",
+    path = "/s3/{bucket}/{key}",
+    params(
+        ("bucket" = String, Path, description = "First segment of the path the object was put at"),
+        ("key" = String, Path, description = "Rest of the path"),
+    ),
+    responses(
+        (status = 200, description = "The object, as it was put"),
+        (status = 403, description = "Missing credentials, or the signature doesn't match"),
+        (status = 404, description = "No object was put at the key"),
+    ),
+    security((), ("bearer" = []), ("api_key" = []))
+)]
+#[route("/s3/{bucket}/{key:.*}", method = "GET", method = "HEAD")]
+pub(crate) async fn s3_get_object(req: HttpRequest, path: web::Path<(String, String)>, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    let api_key = match s3_api_key(&req, &settings.api_keys) {
+        Ok((api_key, _)) => api_key,
+        Err(e) => return s3_error(&req, e),
+    };
+    if settings.signed_urls.is_some() && api_key.is_none() {
+        return s3_error(&req, Error::Forbidden("Access Denied".to_string()));
+    }
+    let (bucket, key) = path.into_inner();
+    let tenant = api_key.map(|api_key| api_key.name.as_str());
+    serve_put_file(&req, &data, tenant, &format!("{}/{}", bucket, key)).await.unwrap_or_else(|e| s3_error(&req, e))
+}
+
+#[utoipa::path(
+    delete,
+    tag = "s3",
+    path = "/s3/{bucket}/{key}",
+    params(
+        ("bucket" = String, Path, description = "First segment of the path the object was put at"),
+        ("key" = String, Path, description = "Rest of the path"),
+    ),
+    responses(
+        (status = 204, description = "The object was deleted, or there was none"),
+        (status = 403, description = "Missing credentials, or the signature doesn't match"),
+    ),
+    security((), ("bearer" = []), ("api_key" = []))
+)]
+#[route("/s3/{bucket}/{key:.*}", method = "DELETE")]
+pub(crate) async fn s3_delete_object(req: HttpRequest, path: web::Path<(String, String)>, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    let api_key = match s3_api_key(&req, &settings.api_keys) {
+        Ok((api_key, _)) => api_key,
+        Err(e) => return s3_error(&req, e),
+    };
+    let (bucket, key) = path.into_inner();
+    let tenant = api_key.map(|api_key| api_key.name.as_str());
+    // S3 answers the same whether or not there was an object
+    match delete_put_file(&req, &data, tenant, &format!("{}/{}", bucket, key)).await {
+        Ok(()) | Err(Error::NotFound(_)) => HttpResponse::NoContent().finish(),
+        Err(e) => s3_error(&req, e),
+    }
+}
@@ -0,0 +1,1277 @@
+// Uploading: multipart and ZIP uploads, what becomes of them, and deleting them again
+
+use actix_multipart::{Field, Multipart};
+use actix_web::http::header;
+use actix_web::error::PayloadError;
+use actix_web::{get, post, route, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use futures_util::stream::StreamExt as _;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::types::ChatId;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use tracing::{debug, error, info, Instrument};
+use crate::config::{ApiKeyConfig, ChatRef};
+use crate::batch::extract_zip;
+use crate::error::Error;
+use crate::telegram::{PhotoVariant, SendOptions, UploadMode, resolve_chat, upload_url};
+use crate::storage::{Idempotency, OutboxEntry, StoredResponse, QuotaReservation, SavedFile, UploadRecord, remove_temp_file, to_hex};
+use crate::upload::{Destination, HostedFile, Settings, UploadData, UploadFlags, WebhookPayload, host_file, is_type_allowed, notify_webhooks, remove_upload};
+use crate::metrics::InFlight;
+use crate::progress::{progress_events, ProgressOutcome, UploadProgress};
+use crate::audit::{note_audited, AuditedError, AuditedFile};
+use crate::http::{ErrorBody, api_key, base_url, client_ip, constant_time_eq, error_response, may_see};
+use crate::http::middleware::request_id;
+
+// Longest text form field accepted next to the file
+pub(crate) const MAX_FORM_FIELD_LENGTH: usize = 8192;
+
+// Read a text form field into a string
+pub(crate) async fn read_text_field(field: &mut Field) -> Result<String, Error> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        if value.len() + data.len() > MAX_FORM_FIELD_LENGTH {
+            return Err(Error::InvalidRequest("Form field is too long".to_string()));
+        }
+        value.extend_from_slice(&data);
+    }
+    String::from_utf8(value).map_err(|_| Error::InvalidRequest("Form field is not valid UTF-8".to_string()))
+}
+
+// Stream a multipart field, or a whole request body, into a new file, returning the SHA-256 of
+// its contents
+pub(crate) async fn write_field<E>(
+    field: &mut (impl futures_util::Stream<Item = Result<web::Bytes, E>> + Unpin),
+    filepath: &str,
+    reservation: &mut QuotaReservation<'_>,
+    received: Option<&AtomicU64>,
+) -> Result<String, Error>
+where
+    Error: From<E>,
+{
+    let mut f = File::create(filepath).map_err(|e| {
+        error!("Failed to create file: {:?}", e);
+        Error::Internal("Failed to save file".to_string())
+    })?;
+
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = field.next().await {
+        // The client went away, stalled or sent a body that doesn't match its signature
+        let data = match chunk {
+            Ok(data) => data,
+            Err(e) => {
+                drop(f);
+                let _ = std::fs::remove_file(filepath);
+                return Err(e.into());
+            }
+        };
+        if !reservation.grow(data.len() as u64) {
+            drop(f);
+            let _ = std::fs::remove_file(filepath);
+            return Err(Error::StorageFull);
+        }
+        if let Some(received) = received {
+            received.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        hasher.update(&data);
+        if let Err(e) = f.write_all(&data) {
+            error!("Failed to write file: {:?}", e);
+            drop(f);
+            let _ = std::fs::remove_file(filepath);
+            return Err(Error::Internal("Failed to save file".to_string()));
+        }
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+// Save the file locally under the ID of the upload, counting the bytes received if asked to
+pub(crate) async fn save_file(
+    mut payload: Multipart,
+    temp_dir: &Path,
+    allowed_types: &[String],
+    reservation: &mut QuotaReservation<'_>,
+    unique_id: Uuid,
+    received: Option<&AtomicU64>,
+) -> Result<SavedFile, Error> {
+    let mut file_path = String::new();
+    let mut upload_id = Uuid::nil();
+    let mut content_hash = String::new();
+    let mut fields = HashMap::new();
+    let mut file_name = String::new();
+    let mut content_type = None;
+
+    // Whichever part fails, the file saved so far doesn't stay behind
+    let parts = async {
+        while let Some(item) = payload.next().await {
+            let mut field = item?;
+            let content_disposition = field.content_disposition().unwrap().clone();
+            let Some(filename) = content_disposition.get_filename() else {
+                // Not a file, but one of the text fields that go with it
+                let name = field.name().unwrap_or_default().to_string();
+                fields.insert(name, read_text_field(&mut field).await?);
+                continue;
+            };
+            debug!("Received file: {:?}", filename);
+
+            if !is_type_allowed(allowed_types, filename, field.content_type()) {
+                error!("Rejected file with disallowed type: {:?} ({:?})", filename, field.content_type());
+                return Err(Error::UnsupportedMediaType("File type is not allowed".to_string()));
+            }
+
+            #[allow(clippy::needless_borrows_for_generic_args)]
+            let sanitized_filename = sanitize_filename::sanitize(&filename);
+            let filepath = temp_dir.join(format!("{}_{}", unique_id, sanitized_filename)).to_string_lossy().into_owned();
+            reservation.hold(unique_id);
+
+            // Create and write to the file, which takes the place of any earlier one in the form
+            let hash = write_field(&mut field, &filepath, reservation, received)
+                .instrument(tracing::info_span!("temp_write", file = %filepath))
+                .await?;
+            if !file_path.is_empty() && file_path != filepath {
+                let _ = std::fs::remove_file(&file_path);
+            }
+            file_path = filepath;
+            upload_id = unique_id;
+            content_hash = hash;
+            content_type = mime_guess::from_path(filename).first().or_else(|| field.content_type().cloned());
+            file_name = sanitized_filename;
+            info!("File created successfully: {:?}", file_path);
+        }
+        Ok(())
+    };
+    if let Err(e) = parts.await {
+        if !file_path.is_empty() {
+            let _ = std::fs::remove_file(&file_path);
+        }
+        return Err(e);
+    }
+
+    if file_path.is_empty() {
+        error!("File path is empty, failed to save file");
+        return Err(Error::InvalidRequest("The form has no file".to_string()));
+    }
+
+    Ok(SavedFile {
+        id: upload_id,
+        file_path,
+        content_hash,
+        fields,
+        file_name,
+        content_type: content_type.map(|content_type| content_type.essence_str().to_string()),
+    })
+}
+
+// Resolve the chat a client asked to upload to, checking its key may do so
+pub(crate) async fn chat_override(
+    data: &UploadData,
+    api_key: Option<&ApiKeyConfig>,
+    chat: &str,
+) -> Result<ChatId, Error> {
+    if !api_key.is_some_and(|api_key| api_key.allow_chat_override) {
+        return Err(Error::Forbidden("This API key may not choose the chat".to_string()));
+    }
+    let chat = chat.parse::<ChatRef>().map_err(Error::InvalidRequest)?;
+    match data.metrics.time_telegram("get_chat", resolve_chat(data.bots.primary(), &chat)).await {
+        Ok(chat_id) => Ok(chat_id),
+        Err(e) => match Error::from(e) {
+            // Telegram not knowing the chat is the client's mistake, not a failed upload
+            Error::TelegramRejected(message) => Err(Error::InvalidRequest(format!("Failed to resolve chat: {}", message))),
+            e => Err(e),
+        },
+    }
+}
+
+// Keys with their own chat or topic post there unless the client asks otherwise
+pub(crate) fn send_defaults(settings: &Settings, api_key: Option<&ApiKeyConfig>) -> SendOptions {
+    let mut defaults = settings.send_options.clone();
+    if let Some(api_key) = api_key.filter(|api_key| api_key.chat_id.is_some() || api_key.message_thread_id.is_some()) {
+        defaults.message_thread_id = api_key.message_thread_id;
+    }
+    defaults
+}
+
+// A chat picked by the client replaces the configured ones, if its key is allowed to, and so
+// does the chat of its key. Failures come with the reason they are counted under.
+pub(crate) async fn upload_destination(
+    data: &UploadData,
+    api_key: Option<&ApiKeyConfig>,
+    requested_chat: Option<&String>,
+) -> Result<Destination, (&'static str, Error)> {
+    let chat = match (requested_chat, api_key.and_then(|api_key| api_key.chat_id.as_ref())) {
+        (Some(chat), _) => match chat_override(data, api_key, chat).await {
+            Ok(chat_id) => Some(chat_id),
+            Err(e) => {
+                error!("Rejected upload to chat {:?}: {}", chat, e);
+                return Err(("invalid", e));
+            }
+        },
+        (None, Some(chat)) => match data.metrics.time_telegram("get_chat", resolve_chat(data.bots.primary(), chat)).await {
+            Ok(chat_id) => Some(chat_id),
+            Err(e) => {
+                let e = Error::from(e);
+                error!("Failed to resolve chat {:?} of the API key: {}", chat, e);
+                return Err(("telegram", e));
+            }
+        },
+        (None, None) => None,
+    };
+    Ok(Destination { chat, tenant: api_key.map(|api_key| api_key.name.clone()), dav_path: None })
+}
+
+#[utoipa::path(
+    tag = "uploads",
+    params(
+        UploadQuery,
+        ("Idempotency-Key" = Option<String>, Header, description = "Unique key of the upload, so a retry returns the original response. Keys are per API key, or per client address without one."),
+    ),
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The hosted file: its URL as text, or details with ?format=json or ?format=sharex", body = UploadResponse),
+        (status = 202, description = "Telegram is unreachable, the upload waits in the outbox"),
+        (status = 303, description = "Redirect to the hosted file with ?format=redirect"),
+        (status = 400, description = "Invalid form fields or query parameters", body = ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 403, description = "The API key may not choose the chat", body = ErrorBody),
+        (status = 409, description = "An upload with the same Idempotency-Key is still in progress", body = ErrorBody),
+        (status = 413, description = "The file is too large", body = ErrorBody),
+        (status = 415, description = "The file type is not allowed or can't be processed", body = ErrorBody),
+        (status = 422, description = "Content moderation blocked the upload, ClamAV found it infected, or the Idempotency-Key was used for a different upload", body = ErrorBody),
+        (status = 429, description = "Telegram is rate limiting uploads", body = ErrorBody),
+        (status = 502, description = "Telegram rejected the upload", body = ErrorBody),
+        (status = 503, description = "Telegram is unavailable, or too many uploads are waiting", body = ErrorBody),
+        (status = 507, description = "The temp directory is full", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[post("/upload")]
+pub(crate) async fn upload(
+    req: HttpRequest,
+    query: web::Query<UploadQuery>,
+    payload: web::Payload,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    let payload = Multipart::new(req.headers(), read_timeout(payload, data.payload_read_timeout));
+    let key = match idempotency_key(&req) {
+        Ok(key) => key,
+        Err(e) => return upload_error(&req, e, response_format(&req, &query)),
+    };
+    let (Some(key), Some(idempotency)) = (key, &data.idempotency) else {
+        return handle_upload(&req, &query, payload, &data).await;
+    };
+
+    // Keys only have to be unique per client: its API key, or without one its address. Keys of
+    // clients that can't be told apart are ignored rather than shared.
+    let settings = data.settings();
+    let client = match api_key(&req, &settings.api_keys) {
+        Some(api_key) => Some(format!("key {}", api_key.name)),
+        None => client_ip(&req, &settings.trusted_proxies).map(|ip| format!("ip {}", ip)),
+    };
+    drop(settings);
+    let Some(client) = client else {
+        return handle_upload(&req, &query, payload, &data).await;
+    };
+    let guard = match idempotency.begin(format!("{}\n{}", client, key)) {
+        Idempotency::Started(guard) => guard,
+        Idempotency::Replay { digest, response: stored } => {
+            // The same key for another upload is a client bug, not a retry
+            match form_digest(payload, req.query_string()).await {
+                Ok(retried) if retried == digest => {}
+                Ok(_) => return upload_error(&req, Error::IdempotencyKeyReused, response_format(&req, &query)),
+                Err(e) => return upload_error(&req, e, response_format(&req, &query)),
+            }
+            info!("Replaying the response to an earlier upload with the same Idempotency-Key");
+            let mut response = HttpResponse::build(stored.status);
+            for (name, value) in &stored.headers {
+                response.append_header((name.clone(), value.clone()));
+            }
+            return response.insert_header(("Idempotent-Replayed", "true")).body(stored.body);
+        }
+        Idempotency::InFlight => {
+            let e = Error::Conflict("An upload with this Idempotency-Key is still in progress".to_string());
+            return upload_error(&req, e, response_format(&req, &query));
+        }
+    };
+
+    // Failures aren't remembered, so the client can retry them with the same key
+    let response = handle_upload(&req, &query, payload, &data).await;
+    if !response.status().is_success() && !response.status().is_redirection() {
+        return response;
+    }
+    let Some(UploadDigest(digest)) = req.extensions_mut().remove::<UploadDigest>() else {
+        return response;
+    };
+    let (response, body) = response.into_parts();
+    let Ok(body) = actix_web::body::to_bytes(body).await else {
+        return upload_error(&req, Error::Internal("Failed to buffer the response".to_string()), ResponseFormat::Txt);
+    };
+    guard.complete(digest, StoredResponse { status: response.status(), headers: response.headers().clone(), body: body.clone() });
+    response.set_body(body).map_into_boxed_body()
+}
+
+// Digest of what an upload asked for, its query, form fields and file, left by handle_upload in
+// the request's extensions
+pub(crate) struct UploadDigest(pub(crate) String);
+
+// The same for a retry whatever boundary its form was encoded with
+pub(crate) fn upload_digest(query: &str, file_name: &str, content_hash: &str, fields: &HashMap<String, String>) -> String {
+    let mut hasher = Sha256::new();
+    for part in [query, file_name, content_hash] {
+        hasher.update(part.len().to_le_bytes());
+        hasher.update(part);
+    }
+    let mut fields: Vec<_> = fields.iter().collect();
+    fields.sort();
+    for (name, value) in fields {
+        for part in [name, value] {
+            hasher.update(part.len().to_le_bytes());
+            hasher.update(part);
+        }
+    }
+    to_hex(&hasher.finalize())
+}
+
+// Read a form the way save_file does for its upload_digest, without keeping the file
+pub(crate) async fn form_digest(mut payload: Multipart, query: &str) -> Result<String, Error> {
+    let mut fields = HashMap::new();
+    let mut file_name = String::new();
+    let mut content_hash = String::new();
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        let Some(filename) = field.content_disposition().and_then(|disposition| disposition.get_filename()).map(sanitize_filename::sanitize) else {
+            let name = field.name().unwrap_or_default().to_string();
+            fields.insert(name, read_text_field(&mut field).await?);
+            continue;
+        };
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = field.next().await {
+            hasher.update(&chunk?);
+        }
+        file_name = filename;
+        content_hash = to_hex(&hasher.finalize());
+    }
+    Ok(upload_digest(query, &file_name, &content_hash, &fields))
+}
+
+// Fail a request body once the client has sent nothing of it for the timeout
+pub(crate) fn read_timeout(
+    payload: web::Payload,
+    timeout: Option<Duration>,
+) -> impl futures_util::Stream<Item = Result<web::Bytes, PayloadError>> {
+    futures_util::stream::unfold(Some(payload), move |payload| async move {
+        let mut payload = payload?;
+        let Some(timeout) = timeout else {
+            return payload.next().await.map(|chunk| (chunk, Some(payload)));
+        };
+        match tokio::time::timeout(timeout, payload.next()).await {
+            Ok(chunk) => chunk.map(|chunk| (chunk, Some(payload))),
+            Err(_) => {
+                let e = std::io::Error::new(std::io::ErrorKind::TimedOut, "The client stopped sending the request body");
+                Some((Err(PayloadError::Io(e)), None))
+            }
+        }
+    })
+    // The multipart parser may poll again after the end
+    .fuse()
+}
+
+// Longest Idempotency-Key accepted
+pub(crate) const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+// The Idempotency-Key header of a request, if it has a usable one
+pub(crate) fn idempotency_key(req: &HttpRequest) -> Result<Option<String>, Error> {
+    let Some(value) = req.headers().get("Idempotency-Key") else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH && key.chars().all(|c| c.is_ascii_graphic()) => {
+            Ok(Some(key.to_string()))
+        }
+        _ => Err(Error::InvalidRequest(format!(
+            "Idempotency-Key must be 1 to {} printable ASCII characters",
+            MAX_IDEMPOTENCY_KEY_LENGTH
+        ))),
+    }
+}
+
+// Size of the request body as the client announced it, 0 if it didn't
+pub(crate) fn content_length(req: &HttpRequest) -> u64 {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+// Checks made before an upload is read, returning whether it has to go to the outbox.
+// Failures come with the reason they are counted under.
+pub(crate) fn admit_upload(req: &HttpRequest, data: &UploadData) -> Result<bool, (&'static str, Error)> {
+    // Don't tie up the server with uploads that are bound to fail while Telegram is down,
+    // unless they can wait in the outbox
+    let circuit = data.circuit_breaker.check();
+    let circuit_open = circuit.is_err();
+    if let (Err(retry_after), None) = (circuit, &data.outbox) {
+        let retry_after = Some(retry_after.as_secs_f64().ceil() as u64);
+        return Err(("circuit_open", Error::TelegramUnavailable { retry_after }));
+    }
+
+    // Nor read uploads that would only be turned away once they are saved
+    if data.queue_full() {
+        let retry_after = data.upload_queue.retry_after_secs.max(1);
+        return Err(("overloaded", Error::Overloaded { retry_after }));
+    }
+
+    // Turn away uploads that won't fit before reading them, as far as the client told us their size
+    let content_length = content_length(req);
+    if !data.temp_quota.has_room(content_length) {
+        error!("Rejected upload of {} bytes, temp directory quota exceeded", content_length);
+        return Err(("quota", Error::StorageFull));
+    }
+    Ok(circuit_open)
+}
+
+// The upload itself, after the Idempotency-Key was dealt with
+pub(crate) async fn handle_upload(
+    req: &HttpRequest,
+    query: &UploadQuery,
+    payload: Multipart,
+    data: &UploadData,
+) -> HttpResponse {
+    let format = response_format(req, query);
+
+    debug!("Starting upload process for chats: {:?}", data.chat_ids);
+    data.metrics.uploads_total.inc();
+    let _in_flight = InFlight::new(&data.metrics.uploads_in_flight);
+
+    let settings = data.settings();
+    let api_key = api_key(req, &settings.api_keys);
+    if !settings.api_keys.is_empty() && api_key.is_none() {
+        data.metrics.upload_failed("unauthorized");
+        return upload_error(req, Error::Unauthorized("Missing or invalid API key".to_string()), format);
+    }
+
+    // An upload announced through POST /progress takes the ID handed out there
+    let progress = match query.progress {
+        Some(id) => match data.progress.get(&id) {
+            Some(progress) if !progress.started.swap(true, Ordering::Relaxed) => {
+                req.extensions_mut().insert(progress.clone());
+                Some((id, progress))
+            }
+            Some(_) => {
+                data.metrics.upload_failed("invalid");
+                return upload_error(req, Error::Conflict("This progress ID is already in use".to_string()), format);
+            }
+            None => {
+                data.metrics.upload_failed("invalid");
+                return upload_error(req, Error::InvalidRequest("Unknown or expired progress ID".to_string()), format);
+            }
+        },
+        None => None,
+    };
+
+    if let Some((_, progress)) = &progress {
+        progress.expected.store(content_length(req), Ordering::Relaxed);
+    }
+    let circuit_open = match admit_upload(req, data) {
+        Ok(circuit_open) => circuit_open,
+        Err((reason, e)) => {
+            data.metrics.upload_failed(reason);
+            return upload_error(req, e, format);
+        }
+    };
+
+    // Save the uploaded file
+    let mut reservation = data.temp_quota.reserve();
+    let upload_id = progress.as_ref().map_or_else(Uuid::new_v4, |(id, _)| *id);
+    let received = progress.as_ref().map(|(_, progress)| &progress.received);
+    let saved = match save_file(payload, &data.temp_dir, &settings.allowed_types, &mut reservation, upload_id, received)
+        .instrument(tracing::info_span!("multipart_read"))
+        .await
+    {
+        Ok(saved) => saved,
+        Err(e) => {
+            error!("Failed to save file: {:?}", e);
+            data.metrics.upload_failed(if e == Error::StorageFull { "quota" } else { "save" });
+            return upload_error(req, e, format);
+        }
+    };
+    let path = Path::new(&saved.file_path);
+    debug!("File saved locally at: {:?}", path);
+    req.extensions_mut().insert(UploadDigest(upload_digest(req.query_string(), &saved.file_name, &saved.content_hash, &saved.fields)));
+
+    let mut options = match send_defaults(&settings, api_key).with_overrides(query, &saved.fields) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("Rejected upload with invalid options: {}", e);
+            data.metrics.upload_failed("invalid");
+            remove_temp_file(path);
+            notify_webhooks(data, &WebhookPayload::failed(saved.id, Some(&saved.file_name), &e));
+            return upload_error(req, Error::InvalidRequest(e), format);
+        }
+    };
+    options.mode = match options.mode.resolve(path, saved.content_type.as_deref()) {
+        Ok(mode) => mode,
+        Err(e) => {
+            error!("Rejected upload of {:?} as {:?}: {}", saved.content_type, options.mode, e);
+            data.metrics.upload_failed("invalid");
+            remove_temp_file(path);
+            notify_webhooks(data, &WebhookPayload::failed(saved.id, Some(&saved.file_name), &e));
+            return upload_error(req, Error::UnsupportedMediaType(e), format);
+        }
+    };
+    options.file_name = Some(saved.file_name.clone());
+    options.progress = progress.as_ref().map(|(_, progress)| progress.sending.clone());
+
+    let requested_chat = saved.fields.get("chat").or(query.chat.as_ref());
+    let destination = match upload_destination(data, api_key, requested_chat).await {
+        Ok(destination) => destination,
+        Err((reason, e)) => {
+            data.metrics.upload_failed(reason);
+            remove_temp_file(path);
+            notify_webhooks(data, &WebhookPayload::failed(saved.id, Some(&saved.file_name), &e.to_string()));
+            return upload_error(req, e, format);
+        }
+    };
+
+    let response = match host_file(data, &saved, &options, &destination, circuit_open).await {
+        Ok(HostedFile::Sent { record, file_path, flags }) => {
+            note_audited(req, AuditedFile::of(&record));
+            let url = upload_url(&settings, &base_url(req, data), data.bots.get(record.bot_id), &record.id, &file_path);
+            debug!("Successfully uploaded image to Telegram, URL: {}", url);
+            upload_response(req, data, &record, &url, flags, format)
+        }
+        Ok(HostedFile::Queued { entry, flags }) => {
+            note_audited(req, AuditedFile::queued(&entry));
+            pending_response(req, data, &entry, flags, format)
+        }
+        Err(e) => {
+            let error = Some(e.to_string());
+            note_audited(req, AuditedFile { content_hash: Some(saved.content_hash.clone()), error, ..AuditedFile::default() });
+            upload_error(req, e, format)
+        }
+    };
+    if let Some((_, progress)) = progress {
+        progress.finish(ProgressOutcome::Done { status: response.status().as_u16() });
+    }
+    response
+}
+
+// Tell the client where a queued upload will show up
+pub(crate) fn pending_response(
+    req: &HttpRequest,
+    data: &UploadData,
+    entry: &OutboxEntry,
+    flags: UploadFlags,
+    format: ResponseFormat,
+) -> HttpResponse {
+    let id = entry.id;
+    // The proxy URL starts working as soon as the upload is delivered
+    let base_url = base_url(req, data);
+    let url = data.settings().file_url(&base_url, &id);
+    let status_url = format!("{}/pending/{}", base_url, id);
+    let mut response = HttpResponse::Accepted();
+    response.insert_header(("X-Upload-Id", id.to_string())).insert_header(("X-Pending", "true"));
+
+    match format {
+        ResponseFormat::Txt => response.body(url),
+        ResponseFormat::Redirect => response.insert_header((header::LOCATION, status_url)).finish(),
+        ResponseFormat::Sharex => response.json(SharexResponse {
+            url: &url,
+            thumbnail_url: data.thumbnail_dir.as_ref().map(|_| data.settings().thumbnail_url(&base_url, &id)),
+            deletion_url: format!("{}/delete/{}/{}", base_url, id, entry.deletion_token),
+        }),
+        ResponseFormat::Json => response.json(serde_json::json!({
+            "id": id,
+            "status": "pending",
+            "url": url,
+            "status_url": status_url,
+            "deletion_token": entry.deletion_token,
+            "converted": flags.converted,
+            "recompressed": flags.recompressed,
+        })),
+    }
+}
+
+// What became of an entry of an archive posted to /upload/zip
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ZipEntryStatus {
+    Hosted,
+    // Telegram is unreachable, the entry waits in the outbox
+    Queued,
+    Failed,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ZipManifestEntry {
+    // Path of the entry inside the archive
+    pub(crate) name: String,
+    pub(crate) status: ZipEntryStatus,
+    pub(crate) id: Option<Uuid>,
+    pub(crate) url: Option<String>,
+    pub(crate) deletion_token: Option<String>,
+    // Machine-readable code and message of what went wrong, for failed entries
+    pub(crate) error: Option<String>,
+    pub(crate) message: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ZipManifest {
+    pub(crate) entries: Vec<ZipManifestEntry>,
+    pub(crate) hosted: usize,
+    pub(crate) queued: usize,
+    pub(crate) failed: usize,
+}
+
+impl ZipManifestEntry {
+    pub(crate) fn failed(name: String, error: &Error) -> ZipManifestEntry {
+        ZipManifestEntry {
+            name,
+            status: ZipEntryStatus::Failed,
+            id: None,
+            url: None,
+            deletion_token: None,
+            error: Some(error.code().to_string()),
+            message: Some(error.to_string()),
+        }
+    }
+}
+
+// Types an archive is accepted as
+pub(crate) const ZIP_TYPES: [&str; 3] = [".zip", "application/zip", "application/x-zip-compressed"];
+
+#[utoipa::path(
+    tag = "uploads",
+    params(UploadQuery),
+    request_body(content = UploadForm, content_type = "multipart/form-data", description = "The form of /upload, with a ZIP archive as the file"),
+    responses(
+        (status = 200, description = "What became of each entry of the archive", body = ZipManifest),
+        (status = 400, description = "The upload isn't a valid archive, or has too many entries", body = ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 403, description = "The API key may not choose the chat", body = ErrorBody),
+        (status = 413, description = "The archive extracts to more than zip_upload.max_total_bytes", body = ErrorBody),
+        (status = 415, description = "The upload isn't a ZIP archive", body = ErrorBody),
+        (status = 503, description = "Telegram is unavailable, or too many uploads are waiting", body = ErrorBody),
+        (status = 507, description = "The temp directory is full", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[post("/upload/zip")]
+pub(crate) async fn upload_zip(
+    req: HttpRequest,
+    query: web::Query<UploadQuery>,
+    payload: web::Payload,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    let payload = Multipart::new(req.headers(), read_timeout(payload, data.payload_read_timeout));
+    let settings = data.settings();
+    let api_key = api_key(&req, &settings.api_keys);
+    if !settings.api_keys.is_empty() && api_key.is_none() {
+        data.metrics.uploads_total.inc();
+        data.metrics.upload_failed("unauthorized");
+        return error_response(&req, Error::Unauthorized("Missing or invalid API key".to_string()));
+    }
+
+    let circuit_open = match admit_upload(&req, &data) {
+        Ok(circuit_open) => circuit_open,
+        Err((reason, e)) => {
+            data.metrics.uploads_total.inc();
+            data.metrics.upload_failed(reason);
+            return error_response(&req, e);
+        }
+    };
+
+    let mut reservation = data.temp_quota.reserve();
+    let zip_types = ZIP_TYPES.map(str::to_string);
+    let archive = match save_file(payload, &data.temp_dir, &zip_types, &mut reservation, Uuid::new_v4(), None)
+        .instrument(tracing::info_span!("multipart_read"))
+        .await
+    {
+        Ok(archive) => archive,
+        Err(e) => {
+            error!("Failed to save archive: {:?}", e);
+            data.metrics.uploads_total.inc();
+            data.metrics.upload_failed(if e == Error::StorageFull { "quota" } else { "save" });
+            return error_response(&req, e);
+        }
+    };
+    let archive_path = PathBuf::from(&archive.file_path);
+
+    // The form fields and query apply to every entry, so they are checked once
+    let requested_chat = archive.fields.get("chat").or(query.chat.as_ref());
+    let prepared = match send_defaults(&settings, api_key).with_overrides(&query, &archive.fields) {
+        Ok(options) => upload_destination(&data, api_key, requested_chat).await.map(|destination| (options, destination)),
+        Err(e) => Err(("invalid", Error::InvalidRequest(e))),
+    };
+    let (options, destination) = match prepared {
+        Ok(prepared) => prepared,
+        Err((reason, e)) => {
+            remove_temp_file(&archive_path);
+            data.metrics.uploads_total.inc();
+            data.metrics.upload_failed(reason);
+            return error_response(&req, e);
+        }
+    };
+
+    // Extraction reads and writes files, away from the async workers. What comes out counts against
+    // the quota as it is written, and takes up the temp directory until it's hosted
+    let extracted = {
+        let (data, allowed_types) = (data.clone(), settings.allowed_types.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut extraction = data.temp_quota.reserve();
+            let extracted = extract_zip(&archive_path, &data.temp_dir, &data.zip_upload, &allowed_types, &mut extraction);
+            remove_temp_file(&archive_path);
+            extracted.map(|entries| (entries, extraction.into_bytes()))
+        })
+        .await
+    };
+    let entries = match extracted {
+        Ok(Ok((entries, extracted_bytes))) => {
+            reservation.take_over(extracted_bytes);
+            entries
+        }
+        Ok(Err(e)) => {
+            data.metrics.uploads_total.inc();
+            data.metrics.upload_failed(if e == Error::StorageFull { "quota" } else { "invalid" });
+            return error_response(&req, e);
+        }
+        Err(e) => {
+            error!("Failed to extract archive: {:?}", e);
+            data.metrics.uploads_total.inc();
+            data.metrics.upload_failed("save");
+            return error_response(&req, Error::Internal("Failed to extract the archive".to_string()));
+        }
+    };
+    for saved in entries.iter().filter_map(|entry| entry.extracted.as_ref().ok()) {
+        reservation.hold(saved.id);
+    }
+    info!("Extracted {} entries from archive {}", entries.len(), archive.id);
+
+    let base_url = base_url(&req, &data);
+    let mut manifest = ZipManifest { entries: Vec::new(), hosted: 0, queued: 0, failed: 0 };
+    for entry in entries {
+        data.metrics.uploads_total.inc();
+        let _in_flight = InFlight::new(&data.metrics.uploads_in_flight);
+        let saved = match entry.extracted {
+            Ok(saved) => saved,
+            Err(e) => {
+                data.metrics.upload_failed("invalid");
+                manifest.entries.push(ZipManifestEntry::failed(entry.name, &e));
+                continue;
+            }
+        };
+        let path = Path::new(&saved.file_path);
+
+        let mut options = options.clone();
+        options.mode = match options.mode.resolve(path, saved.content_type.as_deref()) {
+            Ok(mode) => mode,
+            Err(e) => {
+                data.metrics.upload_failed("invalid");
+                remove_temp_file(path);
+                manifest.entries.push(ZipManifestEntry::failed(entry.name, &Error::UnsupportedMediaType(e)));
+                continue;
+            }
+        };
+        options.file_name = Some(saved.file_name.clone());
+
+        let hosted = host_file(&data, &saved, &options, &destination, circuit_open).await;
+        note_audited(
+            &req,
+            match &hosted {
+                Ok(HostedFile::Sent { record, .. }) => AuditedFile::of(record),
+                Ok(HostedFile::Queued { entry, .. }) => AuditedFile::queued(entry),
+                Err(e) => AuditedFile {
+                    content_hash: Some(saved.content_hash.clone()),
+                    error: Some(e.to_string()),
+                    ..AuditedFile::default()
+                },
+            },
+        );
+        let hosted = match hosted {
+            Ok(HostedFile::Sent { record, file_path, .. }) => ZipManifestEntry {
+                name: entry.name,
+                status: ZipEntryStatus::Hosted,
+                id: Some(record.id),
+                url: Some(upload_url(&settings, &base_url, data.bots.get(record.bot_id), &record.id, &file_path)),
+                deletion_token: Some(record.deletion_token),
+                error: None,
+                message: None,
+            },
+            Ok(HostedFile::Queued { entry: queued, .. }) => ZipManifestEntry {
+                name: entry.name,
+                status: ZipEntryStatus::Queued,
+                id: Some(queued.id),
+                url: Some(settings.file_url(&base_url, &queued.id)),
+                deletion_token: Some(queued.deletion_token),
+                error: None,
+                message: None,
+            },
+            Err(e) => ZipManifestEntry::failed(entry.name, &e),
+        };
+        manifest.entries.push(hosted);
+    }
+
+    for entry in &manifest.entries {
+        match entry.status {
+            ZipEntryStatus::Hosted => manifest.hosted += 1,
+            ZipEntryStatus::Queued => manifest.queued += 1,
+            ZipEntryStatus::Failed => manifest.failed += 1,
+        }
+    }
+    HttpResponse::Ok().json(manifest)
+}
+
+// Check on an upload that went through the outbox
+#[utoipa::path(
+    tag = "uploads",
+    params(("id" = Uuid, Path, description = "ID of the upload")),
+    responses(
+        (status = 200, description = "Whether the upload was delivered, is still pending or failed. The link of a delivered upload is left out when signed_urls is set and the request has no API key."),
+        (status = 404, description = "Upload not found"),
+    )
+)]
+#[get("/pending/{id}")]
+pub(crate) async fn pending_status(req: HttpRequest, id: web::Path<String>, data: web::Data<UploadData>) -> impl Responder {
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, Error::NotFound("Upload not found".to_string()));
+    };
+
+    if let Some(record) = data.registry.get(&id) {
+        // Anyone may check on an upload, but a signed link is only handed to clients with a key
+        let settings = data.settings();
+        let url = (settings.signed_urls.is_none() || api_key(&req, &settings.api_keys).is_some())
+            .then(|| settings.file_url(&base_url(&req, &data), &id));
+        return HttpResponse::Ok().json(serde_json::json!({
+            "id": id,
+            "status": "delivered",
+            "url": url,
+            "uploaded_at": record.uploaded_at,
+        }));
+    }
+
+    match data.outbox.as_ref().and_then(|outbox| outbox.get(&id)) {
+        Some(entry) => HttpResponse::Ok().json(serde_json::json!({
+            "id": id,
+            "status": if entry.failed { "failed" } else { "pending" },
+            "queued_at": entry.queued_at,
+            "attempts": entry.attempts,
+            "last_error": entry.last_error,
+        })),
+        None => error_response(&req, Error::NotFound("Upload not found".to_string())),
+    }
+}
+
+// What the registry knows about an upload
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct UploadInfo {
+    pub(crate) id: Uuid,
+    pub(crate) url: String,
+    // Name of the file as the client sent it, unknown for uploads from before it was recorded
+    pub(crate) file_name: Option<String>,
+    pub(crate) size: Option<u64>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) content_hash: Option<String>,
+    pub(crate) uploaded_at: DateTime<Utc>,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    pub(crate) sent_as: UploadMode,
+    pub(crate) chat_id: i64,
+    pub(crate) message_id: i32,
+    pub(crate) file_id: String,
+    pub(crate) file_unique_id: String,
+}
+
+// Look up an upload, so clients can check what they uploaded earlier and where it is now.
+// Needs an API key whenever uploading does, and only shows uploads made with that key unless
+// it is an admin key.
+#[utoipa::path(
+    tag = "uploads",
+    params(("id" = Uuid, Path, description = "ID of the upload")),
+    responses(
+        (status = 200, description = "The upload's registry record", body = UploadInfo),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 404, description = "Upload not found", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[get("/info/{id}")]
+pub(crate) async fn upload_info(req: HttpRequest, id: web::Path<String>, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    let api_key = api_key(&req, &settings.api_keys);
+    if !settings.api_keys.is_empty() && api_key.is_none() {
+        return error_response(&req, Error::Unauthorized("Missing or invalid API key".to_string()));
+    }
+
+    // Uploads of other tenants look the same as ones that don't exist. Without api_keys
+    // there are no tenants.
+    let visible = |record: &UploadRecord| api_key.map_or(record.tenant.is_none(), |api_key| may_see(api_key, record));
+    let Some(record) = Uuid::parse_str(&id).ok().and_then(|id| data.registry.get(&id)).filter(visible) else {
+        return error_response(&req, Error::NotFound("Upload not found".to_string()));
+    };
+    HttpResponse::Ok().json(UploadInfo {
+        id: record.id,
+        url: settings.file_url(&base_url(&req, &data), &record.id),
+        file_name: record.file_name,
+        size: record.size,
+        width: record.width,
+        height: record.height,
+        content_hash: record.content_hash,
+        uploaded_at: record.uploaded_at,
+        expires_at: record.expires_at,
+        sent_as: record.sent_as,
+        chat_id: record.chat_id,
+        message_id: record.message_id,
+        file_id: record.file_id,
+        file_unique_id: record.file_unique_id,
+    })
+}
+
+// Hand out an upload ID whose progress can be followed, to pass to /upload?progress={id}.
+// Needs an API key whenever uploading does.
+#[utoipa::path(
+    tag = "uploads",
+    responses(
+        (status = 201, description = "The upload ID and the URL of its progress stream"),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[post("/progress")]
+pub(crate) async fn create_progress(req: HttpRequest, data: web::Data<UploadData>) -> impl Responder {
+    let settings = data.settings();
+    if !settings.api_keys.is_empty() && api_key(&req, &settings.api_keys).is_none() {
+        return error_response(&req, Error::Unauthorized("Missing or invalid API key".to_string()));
+    }
+
+    let id = data.progress.create();
+    let base_url = base_url(&req, &data);
+    HttpResponse::Created().json(serde_json::json!({
+        "id": id,
+        "progress_url": format!("{}/progress/{}", base_url, id),
+        "upload_url": format!("{}/upload?progress={}", base_url, id),
+    }))
+}
+
+// Server-Sent Events following an upload started with an ID from POST /progress: `progress`
+// events with the bytes received from the client and sent to Telegram, then `done` or `failed`
+#[utoipa::path(
+    tag = "uploads",
+    params(("id" = Uuid, Path, description = "ID from POST /progress")),
+    responses(
+        (status = 200, description = "Stream of progress events", content_type = "text/event-stream"),
+        (status = 404, description = "Unknown or expired progress ID", body = ErrorBody),
+    )
+)]
+#[get("/progress/{id}")]
+pub(crate) async fn progress_stream(req: HttpRequest, id: web::Path<String>, data: web::Data<UploadData>) -> impl Responder {
+    let Some((id, progress)) = Uuid::parse_str(&id).ok().and_then(|id| Some((id, data.progress.get(&id)?))) else {
+        return error_response(&req, Error::NotFound("Unknown or expired progress ID".to_string()));
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Keep nginx from holding events back until the response is complete
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(progress_events(data.clone(), id, progress))
+}
+
+// Body of the hash precheck
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct ExistsRequest {
+    // SHA-256 of the file as the client would upload it, in hex
+    pub(crate) sha256: String,
+}
+
+// An upload that already has the content a client asked about
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ExistsResponse {
+    pub(crate) id: Uuid,
+    pub(crate) url: String,
+    pub(crate) uploaded_at: DateTime<Utc>,
+    pub(crate) size: Option<u64>,
+}
+
+// Look up an upload by the SHA-256 of its content, so clients can skip sending bytes that are
+// already hosted. Needs an API key whenever uploading does.
+#[utoipa::path(
+    tag = "uploads",
+    request_body = ExistsRequest,
+    responses(
+        (status = 200, description = "An upload with this content exists", body = ExistsResponse),
+        (status = 400, description = "The hash isn't a hex SHA-256", body = ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = ErrorBody),
+        (status = 404, description = "No upload has this content", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = []), ("basic" = []))
+)]
+#[post("/exists")]
+pub(crate) async fn upload_exists(
+    req: HttpRequest,
+    body: web::Json<ExistsRequest>,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    let settings = data.settings();
+    let api_key = api_key(&req, &settings.api_keys);
+    if !settings.api_keys.is_empty() && api_key.is_none() {
+        return error_response(&req, Error::Unauthorized("Missing or invalid API key".to_string()));
+    }
+
+    let sha256 = body.sha256.trim().to_ascii_lowercase();
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return error_response(&req, Error::InvalidRequest("sha256 must be 64 hex characters".to_string()));
+    }
+
+    // Only uploads made with the same key, so clients can't probe for what others uploaded
+    match data.registry.find_by_hash(&sha256, api_key.map(|api_key| api_key.name.as_str()), |_| true) {
+        Some(record) => HttpResponse::Ok().json(ExistsResponse {
+            id: record.id,
+            url: settings.file_url(&base_url(&req, &data), &record.id),
+            uploaded_at: record.uploaded_at,
+            size: record.size,
+        }),
+        None => error_response(&req, Error::NotFound("No upload has this content".to_string())),
+    }
+}
+
+// Delete an upload: its Telegram message, registry record and thumbnail.
+// Reachable with GET too, since that's what ShareX (and a browser) use for deletion URLs.
+#[utoipa::path(
+    method(get, post, delete),
+    path = "/delete/{id}/{token}",
+    tag = "uploads",
+    params(("id" = Uuid, Path, description = "ID of the upload"), ("token" = String, Path, description = "Deletion token returned with the upload")),
+    responses(
+        (status = 200, description = "Upload deleted"),
+        (status = 403, description = "Invalid deletion token"),
+        (status = 404, description = "Upload not found"),
+    )
+)]
+#[route("/delete/{id}/{token}", method = "GET", method = "POST", method = "DELETE")]
+pub(crate) async fn delete_upload(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    data: web::Data<UploadData>,
+) -> impl Responder {
+    let (id, token) = path.into_inner();
+    let Some(record) = Uuid::parse_str(&id).ok().and_then(|id| data.registry.get(&id)) else {
+        return error_response(&req, Error::NotFound("Upload not found".to_string()));
+    };
+    note_audited(&req, AuditedFile::of(&record));
+
+    if !constant_time_eq(&record.deletion_token, &token) {
+        return error_response(&req, Error::Forbidden("Invalid deletion token".to_string()));
+    }
+
+    match remove_upload(&data, &record).await {
+        Ok(()) => {
+            info!("Deleted upload {}", record.id);
+            HttpResponse::Ok().body("Upload deleted")
+        }
+        Err(e) => {
+            error!("Failed to remove upload {} from the registry: {:?}", record.id, e);
+            error_response(&req, Error::Internal("Failed to delete upload".to_string()))
+        }
+    }
+}
+
+// ShareX custom uploader definition pointing at this server, ready to import
+#[utoipa::path(
+    tag = "uploads",
+    responses((status = 200, description = "ShareX custom uploader (.sxcu) for this server", content_type = "application/json"))
+)]
+#[get("/sharex-config")]
+pub(crate) async fn sharex_config(req: HttpRequest, data: web::Data<UploadData>) -> impl Responder {
+    let base_url = base_url(&req, &data);
+    let config = serde_json::json!({
+        "Version": "15.0.0",
+        "Name": format!("anarchic-image-hosting-bot ({})", base_url),
+        "DestinationType": "ImageUploader, FileUploader",
+        "RequestMethod": "POST",
+        "RequestURL": format!("{}/upload", base_url),
+        "Parameters": { "format": "sharex" },
+        "Body": "MultipartFormData",
+        "FileFormName": "file",
+        "URL": "{json:url}",
+        "ThumbnailURL": "{json:thumbnail_url}",
+        "DeletionURL": "{json:deletion_url}",
+        "ErrorMessage": "{json:error}",
+    });
+
+    HttpResponse::Ok()
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"anarchic-image-hosting-bot.sxcu\"",
+        ))
+        .json(config)
+}
+
+// Structured upload response, returned with ?format=json or to clients that accept JSON
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct UploadResponse<'a> {
+    pub(crate) url: &'a str,
+    pub(crate) id: Uuid,
+    pub(crate) deletion_token: &'a str,
+    pub(crate) content_hash: Option<&'a str>,
+    pub(crate) size: Option<u64>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) photo_sizes: &'a [PhotoVariant],
+    // When the upload will be deleted, if it was given a TTL
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    pub(crate) converted: bool,
+    pub(crate) recompressed: bool,
+    pub(crate) deduplicated: bool,
+}
+
+// Shape of the upload response, picked with ?format=
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ResponseFormat {
+    // The bare URL as plain text
+    Txt,
+    // The full upload record
+    Json,
+    // A 303 redirect to the hosted file, for plain HTML forms
+    Redirect,
+    // JSON understood by ShareX custom uploaders, see /sharex-config
+    Sharex,
+}
+
+// Response body of the ShareX profile
+#[derive(Debug, Serialize)]
+pub(crate) struct SharexResponse<'a> {
+    pub(crate) url: &'a str,
+    pub(crate) thumbnail_url: Option<String>,
+    pub(crate) deletion_url: String,
+}
+
+// Report a failed upload in the requested format, quoting the request ID for bug reports
+pub(crate) fn upload_error(req: &HttpRequest, error: Error, format: ResponseFormat) -> HttpResponse {
+    req.extensions_mut().insert(AuditedError(error.to_string()));
+    let request_id = request_id(req);
+    if let Some(progress) = req.extensions().get::<Arc<UploadProgress>>() {
+        progress.finish(ProgressOutcome::Failed { error: error.clone(), request_id: request_id.clone() });
+    }
+    match format {
+        ResponseFormat::Json | ResponseFormat::Sharex => error.response(&request_id),
+        ResponseFormat::Txt | ResponseFormat::Redirect => {
+            error.builder().body(format!("{} (request ID: {})", error, request_id))
+        }
+    }
+}
+
+// Query parameters of the upload endpoint
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct UploadQuery {
+    pub(crate) format: Option<ResponseFormat>,
+    // Kind of message to send the upload as, instead of the configured upload_mode
+    #[serde(rename = "as")]
+    pub(crate) upload_mode: Option<UploadMode>,
+    pub(crate) message_thread_id: Option<i32>,
+    pub(crate) chat: Option<String>,
+    pub(crate) disable_notification: Option<bool>,
+    pub(crate) protect_content: Option<bool>,
+    pub(crate) pin: Option<bool>,
+    pub(crate) spoiler: Option<bool>,
+    // Seconds after which the upload is deleted, 0 to keep it despite a configured default
+    pub(crate) ttl: Option<u64>,
+    // ID from POST /progress, to follow the upload at /progress/{id}
+    pub(crate) progress: Option<Uuid>,
+}
+
+// Multipart form of the upload endpoint, only used to describe it in the OpenAPI document
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct UploadForm {
+    #[schema(value_type = String, format = Binary)]
+    pub(crate) file: Vec<u8>,
+    pub(crate) caption: Option<String>,
+    // HTML, Markdown or MarkdownV2
+    pub(crate) parse_mode: Option<String>,
+    // JSON array of Telegram message entities, instead of parse_mode
+    pub(crate) caption_entities: Option<String>,
+    pub(crate) chat: Option<String>,
+    pub(crate) message_thread_id: Option<i32>,
+    pub(crate) disable_notification: Option<bool>,
+    pub(crate) protect_content: Option<bool>,
+    pub(crate) pin: Option<bool>,
+    pub(crate) spoiler: Option<bool>,
+}
+
+// Pick the response format: an explicit ?format= wins, then the Accept header
+pub(crate) fn response_format(req: &HttpRequest, query: &UploadQuery) -> ResponseFormat {
+    if let Some(format) = query.format {
+        return format;
+    }
+
+    let accepts_json = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if accepts_json {
+        ResponseFormat::Json
+    } else {
+        ResponseFormat::Txt
+    }
+}
+
+// Build the response for a finished upload in the requested format
+pub(crate) fn upload_response(
+    req: &HttpRequest,
+    data: &UploadData,
+    record: &UploadRecord,
+    url: &str,
+    flags: UploadFlags,
+    format: ResponseFormat,
+) -> HttpResponse {
+    let mut response = match format {
+        ResponseFormat::Redirect => HttpResponse::SeeOther(),
+        ResponseFormat::Txt | ResponseFormat::Json | ResponseFormat::Sharex => HttpResponse::Ok(),
+    };
+    response
+        .insert_header(("X-Upload-Id", record.id.to_string()))
+        .insert_header(("X-Converted", flags.converted.to_string()))
+        .insert_header(("X-Recompressed", flags.recompressed.to_string()))
+        .insert_header(("X-Deduplicated", flags.deduplicated.to_string()));
+
+    match format {
+        #[allow(clippy::useless_format)]
+        ResponseFormat::Txt => return response.body(format!("{}", url)),
+        ResponseFormat::Redirect => return response.insert_header((header::LOCATION, url)).finish(),
+        ResponseFormat::Sharex => {
+            let base_url = base_url(req, data);
+            return response.json(SharexResponse {
+                url,
+                thumbnail_url: data.thumbnail_dir.as_ref().map(|_| data.settings().thumbnail_url(&base_url, &record.id)),
+                deletion_url: format!("{}/delete/{}/{}", base_url, record.id, record.deletion_token),
+            });
+        }
+        ResponseFormat::Json => {}
+    }
+
+    response.json(UploadResponse {
+        url,
+        id: record.id,
+        deletion_token: &record.deletion_token,
+        content_hash: record.content_hash.as_deref(),
+        size: record.size,
+        width: record.width,
+        height: record.height,
+        photo_sizes: &record.photo_sizes,
+        expires_at: record.expires_at,
+        converted: flags.converted,
+        recompressed: flags.recompressed,
+        deduplicated: flags.deduplicated,
+    })
+}
@@ -0,0 +1,772 @@
+// Image processing before upload and resizing for the file proxy
+
+use ab_glyph::{point, Font, FontArc, GlyphId, PxScale, ScaleFont};
+use actix_web::web;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, Rgba, RgbaImage};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use tracing::{debug, error, info};
+use crate::config::{WatermarkConfig, WatermarkPosition};
+use crate::error::Error;
+use crate::moderation::ModerationVerdict;
+
+// Telegram rejects photos larger than this
+pub(crate) const PHOTO_SIZE_LIMIT: u64 = 10 * 1024 * 1024;
+
+// Telegram rejects photos whose width and height add up to more than this,
+pub(crate) const PHOTO_DIMENSION_SUM_LIMIT: u32 = 10000;
+
+// and photos whose long side is more than this many times the short one
+pub(crate) const PHOTO_ASPECT_RATIO_LIMIT: u32 = 20;
+
+// Lowest JPEG quality tried before an oversized image gets scaled down further
+pub(crate) const MIN_JPEG_QUALITY: u8 = 50;
+
+pub(crate) fn default_max_dimension() -> u32 {
+    2560
+}
+
+pub(crate) fn default_jpeg_quality() -> u8 {
+    90
+}
+
+pub(crate) fn default_convert_timeout_secs() -> u64 {
+    60
+}
+
+// ISO-BMFF brands of HEIC/HEIF and AVIF files, which can only be decoded by the external converter
+pub(crate) const EXTERNAL_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+// Image processing settings applied to every upload before it is sent to Telegram
+#[derive(Debug, Clone)]
+pub(crate) struct ImageOptions {
+    pub(crate) auto_orient: bool,
+    pub(crate) strip_exif: bool,
+    pub(crate) max_dimension: u32,
+    pub(crate) jpeg_quality: u8,
+    pub(crate) convert_command: Option<Vec<String>>,
+    // How long the external converter may run before it is killed
+    pub(crate) convert_timeout: Duration,
+    pub(crate) watermark: Option<Arc<Watermark>>,
+}
+
+// A watermark ready to be applied, rendered once at startup
+pub(crate) struct Watermark {
+    pub(crate) overlay: RgbaImage,
+    pub(crate) scale: Option<f32>,
+    pub(crate) position: WatermarkPosition,
+    pub(crate) opacity: f32,
+    pub(crate) margin: u32,
+}
+
+impl std::fmt::Debug for Watermark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watermark")
+            .field("size", &self.overlay.dimensions())
+            .field("position", &self.position)
+            .field("opacity", &self.opacity)
+            .finish()
+    }
+}
+
+// What happened to an image while it was being processed
+#[derive(Debug)]
+pub(crate) struct ProcessedImage {
+    // Where the processed file lives, which changes when it was converted to another format
+    pub(crate) file_path: PathBuf,
+    pub(crate) converted: bool,
+    pub(crate) recompressed: bool,
+    // A photo too narrow for Telegram to take, to be sent as a document instead
+    pub(crate) as_document: bool,
+    // Verdict of content moderation on the processed file, if it ran
+    pub(crate) moderation: Option<ModerationVerdict>,
+}
+
+// Run the configured processing steps on an uploaded file. Orienting, stripping metadata and the
+// watermark apply to every image; converting and recompressing only to those sent as photos.
+// `decoded` is the PNG the external converter made of a HEIC/AVIF file, if it ran. The image is
+// decoded once, every step works on its pixels, and it is encoded once, only if a step changed it.
+pub(crate) fn process_image(
+    file_path: &Path,
+    decoded: Option<&Path>,
+    options: &ImageOptions,
+    photo: bool,
+) -> image::ImageResult<ProcessedImage> {
+    let mut processed = ProcessedImage {
+        file_path: file_path.to_path_buf(),
+        converted: false,
+        recompressed: false,
+        as_document: false,
+        moderation: None,
+    };
+
+    // Photos in formats Telegram can't display (HEIC, AVIF, WebP, TIFF) are converted to JPEG, or to
+    // PNG when the image has transparency. Otherwise only JPEGs and PNGs are edited in place.
+    let external = is_external(file_path)?;
+    let reader = match (external, decoded) {
+        (true, Some(decoded)) if photo => ImageReader::open(decoded)?.with_guessed_format()?,
+        (true, _) if photo => return Err(no_converter()),
+        (true, _) => return Ok(processed),
+        (false, _) => ImageReader::open(file_path)?.with_guessed_format()?,
+    };
+    let Some(format) = reader.format() else {
+        debug!("Not an image, skipping processing: {:?}", file_path);
+        return Ok(processed);
+    };
+    let mut convert = photo && (external || matches!(format, ImageFormat::WebP | ImageFormat::Tiff));
+    let mut editable = convert || matches!(format, ImageFormat::Jpeg | ImageFormat::Png);
+    if !photo && !editable {
+        return Ok(processed);
+    }
+
+    let mut decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    let mut photo = photo;
+    if photo && !photo_aspect_fits(width, height) {
+        info!("{:?} is too narrow for a photo ({}x{}), sending it as a document", file_path, width, height);
+        processed.as_document = true;
+        if external {
+            return Ok(processed);
+        }
+        (photo, convert, editable) = (false, false, matches!(format, ImageFormat::Jpeg | ImageFormat::Png));
+        if !editable {
+            return Ok(processed);
+        }
+    }
+    // Photos too large in pixels, or bytes, are scaled down to fit
+    let resize = photo && !photo_dimensions_fit(width, height, options.max_dimension);
+    let oversized = resize || (photo && std::fs::metadata(file_path)?.len() > PHOTO_SIZE_LIMIT);
+
+    let orientation = decoder.orientation()?;
+    let exif = decoder.exif_metadata()?;
+    let icc_profile = decoder.icc_profile()?;
+    // EXIF is only stripped where there is some, keeping the ICC profile. The pixels are rotated
+    // first, and whenever converting, so losing the orientation tag doesn't change how it looks.
+    let strip = editable && options.strip_exif && exif.is_some();
+    let rotate = editable && (convert || strip || options.auto_orient) && orientation != Orientation::NoTransforms;
+    let watermark = options.watermark.as_deref().filter(|_| editable);
+    if !convert && !rotate && !strip && watermark.is_none() && !oversized {
+        return Ok(processed);
+    }
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    if rotate {
+        image.apply_orientation(orientation);
+        debug!("Auto-oriented {:?} (orientation: {:?})", file_path, orientation);
+    }
+    let exif = match exif {
+        _ if strip => {
+            debug!("Stripped EXIF metadata from: {:?}", file_path);
+            None
+        }
+        Some(mut exif) if rotate => {
+            let _ = Orientation::remove_from_exif_chunk(&mut exif);
+            Some(exif)
+        }
+        exif => exif,
+    };
+    if let Some(watermark) = watermark {
+        image = stamp_watermark(image, watermark);
+        debug!("Applied watermark to: {:?}", file_path);
+    }
+
+    let (output_format, output_path) = match convert {
+        true if image.color().has_alpha() => (ImageFormat::Png, file_path.with_extension("png")),
+        true => (ImageFormat::Jpeg, file_path.with_extension("jpg")),
+        false => (format, file_path.to_path_buf()),
+    };
+    let encoded = match editable && !resize {
+        true => Some(encode(&image, output_format, options.jpeg_quality, exif.clone(), icc_profile.clone())?),
+        false => None,
+    };
+    let encoded = match encoded {
+        Some(encoded) if !photo || encoded.len() as u64 <= PHOTO_SIZE_LIMIT => encoded,
+        _ => {
+            processed.recompressed = true;
+            recompress(&image, options, exif, icc_profile)?
+        }
+    };
+
+    std::fs::write(&output_path, &encoded)?;
+    if output_path != file_path {
+        // The caller only knows about the original file, so clean up the converted one on failure
+        if let Err(e) = std::fs::remove_file(file_path) {
+            let _ = std::fs::remove_file(&output_path);
+            return Err(e.into());
+        }
+        info!("Converted {:?} to {:?}", file_path, output_path);
+        processed.file_path = output_path;
+        processed.converted = true;
+    }
+    Ok(processed)
+}
+
+// Whether Telegram takes a photo this wide and high, and it is within max_dimension
+pub(crate) fn photo_dimensions_fit(width: u32, height: u32, max_dimension: u32) -> bool {
+    width as u64 + height as u64 <= PHOTO_DIMENSION_SUM_LIMIT as u64 && width.max(height) <= max_dimension
+}
+
+// Whether a photo is wide or high enough for Telegram, which no scaling can change
+pub(crate) fn photo_aspect_fits(width: u32, height: u32) -> bool {
+    width.max(height) as u64 <= width.min(height) as u64 * PHOTO_ASPECT_RATIO_LIMIT as u64
+}
+
+// Encode an image as JPEG or PNG, keeping its metadata
+pub(crate) fn encode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    quality: u8,
+    exif: Option<Vec<u8>>,
+    icc_profile: Option<Vec<u8>>,
+) -> image::ImageResult<Vec<u8>> {
+    let mut encoded = Vec::new();
+    if format == ImageFormat::Jpeg {
+        let encoder = JpegEncoder::new_with_quality(&mut encoded, quality);
+        match image {
+            DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => encode_with_metadata(image, encoder, exif, icc_profile)?,
+            image => encode_with_metadata(&DynamicImage::ImageRgb8(image.to_rgb8()), encoder, exif, icc_profile)?,
+        }
+    } else {
+        encode_with_metadata(image, PngEncoder::new(&mut encoded), exif, icc_profile)?;
+    }
+    Ok(encoded)
+}
+
+// Run image processing on a blocking thread, mapping failures to the errors clients get. The
+// external converter runs first, as a child process of its own.
+pub(crate) async fn run_image_processing(file_path: &Path, options: &ImageOptions, photo: bool) -> Result<ProcessedImage, Error> {
+    let file_path = file_path.to_path_buf();
+    let options = options.clone();
+
+    let decoded = if photo && is_external(&file_path).unwrap_or(false) {
+        Some(decode_external(&file_path, &options).await.map_err(processing_error)?)
+    } else {
+        None
+    };
+
+    let span = tracing::Span::current();
+    let processing = {
+        let decoded = decoded.clone();
+        tokio::task::spawn_blocking(move || span.in_scope(|| process_image(&file_path, decoded.as_deref(), &options, photo))).await
+    };
+    if let Some(decoded) = &decoded {
+        let _ = std::fs::remove_file(decoded);
+    }
+    match processing {
+        Ok(Ok(processed)) => Ok(processed),
+        Ok(Err(e)) => Err(processing_error(e)),
+        Err(e) => {
+            error!("Image processing task failed: {:?}", e);
+            Err(Error::Internal("Failed to process image".to_string()))
+        }
+    }
+}
+
+// The error clients get for an image that failed to process
+pub(crate) fn processing_error(error: image::ImageError) -> Error {
+    match error {
+        e @ (image::ImageError::Unsupported(_) | image::ImageError::Decoding(_)) => {
+            Error::UnsupportedMediaType(format!("Failed to process image: {}", e))
+        }
+        image::ImageError::Limits(_) => Error::PayloadTooLarge,
+        e => {
+            error!("Failed to process image: {:?}", e);
+            Error::Internal("Failed to process image".to_string())
+        }
+    }
+}
+
+// Read the major brand of an ISO-BMFF file (HEIF, AVIF, MP4, ...), if it is one
+pub(crate) fn isobmff_brand(file_path: &Path) -> std::io::Result<Option<[u8; 4]>> {
+    let mut header = [0u8; 12];
+    let mut file = File::open(file_path)?;
+    if file.read_exact(&mut header).is_err() || &header[4..8] != b"ftyp" {
+        return Ok(None);
+    }
+    Ok(Some([header[8], header[9], header[10], header[11]]))
+}
+
+// Whether the file's contents are of an image format, one the image crate or the external converter reads
+pub(crate) fn is_image(file_path: &Path) -> std::io::Result<bool> {
+    if is_external(file_path)? {
+        return Ok(true);
+    }
+    // Unlike ImageReader::open, this doesn't fall back to the format the file's extension names
+    let reader = ImageReader::new(std::io::BufReader::new(File::open(file_path)?)).with_guessed_format()?;
+    Ok(reader.format().is_some())
+}
+
+// Whether the file is HEIC/HEIF or AVIF, which only the external converter decodes
+pub(crate) fn is_external(file_path: &Path) -> std::io::Result<bool> {
+    Ok(isobmff_brand(file_path)?.is_some_and(|brand| EXTERNAL_BRANDS.contains(&&brand)))
+}
+
+// HEIC/AVIF can't be read without a convert_command
+pub(crate) fn no_converter() -> image::ImageError {
+    image::ImageError::Unsupported(image::error::UnsupportedError::from_format_and_kind(
+        image::error::ImageFormatHint::Name("HEIF/AVIF".to_string()),
+        image::error::UnsupportedErrorKind::Format(image::error::ImageFormatHint::Name(
+            "HEIF/AVIF (no convert_command configured)".to_string(),
+        )),
+    ))
+}
+
+// Decode an image through the configured external converter, returning the path of the PNG it
+// wrote. A converter that hangs is killed once it outlasts the timeout.
+pub(crate) async fn decode_external(file_path: &Path, options: &ImageOptions) -> image::ImageResult<PathBuf> {
+    let Some((program, args)) = options.convert_command.as_deref().and_then(|command| command.split_first()) else {
+        return Err(no_converter());
+    };
+
+    let intermediate_path = file_path.with_extension("converted.png");
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            arg.replace("{input}", &file_path.to_string_lossy())
+                .replace("{output}", &intermediate_path.to_string_lossy())
+        })
+        .collect();
+
+    debug!("Running converter: {} {:?}", program, args);
+    let running = tokio::process::Command::new(program).args(&args).kill_on_drop(true).output();
+    let failure = match tokio::time::timeout(options.convert_timeout, running).await {
+        Ok(Ok(output)) if output.status.success() => return Ok(intermediate_path),
+        Ok(Ok(output)) => {
+            std::io::Error::other(format!("Converter exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim()))
+        }
+        Ok(Err(e)) => e,
+        Err(_) => std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("Converter timed out after {} seconds", options.convert_timeout.as_secs()),
+        ),
+    };
+    let _ = std::fs::remove_file(&intermediate_path);
+    Err(image::ImageError::IoError(failure))
+}
+
+pub(crate) fn write_jpeg(image: &DynamicImage, file_path: &Path, quality: u8) -> image::ImageResult<()> {
+    let mut output = BufWriter::new(File::create(file_path)?);
+    image.write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality))?;
+    output.flush()?;
+    Ok(())
+}
+
+// Encode an image, carrying over whatever metadata the encoder supports
+pub(crate) fn encode_with_metadata(
+    image: &DynamicImage,
+    mut encoder: impl ImageEncoder,
+    exif: Option<Vec<u8>>,
+    icc_profile: Option<Vec<u8>>,
+) -> image::ImageResult<()> {
+    if let Some(exif) = exif {
+        let _ = encoder.set_exif_metadata(exif);
+    }
+    if let Some(icc_profile) = icc_profile {
+        let _ = encoder.set_icc_profile(icc_profile);
+    }
+    image.write_with_encoder(encoder)
+}
+
+// Load the watermark overlay, or render the watermark text with the configured font
+pub(crate) fn load_watermark(config: &WatermarkConfig) -> Result<Watermark, String> {
+    let overlay = match (&config.image, &config.text) {
+        (Some(image_path), _) => image::open(image_path)
+            .map_err(|e| format!("Failed to open watermark image {}: {}", image_path, e))?
+            .into_rgba8(),
+        (None, Some(text)) => {
+            let font_path = config.font.as_ref().ok_or("Watermark text needs a font")?;
+            let font_data = std::fs::read(font_path)
+                .map_err(|e| format!("Failed to read watermark font {}: {}", font_path, e))?;
+            let font = FontArc::try_from_vec(font_data).map_err(|e| format!("Invalid watermark font: {}", e))?;
+            render_text(&font, config.font_size, text, parse_color(&config.color)?)
+        }
+        (None, None) => return Err("Watermark needs either an image or a text".to_string()),
+    };
+
+    Ok(Watermark {
+        overlay,
+        scale: config.scale,
+        position: config.position,
+        opacity: config.opacity.clamp(0.0, 1.0),
+        margin: config.margin,
+    })
+}
+
+// Parse a "#rrggbb" color
+pub(crate) fn parse_color(color: &str) -> Result<Rgba<u8>, String> {
+    let hex = color.trim_start_matches('#');
+    let channel = |range: std::ops::Range<usize>| {
+        hex.get(range)
+            .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+            .ok_or_else(|| format!("Invalid color: {}", color))
+    };
+    if hex.len() != 6 {
+        return Err(format!("Invalid color: {}", color));
+    }
+    Ok(Rgba([channel(0..2)?, channel(2..4)?, channel(4..6)?, 255]))
+}
+
+// Render a single line of text onto a transparent canvas just big enough to hold it
+pub(crate) fn render_text(font: &FontArc, font_size: f32, text: &str, color: Rgba<u8>) -> RgbaImage {
+    let font = font.as_scaled(PxScale::from(font_size));
+
+    let mut glyphs = Vec::new();
+    let mut caret = 0.0;
+    let mut previous: Option<GlyphId> = None;
+    for character in text.chars() {
+        let glyph_id = font.glyph_id(character);
+        if let Some(previous) = previous {
+            caret += font.kern(previous, glyph_id);
+        }
+        glyphs.push(glyph_id.with_scale_and_position(font_size, point(caret, font.ascent())));
+        caret += font.h_advance(glyph_id);
+        previous = Some(glyph_id);
+    }
+
+    let width = caret.ceil().max(1.0) as u32;
+    let height = (font.ascent() - font.descent()).ceil().max(1.0) as u32;
+    let mut canvas = RgbaImage::new(width, height);
+
+    for glyph in glyphs {
+        if let Some(outline) = font.outline_glyph(glyph) {
+            let bounds = outline.px_bounds();
+            outline.draw(|x, y, coverage| {
+                let x = bounds.min.x as i32 + x as i32;
+                let y = bounds.min.y as i32 + y as i32;
+                if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                    let alpha = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+                    let pixel = canvas.get_pixel_mut(x as u32, y as u32);
+                    if alpha > pixel[3] {
+                        *pixel = Rgba([color[0], color[1], color[2], alpha]);
+                    }
+                }
+            });
+        }
+    }
+
+    canvas
+}
+
+// Blend the watermark onto an image
+pub(crate) fn stamp_watermark(image: DynamicImage, watermark: &Watermark) -> DynamicImage {
+    let transparent = image.color().has_alpha();
+    let mut canvas = image.into_rgba8();
+
+    let overlay = match watermark.scale {
+        Some(scale) => {
+            let width = ((canvas.width() as f32 * scale) as u32).max(1);
+            let height = ((watermark.overlay.height() as f32 * width as f32 / watermark.overlay.width() as f32) as u32).max(1);
+            image::imageops::resize(&watermark.overlay, width, height, FilterType::Lanczos3)
+        }
+        None => watermark.overlay.clone(),
+    };
+
+    let (x, y) = watermark_origin(canvas.dimensions(), overlay.dimensions(), watermark.position, watermark.margin);
+    for (overlay_x, overlay_y, source) in overlay.enumerate_pixels() {
+        let (target_x, target_y) = (x + overlay_x as i64, y + overlay_y as i64);
+        if target_x < 0 || target_y < 0 || target_x >= canvas.width() as i64 || target_y >= canvas.height() as i64 {
+            continue;
+        }
+
+        let alpha = source[3] as f32 / 255.0 * watermark.opacity;
+        let target = canvas.get_pixel_mut(target_x as u32, target_y as u32);
+        for channel in 0..3 {
+            target[channel] = (target[channel] as f32 * (1.0 - alpha) + source[channel] as f32 * alpha).round() as u8;
+        }
+    }
+
+    match transparent {
+        true => DynamicImage::ImageRgba8(canvas),
+        false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).into_rgb8()),
+    }
+}
+
+// Top-left corner of the watermark for the given position (may be negative if it doesn't fit)
+pub(crate) fn watermark_origin(image: (u32, u32), overlay: (u32, u32), position: WatermarkPosition, margin: u32) -> (i64, i64) {
+    let (image_width, image_height) = (image.0 as i64, image.1 as i64);
+    let (overlay_width, overlay_height) = (overlay.0 as i64, overlay.1 as i64);
+    let margin = margin as i64;
+
+    match position {
+        WatermarkPosition::TopLeft => (margin, margin),
+        WatermarkPosition::TopRight => (image_width - overlay_width - margin, margin),
+        WatermarkPosition::BottomLeft => (margin, image_height - overlay_height - margin),
+        WatermarkPosition::BottomRight => (image_width - overlay_width - margin, image_height - overlay_height - margin),
+        WatermarkPosition::Center => ((image_width - overlay_width) / 2, (image_height - overlay_height) / 2),
+    }
+}
+
+// Downsize and re-encode an oversized image as JPEG until it fits into Telegram's photo limits
+pub(crate) fn recompress(
+    image: &DynamicImage,
+    options: &ImageOptions,
+    exif: Option<Vec<u8>>,
+    icc_profile: Option<Vec<u8>>,
+) -> image::ImageResult<Vec<u8>> {
+    let mut image = DynamicImage::ImageRgb8(image.to_rgb8());
+    if image.width().max(image.height()) > options.max_dimension {
+        image = image.resize(options.max_dimension, options.max_dimension, FilterType::Lanczos3);
+    }
+    let (width, height) = (image.width() as u64, image.height() as u64);
+    if width + height > PHOTO_DIMENSION_SUM_LIMIT as u64 {
+        let limit = PHOTO_DIMENSION_SUM_LIMIT as u64;
+        let (width, height) = (width * limit / (width + height), height * limit / (width + height));
+        image = image.resize(width as u32, height as u32, FilterType::Lanczos3);
+    }
+
+    let mut quality = options.jpeg_quality;
+    loop {
+        let encoded = encode(&image, ImageFormat::Jpeg, quality, exif.clone(), icc_profile.clone())?;
+        if encoded.len() as u64 <= PHOTO_SIZE_LIMIT {
+            info!("Recompressed to {}x{} at quality {} ({} bytes)", image.width(), image.height(), quality, encoded.len());
+            return Ok(encoded);
+        }
+
+        if quality > MIN_JPEG_QUALITY {
+            quality = quality.saturating_sub(10).max(MIN_JPEG_QUALITY);
+        } else {
+            let (width, height) = (image.width() * 3 / 4, image.height() * 3 / 4);
+            image = image.resize(width.max(1), height.max(1), FilterType::Lanczos3);
+        }
+    }
+}
+
+// Generate a JPEG thumbnail whose longest side is `size` pixels
+pub(crate) fn generate_thumbnail(file_path: &Path, thumbnail_path: &Path, size: u32, quality: u8) -> image::ImageResult<()> {
+    let image = ImageReader::open(file_path)?.with_guessed_format()?.decode()?;
+    let thumbnail = DynamicImage::ImageRgb8(image.thumbnail(size, size).into_rgb8());
+    write_jpeg(&thumbnail, thumbnail_path, quality)
+}
+
+// Largest width or height a proxied image can be resized to
+pub(crate) const MAX_RESIZE_DIMENSION: u32 = 4096;
+
+// How a resized image fills the requested box
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ResizeFit {
+    // Scale to fit inside the box, keeping the aspect ratio
+    Contain,
+    // Scale and crop to cover the whole box, keeping the aspect ratio
+    Cover,
+    // Stretch to exactly the requested size
+    Fill,
+}
+
+// Key of a resized variant in the resize cache
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ResizeKey {
+    pub(crate) id: Uuid,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) fit: ResizeFit,
+}
+
+// A file ready to be sent to the client
+#[derive(Clone)]
+pub(crate) struct ProxiedFile {
+    pub(crate) content_type: String,
+    pub(crate) bytes: web::Bytes,
+}
+
+// Resize an image to the requested box. PNGs stay PNGs, everything else becomes a JPEG.
+pub(crate) fn resize_image(bytes: &[u8], key: &ResizeKey, quality: u8) -> image::ImageResult<ProxiedFile> {
+    let reader = ImageReader::new(std::io::Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader.format();
+    let image = reader.decode()?;
+
+    // A missing dimension follows from the other one and the aspect ratio
+    let (width, height) = match (key.width, key.height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, (image.height() as u64 * width as u64 / image.width().max(1) as u64) as u32),
+        (None, Some(height)) => ((image.width() as u64 * height as u64 / image.height().max(1) as u64) as u32, height),
+        (None, None) => (image.width(), image.height()),
+    };
+    let (width, height) = (width.clamp(1, MAX_RESIZE_DIMENSION), height.clamp(1, MAX_RESIZE_DIMENSION));
+
+    let resized = match key.fit {
+        ResizeFit::Contain => image.resize(width, height, FilterType::Lanczos3),
+        ResizeFit::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+        ResizeFit::Fill => image.resize_exact(width, height, FilterType::Lanczos3),
+    };
+
+    let mut encoded = Vec::new();
+    let content_type = if format == Some(ImageFormat::Png) {
+        resized.write_with_encoder(PngEncoder::new(&mut encoded))?;
+        "image/png"
+    } else {
+        DynamicImage::ImageRgb8(resized.into_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, quality))?;
+        "image/jpeg"
+    };
+
+    Ok(ProxiedFile { content_type: content_type.to_string(), bytes: encoded.into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> ImageOptions {
+        ImageOptions {
+            auto_orient: false,
+            strip_exif: false,
+            max_dimension: default_max_dimension(),
+            jpeg_quality: default_jpeg_quality(),
+            convert_command: None,
+            convert_timeout: Duration::from_secs(default_convert_timeout_secs()),
+            watermark: None,
+        }
+    }
+
+    // EXIF saying the image has to be turned 90° clockwise to be upright
+    const ROTATE_90_EXIF: &[u8] = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0";
+
+    fn jpeg(width: u32, height: u32, exif: Option<&[u8]>, icc_profile: Option<&[u8]>) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(width, height));
+        encode(&image, ImageFormat::Jpeg, 90, exif.map(<[u8]>::to_vec), icc_profile.map(<[u8]>::to_vec)).unwrap()
+    }
+
+    fn metadata(file_path: &Path) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        let mut decoder = ImageReader::open(file_path).unwrap().with_guessed_format().unwrap().into_decoder().unwrap();
+        (decoder.exif_metadata().unwrap(), decoder.icc_profile().unwrap())
+    }
+
+    #[test]
+    fn orients_strips_and_watermarks_in_one_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, jpeg(4, 2, Some(ROTATE_90_EXIF), None)).unwrap();
+        let watermark = Watermark {
+            overlay: RgbaImage::from_pixel(1, 1, Rgba([255, 255, 255, 255])),
+            scale: None,
+            position: WatermarkPosition::TopLeft,
+            opacity: 1.0,
+            margin: 0,
+        };
+        let options = ImageOptions { auto_orient: true, strip_exif: true, watermark: Some(Arc::new(watermark)), ..options() };
+
+        let processed = process_image(&path, None, &options, false).unwrap();
+        assert_eq!(processed.file_path, path);
+        assert!(!processed.converted && !processed.recompressed);
+        let image = image::open(&path).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), (2, 4));
+        assert!(image.get_pixel(0, 0)[0] > 128);
+        assert_eq!(metadata(&path).0, None);
+    }
+
+    #[test]
+    fn strips_exif_but_keeps_the_color_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, jpeg(4, 2, Some(ROTATE_90_EXIF), Some(b"color profile"))).unwrap();
+        let options = ImageOptions { strip_exif: true, ..options() };
+
+        process_image(&path, None, &options, true).unwrap();
+        assert_eq!(metadata(&path), (None, Some(b"color profile".to_vec())));
+        // The orientation tag goes with the rest, so the pixels are turned instead
+        assert_eq!(image::image_dimensions(&path).unwrap(), (2, 4));
+    }
+
+    #[test]
+    fn strips_exif_from_pngs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        let mut png = Vec::new();
+        let mut encoder = PngEncoder::new(&mut png);
+        encoder.set_exif_metadata(b"II*\0\x08\0\0\0\0\0\0\0\0\0".to_vec()).unwrap();
+        DynamicImage::ImageRgb8(image::RgbImage::new(4, 2)).write_with_encoder(encoder).unwrap();
+        std::fs::write(&path, &png).unwrap();
+        assert!(metadata(&path).0.is_some());
+
+        process_image(&path, None, &ImageOptions { strip_exif: true, ..options() }, false).unwrap();
+        assert_eq!(metadata(&path).0, None);
+        assert_eq!(image::image_dimensions(&path).unwrap(), (4, 2));
+    }
+
+    #[test]
+    fn leaves_images_without_exif_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        let original = jpeg(4, 2, None, Some(b"color profile"));
+        std::fs::write(&path, &original).unwrap();
+
+        process_image(&path, None, &ImageOptions { auto_orient: true, strip_exif: true, ..options() }, true).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn checks_telegrams_photo_dimensions() {
+        assert!(photo_dimensions_fit(2560, 1440, 2560));
+        assert!(!photo_dimensions_fit(2561, 1440, 2560));
+        assert!(photo_dimensions_fit(5000, 5000, 10000));
+        assert!(!photo_dimensions_fit(5001, 5000, 10000));
+
+        assert!(photo_aspect_fits(2000, 100));
+        assert!(photo_aspect_fits(100, 2000));
+        assert!(!photo_aspect_fits(2001, 100));
+        assert!(!photo_aspect_fits(100, 0));
+    }
+
+    #[test]
+    fn scales_photos_down_to_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, jpeg(40, 30, None, None)).unwrap();
+
+        let processed = process_image(&path, None, &ImageOptions { max_dimension: 20, ..options() }, true).unwrap();
+        assert!(processed.recompressed && !processed.as_document);
+        assert_eq!(image::image_dimensions(&path).unwrap(), (20, 15));
+
+        // However large max_dimension is, width and height stay within Telegram's sum
+        let image = DynamicImage::ImageLuma8(image::GrayImage::new(9990, 20));
+        let encoded = recompress(&image, &ImageOptions { max_dimension: 20000, ..options() }, None, None).unwrap();
+        let image = image::load_from_memory(&encoded).unwrap();
+        let (width, height) = (image.width(), image.height());
+        assert!(width + height <= PHOTO_DIMENSION_SUM_LIMIT, "{}x{}", width, height);
+    }
+
+    #[test]
+    fn sends_photos_too_narrow_for_telegram_as_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("banner.png");
+        let mut original = Vec::new();
+        image::RgbImage::new(210, 10).write_to(&mut std::io::Cursor::new(&mut original), ImageFormat::Png).unwrap();
+        std::fs::write(&path, &original).unwrap();
+
+        let processed = process_image(&path, None, &options(), true).unwrap();
+        assert!(processed.as_document && !processed.recompressed);
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[actix_web::test]
+    async fn converters_that_hang_are_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.heic");
+        std::fs::write(&path, b"\0\0\0\x18ftypheic\0\0\0\0mif1heic").unwrap();
+        let options = ImageOptions {
+            convert_command: Some(vec!["sleep".to_string(), "30".to_string()]),
+            convert_timeout: Duration::from_millis(200),
+            ..options()
+        };
+
+        let started = std::time::Instant::now();
+        let error = decode_external(&path, &options).await.unwrap_err();
+        assert!(matches!(error, image::ImageError::IoError(ref e) if e.kind() == std::io::ErrorKind::TimedOut), "{:?}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(run_image_processing(&path, &options, true).await, Err(Error::Internal(_))));
+        assert!(!path.with_extension("converted.png").exists());
+    }
+}
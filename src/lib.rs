@@ -0,0 +1,668 @@
+// Image hosting through Telegram: files uploaded over HTTP (or sent to the bot) are posted to a
+// chat and served back from there. The binary runs it from a config file; other programs can
+// embed it with Server::builder() and upload files through an Uploader without the HTTP API.
+// `anarchic-image-hosting-bot upload` is a client for a running server.
+
+mod audit;
+mod batch;
+mod bot;
+mod clamav;
+mod cli;
+mod config;
+mod error;
+mod fake;
+mod http;
+mod image;
+mod logging;
+mod metrics;
+mod moderation;
+mod progress;
+mod s3;
+mod signing;
+mod storage;
+mod systemd;
+mod telegram;
+mod throttle;
+mod upload;
+
+use actix_web::web;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::{error, info};
+use uuid::Uuid;
+
+pub use crate::cli::upload_command;
+pub use crate::config::{Config, CONFIG_FILE};
+pub use crate::error::Error;
+pub use crate::fake::{FakeMessage, FakeTelegram};
+pub use crate::logging::{init_logging, LoggingGuard};
+pub use crate::telegram::{FileRange, Media, PhotoVariant, PostedMedia, SendOptions, SendProgress, TelegramUploader, UploadMode};
+
+use crate::audit::{run_audit_pruning, AuditAction, AuditEntry, AuditLog, AuditSource};
+use crate::bot::{run_bot, BotSettings};
+use crate::metrics::{InFlight, Metrics};
+use crate::progress::ProgressTracker;
+use crate::signing::ReplayGuard;
+use crate::throttle::Bandwidth;
+use crate::storage::{remove_temp_file, IdempotencyStore, run_temp_janitor, save_bytes, Outbox, ProxyCache, Registry, TempQuota};
+use crate::telegram::{resolve_chat, upload_url, BotPool, CircuitBreaker};
+use crate::upload::{
+    host_file, is_type_allowed, Destination, notify_webhooks, run_expiry, run_outbox, HostedFile, Settings, UploadData, WebhookPayload,
+    WEBHOOK_TIMEOUT,
+};
+
+// Collects what a Server needs before it can be built
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Option<Config>,
+    config_file: Option<PathBuf>,
+    telegram: Option<Arc<dyn TelegramUploader>>,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    // File the config is read from if none was given, and reread by POST /admin/reload
+    pub fn config_file(mut self, config_file: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(config_file.into());
+        self
+    }
+
+    // Post through this client instead of the bots from the config, e.g. a FakeTelegram in tests.
+    // The bot commands and inbound uploads need a real bot and stay off.
+    pub fn telegram(mut self, telegram: Arc<dyn TelegramUploader>) -> Self {
+        self.telegram = Some(telegram);
+        self
+    }
+
+    // Check the config; nothing is contacted or opened until the server is started
+    pub fn build(self) -> Result<Server, String> {
+        let config = match (self.config, &self.config_file) {
+            (Some(config), _) => config,
+            (None, Some(config_file)) => Config::load(config_file)?,
+            (None, None) => return Err("The server needs a config or a config file".to_string()),
+        };
+        if config.target_chats().is_empty() {
+            return Err("Either chat_id or chat_ids must be set in the config".to_string());
+        }
+        let settings = Settings::from_config(&config)?;
+        Ok(Server { config, settings, config_file: self.config_file, telegram: self.telegram })
+    }
+}
+
+// The image hosting service: the HTTP API, the bot and the background tasks
+pub struct Server {
+    config: Config,
+    settings: Settings,
+    config_file: Option<PathBuf>,
+    telegram: Option<Arc<dyn TelegramUploader>>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    // Connect to Telegram and start the outbox, bot and temp janitor, without serving HTTP.
+    // The returned Uploader hosts files directly.
+    pub async fn start(self) -> std::io::Result<Uploader> {
+        let data = start_tasks(&self.config, self.settings, self.config_file, self.telegram).await?;
+        Ok(Uploader { data, base_url: self.config.base_url() })
+    }
+
+    // Start everything and serve the HTTP API until the server is stopped
+    pub async fn run(self) -> std::io::Result<()> {
+        let data = start_tasks(&self.config, self.settings, self.config_file, self.telegram).await?;
+        http::serve(&self.config, data).await
+    }
+}
+
+async fn start_tasks(
+    config: &Config,
+    settings: Settings,
+    config_file: Option<PathBuf>,
+    telegram: Option<Arc<dyn TelegramUploader>>,
+) -> std::io::Result<web::Data<UploadData>> {
+    // Initialize the bots
+    let bots = match telegram {
+        Some(telegram) => BotPool::with_uploaders([(0, telegram)]),
+        None => {
+            let tokens =
+                std::iter::once(config.telegram_bot_token.clone()).chain(config.telegram_bot_tokens.iter().cloned());
+            BotPool::new(tokens, config.api_url.as_ref())
+        }
+    };
+
+    // Validate the tokens early; /readyz keeps retrying the primary one if Telegram can't be reached yet
+    let mut bot_validated = true;
+    for (index, pooled) in bots.bots.iter().enumerate() {
+        match pooled.bot.get_me().await {
+            Ok(username) => info!("Authorized as @{}", username),
+            Err(e) => {
+                error!("Failed to validate bot token {} (bot {}): {:?}", index, pooled.id, e);
+                if index == 0 {
+                    bot_validated = false;
+                }
+            }
+        }
+    }
+
+    let mut chat_ids = Vec::new();
+    for chat in &config.target_chats() {
+        let chat_id = resolve_chat(bots.primary(), chat)
+            .await
+            .map_err(|e| std::io::Error::other(format!("Failed to resolve chat {:?}: {:?}", chat, e)))?;
+        chat_ids.push(chat_id);
+    }
+
+    let semaphore = Semaphore::new(config.max_concurrent_uploads);
+    let upload_data = web::Data::new(UploadData {
+        bots,
+        chat_ids,
+        chat_mode: config.chat_mode,
+        semaphore,
+        max_concurrent_uploads: config.max_concurrent_uploads,
+        upload_queue: config.upload_queue.clone(),
+        payload_read_timeout: (config.http_server.payload_read_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.http_server.payload_read_timeout_secs)),
+        replay_guard: ReplayGuard::default(),
+        thumbnail_dir: config.thumbnail_dir.clone(),
+        archive_dir: config.archive_dir.clone(),
+        thumbnail_size: config.thumbnail_size,
+        registry: Registry::open(config.registry_path.clone())?,
+        temp_dir: config.temp_dir.clone(),
+        zip_upload: config.zip_upload.clone(),
+        metrics: Metrics::new().map_err(std::io::Error::other)?,
+        bot_validated: AtomicBool::new(bot_validated),
+        readiness_check_telegram: config.readiness_check_telegram,
+        resize_cache: Mutex::new(LruCache::new(
+            NonZeroUsize::new(config.resize_cache_size).unwrap_or(NonZeroUsize::MIN),
+        )),
+        cache_max_age_secs: config.cache_max_age_secs,
+        idempotency: (config.idempotency_window_secs > 0)
+            .then(|| IdempotencyStore::new(Duration::from_secs(config.idempotency_window_secs))),
+        progress: ProgressTracker::default(),
+        bandwidth: Bandwidth::new(&config.bandwidth),
+        settings: RwLock::new(Arc::new(settings)),
+        retry: config.retry.clone(),
+        circuit_breaker: CircuitBreaker::new(&config.circuit_breaker),
+        outbox: config.outbox.as_ref().map(Outbox::open).transpose()?,
+        temp_quota: TempQuota::new(config.temp_dir_quota),
+        proxy_cache: config.proxy_cache.as_ref().map(ProxyCache::open).transpose()?,
+        audit: config.audit.as_ref().map(AuditLog::open).transpose()?,
+        http_client: reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build().map_err(std::io::Error::other)?,
+        config_file,
+    });
+
+    std::fs::create_dir_all(&config.temp_dir)?;
+    if let Some(thumbnail_dir) = &config.thumbnail_dir {
+        std::fs::create_dir_all(thumbnail_dir)?;
+    }
+    if let Some(archive_dir) = &config.archive_dir {
+        std::fs::create_dir_all(archive_dir)?;
+    }
+
+    if upload_data.outbox.is_some() {
+        tokio::spawn(run_outbox(upload_data.clone()));
+    }
+
+    if config.inbound.is_some() || config.admin_commands {
+        let settings = BotSettings {
+            inbound: config.inbound.clone(),
+            admin_commands: config.admin_commands,
+            base_url: config.base_url(),
+        };
+        tokio::spawn(run_bot(upload_data.clone(), settings));
+    }
+
+    tokio::spawn(run_expiry(upload_data.clone()));
+
+    if upload_data.audit.as_ref().is_some_and(|audit| audit.retention.is_some()) {
+        tokio::spawn(run_audit_pruning(upload_data.clone()));
+    }
+
+    if config.temp_cleanup.max_age_secs > 0 {
+        tokio::spawn(run_temp_janitor(upload_data.clone(), config.temp_cleanup.clone()));
+    }
+
+    Ok(upload_data)
+}
+
+// Hosts files on a started server, going through the same steps as uploads over HTTP
+#[derive(Clone)]
+pub struct Uploader {
+    data: web::Data<UploadData>,
+    base_url: String,
+}
+
+// Where a file hosted through an Uploader ended up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upload {
+    Hosted { id: Uuid, url: String, deletion_token: String },
+    // Telegram is unavailable, the file waits in the outbox and will be served at /f/{id}
+    Queued { id: Uuid },
+}
+
+impl Uploader {
+    // Host a file from disk; the file itself is left in place
+    pub async fn upload_file(&self, file_path: impl AsRef<Path>) -> Result<Upload, Error> {
+        let file_path = file_path.as_ref();
+        let bytes = tokio::fs::read(file_path)
+            .await
+            .map_err(|e| Error::InvalidRequest(format!("Failed to read {:?}: {}", file_path, e)))?;
+        let file_name = file_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        self.upload_bytes(&bytes, &file_name).await
+    }
+
+    // Host a file that is already in memory, with the configured send and image options
+    pub async fn upload_bytes(&self, bytes: &[u8], file_name: &str) -> Result<Upload, Error> {
+        let data = &self.data;
+        let failed = |reason: &str, error: Error| {
+            data.metrics.upload_failed(reason);
+            error
+        };
+
+        data.metrics.uploads_total.inc();
+        let _in_flight = InFlight::new(&data.metrics.uploads_in_flight);
+
+        let file_name = sanitize_filename::sanitize(if file_name.is_empty() { "file" } else { file_name });
+        let content_type = mime_guess::from_path(&file_name).first().map(|mime| mime.to_string());
+        let settings = data.settings();
+        if !is_type_allowed(&settings.allowed_types, &file_name, None) {
+            return Err(failed("save", Error::UnsupportedMediaType("File type is not allowed".to_string())));
+        }
+
+        let circuit = data.circuit_breaker.check();
+        let circuit_open = circuit.is_err();
+        if let (Err(retry_after), None) = (circuit, &data.outbox) {
+            let retry_after = Some(retry_after.as_secs_f64().ceil() as u64);
+            return Err(failed("circuit_open", Error::TelegramUnavailable { retry_after }));
+        }
+
+        let mut reservation = data.temp_quota.reserve();
+        if !reservation.grow(bytes.len() as u64) {
+            return Err(failed("quota", Error::StorageFull));
+        }
+        let saved = save_bytes(&data.temp_dir, bytes, &file_name, content_type)
+            .await
+            .map_err(|e| {
+                error!("Failed to save file: {:?}", e);
+                failed("save", Error::Internal("Failed to save file".to_string()))
+            })?;
+        reservation.hold(saved.id);
+        let path = Path::new(&saved.file_path);
+
+        let mut options = settings.send_options.clone();
+        options.mode = match options.mode.resolve(path, saved.content_type.as_deref()) {
+            Ok(mode) => mode,
+            Err(e) => {
+                remove_temp_file(path);
+                notify_webhooks(data, &WebhookPayload::failed(saved.id, Some(&saved.file_name), &e));
+                return Err(failed("invalid", Error::UnsupportedMediaType(e)));
+            }
+        };
+        options.file_name = Some(saved.file_name.clone());
+
+        let hosted = host_file(data, &saved, &options, &Destination::default(), circuit_open).await;
+        data.audit(AuditEntry {
+            upload_id: match &hosted {
+                Ok(HostedFile::Sent { record, .. }) => Some(record.id),
+                Ok(HostedFile::Queued { entry, .. }) => Some(entry.id),
+                Err(_) => None,
+            },
+            content_hash: Some(saved.content_hash.clone()),
+            error: hosted.as_ref().err().map(Error::to_string),
+            ..AuditEntry::new(AuditAction::Upload, AuditSource::Library, hosted.is_ok())
+        });
+        match hosted {
+            Ok(HostedFile::Sent { record, file_path, .. }) => Ok(Upload::Hosted {
+                id: record.id,
+                url: upload_url(&settings, &self.base_url, data.bots.get(record.bot_id), &record.id, &file_path),
+                deletion_token: record.deletion_token,
+            }),
+            Ok(HostedFile::Queued { entry, .. }) => Ok(Upload::Queued { id: entry.id }),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+
+    fn png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ::image::RgbImage::from_pixel(4, 3, ::image::Rgb([200, 30, 30]))
+            .write_to(&mut std::io::Cursor::new(&mut bytes), ::image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    // A started server posting to a FakeTelegram, with its files kept in dir and the given
+    // config on top of what it needs
+    async fn start(dir: &Path, telegram: Arc<FakeTelegram>, extra: serde_json::Value) -> web::Data<UploadData> {
+        let mut config = serde_json::json!({
+            "telegram_bot_token": "123:fake",
+            "chat_id": -1001,
+            "max_concurrent_uploads": 2,
+            "host": "127.0.0.1",
+            "port": "8080",
+            "temp_dir": dir.join("tmp"),
+            "registry_path": dir.join("uploads.json"),
+        });
+        config.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        let config: Config = serde_json::from_value(config).unwrap();
+        Server::builder().config(config).telegram(telegram).build().unwrap().start().await.unwrap().data
+    }
+
+    // A JPEG carrying an (empty) EXIF block
+    fn jpeg_with_exif() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = ::image::codecs::jpeg::JpegEncoder::new(&mut bytes);
+        ::image::ImageEncoder::set_exif_metadata(&mut encoder, b"II*\0\x08\0\0\0\0\0\0\0\0\0".to_vec()).unwrap();
+        ::image::DynamicImage::ImageRgb8(::image::RgbImage::new(4, 3)).write_with_encoder(encoder).unwrap();
+        bytes
+    }
+
+    fn upload_request(bytes: &[u8]) -> TestRequest {
+        upload_file_request("/upload?format=json", "red.png", bytes)
+    }
+
+    fn upload_file_request(uri: &str, file_name: &str, bytes: &[u8]) -> TestRequest {
+        let content_type = mime_guess::from_path(file_name).first_or_octet_stream();
+        let mut body = format!("--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n", file_name).into_bytes();
+        body.extend_from_slice(format!("Content-Type: {}\r\n\r\n", content_type).as_bytes());
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+        TestRequest::post()
+            .uri(uri)
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=boundary"))
+            .set_payload(body)
+    }
+
+    #[actix_web::test]
+    async fn uploads_are_hosted_served_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let data = start(dir.path(), telegram.clone(), serde_json::json!({})).await;
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+        let image = png();
+
+        let response = test::call_service(&app, upload_request(&image).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let uploaded: serde_json::Value = test::read_body_json(response).await;
+        let id = Uuid::parse_str(uploaded["id"].as_str().unwrap()).unwrap();
+
+        // Posted once, and remembered by the registry
+        let messages = telegram.messages();
+        assert_eq!(messages.len(), 1);
+        let record = data.registry.get(&id).unwrap();
+        assert_eq!((record.chat_id, record.message_id), (messages[0].chat_id.0, messages[0].message_id.0));
+        assert_eq!(record.sent_as, UploadMode::Photo);
+        assert_eq!((record.width, record.height), (Some(4), Some(3)));
+
+        let response = test::call_service(&app, TestRequest::get().uri(&format!("/f/{}", id)).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(test::read_body(response).await, image);
+
+        let delete = format!("/delete/{}/{}", id, uploaded["deletion_token"].as_str().unwrap());
+        let response = test::call_service(&app, TestRequest::delete().uri(&delete).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(telegram.messages()[0].deleted);
+        assert!(data.registry.get(&id).is_none());
+        let response = test::call_service(&app, TestRequest::get().uri(&format!("/f/{}", id)).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn uploaders_host_files_without_http() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let data = start(dir.path(), telegram.clone(), serde_json::json!({})).await;
+        let uploader = Uploader { data: data.clone(), base_url: "http://localhost:8080".to_string() };
+
+        let Upload::Hosted { id, .. } = uploader.upload_bytes(&png(), "red.png").await.unwrap() else {
+            panic!("The upload was queued");
+        };
+        let record = data.registry.get(&id).unwrap();
+        assert_eq!(telegram.file(&record.file_id), Some(png()));
+        // The temporary copy is gone once the file is posted
+        assert_eq!(std::fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn retries_with_an_idempotency_key_get_the_first_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        // Without deduplication, only the key keeps the retry from being posted again
+        let data = start(dir.path(), telegram.clone(), serde_json::json!({ "deduplicate": false })).await;
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+        let image = png();
+
+        // Clients without an API key are told apart by their address
+        let keyed = |key: &str, peer: &str, bytes: &[u8]| {
+            upload_request(bytes).insert_header(("Idempotency-Key", key)).peer_addr(peer.parse().unwrap()).to_request()
+        };
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            let response = test::call_service(&app, keyed("retry-1", "192.0.2.1:1000", &image)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            responses.push(test::read_body(response).await);
+        }
+        assert_eq!(telegram.messages().len(), 1);
+        assert_eq!(responses[0], responses[1]);
+        assert_eq!(data.registry.records().len(), 1);
+
+        // Another key, or the same key from someone else, is another upload
+        assert_eq!(test::call_service(&app, keyed("retry-2", "192.0.2.1:1000", &image)).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, keyed("retry-1", "192.0.2.2:1000", &image)).await.status(), StatusCode::OK);
+        assert_eq!(telegram.messages().len(), 3);
+
+        // The key can't be used again for another file
+        let response = test::call_service(&app, keyed("retry-1", "192.0.2.1:1000", &jpeg_with_exif())).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(telegram.messages().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn signed_urls_keep_telegram_links_out_of_responses() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let extra = serde_json::json!({ "signed_urls": { "secret": "secret", "expiry_secs": 60 } });
+        let data = start(dir.path(), telegram, extra).await;
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+
+        let response = test::call_service(&app, upload_request(&png()).to_request()).await;
+        let uploaded: serde_json::Value = test::read_body_json(response).await;
+        let url = uploaded["url"].as_str().unwrap();
+        assert!(url.contains(&format!("/f/{}?exp=", uploaded["id"].as_str().unwrap())), "{}", url);
+        assert!(url.contains("&sig="), "{}", url);
+    }
+
+    #[actix_web::test]
+    async fn images_sent_as_documents_are_processed_too() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let data = start(dir.path(), telegram.clone(), serde_json::json!({ "strip_exif": true })).await;
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+        let image = jpeg_with_exif();
+        let mut decoder = ::image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(&image)).unwrap();
+        assert!(::image::ImageDecoder::exif_metadata(&mut decoder).unwrap().is_some());
+
+        let request = upload_file_request("/upload?format=json&as=document", "photo.jpg", &image).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let messages = telegram.messages();
+        assert_eq!(messages[0].sent_as, UploadMode::Document);
+        let posted = telegram.file(&messages[0].file_id).unwrap();
+        let mut decoder = ::image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(&posted)).unwrap();
+        assert!(::image::ImageDecoder::exif_metadata(&mut decoder).unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn duplicates_get_uploads_of_their_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let data = start(dir.path(), telegram.clone(), serde_json::json!({})).await;
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+        let image = png();
+
+        let mut uploads = Vec::new();
+        for uri in ["/upload?format=json", "/upload?format=json", "/upload?format=json&as=document"] {
+            let response = test::call_service(&app, upload_file_request(uri, "red.png", &image).to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let deduplicated = response.headers().get("X-Deduplicated").unwrap().to_str().unwrap().to_string();
+            let uploaded: serde_json::Value = test::read_body_json(response).await;
+            uploads.push((deduplicated, uploaded));
+        }
+        let deduplicated: Vec<&str> = uploads.iter().map(|(deduplicated, _)| deduplicated.as_str()).collect();
+        assert_eq!(deduplicated, ["false", "true", "false"]);
+        let (first, second) = (&uploads[0].1, &uploads[1].1);
+        assert_ne!(first["id"], second["id"]);
+        assert_ne!(first["deletion_token"], second["deletion_token"]);
+
+        // The duplicate reposted the stored file rather than uploading it
+        let messages = telegram.messages();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].file_id, messages[1].file_id);
+
+        // Deleting one leaves the other be
+        let delete = format!("/delete/{}/{}", first["id"].as_str().unwrap(), first["deletion_token"].as_str().unwrap());
+        assert_eq!(test::call_service(&app, TestRequest::delete().uri(&delete).to_request()).await.status(), StatusCode::OK);
+        assert!(!telegram.messages()[1].deleted);
+        let response = test::call_service(&app, TestRequest::get().uri(&format!("/f/{}", second["id"].as_str().unwrap())).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn puts_replace_the_upload_at_their_path_once_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let extra = serde_json::json!({
+            "outbox": { "directory": dir.path().join("outbox"), "retry_interval_secs": 1 },
+            "circuit_breaker": { "failure_threshold": 1, "cooldown_secs": 3600 },
+        });
+        let data = start(dir.path(), telegram.clone(), extra).await;
+        let app = test::init_service(http::app(data.clone(), None, false, true, false)).await;
+        let put = || TestRequest::put().uri("/dav/pics/red.png").set_payload(png()).to_request();
+        assert_eq!(test::call_service(&app, put()).await.status(), StatusCode::CREATED);
+        let first = data.registry.find_by_dav_path("pics/red.png", None).unwrap();
+
+        // Queued while Telegram is down, the new upload leaves the first one be
+        data.circuit_breaker.record_failure(&data.metrics);
+        assert_eq!(test::call_service(&app, put()).await.status(), StatusCode::NO_CONTENT);
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert_eq!(data.registry.find_by_dav_path("pics/red.png", None).unwrap().id, first.id);
+        assert!(!telegram.messages()[0].deleted);
+
+        // Once it's delivered, it takes the first one's place
+        data.circuit_breaker.record_success(&data.metrics);
+        for _ in 0..50 {
+            if data.registry.get(&first.id).is_none() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(data.registry.get(&first.id).is_none());
+        assert!(telegram.messages()[0].deleted);
+        assert_eq!(data.registry.records().iter().filter(|record| record.dav_path.is_some()).count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn rejected_forms_leave_no_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let data = start(dir.path(), telegram.clone(), serde_json::json!({ "allowed_types": ["image/*"] })).await;
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+        let mut body = b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"red.png\"\r\n".to_vec();
+        body.extend_from_slice(b"Content-Type: image/png\r\n\r\n");
+        body.extend_from_slice(&png());
+        body.extend_from_slice(b"\r\n--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"run.exe\"\r\n");
+        body.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\nMZ\r\n--boundary--\r\n");
+        let request = TestRequest::post()
+            .uri("/upload")
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=boundary"))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(std::fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+        assert!(telegram.messages().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn queued_uploads_are_given_up_after_max_attempts() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let extra = serde_json::json!({
+            "outbox": { "directory": dir.path().join("outbox"), "retry_interval_secs": 1, "max_attempts": 2 },
+            "retry": { "max_retries": 0 },
+            "circuit_breaker": { "failure_threshold": 0 },
+        });
+        let data = start(dir.path(), telegram.clone(), extra).await;
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+        telegram.set_unavailable(true);
+        let response = test::call_service(&app, upload_request(&png()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let id: Uuid = response.headers().get("X-Upload-Id").unwrap().to_str().unwrap().parse().unwrap();
+
+        let outbox = data.outbox.as_ref().unwrap();
+        for _ in 0..50 {
+            if outbox.get(&id).unwrap().failed {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let entry = outbox.get(&id).unwrap();
+        assert!(entry.failed);
+        assert_eq!(entry.attempts, 2);
+        assert!(!outbox.image_path(&entry).exists());
+
+        // Even once Telegram is back
+        telegram.set_unavailable(false);
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(telegram.messages().is_empty());
+        assert!(data.registry.get(&id).is_none());
+    }
+
+    #[actix_web::test]
+    async fn upload_slots_are_given_back_however_uploads_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let telegram = Arc::new(FakeTelegram::new());
+        let extra = serde_json::json!({
+            "max_concurrent_uploads": 1,
+            "upload_queue": { "max_wait_secs": 1 },
+            "retry": { "max_retries": 0 },
+        });
+        let data = start(dir.path(), telegram.clone(), extra).await;
+
+        // Waiting out max_wait_secs, or giving up sooner, leaves the queue
+        let slot = upload::acquire_upload_slot(&data).await.unwrap();
+        let waited = upload::acquire_upload_slot(&data).await;
+        assert!(matches!(waited, Err(Error::Overloaded { .. })));
+        let abandoned = tokio::time::timeout(std::time::Duration::from_millis(50), upload::acquire_upload_slot(&data)).await;
+        assert!(abandoned.is_err());
+        assert_eq!(data.metrics.uploads_waiting.get(), 0);
+        drop(slot);
+        assert_eq!(data.semaphore.available_permits(), 1);
+
+        // Uploads that fail give their slot back
+        let app = test::init_service(http::app(data.clone(), None, false, false, false)).await;
+        telegram.set_unavailable(true);
+        let response = test::call_service(&app, upload_request(&png()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(data.semaphore.available_permits(), 1);
+        telegram.set_unavailable(false);
+        let response = test::call_service(&app, upload_request(&png()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(data.semaphore.available_permits(), 1);
+    }
+}
@@ -0,0 +1,114 @@
+// Logging to stdout, rotating files and OpenTelemetry
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use crate::config::{Config, LogFileConfig, LogFormat, LogRotation, OtelConfig};
+
+// Open a log file that is rotated as configured
+pub(crate) fn rolling_appender(file: &LogFileConfig) -> Result<RollingFileAppender, String> {
+    let rotation = match file.rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut builder = RollingFileAppender::builder().rotation(rotation).filename_prefix(&file.prefix);
+    if let Some(max_files) = file.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    std::fs::create_dir_all(&file.directory).map_err(|e| format!("Failed to create log directory: {}", e))?;
+    builder.build(&file.directory).map_err(|e| format!("Failed to open log file: {}", e))
+}
+
+// Keeps log and trace exporters running; flushes them when dropped
+pub struct LoggingGuard {
+    _file: Option<WorkerGuard>,
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = &self.tracer_provider {
+            if let Err(e) = tracer_provider.shutdown() {
+                eprintln!("Failed to flush traces: {:?}", e);
+            }
+        }
+    }
+}
+
+// Build the OTLP exporter pipeline for spans
+pub(crate) fn init_tracer_provider(config: &OtelConfig) -> Result<SdkTracerProvider, String> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()
+        .map_err(|e| format!("Failed to create OTLP exporter: {}", e))?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio.clamp(0.0, 1.0),
+        ))))
+        .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+        .build())
+}
+
+// Set up logging to stdout and, if configured, to rotating files and an OTLP collector.
+// RUST_LOG overrides the configured levels. Fails if a logger is set up already.
+pub fn init_logging(config: &Config) -> Result<LoggingGuard, String> {
+    let (config, otel) = (&config.log, config.otel.as_ref());
+    let filter = || {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            let directives = std::iter::once(config.level.clone())
+                .chain(config.modules.iter().map(|(module, level)| format!("{}={}", module, level)))
+                .collect::<Vec<_>>()
+                .join(",");
+            EnvFilter::new(directives)
+        })
+    };
+
+    fn format_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+        W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+        match format {
+            LogFormat::Pretty => layer.boxed(),
+            LogFormat::Json => layer.json().boxed(),
+        }
+    }
+
+    let mut guard = None;
+    let file_layer = match &config.file {
+        Some(file) => {
+            let (writer, file_guard) = tracing_appender::non_blocking(rolling_appender(file)?);
+            guard = Some(file_guard);
+            Some(format_layer(config.format, writer, false).with_filter(filter()))
+        }
+        None => None,
+    };
+
+    let tracer_provider = otel.map(init_tracer_provider).transpose()?;
+    let otel_layer = tracer_provider.as_ref().map(|tracer_provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer_provider.tracer("anarchic-image-hosting-bot"))
+            .with_filter(filter())
+    });
+
+    tracing_subscriber::registry()
+        .with(format_layer(config.format, std::io::stdout, true).with_filter(filter()))
+        .with(file_layer)
+        .with(otel_layer)
+        .try_init()
+        .map_err(|e| format!("Failed to set up logging: {}", e))?;
+
+    Ok(LoggingGuard { _file: guard, tracer_provider })
+}
@@ -0,0 +1,128 @@
+// Prometheus metrics
+
+use prometheus::{Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts};
+use tracing::Instrument;
+
+// Prometheus metrics, exposed at /metrics
+pub(crate) struct Metrics {
+    pub(crate) registry: prometheus::Registry,
+    pub(crate) uploads_total: IntCounter,
+    pub(crate) uploads_failed: IntCounterVec,
+    pub(crate) bytes_uploaded: IntCounter,
+    pub(crate) telegram_latency: HistogramVec,
+    pub(crate) telegram_retries: IntCounterVec,
+    pub(crate) telegram_circuit_open: IntGauge,
+    pub(crate) bot_recent_calls: IntGaugeVec,
+    pub(crate) semaphore_wait: Histogram,
+    pub(crate) uploads_in_flight: IntGauge,
+    pub(crate) uploads_waiting: IntGauge,
+    pub(crate) temp_dir_bytes: IntGauge,
+    pub(crate) proxy_cache_lookups: IntCounterVec,
+    pub(crate) proxy_cache_bytes: IntGauge,
+    pub(crate) clamav_scans: IntCounterVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> prometheus::Result<Metrics> {
+        let registry = prometheus::Registry::new_custom(Some("anarchic".to_string()), None)?;
+
+        let uploads_total = IntCounter::new("uploads_total", "Upload requests received")?;
+        let uploads_failed = IntCounterVec::new(
+            Opts::new("uploads_failed_total", "Failed uploads by the stage they failed in"),
+            &["reason"],
+        )?;
+        let bytes_uploaded = IntCounter::new("uploaded_bytes_total", "Bytes sent to Telegram")?;
+        let telegram_latency = HistogramVec::new(
+            HistogramOpts::new("telegram_request_duration_seconds", "Latency of Telegram API calls"),
+            &["method"],
+        )?;
+        let telegram_retries = IntCounterVec::new(
+            Opts::new("telegram_retries_total", "Telegram API calls repeated after a transient failure"),
+            &["method"],
+        )?;
+        let telegram_circuit_open =
+            IntGauge::new("telegram_circuit_open", "Whether uploads are failing fast because Telegram is down")?;
+        let bot_recent_calls = IntGaugeVec::new(
+            Opts::new("telegram_bot_recent_calls", "Telegram API calls per bot within the last minute"),
+            &["bot"],
+        )?;
+        let semaphore_wait = Histogram::with_opts(HistogramOpts::new(
+            "upload_semaphore_wait_seconds",
+            "Time uploads spend waiting for a free upload slot",
+        ))?;
+        let uploads_in_flight = IntGauge::new("uploads_in_flight", "Uploads currently being handled")?;
+        let uploads_waiting = IntGauge::new("uploads_waiting", "Uploads currently waiting for a free upload slot")?;
+        let temp_dir_bytes = IntGauge::new("temp_dir_bytes", "Bytes currently stored in the temp directory")?;
+        let proxy_cache_lookups = IntCounterVec::new(
+            Opts::new("proxy_cache_lookups_total", "Proxied files looked up in the disk cache"),
+            &["result"],
+        )?;
+        let proxy_cache_bytes = IntGauge::new("proxy_cache_bytes", "Bytes stored in the proxy disk cache")?;
+        let clamav_scans = IntCounterVec::new(
+            Opts::new("clamav_scans_total", "Uploads scanned by ClamAV, by whether they were clean"),
+            &["result"],
+        )?;
+
+        registry.register(Box::new(uploads_total.clone()))?;
+        registry.register(Box::new(uploads_failed.clone()))?;
+        registry.register(Box::new(bytes_uploaded.clone()))?;
+        registry.register(Box::new(telegram_latency.clone()))?;
+        registry.register(Box::new(telegram_retries.clone()))?;
+        registry.register(Box::new(telegram_circuit_open.clone()))?;
+        registry.register(Box::new(bot_recent_calls.clone()))?;
+        registry.register(Box::new(semaphore_wait.clone()))?;
+        registry.register(Box::new(uploads_in_flight.clone()))?;
+        registry.register(Box::new(uploads_waiting.clone()))?;
+        registry.register(Box::new(temp_dir_bytes.clone()))?;
+        registry.register(Box::new(proxy_cache_lookups.clone()))?;
+        registry.register(Box::new(proxy_cache_bytes.clone()))?;
+        registry.register(Box::new(clamav_scans.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            uploads_total,
+            uploads_failed,
+            bytes_uploaded,
+            telegram_latency,
+            telegram_retries,
+            telegram_circuit_open,
+            bot_recent_calls,
+            semaphore_wait,
+            uploads_in_flight,
+            uploads_waiting,
+            temp_dir_bytes,
+            proxy_cache_lookups,
+            proxy_cache_bytes,
+            clamav_scans,
+        })
+    }
+
+    pub(crate) fn upload_failed(&self, reason: &str) {
+        self.uploads_failed.with_label_values(&[reason]).inc();
+    }
+
+    // Await a Telegram API call inside its own span, recording how long it took
+    pub(crate) async fn time_telegram<F: std::future::IntoFuture>(&self, method: &str, request: F) -> F::Output {
+        let timer = self.telegram_latency.with_label_values(&[method]).start_timer();
+        let span = tracing::info_span!("telegram_request", method);
+        let output = request.into_future().instrument(span).await;
+        timer.observe_duration();
+        output
+    }
+}
+
+// Counts an upload as in flight for as long as it is alive
+pub(crate) struct InFlight<'a>(pub(crate) &'a IntGauge);
+
+impl<'a> InFlight<'a> {
+    pub(crate) fn new(gauge: &'a IntGauge) -> InFlight<'a> {
+        gauge.inc();
+        InFlight(gauge)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
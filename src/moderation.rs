@@ -0,0 +1,114 @@
+// Content moderation: images are scored by an external classifier before they are posted
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+use crate::config::ModerationConfig;
+use crate::error::Error;
+
+// What the classifier made of an upload, kept in the registry
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ModerationVerdict {
+    // From 0 (harmless) to 1 (certainly NSFW)
+    pub(crate) score: f64,
+    // Scored above flag_above, hosted but marked for review
+    pub(crate) flagged: bool,
+}
+
+// Answer of the classifier, from its HTTP response or the command's output
+#[derive(Debug, Deserialize)]
+pub(crate) struct ClassifierResponse {
+    pub(crate) score: f64,
+}
+
+// Score an image and decide whether it may be hosted. Files that aren't images aren't
+// moderated. A classifier that can't be reached blocks the upload unless fail_open is set.
+pub(crate) async fn moderate(
+    config: &ModerationConfig,
+    client: &reqwest::Client,
+    file_path: &Path,
+    content_type: Option<&str>,
+) -> Result<Option<ModerationVerdict>, Error> {
+    if image::image_dimensions(file_path).is_err() {
+        return Ok(None);
+    }
+
+    let timeout = Duration::from_secs(config.timeout_secs.max(1));
+    let score = match tokio::time::timeout(timeout, classify(config, client, file_path, content_type)).await {
+        Ok(Ok(score)) => score,
+        Ok(Err(e)) if config.fail_open => {
+            warn!("Content moderation failed, hosting {:?} unchecked: {}", file_path, e);
+            return Ok(None);
+        }
+        Err(_) if config.fail_open => {
+            warn!("Content moderation timed out, hosting {:?} unchecked", file_path);
+            return Ok(None);
+        }
+        Ok(Err(e)) => {
+            error!("Content moderation failed for {:?}: {}", file_path, e);
+            return Err(Error::Internal("Content moderation is unavailable".to_string()));
+        }
+        Err(_) => {
+            error!("Content moderation timed out for {:?}", file_path);
+            return Err(Error::Internal("Content moderation is unavailable".to_string()));
+        }
+    };
+    debug!("Moderation score of {:?}: {}", file_path, score);
+
+    if score > config.block_above {
+        info!("Blocked {:?} with a moderation score of {}", file_path, score);
+        return Err(Error::ContentBlocked);
+    }
+    let flagged = config.flag_above.is_some_and(|flag_above| score > flag_above);
+    if flagged {
+        info!("Flagged {:?} with a moderation score of {}", file_path, score);
+    }
+    Ok(Some(ModerationVerdict { score, flagged }))
+}
+
+// Ask the configured classifier for the score of an image
+pub(crate) async fn classify(
+    config: &ModerationConfig,
+    client: &reqwest::Client,
+    file_path: &Path,
+    content_type: Option<&str>,
+) -> Result<f64, String> {
+    let response: ClassifierResponse = match (&config.url, &config.command) {
+        (Some(url), _) => {
+            let bytes = tokio::fs::read(file_path).await.map_err(|e| format!("Failed to read the file: {}", e))?;
+            let content_type = content_type.unwrap_or("application/octet-stream").to_string();
+            let response = client
+                .post(url.clone())
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(bytes)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| format!("Classifier request failed: {}", e))?;
+            response.json().await.map_err(|e| format!("Invalid classifier response: {}", e))?
+        }
+        (None, Some(command)) => {
+            let Some((program, args)) = command.split_first() else {
+                return Err("moderation.command is empty".to_string());
+            };
+            let args = args.iter().map(|arg| arg.replace("{input}", &file_path.to_string_lossy()));
+            let output = tokio::process::Command::new(program)
+                .args(args)
+                .kill_on_drop(true)
+                .output()
+                .await
+                .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+            if !output.status.success() {
+                return Err(format!("{} exited with {}: {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim()));
+            }
+            serde_json::from_slice(&output.stdout).map_err(|e| format!("Invalid output of {}: {}", program, e))?
+        }
+        (None, None) => return Err("moderation needs a url or a command".to_string()),
+    };
+
+    match response.score {
+        score if (0.0..=1.0).contains(&score) => Ok(score),
+        score => Err(format!("Score {} is outside 0 to 1", score)),
+    }
+}
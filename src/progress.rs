@@ -0,0 +1,151 @@
+// Progress of uploads as they come in and go out to Telegram, streamed to clients as Server-Sent Events
+
+use actix_web::web::{self, Bytes};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use crate::error::Error;
+use crate::telegram::SendProgress;
+use crate::upload::UploadData;
+
+// How long an ID handed out by POST /progress waits for its upload
+pub(crate) const PROGRESS_UNUSED_TTL: Duration = Duration::from_secs(10 * 60);
+
+// How long the outcome of an upload stays around for progress streams that connect late
+pub(crate) const PROGRESS_FINISHED_TTL: Duration = Duration::from_secs(60);
+
+// How often progress streams look for changes
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+// Progress streams send a comment at least this often so proxies don't close them
+pub(crate) const PROGRESS_KEEPALIVE: Duration = Duration::from_secs(15);
+
+// How an upload being tracked ended
+#[derive(Debug, Clone)]
+pub(crate) enum ProgressOutcome {
+    Done { status: u16 },
+    // With the ID of the upload request, to find it in the logs
+    Failed { error: Error, request_id: String },
+}
+
+// Counters of a single upload, updated by the upload handler while the stream reads them
+#[derive(Debug, Default)]
+pub(crate) struct UploadProgress {
+    // Set once an upload claimed the ID, so it can't be used twice
+    pub(crate) started: AtomicBool,
+    // Bytes of the file received from the client
+    pub(crate) received: AtomicU64,
+    // Content-Length of the upload request, 0 if the client didn't send one
+    pub(crate) expected: AtomicU64,
+    pub(crate) sending: Arc<SendProgress>,
+    pub(crate) outcome: Mutex<Option<(Instant, ProgressOutcome)>>,
+}
+
+impl UploadProgress {
+    pub(crate) fn finish(&self, outcome: ProgressOutcome) {
+        let mut current = self.outcome.lock().unwrap();
+        // The first outcome counts; the handler reports success after errors have been recorded
+        if current.is_none() {
+            *current = Some((Instant::now(), outcome));
+        }
+    }
+
+    // The event describing the current state
+    pub(crate) fn event(&self, id: &Uuid) -> (&'static str, String) {
+        if let Some((_, outcome)) = &*self.outcome.lock().unwrap() {
+            return match outcome {
+                ProgressOutcome::Done { status } => ("done", serde_json::json!({ "id": id, "status": status }).to_string()),
+                ProgressOutcome::Failed { error, request_id } => ("failed", error.body(request_id).to_string()),
+            };
+        }
+
+        let (sent, to_send) = (self.sending.sent.load(Ordering::Relaxed), self.sending.total.load(Ordering::Relaxed));
+        let received = self.received.load(Ordering::Relaxed);
+        let phase = match (received, to_send) {
+            (_, 1..) => "sending",
+            (1.., _) => "receiving",
+            _ => "waiting",
+        };
+        let event = ProgressEvent {
+            id,
+            phase,
+            received,
+            expected: self.expected.load(Ordering::Relaxed),
+            sent,
+            to_send,
+        };
+        ("progress", serde_json::to_string(&event).unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ProgressEvent<'a> {
+    pub(crate) id: &'a Uuid,
+    // waiting, receiving or sending
+    pub(crate) phase: &'static str,
+    pub(crate) received: u64,
+    pub(crate) expected: u64,
+    pub(crate) sent: u64,
+    pub(crate) to_send: u64,
+}
+
+// Uploads whose progress can be followed, by the upload ID handed out for them
+#[derive(Default)]
+pub(crate) struct ProgressTracker {
+    pub(crate) entries: Mutex<HashMap<Uuid, (Instant, Arc<UploadProgress>)>>,
+}
+
+impl ProgressTracker {
+    // Hand out an ID for an upload that is about to start
+    pub(crate) fn create(&self) -> Uuid {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (created_at, progress)| match &*progress.outcome.lock().unwrap() {
+            Some((finished_at, _)) => finished_at.elapsed() < PROGRESS_FINISHED_TTL,
+            None => created_at.elapsed() < PROGRESS_UNUSED_TTL || progress.started.load(Ordering::Relaxed),
+        });
+        let id = Uuid::new_v4();
+        entries.insert(id, (Instant::now(), Arc::new(UploadProgress::default())));
+        id
+    }
+
+    pub(crate) fn get(&self, id: &Uuid) -> Option<Arc<UploadProgress>> {
+        self.entries.lock().unwrap().get(id).map(|(_, progress)| progress.clone())
+    }
+}
+
+// Server-Sent Events for an upload: `progress` whenever the counters change, then a single
+// `done` or `failed` event before the stream ends
+pub(crate) fn progress_events(
+    data: web::Data<UploadData>,
+    id: Uuid,
+    progress: Arc<UploadProgress>,
+) -> impl futures_util::Stream<Item = Result<Bytes, actix_web::Error>> {
+    let state = (progress, None::<String>, Instant::now(), false);
+    futures_util::stream::unfold(state, move |(progress, last, last_sent, finished)| {
+        let data = data.clone();
+        async move {
+            if finished {
+                return None;
+            }
+            let mut last_sent = last_sent;
+            loop {
+                // Forgotten by the tracker: the upload never started
+                data.progress.get(&id)?;
+                let (event, body) = progress.event(&id);
+                if last.as_deref() != Some(body.as_str()) {
+                    let message = Bytes::from(format!("event: {}\ndata: {}\n\n", event, body));
+                    let finished = event != "progress";
+                    return Some((Ok(message), (progress, Some(body), Instant::now(), finished)));
+                }
+                if last_sent.elapsed() >= PROGRESS_KEEPALIVE {
+                    last_sent = Instant::now();
+                    return Some((Ok(Bytes::from_static(b": keepalive\n\n")), (progress, last, last_sent, false)));
+                }
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
+            }
+        }
+    })
+}
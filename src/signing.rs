@@ -0,0 +1,187 @@
+// Signed requests: clients prove they hold an API key without sending it, by signing the method,
+// target and time of the request and the SHA-256 of its body with the key
+
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::storage::to_hex;
+
+// How far the timestamp of a signed request may be from the server's clock
+pub(crate) const SIGNATURE_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+// The X-Signature header: `key=<name>, timestamp=<unix seconds>, signature=<hex>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestSignature {
+    // Name of the API key the request was signed with
+    pub(crate) key: String,
+    pub(crate) timestamp: i64,
+    pub(crate) signature: String,
+}
+
+impl RequestSignature {
+    pub(crate) fn parse(header: &str) -> Result<RequestSignature, String> {
+        let mut fields = HashMap::new();
+        for pair in header.split(',') {
+            let (name, value) = pair.split_once('=').ok_or_else(|| format!("Invalid X-Signature field: {:?}", pair.trim()))?;
+            fields.insert(name.trim().to_ascii_lowercase(), value.trim().trim_matches('"').to_string());
+        }
+        let mut field = |name: &str| fields.remove(name).ok_or_else(|| format!("X-Signature has no {}", name));
+        Ok(RequestSignature {
+            key: field("key")?,
+            timestamp: field("timestamp")?.parse().map_err(|_| "X-Signature has an invalid timestamp".to_string())?,
+            signature: field("signature")?.to_ascii_lowercase(),
+        })
+    }
+}
+
+// What gets signed: the method, path and query, the timestamp and the hex SHA-256 of the body,
+// one per line
+pub(crate) fn request_signature(secret: &str, method: &str, target: &str, timestamp: i64, body_sha256: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}\n{}\n{}", method, target, timestamp, body_sha256).as_bytes());
+    to_hex(&mac.finalize().into_bytes())
+}
+
+// Signatures accepted recently, so a captured request can't be sent again while its timestamp
+// is still acceptable
+#[derive(Default)]
+pub(crate) struct ReplayGuard {
+    pub(crate) seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl ReplayGuard {
+    // Remember a signature, returning false if it was used before
+    pub(crate) fn first_use(&self, signature: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, seen_at| seen_at.elapsed() < SIGNATURE_MAX_AGE * 2);
+        seen.insert(signature.to_string(), Instant::now()).is_none()
+    }
+}
+
+// Pass a request body through, failing it if it doesn't hash to what was signed. The last chunk
+// is held back until it's checked, so nothing reads a tampered body to the end.
+pub(crate) fn verify_body(
+    body: impl Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static,
+    body_sha256: String,
+    length: Option<u64>,
+) -> impl Stream<Item = Result<Bytes, PayloadError>> {
+    let mismatch = || {
+        let e = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "The body doesn't match X-Content-Sha256");
+        PayloadError::Io(e)
+    };
+    let state = (body, Sha256::new(), 0u64, false);
+    futures_util::stream::unfold(state, move |(mut body, mut hasher, mut received, done)| {
+        let body_sha256 = body_sha256.clone();
+        async move {
+            if done {
+                return None;
+            }
+            let matches = |hasher: Sha256| to_hex(&hasher.finalize()) == body_sha256;
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    hasher.update(&chunk);
+                    received += chunk.len() as u64;
+                    if length.is_some_and(|length| received >= length) {
+                        let chunk = if matches(hasher.clone()) { Ok(chunk) } else { Err(mismatch()) };
+                        return Some((chunk, (body, hasher, received, true)));
+                    }
+                    Some((Ok(chunk), (body, hasher, received, false)))
+                }
+                Some(Err(e)) => Some((Err(e), (body, hasher, received, true))),
+                None if !matches(hasher.clone()) => Some((Err(mismatch()), (body, hasher, received, true))),
+                None => None,
+            }
+        }
+    })
+    .fuse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SHA-256 of "hello"
+    const BODY_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn parses_the_header() {
+        let signature = RequestSignature::parse("key=ci, timestamp=1700000000, signature=\"ABCDEF\"").unwrap();
+        assert_eq!(
+            signature,
+            RequestSignature { key: "ci".to_string(), timestamp: 1_700_000_000, signature: "abcdef".to_string() }
+        );
+        // Field names are case-insensitive and can come in any order
+        let signature = RequestSignature::parse("Signature=ab,KEY=ci,Timestamp=1").unwrap();
+        assert_eq!((signature.key.as_str(), signature.timestamp), ("ci", 1));
+    }
+
+    #[test]
+    fn rejects_incomplete_headers() {
+        assert!(RequestSignature::parse("key=ci, timestamp=1700000000").is_err());
+        assert!(RequestSignature::parse("key=ci, timestamp=soon, signature=ab").is_err());
+        assert!(RequestSignature::parse("key=ci; timestamp=1700000000; signature=ab").is_err());
+        assert!(RequestSignature::parse("").is_err());
+    }
+
+    #[test]
+    fn signs_method_target_timestamp_and_body() {
+        // HMAC-SHA256 of "POST\n/upload?format=json\n1700000000\n<BODY_SHA256>" with "secret"
+        assert_eq!(
+            request_signature("secret", "POST", "/upload?format=json", 1_700_000_000, BODY_SHA256),
+            "b33c48380a326ab9b501f698b192619d528f83d90bdcbbb5ce32e5cbecf8327d"
+        );
+        assert_ne!(
+            request_signature("secret", "POST", "/upload", 1_700_000_000, BODY_SHA256),
+            request_signature("secret", "POST", "/upload?format=json", 1_700_000_000, BODY_SHA256)
+        );
+    }
+
+    #[test]
+    fn replay_guard_accepts_a_signature_once() {
+        let replay_guard = ReplayGuard::default();
+        assert!(replay_guard.first_use("ab"));
+        assert!(!replay_guard.first_use("ab"));
+        assert!(replay_guard.first_use("cd"));
+    }
+
+    async fn read(chunks: &[&'static str], length: Option<u64>) -> Vec<Result<Bytes, PayloadError>> {
+        let chunks: Vec<Result<Bytes, PayloadError>> = chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk.as_bytes()))).collect();
+        let body = futures_util::stream::iter(chunks);
+        verify_body(body, BODY_SHA256.to_string(), length).collect().await
+    }
+
+    #[actix_web::test]
+    async fn passes_a_matching_body_through() {
+        for length in [None, Some(5)] {
+            let chunks = read(&["he", "llo"], length).await;
+            let body: Vec<u8> = chunks.into_iter().flat_map(|chunk| chunk.unwrap().to_vec()).collect();
+            assert_eq!(body, b"hello");
+        }
+    }
+
+    #[actix_web::test]
+    async fn fails_a_tampered_body() {
+        // Without Content-Length the mismatch shows once the body ends
+        let chunks = read(&["he", "llO"], None).await;
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[..2].iter().all(Result::is_ok));
+        assert!(chunks[2].is_err());
+
+        // With it, the last chunk is failed instead of being handed over
+        let chunks = read(&["he", "llO"], Some(5)).await;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert!(chunks[1].is_err());
+    }
+
+    #[actix_web::test]
+    async fn fails_a_truncated_body() {
+        let chunks = read(&["he"], Some(5)).await;
+        assert!(chunks.last().unwrap().is_err());
+    }
+}
@@ -0,0 +1,1226 @@
+// Everything kept on disk: temp files, thumbnails, the archive, registry, outbox and proxy cache
+
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes};
+use chrono::{DateTime, NaiveDate, Utc};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
+use crate::moderation::ModerationVerdict;
+use crate::config::{OutboxConfig, ProxyCacheConfig, TempCleanupConfig};
+use crate::telegram::{MirroredMessage, PhotoVariant, SendOptions, UploadMode};
+use crate::image::ProxiedFile;
+use crate::upload::UploadData;
+
+// A file received from the client and saved to the temp directory
+pub(crate) struct SavedFile {
+    // Unique ID of the upload, also used in the temporary filename
+    pub(crate) id: Uuid,
+    pub(crate) file_path: String,
+    // Hex-encoded SHA-256 of the uploaded bytes
+    pub(crate) content_hash: String,
+    // Text fields sent along with the file
+    pub(crate) fields: HashMap<String, String>,
+    // Name of the file on the client, sanitized
+    pub(crate) file_name: String,
+    // MIME type going by the file name, or else what the client declared
+    pub(crate) content_type: Option<String>,
+}
+
+// Write a file that is already in memory to the temp directory, as if it had been uploaded
+pub(crate) async fn save_bytes(
+    temp_dir: &Path,
+    bytes: &[u8],
+    file_name: &str,
+    content_type: Option<String>,
+) -> std::io::Result<SavedFile> {
+    let id = Uuid::new_v4();
+    let temp_path = temp_dir.join(format!("{}_{}", id, file_name)).to_string_lossy().into_owned();
+    tokio::fs::write(&temp_path, bytes).await?;
+    info!("File created successfully: {:?}", temp_path);
+
+    Ok(SavedFile {
+        id,
+        file_path: temp_path,
+        content_hash: to_hex(&Sha256::digest(bytes)),
+        fields: HashMap::new(),
+        file_name: file_name.to_string(),
+        content_type,
+    })
+}
+
+// Lowercase hex encoding of a digest
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Bytes that uploads in progress have spooled to the temp dir, limited by an optional quota
+pub(crate) struct TempQuota {
+    pub(crate) limit: Option<u64>,
+    pub(crate) spooled: AtomicU64,
+    // IDs of the temp files uploads in progress still need, kept from the janitor
+    pub(crate) held: Mutex<HashSet<Uuid>>,
+}
+
+impl TempQuota {
+    pub(crate) fn new(limit: Option<u64>) -> TempQuota {
+        TempQuota { limit, spooled: AtomicU64::new(0), held: Mutex::new(HashSet::new()) }
+    }
+
+    pub(crate) fn holds(&self, id: &Uuid) -> bool {
+        self.held.lock().unwrap().contains(id)
+    }
+
+    // Whether this many more bytes would still fit
+    pub(crate) fn has_room(&self, bytes: u64) -> bool {
+        self.limit.is_none_or(|limit| self.spooled.load(Ordering::Relaxed).saturating_add(bytes) <= limit)
+    }
+
+    pub(crate) fn reserve(&self) -> QuotaReservation<'_> {
+        QuotaReservation { quota: self, bytes: 0, ids: Vec::new() }
+    }
+}
+
+// One upload's share of the temp dir quota, given back once the upload is done with its files
+pub(crate) struct QuotaReservation<'a> {
+    pub(crate) quota: &'a TempQuota,
+    pub(crate) bytes: u64,
+    // Temp files named after these IDs are the upload's
+    pub(crate) ids: Vec<Uuid>,
+}
+
+impl QuotaReservation<'_> {
+    // Account for more spooled bytes, failing if that would exceed the quota
+    pub(crate) fn grow(&mut self, bytes: u64) -> bool {
+        let previous = self.quota.spooled.fetch_add(bytes, Ordering::Relaxed);
+        self.bytes += bytes;
+        self.quota.limit.is_none_or(|limit| previous + bytes <= limit)
+    }
+
+    // Give up the reservation without giving its bytes back, for another one to take over
+    pub(crate) fn into_bytes(mut self) -> u64 {
+        std::mem::take(&mut self.bytes)
+    }
+
+    // Take over bytes another reservation of the same quota already accounted for
+    pub(crate) fn take_over(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    // Keep the janitor away from the temp file of this ID until the reservation is dropped
+    pub(crate) fn hold(&mut self, id: Uuid) {
+        self.quota.held.lock().unwrap().insert(id);
+        self.ids.push(id);
+    }
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        self.quota.spooled.fetch_sub(self.bytes, Ordering::Relaxed);
+        if !self.ids.is_empty() {
+            let mut held = self.quota.held.lock().unwrap();
+            for id in &self.ids {
+                held.remove(id);
+            }
+        }
+    }
+}
+
+// A response to replay for a retried upload
+#[derive(Debug, Clone)]
+pub(crate) struct StoredResponse {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Bytes,
+}
+
+pub(crate) enum IdempotencyEntry {
+    // The first request with the key is still being handled
+    InFlight,
+    // Digest is the upload_digest of the request the response was to
+    Completed { stored_at: Instant, digest: String, response: StoredResponse },
+}
+
+// What to do with an upload carrying an Idempotency-Key
+pub(crate) enum Idempotency<'a> {
+    // First time the key is seen: handle the upload, then record it through the guard
+    Started(IdempotencyGuard<'a>),
+    Replay { digest: String, response: StoredResponse },
+    InFlight,
+}
+
+// Uploads by the key they were made with, remembered in memory for a while
+pub(crate) struct IdempotencyStore {
+    pub(crate) window: Duration,
+    pub(crate) entries: Mutex<HashMap<String, IdempotencyEntry>>,
+}
+
+impl IdempotencyStore {
+    pub(crate) fn new(window: Duration) -> IdempotencyStore {
+        IdempotencyStore { window, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub(crate) fn begin(&self, key: String) -> Idempotency<'_> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| match entry {
+            IdempotencyEntry::InFlight => true,
+            IdempotencyEntry::Completed { stored_at, .. } => stored_at.elapsed() < self.window,
+        });
+        match entries.get(&key) {
+            Some(IdempotencyEntry::Completed { digest, response, .. }) => {
+                Idempotency::Replay { digest: digest.clone(), response: response.clone() }
+            }
+            Some(IdempotencyEntry::InFlight) => Idempotency::InFlight,
+            None => {
+                entries.insert(key.clone(), IdempotencyEntry::InFlight);
+                Idempotency::Started(IdempotencyGuard { store: self, key, completed: false })
+            }
+        }
+    }
+}
+
+// Claim on a key while its upload runs; the key is freed again unless the upload completes
+pub(crate) struct IdempotencyGuard<'a> {
+    pub(crate) store: &'a IdempotencyStore,
+    pub(crate) key: String,
+    pub(crate) completed: bool,
+}
+
+impl IdempotencyGuard<'_> {
+    pub(crate) fn complete(mut self, digest: String, response: StoredResponse) {
+        let entry = IdempotencyEntry::Completed { stored_at: Instant::now(), digest, response };
+        self.store.entries.lock().unwrap().insert(self.key.clone(), entry);
+        self.completed = true;
+    }
+}
+
+impl Drop for IdempotencyGuard<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.store.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+// Path of the stored thumbnail for an upload
+pub(crate) fn thumbnail_path(thumbnail_dir: &Path, id: &Uuid) -> PathBuf {
+    thumbnail_dir.join(format!("{}.jpg", id))
+}
+
+// What is known about an archived original, stored next to it as `{id}.json`
+#[derive(Debug, Serialize)]
+pub(crate) struct ArchivedMetadata<'a> {
+    pub(crate) id: Uuid,
+    // Name of the file on the client
+    pub(crate) file_name: &'a str,
+    pub(crate) content_type: Option<&'a str>,
+    pub(crate) content_hash: &'a str,
+    pub(crate) size: u64,
+    pub(crate) archived_at: DateTime<Utc>,
+}
+
+// An original copied to the archive, until its upload turns out to have failed
+pub(crate) struct ArchivedUpload {
+    pub(crate) file_path: PathBuf,
+    pub(crate) metadata_path: PathBuf,
+}
+
+impl ArchivedUpload {
+    pub(crate) fn discard(&self) {
+        for path in [&self.file_path, &self.metadata_path] {
+            if let Err(e) = std::fs::remove_file(path) {
+                error!("Failed to delete archived file {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+// Copy the file as it was uploaded into archive_dir/YYYY/MM/DD, before processing changes it
+pub(crate) async fn archive_upload(archive_dir: &Path, saved: &SavedFile) -> std::io::Result<ArchivedUpload> {
+    let archived_at = Utc::now();
+    let directory = archive_dir.join(archived_at.format("%Y/%m/%d").to_string());
+    tokio::fs::create_dir_all(&directory).await?;
+
+    let file_path = match Path::new(&saved.file_path).extension() {
+        Some(extension) => directory.join(format!("{}.{}", saved.id, extension.to_string_lossy())),
+        None => directory.join(saved.id.to_string()),
+    };
+    let metadata_path = directory.join(format!("{}.json", saved.id));
+    let size = tokio::fs::copy(&saved.file_path, &file_path).await?;
+
+    let archived = ArchivedUpload { file_path, metadata_path };
+    let metadata = ArchivedMetadata {
+        id: saved.id,
+        file_name: &saved.file_name,
+        content_type: saved.content_type.as_deref(),
+        content_hash: &saved.content_hash,
+        size,
+        archived_at,
+    };
+    let written = match serde_json::to_vec_pretty(&metadata) {
+        Ok(metadata) => tokio::fs::write(&archived.metadata_path, metadata).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&archived.file_path);
+        return Err(e);
+    }
+    Ok(archived)
+}
+
+// Remove a temporary file, logging instead of failing
+pub(crate) fn remove_temp_file(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        error!("Failed to delete temporary file: {:?}", e);
+    }
+}
+
+// A finished upload, as remembered by the registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct UploadRecord {
+    pub(crate) id: Uuid,
+    // Bot that uploaded the file, the primary bot if unset
+    #[serde(default)]
+    pub(crate) bot_id: Option<u64>,
+    pub(crate) file_id: String,
+    pub(crate) file_unique_id: String,
+    pub(crate) chat_id: i64,
+    pub(crate) message_id: i32,
+    pub(crate) uploaded_at: DateTime<Utc>,
+    // Hex-encoded SHA-256 of the original upload, used to detect duplicates
+    #[serde(default)]
+    pub(crate) content_hash: Option<String>,
+    // Secret needed to delete the upload
+    #[serde(default = "generate_deletion_token")]
+    pub(crate) deletion_token: String,
+    // Size in bytes of the file as it was sent to Telegram
+    #[serde(default)]
+    pub(crate) size: Option<u64>,
+    #[serde(default)]
+    pub(crate) width: Option<u32>,
+    #[serde(default)]
+    pub(crate) height: Option<u32>,
+    #[serde(default)]
+    pub(crate) photo_sizes: Vec<PhotoVariant>,
+    // Copies posted to other chats in mirror mode
+    #[serde(default)]
+    pub(crate) mirrors: Vec<MirroredMessage>,
+    // Kind of message the upload was sent as
+    #[serde(default)]
+    pub(crate) sent_as: UploadMode,
+    // When the upload is deleted again, for uploads with a TTL
+    #[serde(default)]
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    // Verdict of content moderation, for images that went through it
+    #[serde(default)]
+    pub(crate) moderation: Option<ModerationVerdict>,
+    // Name of the API key the upload was made with
+    #[serde(default)]
+    pub(crate) tenant: Option<String>,
+    // Path under /dav/ the upload was put at, for uploads through WebDAV
+    #[serde(default)]
+    pub(crate) dav_path: Option<String>,
+    // Name of the file as the client sent it
+    #[serde(default)]
+    pub(crate) file_name: Option<String>,
+}
+
+pub(crate) fn generate_deletion_token() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+// Uploads one API key made on one day and their size, counted as they are hosted and kept
+// when the uploads are deleted again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DailyUsage {
+    // Name of the API key, null for uploads made without one
+    pub(crate) key: Option<String>,
+    pub(crate) date: NaiveDate,
+    pub(crate) uploads: u64,
+    pub(crate) bytes: u64,
+}
+
+// Usage by API key and day
+pub(crate) type UsageLedger = BTreeMap<(Option<String>, NaiveDate), (u64, u64)>;
+
+// Changes between compactions of the registry
+pub(crate) const REGISTRY_COMPACT_AFTER: usize = 1000;
+
+// A change to the registry, as a line of its log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RegistryChange {
+    Insert(Box<UploadRecord>),
+    Remove(Uuid),
+}
+
+// Work for the thread that writes the registry to disk, in the order the changes were made
+pub(crate) enum RegistryWrite {
+    // Append a line to the log, and rewrite the usage file when it changed
+    Change { line: Vec<u8>, usage: Option<Vec<DailyUsage>>, done: oneshot::Sender<std::io::Result<()>> },
+    // Save every record to the registry file, which then has all that's in the log
+    Compact(Vec<UploadRecord>),
+}
+
+// The records of a registry by id, and the ids of the records of every content hash
+#[derive(Default)]
+pub(crate) struct RegistryRecords {
+    pub(crate) by_id: HashMap<Uuid, UploadRecord>,
+    pub(crate) by_hash: HashMap<String, Vec<Uuid>>,
+}
+
+impl RegistryRecords {
+    pub(crate) fn insert(&mut self, record: UploadRecord) -> Option<UploadRecord> {
+        let replaced = self.remove(&record.id);
+        if let Some(content_hash) = &record.content_hash {
+            self.by_hash.entry(content_hash.clone()).or_default().push(record.id);
+        }
+        self.by_id.insert(record.id, record);
+        replaced
+    }
+
+    pub(crate) fn remove(&mut self, id: &Uuid) -> Option<UploadRecord> {
+        let removed = self.by_id.remove(id)?;
+        if let Some(content_hash) = &removed.content_hash {
+            if let Some(ids) = self.by_hash.get_mut(content_hash) {
+                ids.retain(|other| other != id);
+                if ids.is_empty() {
+                    self.by_hash.remove(content_hash);
+                }
+            }
+        }
+        Some(removed)
+    }
+}
+
+// Index of finished uploads, kept in memory. The JSON file at path holds the records as of the
+// last compaction and every change since is appended to a log next to it, so a change doesn't
+// rewrite them all. Writing happens on a thread of its own, off the workers serving requests.
+pub(crate) struct Registry {
+    pub(crate) records: Mutex<RegistryRecords>,
+    // Saved next to the registry, as uploads.usage.json for uploads.json
+    pub(crate) usage: Mutex<UsageLedger>,
+    pub(crate) writer: mpsc::Sender<RegistryWrite>,
+    // Changes logged since the last compaction, counted under the lock on records
+    pub(crate) logged: AtomicUsize,
+    pub(crate) compact_after: usize,
+}
+
+impl Registry {
+    // Load the registry from disk, starting empty if the file doesn't exist yet
+    pub(crate) fn open(path: PathBuf) -> std::io::Result<Registry> {
+        let mut records = RegistryRecords::default();
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str::<Vec<UploadRecord>>(&content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+                .into_iter()
+                .for_each(|record| {
+                    records.insert(record);
+                }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        // Replay what changed since the last compaction. A crash can leave the last line cut off.
+        let log_path = Registry::log_path(&path);
+        let mut replayed = 0;
+        match File::open(&log_path) {
+            Ok(log) => {
+                for (number, line) in BufReader::new(log).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<RegistryChange>(&line) {
+                        Ok(RegistryChange::Insert(record)) => {
+                            records.insert(*record);
+                        }
+                        Ok(RegistryChange::Remove(id)) => {
+                            records.remove(&id);
+                        }
+                        Err(e) => {
+                            warn!("Skipped line {} of registry log {:?}: {}", number + 1, log_path, e);
+                            continue;
+                        }
+                    }
+                    replayed += 1;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        info!("Loaded {} uploads from registry {:?}, replaying {} changes", records.by_id.len(), path, replayed);
+
+        // Usage wasn't tracked before: start from the uploads still around
+        let usage = match std::fs::read_to_string(Registry::usage_path(&path)) {
+            Ok(content) => serde_json::from_str::<Vec<DailyUsage>>(&content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+                .into_iter()
+                .map(|usage| ((usage.key, usage.date), (usage.uploads, usage.bytes)))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut usage = UsageLedger::new();
+                records.by_id.values().for_each(|record| count_usage(&mut usage, record));
+                usage
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Start from a compacted registry and an empty log
+        save_registry(&path, records.by_id.values().cloned().collect())?;
+        let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        log.set_len(0)?;
+
+        let (writer, writes) = mpsc::channel();
+        let writer_path = path.clone();
+        std::thread::Builder::new()
+            .name("registry-writer".to_string())
+            .spawn(move || run_registry_writer(writer_path, log, writes))?;
+        Ok(Registry {
+            records: Mutex::new(records),
+            usage: Mutex::new(usage),
+            writer,
+            logged: AtomicUsize::new(0),
+            compact_after: REGISTRY_COMPACT_AFTER,
+        })
+    }
+
+    pub(crate) fn usage_path(path: &Path) -> PathBuf {
+        path.with_extension("usage.json")
+    }
+
+    // uploads.log for uploads.json
+    pub(crate) fn log_path(path: &Path) -> PathBuf {
+        path.with_extension("log")
+    }
+
+    // Usage of every API key on every day, ordered by key and day
+    pub(crate) fn usage(&self) -> Vec<DailyUsage> {
+        usage_entries(&self.usage.lock().unwrap())
+    }
+
+    pub(crate) fn get(&self, id: &Uuid) -> Option<UploadRecord> {
+        self.records.lock().unwrap().by_id.get(id).cloned()
+    }
+
+    // Every upload, the newest first
+    pub(crate) fn records(&self) -> Vec<UploadRecord> {
+        let mut records: Vec<UploadRecord> = self.records.lock().unwrap().by_id.values().cloned().collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.uploaded_at));
+        records
+    }
+
+    // An upload of the same bytes made with the same API key (or without one), of those that
+    // pass the filter
+    pub(crate) fn find_by_hash(
+        &self,
+        content_hash: &str,
+        tenant: Option<&str>,
+        filter: impl Fn(&UploadRecord) -> bool,
+    ) -> Option<UploadRecord> {
+        let records = self.records.lock().unwrap();
+        records
+            .by_hash
+            .get(content_hash)?
+            .iter()
+            .filter_map(|id| records.by_id.get(id))
+            .find(|record| record.tenant.as_deref() == tenant && filter(record))
+            .cloned()
+    }
+
+    // The upload put at a WebDAV path by a tenant, the latest one if there are several
+    pub(crate) fn find_by_dav_path(&self, dav_path: &str, tenant: Option<&str>) -> Option<UploadRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|record| record.dav_path.as_deref() == Some(dav_path) && record.tenant.as_deref() == tenant)
+            .max_by_key(|record| record.uploaded_at)
+            .cloned()
+    }
+
+    pub(crate) async fn remove(&self, id: &Uuid) -> std::io::Result<Option<UploadRecord>> {
+        let (removed, written) = {
+            let mut records = self.records.lock().unwrap();
+            let Some(removed) = records.remove(id) else {
+                return Ok(None);
+            };
+            (removed, self.log(&records, &RegistryChange::Remove(*id), None)?)
+        };
+        written.await.unwrap_or_else(|_| Err(std::io::Error::other("The registry writer stopped")))?;
+        Ok(Some(removed))
+    }
+
+    pub(crate) async fn insert(&self, record: UploadRecord) -> std::io::Result<()> {
+        let written = {
+            let mut records = self.records.lock().unwrap();
+            let usage = (!records.by_id.contains_key(&record.id)).then(|| {
+                let mut usage = self.usage.lock().unwrap();
+                count_usage(&mut usage, &record);
+                usage_entries(&usage)
+            });
+            records.insert(record.clone());
+            self.log(&records, &RegistryChange::Insert(Box::new(record)), usage)?
+        };
+        written.await.unwrap_or_else(|_| Err(std::io::Error::other("The registry writer stopped")))
+    }
+
+    // Insert an upload put at a path in place of the ones there before, which are returned.
+    // Finding and swapping them under one lock leaves a single upload at the path however many
+    // puts to it finish at once.
+    pub(crate) async fn replace(&self, record: UploadRecord) -> std::io::Result<Vec<UploadRecord>> {
+        let (replaced, written) = {
+            let mut records = self.records.lock().unwrap();
+            let at_path = |other: &UploadRecord| {
+                other.id != record.id && other.dav_path == record.dav_path && other.tenant == record.tenant
+            };
+            let ids: Vec<Uuid> = records.by_id.values().filter(|other| at_path(other)).map(|other| other.id).collect();
+            let (mut replaced, mut written) = (Vec::new(), Vec::new());
+            for id in ids {
+                if let Some(removed) = records.remove(&id) {
+                    replaced.push(removed);
+                    written.push(self.log(&records, &RegistryChange::Remove(id), None)?);
+                }
+            }
+            let usage = (!records.by_id.contains_key(&record.id)).then(|| {
+                let mut usage = self.usage.lock().unwrap();
+                count_usage(&mut usage, &record);
+                usage_entries(&usage)
+            });
+            records.insert(record.clone());
+            written.push(self.log(&records, &RegistryChange::Insert(Box::new(record)), usage)?);
+            (replaced, written)
+        };
+        for written in written {
+            written.await.unwrap_or_else(|_| Err(std::io::Error::other("The registry writer stopped")))?;
+        }
+        Ok(replaced)
+    }
+
+    // Hand a change already made to the records to the writer. Called with the records locked,
+    // so changes reach the log in the order they were made, and a compaction gets the records
+    // as they are after the changes logged before it.
+    pub(crate) fn log(
+        &self,
+        records: &RegistryRecords,
+        change: &RegistryChange,
+        usage: Option<Vec<DailyUsage>>,
+    ) -> std::io::Result<oneshot::Receiver<std::io::Result<()>>> {
+        let mut line = serde_json::to_vec(change)?;
+        line.push(b'\n');
+        let (done, written) = oneshot::channel();
+        let stopped = |_| std::io::Error::other("The registry writer stopped");
+        self.writer.send(RegistryWrite::Change { line, usage, done }).map_err(stopped)?;
+        if self.logged.fetch_add(1, Ordering::Relaxed) + 1 >= self.compact_after {
+            self.logged.store(0, Ordering::Relaxed);
+            self.writer.send(RegistryWrite::Compact(records.by_id.values().cloned().collect())).map_err(stopped)?;
+        }
+        Ok(written)
+    }
+}
+
+// Write all records to a temporary file first, so a crash never leaves a truncated registry
+pub(crate) fn save_registry(path: &Path, mut records: Vec<UploadRecord>) -> std::io::Result<()> {
+    records.sort_by_key(|record| record.uploaded_at);
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(&records)?)?;
+    std::fs::rename(&temp_path, path)
+}
+
+// Write the registry's changes as they come, until the registry is dropped. Should the
+// process stop between compacting and emptying the log, the log is replayed onto records that
+// already have all of it, which leaves them as they are.
+pub(crate) fn run_registry_writer(path: PathBuf, mut log: File, writes: mpsc::Receiver<RegistryWrite>) {
+    let usage_path = Registry::usage_path(&path);
+    for write in writes {
+        match write {
+            RegistryWrite::Change { line, usage, done } => {
+                let written = log.write_all(&line).and_then(|()| log.flush()).and_then(|()| match usage {
+                    Some(usage) => {
+                        let temp_path = usage_path.with_extension("tmp");
+                        std::fs::write(&temp_path, serde_json::to_vec_pretty(&usage)?)?;
+                        std::fs::rename(&temp_path, &usage_path)
+                    }
+                    None => Ok(()),
+                });
+                let _ = done.send(written);
+            }
+            RegistryWrite::Compact(records) => {
+                let count = records.len();
+                match save_registry(&path, records).and_then(|()| log.set_len(0)) {
+                    Ok(()) => debug!("Compacted registry {:?} to {} uploads", path, count),
+                    Err(e) => error!("Failed to compact registry {:?}: {:?}", path, e),
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn usage_entries(usage: &UsageLedger) -> Vec<DailyUsage> {
+    usage
+        .iter()
+        .map(|((key, date), (uploads, bytes))| DailyUsage { key: key.clone(), date: *date, uploads: *uploads, bytes: *bytes })
+        .collect()
+}
+
+pub(crate) fn count_usage(usage: &mut UsageLedger, record: &UploadRecord) {
+    let (uploads, bytes) = usage.entry((record.tenant.clone(), record.uploaded_at.date_naive())).or_default();
+    *uploads += 1;
+    *bytes += record.size.unwrap_or(0);
+}
+
+// An upload waiting in the outbox until Telegram takes it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct OutboxEntry {
+    pub(crate) id: Uuid,
+    // Name of the image file next to the entry
+    pub(crate) file_name: String,
+    pub(crate) content_hash: String,
+    #[serde(default)]
+    pub(crate) options: SendOptions,
+    // Chat picked by the client instead of the configured ones
+    #[serde(default)]
+    pub(crate) chat_id: Option<i64>,
+    // Handed to the client when queuing, so the upload can be deleted once delivered
+    pub(crate) deletion_token: String,
+    pub(crate) queued_at: DateTime<Utc>,
+    pub(crate) attempts: u32,
+    pub(crate) last_error: Option<String>,
+    // Telegram rejected the file for good; kept so pollers can find out
+    pub(crate) failed: bool,
+    #[serde(default)]
+    pub(crate) moderation: Option<ModerationVerdict>,
+    #[serde(default)]
+    pub(crate) tenant: Option<String>,
+    #[serde(default)]
+    pub(crate) dav_path: Option<String>,
+}
+
+// Disk-backed queue of uploads that could not be sent to Telegram yet.
+// Every entry is an image file plus a `{id}.json` file describing it.
+pub(crate) struct Outbox {
+    pub(crate) directory: PathBuf,
+    pub(crate) retry_interval: Duration,
+    pub(crate) max_attempts: u32,
+    pub(crate) entries: Mutex<HashMap<Uuid, OutboxEntry>>,
+}
+
+impl Outbox {
+    // Pick up entries left over from a previous run
+    pub(crate) fn open(config: &OutboxConfig) -> std::io::Result<Outbox> {
+        std::fs::create_dir_all(&config.directory)?;
+        let mut entries = HashMap::new();
+        for dir_entry in std::fs::read_dir(&config.directory)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            match std::fs::read_to_string(&path).map(|content| serde_json::from_str::<OutboxEntry>(&content)) {
+                Ok(Ok(entry)) => {
+                    entries.insert(entry.id, entry);
+                }
+                Ok(Err(e)) => error!("Failed to parse outbox entry {:?}: {:?}", path, e),
+                Err(e) => error!("Failed to read outbox entry {:?}: {:?}", path, e),
+            }
+        }
+
+        info!("Loaded {} uploads from outbox {:?}", entries.len(), config.directory);
+        Ok(Outbox {
+            directory: config.directory.clone(),
+            retry_interval: Duration::from_secs(config.retry_interval_secs),
+            max_attempts: config.max_attempts,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub(crate) fn get(&self, id: &Uuid) -> Option<OutboxEntry> {
+        self.entries.lock().unwrap().get(id).cloned()
+    }
+
+    pub(crate) fn entries(&self) -> Vec<OutboxEntry> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+
+    // Entries still waiting for delivery, oldest first
+    pub(crate) fn pending(&self) -> Vec<OutboxEntry> {
+        let mut pending: Vec<OutboxEntry> =
+            self.entries.lock().unwrap().values().filter(|entry| !entry.failed).cloned().collect();
+        pending.sort_by_key(|entry| entry.queued_at);
+        pending
+    }
+
+    // Whether delivering the entry was tried as often as it may be
+    pub(crate) fn out_of_attempts(&self, entry: &OutboxEntry) -> bool {
+        self.max_attempts > 0 && entry.attempts >= self.max_attempts
+    }
+
+    pub(crate) fn image_path(&self, entry: &OutboxEntry) -> PathBuf {
+        self.directory.join(&entry.file_name)
+    }
+
+    pub(crate) fn entry_path(&self, id: &Uuid) -> PathBuf {
+        self.directory.join(format!("{}.json", id))
+    }
+
+    // Move the file into the outbox and save its entry
+    pub(crate) fn queue(&self, mut entry: OutboxEntry, file_path: &Path) -> std::io::Result<OutboxEntry> {
+        entry.file_name = match file_path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => format!("{}.{}", entry.id, extension),
+            None => entry.id.to_string(),
+        };
+        let image_path = self.image_path(&entry);
+        // The temp dir may be on another file system, where renaming doesn't work
+        if std::fs::rename(file_path, &image_path).is_err() {
+            std::fs::copy(file_path, &image_path)?;
+            std::fs::remove_file(file_path)?;
+        }
+
+        self.update(entry.clone())?;
+        Ok(entry)
+    }
+
+    pub(crate) fn update(&self, entry: OutboxEntry) -> std::io::Result<()> {
+        let entry_path = self.entry_path(&entry.id);
+        let temp_path = entry_path.with_extension("tmp");
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(&entry)?)?;
+        std::fs::rename(&temp_path, &entry_path)?;
+        self.entries.lock().unwrap().insert(entry.id, entry);
+        Ok(())
+    }
+
+    // Forget a delivered entry and delete its files
+    pub(crate) fn remove(&self, entry: &OutboxEntry) {
+        self.entries.lock().unwrap().remove(&entry.id);
+        for path in [self.image_path(entry), self.entry_path(&entry.id)] {
+            if let Err(e) = std::fs::remove_file(&path) {
+                error!("Failed to delete outbox file {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+// Files the proxy downloaded from Telegram, stored as `{id}.{extension}` and evicted
+// least recently used first once they take up more than the configured size
+pub(crate) struct ProxyCache {
+    pub(crate) directory: PathBuf,
+    pub(crate) max_bytes: u64,
+    pub(crate) state: Mutex<ProxyCacheState>,
+}
+
+pub(crate) struct ProxyCacheState {
+    // Size of every cached file by the upload it belongs to
+    pub(crate) files: LruCache<Uuid, (PathBuf, u64)>,
+    pub(crate) size: u64,
+}
+
+impl ProxyCache {
+    // Pick up files cached by a previous run, the most recently used last
+    pub(crate) fn open(config: &ProxyCacheConfig) -> std::io::Result<ProxyCache> {
+        std::fs::create_dir_all(&config.directory)?;
+        let mut cached = Vec::new();
+        for dir_entry in std::fs::read_dir(&config.directory)? {
+            let dir_entry = dir_entry?;
+            let path = dir_entry.path();
+            let id = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| Uuid::parse_str(stem).ok());
+            let metadata = dir_entry.metadata()?;
+            match id {
+                Some(id) if metadata.is_file() && path.extension().is_some_and(|extension| extension != "tmp") => {
+                    cached.push((metadata.modified()?, id, path, metadata.len()));
+                }
+                // Leftovers of writes that were interrupted
+                _ if metadata.is_file() => remove_temp_file(&path),
+                _ => {}
+            }
+        }
+        cached.sort_by_key(|(modified, ..)| *modified);
+
+        let cache = ProxyCache {
+            directory: config.directory.clone(),
+            max_bytes: config.max_bytes,
+            state: Mutex::new(ProxyCacheState { files: LruCache::unbounded(), size: 0 }),
+        };
+        let mut evicted = Vec::new();
+        for (_, id, path, size) in cached {
+            evicted.extend(cache.insert(id, path, size));
+        }
+        cache.delete(evicted);
+        info!("Loaded {} bytes of cached files from {:?}", cache.size(), config.directory);
+        Ok(cache)
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    pub(crate) async fn get(&self, id: &Uuid) -> Option<ProxiedFile> {
+        let path = self.state.lock().unwrap().files.get(id).map(|(path, _)| path.clone())?;
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => {
+                // Keep the order of use across restarts
+                if let Err(e) = File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now())) {
+                    debug!("Failed to touch cached file {:?}: {:?}", path, e);
+                }
+                let content_type = mime_guess::from_path(&path).first_or_octet_stream().to_string();
+                Some(ProxiedFile { content_type, bytes: bytes.into() })
+            }
+            Err(e) => {
+                error!("Failed to read cached file {:?}: {:?}", path, e);
+                self.remove(id);
+                None
+            }
+        }
+    }
+
+    pub(crate) async fn put(&self, id: Uuid, file: &ProxiedFile) {
+        let size = file.bytes.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+
+        // Pick an extension that maps back to the same content type when reading the file
+        let extension = mime_guess::get_mime_extensions_str(&file.content_type)
+            .and_then(|extensions| {
+                extensions.iter().find(|extension| {
+                    mime_guess::from_ext(extension).first_raw() == Some(file.content_type.as_str())
+                })
+            })
+            .copied()
+            .unwrap_or("bin");
+        let path = self.directory.join(format!("{}.{}", id, extension));
+        let temp_path = path.with_extension("tmp");
+        let written = async {
+            tokio::fs::write(&temp_path, &file.bytes).await?;
+            tokio::fs::rename(&temp_path, &path).await
+        };
+        if let Err(e) = written.await {
+            error!("Failed to cache file {:?}: {:?}", path, e);
+            remove_temp_file(&temp_path);
+            return;
+        }
+
+        let evicted = self.insert(id, path, size);
+        self.delete(evicted);
+    }
+
+    // Drop a file, e.g. because its upload was deleted
+    pub(crate) fn remove(&self, id: &Uuid) {
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let removed = state.files.pop(id);
+            if let Some((_, size)) = &removed {
+                state.size -= size;
+            }
+            removed
+        };
+        self.delete(removed.map(|(path, _)| path));
+    }
+
+    // Record a file, returning the ones that had to make room for it
+    pub(crate) fn insert(&self, id: Uuid, path: PathBuf, size: u64) -> Vec<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        let mut evicted = Vec::new();
+        if let Some((old_path, old_size)) = state.files.put(id, (path.clone(), size)) {
+            state.size -= old_size;
+            if old_path != path {
+                evicted.push(old_path);
+            }
+        }
+        state.size += size;
+        while state.size > self.max_bytes {
+            let Some((_, (path, size))) = state.files.pop_lru() else {
+                break;
+            };
+            state.size -= size;
+            evicted.push(path);
+        }
+        evicted
+    }
+
+    pub(crate) fn delete(&self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            if let Err(e) = std::fs::remove_file(&path) {
+                error!("Failed to delete cached file {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+// Total size of the files directly inside a directory
+pub(crate) fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+// Delete files in a directory that haven't been modified for max_age, returning how many and their size
+pub(crate) fn remove_stale_files(path: &Path, max_age: Duration, quota: &TempQuota) -> std::io::Result<(usize, u64)> {
+    let (mut removed, mut reclaimed) = (0, 0);
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        // Only files this server wrote, named {uuid}_{file name}, and none an upload still needs.
+        // The temp dir may be shared with other programs.
+        let Some(id) = temp_file_id(&entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        if quota.holds(&id) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let age = metadata.modified()?.elapsed().unwrap_or_default();
+        if !metadata.is_file() || age < max_age {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => {
+                removed += 1;
+                reclaimed += metadata.len();
+            }
+            Err(e) => error!("Failed to delete stale temp file {:?}: {:?}", entry.path(), e),
+        }
+    }
+    Ok((removed, reclaimed))
+}
+
+// ID of the upload a temp file was written for
+pub(crate) fn temp_file_id(file_name: &str) -> Option<Uuid> {
+    let (id, _) = file_name.split_once('_')?;
+    Uuid::try_parse(id).ok()
+}
+
+// Periodically clear out temp files that no upload is going to pick up anymore
+pub(crate) async fn run_temp_janitor(data: web::Data<UploadData>, config: TempCleanupConfig) {
+    let max_age = Duration::from_secs(config.max_age_secs);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let data = data.clone();
+        match tokio::task::spawn_blocking(move || remove_stale_files(&data.temp_dir, max_age, &data.temp_quota)).await {
+            Ok(Ok((0, _))) => debug!("No stale temp files to delete"),
+            Ok(Ok((removed, reclaimed))) => {
+                info!("Deleted {} stale temp files, reclaimed {} bytes", removed, reclaimed)
+            }
+            Ok(Err(e)) => error!("Failed to clean up temp directory: {:?}", e),
+            Err(e) => error!("Failed to clean up temp directory: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_file(dir: &Path, name: &str, modified: SystemTime) {
+        let file = File::create(dir.join(name)).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn janitor_only_removes_stale_files_of_its_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = TempQuota::new(None);
+        let long_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        let (stale, held, fresh) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        write_file(dir.path(), &format!("{}_cat.png", stale), long_ago);
+        write_file(dir.path(), &format!("{}_dog.png", held), long_ago);
+        write_file(dir.path(), &format!("{}_bird.png", fresh), SystemTime::now());
+        write_file(dir.path(), "someone-elses.tmp", long_ago);
+        write_file(dir.path(), "not-a-uuid_cat.png", long_ago);
+
+        let mut reservation = quota.reserve();
+        reservation.hold(held);
+        let (removed, _) = remove_stale_files(dir.path(), Duration::from_secs(60 * 60), &quota).unwrap();
+        assert_eq!(removed, 1);
+        assert!(!dir.path().join(format!("{}_cat.png", stale)).exists());
+        assert!(dir.path().join(format!("{}_dog.png", held)).exists());
+        assert!(dir.path().join(format!("{}_bird.png", fresh)).exists());
+        assert!(dir.path().join("someone-elses.tmp").exists());
+        assert!(dir.path().join("not-a-uuid_cat.png").exists());
+
+        // Once the upload is done with it, the file is fair game
+        drop(reservation);
+        assert!(!quota.holds(&held));
+        let (removed, _) = remove_stale_files(dir.path(), Duration::from_secs(60 * 60), &quota).unwrap();
+        assert_eq!(removed, 1);
+        assert!(!dir.path().join(format!("{}_dog.png", held)).exists());
+    }
+
+    #[test]
+    fn reservations_give_back_their_bytes() {
+        let quota = TempQuota::new(Some(100));
+        let mut reservation = quota.reserve();
+        assert!(reservation.grow(60));
+        assert!(!quota.has_room(50));
+        assert!(!reservation.grow(50));
+        drop(reservation);
+        assert!(quota.has_room(100));
+    }
+
+    fn record(content_hash: &str) -> UploadRecord {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "file_id": "file",
+            "file_unique_id": "unique",
+            "chat_id": 1,
+            "message_id": 1,
+            "uploaded_at": Utc::now(),
+            "content_hash": content_hash,
+            "size": 10,
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn registry_replays_its_log_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uploads.json");
+        let (kept, removed) = (record("a"), record("b"));
+        {
+            let registry = Registry::open(path.clone()).unwrap();
+            registry.insert(kept.clone()).await.unwrap();
+            registry.insert(removed.clone()).await.unwrap();
+            assert!(registry.remove(&removed.id).await.unwrap().is_some());
+            assert!(registry.remove(&removed.id).await.unwrap().is_none());
+        }
+        assert_eq!(std::fs::read_to_string(Registry::log_path(&path)).unwrap().lines().count(), 3);
+
+        let registry = Registry::open(path.clone()).unwrap();
+        assert!(registry.get(&kept.id).is_some());
+        assert!(registry.get(&removed.id).is_none());
+        assert_eq!(registry.usage()[0].uploads, 2);
+        // Opening compacted the log into the registry file
+        assert!(std::fs::read_to_string(Registry::log_path(&path)).unwrap().is_empty());
+        let saved: Vec<UploadRecord> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn registry_compacts_its_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uploads.json");
+        let mut registry = Registry::open(path.clone()).unwrap();
+        registry.compact_after = 2;
+        for hash in ["a", "b"] {
+            registry.insert(record(hash)).await.unwrap();
+        }
+        // Acknowledged after the compaction queued behind the second insert
+        registry.insert(record("c")).await.unwrap();
+        assert_eq!(std::fs::read_to_string(Registry::log_path(&path)).unwrap().lines().count(), 1);
+        let saved: Vec<UploadRecord> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.len(), 2);
+        drop(registry);
+        assert_eq!(Registry::open(path).unwrap().records().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn registry_finds_uploads_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Registry::open(dir.path().join("uploads.json")).unwrap();
+        let (first, second) = (record("a"), UploadRecord { tenant: Some("team".to_string()), ..record("a") });
+        registry.insert(first.clone()).await.unwrap();
+        registry.insert(second.clone()).await.unwrap();
+        assert_eq!(registry.find_by_hash("a", None, |_| true).unwrap().id, first.id);
+        assert_eq!(registry.find_by_hash("a", Some("team"), |_| true).unwrap().id, second.id);
+        assert!(registry.find_by_hash("b", None, |_| true).is_none());
+
+        registry.remove(&first.id).await.unwrap();
+        assert!(registry.find_by_hash("a", None, |_| true).is_none());
+        // Replacing a record moves it to its new hash
+        registry.insert(UploadRecord { content_hash: Some("b".to_string()), ..second.clone() }).await.unwrap();
+        assert!(registry.find_by_hash("a", Some("team"), |_| true).is_none());
+        assert_eq!(registry.find_by_hash("b", Some("team"), |_| true).unwrap().id, second.id);
+        assert!(!registry.records.lock().unwrap().by_hash.contains_key("a"));
+    }
+
+    #[actix_web::test]
+    async fn registry_replaces_uploads_at_a_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uploads.json");
+        let registry = Registry::open(path.clone()).unwrap();
+        let at = |dav_path: &str, tenant: Option<&str>| UploadRecord {
+            dav_path: Some(dav_path.to_string()),
+            tenant: tenant.map(str::to_string),
+            ..record("a")
+        };
+        let (first, other_path, other_tenant) = (at("red.png", None), at("blue.png", None), at("red.png", Some("team")));
+        for record in [&first, &other_path, &other_tenant] {
+            assert!(registry.replace(record.clone()).await.unwrap().is_empty());
+        }
+        let second = at("red.png", None);
+        let replaced = registry.replace(second.clone()).await.unwrap();
+        assert_eq!(replaced.iter().map(|record| record.id).collect::<Vec<_>>(), [first.id]);
+        assert!(registry.get(&first.id).is_none());
+        assert_eq!(registry.find_by_dav_path("red.png", None).unwrap().id, second.id);
+        assert!(registry.get(&other_path.id).is_some());
+        assert!(registry.get(&other_tenant.id).is_some());
+
+        drop(registry);
+        let registry = Registry::open(path).unwrap();
+        assert_eq!(registry.records().len(), 3);
+        assert!(registry.get(&first.id).is_none());
+    }
+
+    #[test]
+    fn outbox_entries_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = OutboxConfig { directory: dir.path().join("outbox"), retry_interval_secs: 60, max_attempts: 3 };
+        let entry = |content_hash: &str| -> OutboxEntry {
+            serde_json::from_value(serde_json::json!({
+                "id": Uuid::new_v4(),
+                "file_name": "",
+                "content_hash": content_hash,
+                "deletion_token": "token",
+                "queued_at": Utc::now(),
+                "attempts": 0,
+                "failed": false,
+            }))
+            .unwrap()
+        };
+        let (first, second) = {
+            let outbox = Outbox::open(&config).unwrap();
+            let file_path = dir.path().join("red.png");
+            std::fs::write(&file_path, b"png").unwrap();
+            let mut first = outbox.queue(entry("a"), &file_path).unwrap();
+            assert!(!file_path.exists());
+            std::fs::write(&file_path, b"png").unwrap();
+            let second = outbox.queue(entry("b"), &file_path).unwrap();
+
+            first.attempts = 3;
+            first.last_error = Some("Bad Gateway".to_string());
+            assert!(outbox.out_of_attempts(&first));
+            first.failed = true;
+            outbox.update(first.clone()).unwrap();
+            (first, second)
+        };
+
+        let outbox = Outbox::open(&config).unwrap();
+        let reloaded = outbox.get(&first.id).unwrap();
+        assert_eq!((reloaded.attempts, reloaded.failed), (3, true));
+        assert_eq!(reloaded.last_error.as_deref(), Some("Bad Gateway"));
+        // Given up entries are kept for pollers, but no longer delivered
+        assert_eq!(outbox.pending().iter().map(|entry| entry.id).collect::<Vec<_>>(), [second.id]);
+        assert_eq!(std::fs::read(outbox.image_path(&second)).unwrap(), b"png");
+        assert!(!outbox.out_of_attempts(&second));
+
+        outbox.remove(&second);
+        assert!(!outbox.image_path(&second).exists());
+        assert!(Outbox::open(&config).unwrap().get(&second.id).is_none());
+    }
+}
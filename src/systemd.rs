@@ -0,0 +1,116 @@
+// systemd integration: listening sockets handed over through socket activation, and the
+// sd_notify protocol for readiness, shutdown and watchdog pings
+
+use std::time::Duration;
+use tracing::{info, warn};
+
+// The first file descriptor systemd passes sockets in
+pub(crate) const LISTEN_FDS_START: i32 = 3;
+
+// A socket systemd listens on for us
+pub(crate) enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+// Whether an environment variable meant for a single process, like LISTEN_PID, names this one.
+// A variable that is missing counts as a match.
+pub(crate) fn for_this_process(variable: &str) -> bool {
+    std::env::var(variable).map_or(true, |pid| pid.parse() == Ok(std::process::id()))
+}
+
+// The sockets passed through socket activation, if the server was started that way. Only to
+// be called once, since the listeners take ownership of the file descriptors.
+pub(crate) fn inherited_listeners() -> Vec<Listener> {
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<i32>().ok()).unwrap_or(0);
+    if count <= 0 || std::env::var_os("LISTEN_PID").is_none() || !for_this_process("LISTEN_PID") {
+        return Vec::new();
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                // SAFETY: systemd hands these file descriptors to this process, and nothing
+                // else takes ownership of them
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                // Unix sockets have no address a TCP listener can make sense of
+                if let Ok(address) = listener.local_addr() {
+                    info!("Listening on socket {} from systemd: {}", fd, address);
+                    return Listener::Tcp(listener);
+                }
+                // SAFETY: as above, the file descriptor only moves from one owner to the other
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(listener.into_raw_fd()) };
+                let address = listener.local_addr().ok().and_then(|address| address.as_pathname().map(|path| path.to_path_buf()));
+                info!("Listening on socket {} from systemd: {:?}", fd, address);
+                Listener::Unix(listener)
+            })
+            .collect()
+    }
+    #[cfg(not(unix))]
+    Vec::new()
+}
+
+// Tell systemd about the state of the server, e.g. READY=1. Does nothing unless it runs as a
+// Type=notify service.
+pub(crate) fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+        let send = || -> std::io::Result<usize> {
+            let datagram = UnixDatagram::unbound()?;
+            // Sockets starting with @ are in the abstract namespace
+            #[cfg(target_os = "linux")]
+            if let Some(name) = socket.to_str().and_then(|socket| socket.strip_prefix('@')) {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                return datagram.send_to_addr(state.as_bytes(), &address);
+            }
+            datagram.send_to(state.as_bytes(), &socket)
+        };
+        if let Err(e) = send() {
+            warn!("Failed to notify systemd of {:?}: {:?}", state, e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (socket, state);
+}
+
+// How often systemd wants to hear from the server, if WatchdogSec= is set for it
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    for_this_process("WATCHDOG_PID").then(|| Duration::from_micros(usec))
+}
+
+// Ping the watchdog at half the interval it was given, for as long as the runtime keeps going
+pub(crate) async fn run_watchdog(interval: Duration) {
+    let mut ticks = tokio::time::interval(interval / 2);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+// Report the shutdown once a signal starts it; actix-web handles the signal itself
+pub(crate) async fn notify_stopping() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            return;
+        };
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    notify("STOPPING=1");
+}
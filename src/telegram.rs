@@ -0,0 +1,1163 @@
+// Talking to the Bot API: the bot pool, retries, the circuit breaker and sending uploads
+
+use image::ImageReader;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use actix_web::http::header::ByteRangeSpec;
+use actix_web::web::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{BoxStream, Stream, StreamExt as _};
+use reqwest::{header, StatusCode};
+use teloxide::prelude::*;
+use teloxide::net::Download;
+use teloxide::types::{InputFile, ChatId, MessageEntity, MessageId, ParseMode, Recipient, ThreadId};
+use teloxide::{ApiError, RequestError};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use tracing::{debug, error, info, warn};
+use crate::config::{ChatMode, ChatRef, CircuitBreakerConfig, RetryConfig};
+use crate::error::Error;
+use crate::image::ProxiedFile;
+use crate::metrics::Metrics;
+use crate::http::UploadQuery;
+use crate::upload::Settings;
+
+// What an upload is posted from: a file on disk or a file Telegram already stores
+#[derive(Debug, Clone, Copy)]
+pub enum Media<'a> {
+    File(&'a Path),
+    FileId(&'a str),
+}
+
+// The file of a freshly posted message, as far as hosting it is concerned
+#[derive(Debug, Clone)]
+pub struct PostedMedia {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+    pub file_id: String,
+    pub file_unique_id: String,
+    // Every size Telegram generated for a photo, smallest first; empty for other kinds
+    pub photo_sizes: Vec<PhotoVariant>,
+}
+
+// The Bot API calls uploads and the file proxy depend on. The real implementation sends them
+// through teloxide; FakeTelegram keeps everything in memory so the pipeline runs without a bot.
+pub trait TelegramUploader: Send + Sync {
+    // Post a file as the kind of message options.mode asks for; None if the message Telegram
+    // answered with carries no file of that kind
+    fn send_media<'a>(
+        &'a self,
+        chat_id: ChatId,
+        media: Media<'a>,
+        options: &'a SendOptions,
+    ) -> BoxFuture<'a, Result<Option<PostedMedia>, RequestError>>;
+
+    // Path of a stored file, to download it or build its URL from
+    fn get_file<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<String, RequestError>>;
+
+    fn download_file<'a>(&'a self, file_path: &'a str) -> BoxFuture<'a, Result<Vec<u8>, RequestError>>;
+
+    // The part of a stored file a Range header asks for. Unless overridden the whole file is
+    // downloaded and cut down.
+    fn download_range<'a>(&'a self, file_path: &'a str, spec: &'a ByteRangeSpec) -> BoxFuture<'a, Result<FileRange, RequestError>> {
+        Box::pin(async move { Ok(FileRange::of(&self.download_file(file_path).await?, spec)) })
+    }
+
+    fn pin_message(&self, chat_id: ChatId, message_id: MessageId, disable_notification: bool) -> BoxFuture<'_, Result<(), RequestError>>;
+
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> BoxFuture<'_, Result<(), RequestError>>;
+
+    // Username of the bot, which also checks that its token is valid
+    fn get_me(&self) -> BoxFuture<'_, Result<String, RequestError>>;
+
+    // Numeric ID of a public chat given as @username
+    fn resolve_username<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<ChatId, RequestError>>;
+
+    // Public download URL of a file path handed out by get_file
+    fn file_url(&self, file_path: &str) -> String;
+
+    // The teloxide bot, for answering messages sent to it; fakes have none
+    fn bot(&self) -> Option<&Bot> {
+        None
+    }
+}
+
+// Bytes of a file as they are read, rather than held in memory all at once
+pub type FileBody = BoxStream<'static, std::io::Result<Bytes>>;
+
+// Part of a stored file, as asked for with a Range header
+pub enum FileRange {
+    // Bytes start to end of a file of the given length, both included
+    Partial { body: FileBody, start: u64, end: u64, length: u64 },
+    // The range starts past the end of a file of the given length
+    Unsatisfiable { length: u64 },
+}
+
+impl FileRange {
+    pub fn of(bytes: &[u8], spec: &ByteRangeSpec) -> FileRange {
+        let length = bytes.len() as u64;
+        match spec.to_satisfiable_range(length) {
+            Some((start, end)) => {
+                let part = Bytes::copy_from_slice(&bytes[start as usize..=end as usize]);
+                FileRange::Partial { body: futures_util::stream::once(async move { Ok(part) }).boxed(), start, end, length }
+            }
+            None => FileRange::Unsatisfiable { length },
+        }
+    }
+}
+
+impl std::fmt::Debug for FileRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileRange::Partial { start, end, length, .. } => {
+                f.debug_struct("Partial").field("start", start).field("end", end).field("length", length).finish()
+            }
+            FileRange::Unsatisfiable { length } => f.debug_struct("Unsatisfiable").field("length", length).finish(),
+        }
+    }
+}
+
+// Cut `length` bytes, from `skip` bytes in, out of a body, failing if it ends before that
+pub(crate) fn slice_body(body: impl Stream<Item = std::io::Result<Bytes>> + Send + 'static, skip: u64, length: u64) -> FileBody {
+    futures_util::stream::unfold((Box::pin(body), skip, length), |(mut body, mut skip, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        loop {
+            let chunk = match body.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => return Some((Err(e), (body, skip, 0))),
+                None => {
+                    let e = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "The file ended before the range did");
+                    return Some((Err(e), (body, skip, 0)));
+                }
+            };
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            let taken = remaining.min(chunk.len() as u64 - skipped);
+            if taken > 0 {
+                let part = chunk.slice(skipped as usize..(skipped + taken) as usize);
+                return Some((Ok(part), (body, skip, remaining - taken)));
+            }
+        }
+    })
+    .boxed()
+}
+
+// Chunks of a file read from where it is positioned
+pub(crate) fn file_body(file: tokio::fs::File) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static {
+    futures_util::stream::unfold(file, |mut file| async move {
+        let mut chunk = vec![0; 64 * 1024];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(Bytes::from(chunk)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    })
+}
+
+// The range and file length of a Content-Range header, no range for "bytes */length"
+pub(crate) fn parse_content_range(value: &str) -> Option<(Option<(u64, u64)>, u64)> {
+    let (range, length) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let length = length.trim().parse().ok()?;
+    if range.trim() == "*" {
+        return Some((None, length));
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start <= end && end < length).then_some((Some((start, end)), length))
+}
+
+
+// TelegramUploader talking to the Bot API through teloxide
+pub(crate) struct TeloxideUploader(pub(crate) Bot);
+
+impl TelegramUploader for TeloxideUploader {
+    fn send_media<'a>(
+        &'a self,
+        chat_id: ChatId,
+        media: Media<'a>,
+        options: &'a SendOptions,
+    ) -> BoxFuture<'a, Result<Option<PostedMedia>, RequestError>> {
+        Box::pin(async move {
+            let file = match (media, &options.progress) {
+                // Stream the file through a counter, starting again from zero on every attempt
+                (Media::File(path), Some(progress)) => {
+                    let file = tokio::fs::File::open(path).await?;
+                    progress.total.store(file.metadata().await?.len(), Ordering::Relaxed);
+                    progress.sent.store(0, Ordering::Relaxed);
+                    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                    InputFile::read(CountingReader { file, progress: progress.clone() }).file_name(file_name)
+                }
+                (Media::File(path), None) => InputFile::file(path),
+                (Media::FileId(file_id), _) => InputFile::file_id(file_id.to_string()),
+            };
+            let message = send_media(&self.0, chat_id, file, options).await?;
+            Ok(posted_media(&message, options.mode))
+        })
+    }
+
+    fn get_file<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<String, RequestError>> {
+        Box::pin(async move { Ok(self.0.get_file(file_id).await?.path) })
+    }
+
+    fn download_file<'a>(&'a self, file_path: &'a str) -> BoxFuture<'a, Result<Vec<u8>, RequestError>> {
+        Box::pin(async move {
+            // A local Bot API server keeps its files on this machine
+            if is_local_file_path(file_path) {
+                return Ok(tokio::fs::read(file_path).await?);
+            }
+            let mut bytes = Vec::new();
+            self.0.download_file(file_path, &mut bytes).await?;
+            Ok(bytes)
+        })
+    }
+
+    fn download_range<'a>(&'a self, file_path: &'a str, spec: &'a ByteRangeSpec) -> BoxFuture<'a, Result<FileRange, RequestError>> {
+        Box::pin(async move {
+            if is_local_file_path(file_path) {
+                let mut file = tokio::fs::File::open(file_path).await?;
+                let length = file.metadata().await?.len();
+                let Some((start, end)) = spec.to_satisfiable_range(length) else {
+                    return Ok(FileRange::Unsatisfiable { length });
+                };
+                file.seek(std::io::SeekFrom::Start(start)).await?;
+                let body = slice_body(file_body(file), 0, end - start + 1);
+                return Ok(FileRange::Partial { body, start, end, length });
+            }
+
+            // The Bot API's file server honors ranges itself, so only the part asked for is downloaded
+            let response = self.0.client().get(self.file_url(file_path)).header(header::RANGE, format!("bytes={}", spec)).send().await?;
+            let content_range = response
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_content_range);
+            match (response.status(), content_range) {
+                (StatusCode::PARTIAL_CONTENT, Some((Some((start, end)), length))) => {
+                    let body = response.bytes_stream().map(|chunk| chunk.map_err(std::io::Error::other));
+                    Ok(FileRange::Partial { body: slice_body(body, 0, end - start + 1), start, end, length })
+                }
+                (StatusCode::RANGE_NOT_SATISFIABLE, Some((None, length))) => Ok(FileRange::Unsatisfiable { length }),
+                // Servers are free to ignore a range and send the whole file, which is cut down as it arrives
+                _ => {
+                    let response = response.error_for_status()?;
+                    let Some(length) = response.content_length() else {
+                        return Ok(FileRange::of(&response.bytes().await?, spec));
+                    };
+                    let Some((start, end)) = spec.to_satisfiable_range(length) else {
+                        return Ok(FileRange::Unsatisfiable { length });
+                    };
+                    let body = response.bytes_stream().map(|chunk| chunk.map_err(std::io::Error::other));
+                    Ok(FileRange::Partial { body: slice_body(body, start, end - start + 1), start, end, length })
+                }
+            }
+        })
+    }
+
+    fn pin_message(&self, chat_id: ChatId, message_id: MessageId, disable_notification: bool) -> BoxFuture<'_, Result<(), RequestError>> {
+        Box::pin(async move {
+            self.0.pin_chat_message(chat_id, message_id).disable_notification(disable_notification).await?;
+            Ok(())
+        })
+    }
+
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> BoxFuture<'_, Result<(), RequestError>> {
+        Box::pin(async move {
+            self.0.delete_message(chat_id, message_id).await?;
+            Ok(())
+        })
+    }
+
+    fn get_me(&self) -> BoxFuture<'_, Result<String, RequestError>> {
+        Box::pin(async move { Ok(self.0.get_me().await?.username().to_string()) })
+    }
+
+    fn resolve_username<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<ChatId, RequestError>> {
+        Box::pin(async move { Ok(self.0.get_chat(Recipient::ChannelUsername(username.to_string())).await?.id) })
+    }
+
+    fn file_url(&self, file_path: &str) -> String {
+        let mut file_url = self.0.api_url();
+        file_url
+            .path_segments_mut()
+            .expect("Bot API URL can't be a base")
+            .pop_if_empty()
+            .push("file")
+            .push(&format!("bot{}", self.0.token()))
+            .extend(file_path.split('/'));
+        debug!("Generated file URL: {}", file_url);
+        file_url.into()
+    }
+
+    fn bot(&self) -> Option<&Bot> {
+        Some(&self.0)
+    }
+}
+
+// The file Telegram stored for a message sent as the given kind
+fn posted_media(message: &Message, mode: UploadMode) -> Option<PostedMedia> {
+    let (file, photo_sizes) = match mode {
+        UploadMode::Photo | UploadMode::Auto => {
+            let photo = message.photo()?;
+            let photo_sizes = photo
+                .iter()
+                .map(|size| PhotoVariant {
+                    file_id: size.file.id.clone(),
+                    file_unique_id: size.file.unique_id.clone(),
+                    width: size.width,
+                    height: size.height,
+                    file_size: size.file.size,
+                })
+                .collect();
+            (&photo.last()?.file, photo_sizes)
+        }
+        UploadMode::Document => (&message.document()?.file, Vec::new()),
+        UploadMode::Video => (&message.video()?.file, Vec::new()),
+    };
+    Some(PostedMedia {
+        chat_id: message.chat.id,
+        message_id: message.id,
+        file_id: file.id.clone(),
+        file_unique_id: file.unique_id.clone(),
+        photo_sizes,
+    })
+}
+
+// Look up the numeric ID of a chat, asking Telegram for chats given by username
+pub(crate) async fn resolve_chat(telegram: &dyn TelegramUploader, chat: &ChatRef) -> Result<ChatId, RequestError> {
+    match chat {
+        ChatRef::Id(id) => Ok(ChatId(*id)),
+        // Numeric IDs written as strings
+        ChatRef::Username(username) if username.parse::<i64>().is_ok() => Ok(ChatId(username.parse().unwrap())),
+        ChatRef::Username(username) => {
+            let username = format!("@{}", username.trim_start_matches('@'));
+            let chat_id = telegram.resolve_username(&username).await?;
+            debug!("Resolved chat {} to {}", username, chat_id);
+            Ok(chat_id)
+        }
+    }
+}
+
+// A file stored on Telegram after sending it to the chat
+pub(crate) struct SentFile {
+    // Which bot of the pool sent it
+    pub(crate) bot_id: u64,
+    pub(crate) sent_as: UploadMode,
+    pub(crate) file_id: String,
+    pub(crate) file_unique_id: String,
+    pub(crate) chat_id: ChatId,
+    pub(crate) message_id: MessageId,
+    // Download path of the file on Telegram's servers
+    pub(crate) file_path: String,
+    // Every size Telegram generated for the photo, smallest first
+    pub(crate) photo_sizes: Vec<PhotoVariant>,
+}
+
+// One of the sizes Telegram stores a photo in
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PhotoVariant {
+    pub file_id: String,
+    pub file_unique_id: String,
+    pub width: u32,
+    pub height: u32,
+    pub file_size: u32,
+}
+
+// Whether a failed Telegram call is worth repeating: rate limits, network trouble and server errors
+pub(crate) fn is_transient(error: &RequestError) -> bool {
+    match error {
+        RequestError::RetryAfter(_) => true,
+        RequestError::Network(e) => {
+            e.is_timeout() || e.is_connect() || e.status().is_some_and(|status| status.is_server_error())
+        }
+        // Gateways in front of the Bot API answer 5xx with HTML instead of JSON
+        RequestError::InvalidJson { .. } => true,
+        RequestError::Api(ApiError::Unknown(description)) => {
+            ["Internal Server Error", "Bad Gateway", "Service Unavailable", "Gateway Timeout"]
+                .iter()
+                .any(|server_error| description.contains(server_error))
+        }
+        _ => false,
+    }
+}
+
+// Delay before the given retry: exponential backoff with jitter, or what Telegram asked for on 429
+pub(crate) fn retry_delay(retry: &RetryConfig, attempt: u32, error: &RequestError) -> Duration {
+    if let RequestError::RetryAfter(seconds) = error {
+        return seconds.duration();
+    }
+    let backoff = retry
+        .initial_delay_ms
+        .saturating_mul(1 << attempt.min(16))
+        .min(retry.max_delay_ms);
+    // Equal jitter, so concurrent uploads don't all retry at the same instant
+    let jitter = (uuid::Uuid::new_v4().as_u128() % (backoff / 2 + 1) as u128) as u64;
+    Duration::from_millis(backoff / 2 + jitter)
+}
+
+// Run a Telegram call, repeating it on transient failures until the retry budget is used up
+pub(crate) async fn retry_telegram<F, R, T>(
+    retry: &RetryConfig,
+    metrics: &Metrics,
+    method: &str,
+    mut request: F,
+) -> Result<T, RequestError>
+where
+    F: FnMut() -> R,
+    R: std::future::IntoFuture<Output = Result<T, RequestError>>,
+{
+    let mut attempt = 0;
+    let mut waited = Duration::ZERO;
+    loop {
+        let error = match metrics.time_telegram(method, request()).await {
+            Ok(output) => return Ok(output),
+            Err(e) => e,
+        };
+        let delay = retry_delay(retry, attempt, &error);
+        if attempt >= retry.max_retries || !is_transient(&error) || waited + delay > retry.budget() {
+            // What went wrong in detail; callers only get to see the kind of failure
+            if is_transient(&error) {
+                warn!("Telegram {} failed, giving up after {} retries: {:?}", method, attempt, error);
+            }
+            return Err(error);
+        }
+        attempt += 1;
+        waited += delay;
+        warn!("Telegram {} failed, retrying in {:?} (attempt {}/{}): {:?}", method, delay, attempt, retry.max_retries, error);
+        metrics.telegram_retries.with_label_values(&[method]).inc();
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Tracks consecutive Telegram failures; once open, uploads fail fast until a probe gets through
+pub(crate) struct CircuitBreaker {
+    pub(crate) failure_threshold: u32,
+    pub(crate) cooldown: Duration,
+    pub(crate) state: Mutex<CircuitState>,
+}
+
+#[derive(Default)]
+pub(crate) struct CircuitState {
+    pub(crate) consecutive_failures: u32,
+    // When the circuit was opened or last let a probe through
+    pub(crate) opened_at: Option<std::time::Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: &CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: config.failure_threshold,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            state: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    // Ok if an upload may go ahead, otherwise how long until the next probe
+    pub(crate) fn check(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let Some(opened_at) = state.opened_at else {
+            return Ok(());
+        };
+        let elapsed = opened_at.elapsed();
+        if elapsed < self.cooldown {
+            return Err(self.cooldown - elapsed);
+        }
+        // Let this upload probe Telegram; everyone else waits for another cooldown
+        info!("Circuit breaker half-open, probing Telegram");
+        state.opened_at = Some(std::time::Instant::now());
+        Ok(())
+    }
+
+    pub(crate) fn record_success(&self, metrics: &Metrics) {
+        let mut state = self.state.lock().unwrap();
+        if state.opened_at.take().is_some() {
+            info!("Telegram recovered, closing circuit breaker");
+            metrics.telegram_circuit_open.set(0);
+        }
+        state.consecutive_failures = 0;
+    }
+
+    pub(crate) fn record_failure(&self, metrics: &Metrics) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failure_threshold {
+            if state.opened_at.is_none() {
+                warn!("{} consecutive Telegram failures, opening circuit breaker", state.consecutive_failures);
+                metrics.telegram_circuit_open.set(1);
+            }
+            state.opened_at = Some(std::time::Instant::now());
+        }
+    }
+}
+
+// Window over which each bot's API usage is tracked
+pub(crate) const BOT_USAGE_WINDOW: Duration = Duration::from_secs(60);
+
+// One bot of the token pool along with its recent API usage
+pub(crate) struct PooledBot {
+    pub(crate) bot: Arc<dyn TelegramUploader>,
+    // Telegram's ID of the bot, the part of the token before the colon
+    pub(crate) id: u64,
+    pub(crate) recent_calls: Mutex<std::collections::VecDeque<std::time::Instant>>,
+}
+
+impl PooledBot {
+    pub(crate) fn record_call(&self) {
+        let mut recent_calls = self.recent_calls.lock().unwrap();
+        let now = std::time::Instant::now();
+        while recent_calls.front().is_some_and(|call| now.duration_since(*call) > BOT_USAGE_WINDOW) {
+            recent_calls.pop_front();
+        }
+        recent_calls.push_back(now);
+    }
+
+    // API calls made within the usage window
+    pub(crate) fn recent_calls(&self) -> usize {
+        let recent_calls = self.recent_calls.lock().unwrap();
+        recent_calls.iter().filter(|call| call.elapsed() <= BOT_USAGE_WINDOW).count()
+    }
+}
+
+// Bots that uploads are spread over round-robin, so no single bot runs into flood limits
+pub(crate) struct BotPool {
+    pub(crate) bots: Vec<PooledBot>,
+    pub(crate) next: AtomicUsize,
+}
+
+impl BotPool {
+    pub(crate) fn new(tokens: impl IntoIterator<Item = String>, api_url: Option<&url::Url>) -> BotPool {
+        let bots = tokens
+            .into_iter()
+            .map(|token| {
+                let id = token.split(':').next().and_then(|id| id.parse().ok()).unwrap_or(0);
+                let bot = match api_url {
+                    Some(api_url) => Bot::new(token).set_api_url(api_url.clone()),
+                    None => Bot::new(token),
+                };
+                (id, Arc::new(TeloxideUploader(bot)) as Arc<dyn TelegramUploader>)
+            });
+        BotPool::with_uploaders(bots)
+    }
+
+    // A pool of given clients, such as a FakeTelegram, by bot ID
+    pub(crate) fn with_uploaders(uploaders: impl IntoIterator<Item = (u64, Arc<dyn TelegramUploader>)>) -> BotPool {
+        let bots = uploaders
+            .into_iter()
+            .map(|(id, bot)| PooledBot { id, bot, recent_calls: Mutex::new(std::collections::VecDeque::new()) })
+            .collect();
+        BotPool { bots, next: AtomicUsize::new(0) }
+    }
+
+    // The bot from telegram_bot_token, used for everything not tied to a particular upload
+    pub(crate) fn primary(&self) -> &dyn TelegramUploader {
+        self.bots[0].bot.as_ref()
+    }
+
+    // The bot that stored a file; file IDs only work for the bot that received them
+    pub(crate) fn get(&self, id: Option<u64>) -> &dyn TelegramUploader {
+        self.pooled(id).bot.as_ref()
+    }
+
+    pub(crate) fn pooled(&self, id: Option<u64>) -> &PooledBot {
+        id.and_then(|id| self.bots.iter().find(|pooled| pooled.id == id)).unwrap_or(&self.bots[0])
+    }
+
+    // Next bot in turn, passing over bots that have been much busier than the others lately
+    pub(crate) fn next(&self) -> &PooledBot {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.bots.len())
+            .map(|offset| &self.bots[(start + offset) % self.bots.len()])
+            .min_by_key(|pooled| pooled.recent_calls())
+            .unwrap()
+    }
+}
+
+// Which kind of Telegram message an upload is sent as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadMode {
+    // A compressed photo, after running the image processing steps
+    #[default]
+    Photo,
+    // The file as it is
+    Document,
+    Video,
+    // Photo for images, video for videos, document for everything else
+    Auto,
+}
+
+impl std::fmt::Display for UploadMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl UploadMode {
+    // Name of the mode as written in the config and API
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            UploadMode::Photo => "photo",
+            UploadMode::Document => "document",
+            UploadMode::Video => "video",
+            UploadMode::Auto => "auto",
+        }
+    }
+
+    // Bot API method used to send an upload, for metrics and logs
+    pub(crate) fn method(self) -> &'static str {
+        match self {
+            UploadMode::Photo | UploadMode::Auto => "send_photo",
+            UploadMode::Document => "send_document",
+            UploadMode::Video => "send_video",
+        }
+    }
+
+    // Check the mode suits the file and settle `auto` on a concrete mode
+    pub(crate) fn resolve(self, file_path: &Path, content_type: Option<&str>) -> Result<UploadMode, String> {
+        let kind = content_type.and_then(|content_type| content_type.split('/').next());
+        let is_image = kind == Some("image") || ImageReader::open(file_path)
+            .and_then(|reader| reader.with_guessed_format())
+            .is_ok_and(|reader| reader.format().is_some());
+        let is_video = kind == Some("video");
+
+        match self {
+            UploadMode::Auto if is_image => Ok(UploadMode::Photo),
+            UploadMode::Auto if is_video => Ok(UploadMode::Video),
+            UploadMode::Auto => Ok(UploadMode::Document),
+            UploadMode::Photo if !is_image => Err("Only images can be sent as photo".to_string()),
+            UploadMode::Video if !is_video => Err("Only videos can be sent as video".to_string()),
+            mode => Ok(mode),
+        }
+    }
+}
+
+// Bytes of a file streamed to Telegram so far, for progress reports
+#[derive(Debug, Default)]
+pub struct SendProgress {
+    pub sent: AtomicU64,
+    pub total: AtomicU64,
+}
+
+// Reads a file to send while counting what Telegram has taken of it
+pub(crate) struct CountingReader {
+    pub(crate) file: tokio::fs::File,
+    pub(crate) progress: Arc<SendProgress>,
+}
+
+impl tokio::io::AsyncRead for CountingReader {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = std::pin::Pin::new(&mut self.file).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.progress.sent.fetch_add(read, Ordering::Relaxed);
+        poll
+    }
+}
+
+// How an upload is posted to Telegram, from the config and overridden per request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SendOptions {
+    // Forum topic to post into
+    #[serde(default)]
+    pub message_thread_id: Option<i32>,
+    // Post without a notification sound
+    #[serde(default)]
+    pub disable_notification: bool,
+    // Keep the image from being forwarded or saved
+    #[serde(default)]
+    pub protect_content: bool,
+    // Pin the message once it's posted
+    #[serde(default)]
+    pub pin: bool,
+    // Blur the image or video in the chat until it's tapped
+    #[serde(default)]
+    pub spoiler: bool,
+    // Text shown under the image
+    #[serde(default)]
+    pub caption: Option<String>,
+    // How the caption is formatted, unless caption_entities are given
+    #[serde(default)]
+    pub parse_mode: Option<ParseMode>,
+    // Formatting of the caption as Telegram message entities
+    #[serde(default)]
+    pub caption_entities: Option<Vec<MessageEntity>>,
+    #[serde(default)]
+    pub mode: UploadMode,
+    // Name the file is shown with when sent as a document or video
+    #[serde(default)]
+    pub file_name: Option<String>,
+    // Seconds after delivery at which the upload is deleted again
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    // Where to count the bytes sent to Telegram, when a client follows the upload's progress
+    #[serde(skip)]
+    pub progress: Option<Arc<SendProgress>>,
+}
+
+// Longest caption Telegram accepts
+pub(crate) const MAX_CAPTION_LENGTH: usize = 1024;
+
+// A yes/no form field, as HTML checkboxes and curl send them
+pub(crate) fn parse_flag(name: &str, value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" | "" => Ok(false),
+        _ => Err(format!("Invalid {}", name)),
+    }
+}
+
+impl SendOptions {
+    // Apply the per-request overrides from the query or the form fields, the latter winning
+    pub(crate) fn with_overrides(&self, query: &UploadQuery, fields: &HashMap<String, String>) -> Result<SendOptions, String> {
+        let mut options = self.clone();
+        if let Some(mode) = query.upload_mode {
+            options.mode = mode;
+        }
+        if let Some(message_thread_id) = query.message_thread_id {
+            options.message_thread_id = Some(message_thread_id);
+        }
+        if let Some(message_thread_id) = fields.get("message_thread_id") {
+            let message_thread_id = message_thread_id.trim().parse().map_err(|_| "Invalid message_thread_id")?;
+            options.message_thread_id = Some(message_thread_id);
+        }
+
+        let ttl = match fields.get("ttl") {
+            Some(ttl) => Some(ttl.trim().parse().map_err(|_| "Invalid ttl")?),
+            None => query.ttl,
+        };
+        if let Some(ttl) = ttl {
+            // 0 keeps the upload, even when the config has a default TTL
+            options.ttl_secs = (ttl > 0).then_some(ttl);
+        }
+
+        let flags = [
+            ("disable_notification", query.disable_notification, &mut options.disable_notification),
+            ("protect_content", query.protect_content, &mut options.protect_content),
+            ("pin", query.pin, &mut options.pin),
+            ("spoiler", query.spoiler, &mut options.spoiler),
+        ];
+        for (name, from_query, flag) in flags {
+            if let Some(value) = from_query {
+                *flag = value;
+            }
+            if let Some(value) = fields.get(name) {
+                *flag = parse_flag(name, value)?;
+            }
+        }
+
+        if let Some(caption) = fields.get("caption").filter(|caption| !caption.trim().is_empty()) {
+            if caption.chars().count() > MAX_CAPTION_LENGTH {
+                return Err(format!("Caption is longer than {} characters", MAX_CAPTION_LENGTH));
+            }
+            options.caption = Some(caption.clone());
+        }
+        if let Some(parse_mode) = fields.get("parse_mode").filter(|parse_mode| !parse_mode.trim().is_empty()) {
+            options.parse_mode = Some(parse_mode.trim().parse().map_err(|_| "Invalid parse_mode")?);
+        }
+        if let Some(caption_entities) = fields.get("caption_entities") {
+            let caption_entities = serde_json::from_str(caption_entities)
+                .map_err(|e| format!("Invalid caption_entities: {}", e))?;
+            options.caption_entities = Some(caption_entities);
+        }
+        if options.parse_mode.is_some() && options.caption_entities.is_some() {
+            return Err("Only one of parse_mode and caption_entities can be given".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// Set the options shared by every kind of upload on a send_photo/send_document/send_video request
+macro_rules! with_send_options {
+    ($request:expr, $options:expr) => {{
+        let options: &SendOptions = $options;
+        let mut request = $request
+            .disable_notification(options.disable_notification)
+            .protect_content(options.protect_content);
+        if let Some(message_thread_id) = options.message_thread_id {
+            request = request.message_thread_id(ThreadId(MessageId(message_thread_id)));
+        }
+        if let Some(caption) = &options.caption {
+            request = request.caption(caption);
+        }
+        if let Some(parse_mode) = options.parse_mode {
+            request = request.parse_mode(parse_mode);
+        }
+        if let Some(caption_entities) = &options.caption_entities {
+            request = request.caption_entities(caption_entities.iter().cloned());
+        }
+        request
+    }};
+}
+
+// Post a file as the kind of message the upload mode asks for, with the options chosen for the upload
+pub(crate) async fn send_media(bot: &Bot, chat_id: ChatId, file: InputFile, options: &SendOptions) -> Result<Message, RequestError> {
+    let file = match &options.file_name {
+        Some(file_name) if options.mode != UploadMode::Photo => file.file_name(file_name.clone()),
+        _ => file,
+    };
+    match options.mode {
+        UploadMode::Photo | UploadMode::Auto => {
+            with_send_options!(bot.send_photo(chat_id, file), options).has_spoiler(options.spoiler).await
+        }
+        // Telegram has no spoilers for documents
+        UploadMode::Document => with_send_options!(bot.send_document(chat_id, file), options).await,
+        UploadMode::Video => {
+            with_send_options!(bot.send_video(chat_id, file), options).has_spoiler(options.spoiler).await
+        }
+    }
+}
+
+// Pin a freshly posted upload; not being allowed to pin shouldn't fail the upload
+pub(crate) async fn pin_message(bot: &PooledBot, chat_id: ChatId, message_id: MessageId, options: &SendOptions, metrics: &Metrics) {
+    bot.record_call();
+    let pinned = bot.bot.pin_message(chat_id, message_id, options.disable_notification);
+    match metrics.time_telegram("pin_chat_message", pinned).await {
+        Ok(_) => debug!("Pinned message {} in chat {}", message_id, chat_id),
+        Err(e) => error!("Failed to pin message {} in chat {}: {:?}", message_id, chat_id, e),
+    }
+}
+
+// A copy of an upload posted to another chat in mirror mode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct MirroredMessage {
+    pub(crate) chat_id: i64,
+    pub(crate) message_id: i32,
+}
+
+// Send the image to the target chats, failing over to the next chat when one refuses it.
+// In mirror mode the stored file is then also posted to every other chat.
+pub(crate) async fn send_to_chats(
+    media: Media<'_>,
+    bot: &PooledBot,
+    chat_ids: &[ChatId],
+    mode: ChatMode,
+    options: &SendOptions,
+    metrics: &Metrics,
+    retry: &RetryConfig,
+) -> Result<(SentFile, Vec<MirroredMessage>), Error> {
+    let mut last_error = Error::Internal("No chat to upload to".to_string());
+    let mut sent = None;
+    for &chat_id in chat_ids {
+        match upload_to_telegram(media, bot, chat_id, options, metrics, retry).await {
+            Ok(file) => {
+                sent = Some(file);
+                break;
+            }
+            // Telegram itself is in trouble, the next chat won't fare any better
+            Err(e) if e.is_transient() => return Err(e),
+            Err(e) => {
+                error!("Failed to upload to chat {}, trying the next one: {:?}", chat_id, e);
+                last_error = e;
+            }
+        }
+    }
+    let Some(sent) = sent else {
+        return Err(last_error);
+    };
+
+    let mut mirrors = Vec::new();
+    if mode == ChatMode::Mirror {
+        for &chat_id in chat_ids.iter().filter(|&&chat_id| chat_id != sent.chat_id) {
+            // Reuse the stored file rather than uploading it again
+            let copied = retry_telegram(retry, metrics, options.mode.method(), || {
+                bot.record_call();
+                bot.bot.send_media(chat_id, Media::FileId(&sent.file_id), options)
+            })
+            .await;
+            match copied {
+                Ok(Some(posted)) => mirrors.push(MirroredMessage { chat_id: chat_id.0, message_id: posted.message_id.0 }),
+                Ok(None) => error!("Failed to mirror upload to chat {}: no file in the response", chat_id),
+                Err(e) => error!("Failed to mirror upload to chat {}: {:?}", chat_id, e),
+            }
+        }
+    }
+
+    if options.pin {
+        pin_message(bot, sent.chat_id, sent.message_id, options, metrics).await;
+        for mirror in &mirrors {
+            pin_message(bot, ChatId(mirror.chat_id), MessageId(mirror.message_id), options, metrics).await;
+        }
+    }
+
+    Ok((sent, mirrors))
+}
+
+// Upload the image to Telegram, or post a file it stores again, and return where it is stored
+pub(crate) async fn upload_to_telegram(
+    media: Media<'_>,
+    bot: &PooledBot,
+    chat_id: ChatId,
+    options: &SendOptions,
+    metrics: &Metrics,
+    retry: &RetryConfig,
+) -> Result<SentFile, Error> {
+    debug!("Uploading file: {:?} to Telegram chat: {:?} as bot {}", media, chat_id, bot.id);
+    
+    let posted = retry_telegram(retry, metrics, options.mode.method(), || {
+        bot.record_call();
+        bot.bot.send_media(chat_id, media, options)
+    })
+    .await?
+    .ok_or_else(|| Error::TelegramRejected(format!("No {:?} in the response", options.mode)))?;
+    
+    let file_id = posted.file_id;
+    debug!("File uploaded to Telegram, received file ID: {:?}", file_id);
+    
+    // Get the file path
+    let file_path = retry_telegram(retry, metrics, "get_file", || {
+        bot.record_call();
+        bot.bot.get_file(&file_id)
+    })
+    .await?;
+
+    Ok(SentFile {
+        bot_id: bot.id,
+        sent_as: options.mode,
+        file_id,
+        file_unique_id: posted.file_unique_id,
+        chat_id: posted.chat_id,
+        message_id: posted.message_id,
+        file_path,
+        photo_sizes: posted.photo_sizes,
+    })
+}
+
+// Whether a file path is a path on the local disk, which a Bot API server in --local mode hands out
+pub(crate) fn is_local_file_path(file_path: &str) -> bool {
+    Path::new(file_path).is_absolute()
+}
+
+// URL to hand out for an upload: Telegram's file URL, or our own proxy when the
+// Bot API server only knows a local path that clients can't reach. With signed_urls it is
+// always the signed proxy link, as Telegram's URL never expires.
+pub(crate) fn upload_url(
+    settings: &Settings,
+    base_url: &str,
+    telegram: &dyn TelegramUploader,
+    id: &Uuid,
+    file_path: &str,
+) -> String {
+    if settings.signed_urls.is_some() || is_local_file_path(file_path) {
+        settings.file_url(base_url, id)
+    } else {
+        telegram.file_url(file_path)
+    }
+}
+
+// Download the part of a file a Range header asks for from Telegram
+pub(crate) async fn download_range_from_telegram(
+    telegram: &dyn TelegramUploader,
+    file_id: &str,
+    spec: &ByteRangeSpec,
+    metrics: &Metrics,
+) -> Result<(String, FileRange), Error> {
+    let file_path = metrics.time_telegram("get_file", telegram.get_file(file_id)).await?;
+    let range = metrics.time_telegram("download_file", telegram.download_range(&file_path, spec)).await?;
+    Ok((mime_guess::from_path(&file_path).first_or_octet_stream().to_string(), range))
+}
+
+// Download a file from Telegram into memory
+pub(crate) async fn download_from_telegram(
+    telegram: &dyn TelegramUploader,
+    file_id: &str,
+    metrics: &Metrics,
+) -> Result<ProxiedFile, Error> {
+    let file_path = metrics.time_telegram("get_file", telegram.get_file(file_id)).await?;
+    let bytes = metrics.time_telegram("download_file", telegram.download_file(&file_path)).await?;
+
+    let content_type = mime_guess::from_path(&file_path).first_or_octet_stream().to_string();
+    Ok(ProxiedFile { content_type, bytes: bytes.into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_ranges() {
+        assert_eq!(parse_content_range("bytes 0-99/1000"), Some((Some((0, 99)), 1000)));
+        assert_eq!(parse_content_range("bytes */1000"), Some((None, 1000)));
+        assert_eq!(parse_content_range("bytes 100-99/1000"), None);
+        assert_eq!(parse_content_range("bytes 0-1000/1000"), None);
+        assert_eq!(parse_content_range("bytes 0-99/*"), None);
+    }
+
+    #[actix_web::test]
+    async fn fakes_cut_down_whole_downloads() {
+        let telegram = crate::FakeTelegram::new();
+        let path = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(path.path(), b"0123456789").unwrap();
+        let options = SendOptions { mode: UploadMode::Document, ..SendOptions::default() };
+        let posted = telegram.send_media(ChatId(1), Media::File(path.path()), &options).await.unwrap().unwrap();
+        let file_path = telegram.get_file(&posted.file_id).await.unwrap();
+
+        let FileRange::Partial { body, start, end, length } = telegram.download_range(&file_path, &ByteRangeSpec::Last(4)).await.unwrap() else {
+            panic!("The range wasn't satisfied");
+        };
+        assert_eq!((start, end, length), (6, 9, 10));
+        assert_eq!(collect(body).await.unwrap(), b"6789");
+        let range = telegram.download_range(&file_path, &ByteRangeSpec::From(10)).await.unwrap();
+        assert!(matches!(range, FileRange::Unsatisfiable { length: 10 }));
+    }
+
+    async fn collect(body: FileBody) -> std::io::Result<Vec<u8>> {
+        let chunks: Vec<_> = body.collect().await;
+        Ok(chunks.into_iter().collect::<std::io::Result<Vec<_>>>()?.concat())
+    }
+
+    fn chunks(chunks: &[&'static [u8]]) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static {
+        futures_util::stream::iter(chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk))).collect::<Vec<_>>())
+    }
+
+    #[actix_web::test]
+    async fn slices_bodies_as_they_stream() {
+        assert_eq!(collect(slice_body(chunks(&[b"0123", b"4567", b"89"]), 3, 6)).await.unwrap(), b"345678");
+        assert_eq!(collect(slice_body(chunks(&[b"0123", b"4567"]), 0, 4)).await.unwrap(), b"0123");
+        // A body ending before the range does is an error, not a short response
+        let error = collect(slice_body(chunks(&[b"0123"]), 2, 4)).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[actix_web::test]
+    async fn streams_ranges_of_local_files() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), vec![7u8; 200 * 1024]).unwrap();
+        let mut opened = tokio::fs::File::open(file.path()).await.unwrap();
+        opened.seek(std::io::SeekFrom::Start(1000)).await.unwrap();
+        let body = slice_body(file_body(opened), 0, 150 * 1024);
+        let sizes: Vec<usize> = body.map(|chunk| chunk.unwrap().len()).collect().await;
+        // Read in chunks rather than all at once
+        assert!(sizes.len() > 1 && sizes.iter().all(|size| *size <= 64 * 1024));
+        assert_eq!(sizes.iter().sum::<usize>(), 150 * 1024);
+    }
+
+    #[test]
+    fn circuit_breaker_opens_probes_and_closes() {
+        let metrics = Metrics::new().unwrap();
+        let config = CircuitBreakerConfig { failure_threshold: 2, cooldown_secs: 0 };
+        let breaker = CircuitBreaker { cooldown: Duration::from_millis(50), ..CircuitBreaker::new(&config) };
+        breaker.record_failure(&metrics);
+        assert!(breaker.check().is_ok());
+        breaker.record_failure(&metrics);
+        assert!(breaker.check().is_err());
+        assert_eq!(metrics.telegram_circuit_open.get(), 1);
+
+        // Half-open after the cooldown: one probe goes through, a failed one keeps it open
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+        breaker.record_failure(&metrics);
+        assert!(breaker.check().is_err());
+
+        // A probe that gets through closes it
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        breaker.record_success(&metrics);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+        assert_eq!(metrics.telegram_circuit_open.get(), 0);
+        // And the count starts over
+        breaker.record_failure(&metrics);
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn retry_delays_back_off_up_to_the_cap() {
+        let retry = RetryConfig { max_retries: 10, initial_delay_ms: 100, max_delay_ms: 1000, max_total_delay_ms: 10_000 };
+        let error = || RequestError::Api(ApiError::Unknown("Bad Gateway".to_string()));
+        // Equal jitter keeps each delay between half the backoff and all of it
+        for (attempt, backoff) in [(0, 100), (1, 200), (2, 400), (3, 800), (4, 1000), (20, 1000)] {
+            for _ in 0..20 {
+                let delay = retry_delay(&retry, attempt, &error()).as_millis() as u64;
+                assert!((backoff / 2..=backoff).contains(&delay), "attempt {}: {}ms", attempt, delay);
+            }
+        }
+        // Telegram's own wait wins over the backoff and its cap
+        let rate_limited = RequestError::RetryAfter(teloxide::types::Seconds::from_seconds(7));
+        assert_eq!(retry_delay(&retry, 0, &rate_limited), Duration::from_secs(7));
+    }
+
+    #[actix_web::test]
+    async fn retries_transient_failures_only() {
+        let metrics = Metrics::new().unwrap();
+        let retry = RetryConfig { max_retries: 3, initial_delay_ms: 1, max_delay_ms: 2, max_total_delay_ms: 5_000 };
+        let calls = AtomicUsize::new(0);
+        // Fails twice with a gateway error, then goes through
+        let result = retry_telegram(&retry, &metrics, "get_file", || {
+            let call = calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                match call {
+                    0 | 1 => Err(RequestError::Api(ApiError::Unknown("Bad Gateway".to_string()))),
+                    _ => Ok(call),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(metrics.telegram_retries.with_label_values(&["get_file"]).get(), 2);
+
+        // Telegram refusing the request is final
+        calls.store(0, Ordering::Relaxed);
+        let result = retry_telegram(&retry, &metrics, "send_photo", || {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async { Err::<(), _>(RequestError::Api(ApiError::ChatNotFound)) }
+        })
+        .await;
+        assert!(matches!(result, Err(RequestError::Api(ApiError::ChatNotFound))));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Gives up once out of retries
+        calls.store(0, Ordering::Relaxed);
+        let result = retry_telegram(&retry, &metrics, "send_document", || {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async { Err::<(), _>(RequestError::Api(ApiError::Unknown("Service Unavailable".to_string()))) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+
+    #[actix_web::test]
+    async fn retries_honour_retry_after_within_the_budget() {
+        let metrics = Metrics::new().unwrap();
+        let retry = RetryConfig { max_retries: 3, initial_delay_ms: 1, max_delay_ms: 2, max_total_delay_ms: 1_500 };
+        let calls = AtomicUsize::new(0);
+        let rate_limited = || RequestError::RetryAfter(teloxide::types::Seconds::from_seconds(1));
+        let started = std::time::Instant::now();
+        let result = retry_telegram(&retry, &metrics, "send_photo", || {
+            let call = calls.fetch_add(1, Ordering::Relaxed);
+            async move { if call == 0 { Err(rate_limited()) } else { Ok(()) } }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // A second wait would go over the budget, so the call fails rather than waiting
+        calls.store(0, Ordering::Relaxed);
+        let started = std::time::Instant::now();
+        let result = retry_telegram(&retry, &metrics, "send_photo", || {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move { Err::<(), _>(rate_limited()) }
+        })
+        .await;
+        assert!(matches!(result, Err(RequestError::RetryAfter(_))));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+}
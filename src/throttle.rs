@@ -0,0 +1,119 @@
+// Egress rate limits for files served through this server
+
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt as _};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::config::BandwidthConfig;
+
+// Bytes sent to the client at once while throttling
+pub(crate) const THROTTLE_CHUNK_SIZE: usize = 16 * 1024;
+
+// Token bucket holding up to a second's worth of bytes. Taking more than is there puts the
+// bucket in debt, which the caller waits out.
+pub(crate) struct TokenBucket {
+    pub(crate) rate: u64,
+    // Bytes available, and when that was last worked out
+    pub(crate) state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u64) -> TokenBucket {
+        TokenBucket { rate, state: Mutex::new((rate as f64, Instant::now())) }
+    }
+
+    // Take some bytes, returning how long to wait before sending them
+    pub(crate) fn take(&self, bytes: usize) -> Duration {
+        let rate = self.rate as f64;
+        let mut state = self.state.lock().unwrap();
+        let (available, updated_at) = &mut *state;
+        let now = Instant::now();
+        *available = (*available + now.duration_since(*updated_at).as_secs_f64() * rate).min(rate);
+        *updated_at = now;
+        *available -= bytes as f64;
+        match *available {
+            available if available < 0.0 => Duration::from_secs_f64(-available / rate),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+// The configured limits, with the bucket shared by every connection
+pub(crate) struct Bandwidth {
+    pub(crate) per_connection: u64,
+    pub(crate) global: Option<Arc<TokenBucket>>,
+}
+
+impl Bandwidth {
+    pub(crate) fn new(config: &BandwidthConfig) -> Bandwidth {
+        let global = (config.global_bytes_per_sec > 0).then(|| Arc::new(TokenBucket::new(config.global_bytes_per_sec)));
+        Bandwidth { per_connection: config.per_connection_bytes_per_sec, global }
+    }
+
+    pub(crate) fn is_limited(&self) -> bool {
+        self.per_connection > 0 || self.global.is_some()
+    }
+
+    // Hand out a body in chunks no faster than the limits allow
+    pub(crate) fn throttle(&self, bytes: Bytes) -> impl Stream<Item = Result<Bytes, Infallible>> + 'static {
+        self.throttle_stream(futures_util::stream::once(async move { Ok(bytes) }))
+    }
+
+    // The same for a body that is still arriving
+    pub(crate) fn throttle_stream<E: 'static>(
+        &self,
+        body: impl Stream<Item = Result<Bytes, E>> + 'static,
+    ) -> impl Stream<Item = Result<Bytes, E>> + 'static {
+        let connection = (self.per_connection > 0).then(|| Arc::new(TokenBucket::new(self.per_connection)));
+        let global = self.global.clone();
+        futures_util::stream::unfold((Box::pin(body), Bytes::new()), move |(mut body, mut pending)| {
+            let buckets: Vec<_> = [connection.clone(), global.clone()].into_iter().flatten().collect();
+            async move {
+                while pending.is_empty() {
+                    match body.next().await? {
+                        Ok(chunk) => pending = chunk,
+                        Err(e) => return Some((Err(e), (body, pending))),
+                    }
+                }
+                let chunk = pending.split_to(pending.len().min(THROTTLE_CHUNK_SIZE));
+                let wait = buckets.iter().map(|bucket| bucket.take(chunk.len())).max().unwrap_or_default();
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                Some((Ok(chunk), (body, pending)))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close_to(wait: Duration, millis: u64) -> bool {
+        wait.as_millis().abs_diff(millis as u128) <= 5
+    }
+
+    #[test]
+    fn buckets_allow_a_second_of_burst() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        // Beyond the burst, the debt is waited out at the rate
+        assert!(close_to(bucket.take(500), 500));
+        assert!(close_to(bucket.take(500), 1000));
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate_up_to_a_second() {
+        let bucket = TokenBucket::new(1000);
+        *bucket.state.lock().unwrap() = (0.0, Instant::now() - Duration::from_millis(500));
+        assert_eq!(bucket.take(400), Duration::ZERO);
+        assert!(close_to(bucket.take(300), 200));
+
+        // However long it sat idle, a second's worth is all it holds
+        *bucket.state.lock().unwrap() = (0.0, Instant::now() - Duration::from_secs(60));
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert!(!bucket.take(100).is_zero());
+    }
+}
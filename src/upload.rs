@@ -0,0 +1,949 @@
+// The upload pipeline shared by the HTTP API, the bot and the library, plus upload webhooks
+
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use serde::Serialize;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use teloxide::types::{ChatId, MessageId};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use uuid::Uuid;
+use tracing::{debug, error, info, warn, Instrument};
+use crate::audit::{AuditAction, AuditEntry, AuditLog, AuditSource};
+use crate::error::Error;
+use ipnet::IpNet;
+use crate::config::{
+    parse_network, ApiKeyConfig, ChatMode, ClamavConfig, Config, FeedConfig, GalleryConfig, ModerationConfig, RetryConfig,
+    SignedUrlConfig, UploadQueueConfig, WebhookConfig, WebhookEvent, ZipUploadConfig,
+};
+use crate::moderation::{moderate, ModerationVerdict};
+use crate::clamav::scan_upload;
+use crate::telegram::{BotPool, CircuitBreaker, Media, MirroredMessage, SendOptions, SentFile, UploadMode, send_to_chats};
+use crate::image::{ImageOptions, ProcessedImage, ProxiedFile, ResizeKey, generate_thumbnail, load_watermark, run_image_processing};
+use crate::progress::ProgressTracker;
+use crate::throttle::Bandwidth;
+use crate::storage::{ArchivedUpload, IdempotencyStore, Outbox, OutboxEntry, ProxyCache, Registry, SavedFile, TempQuota, UploadRecord, archive_upload, generate_deletion_token, remove_temp_file, thumbnail_path, to_hex};
+use crate::metrics::{InFlight, Metrics};
+use crate::signing::ReplayGuard;
+
+// Check whether a file is covered by the allowlist, by its extension or its MIME type.
+// The MIME type is guessed from the extension, falling back to the one declared by the client.
+pub(crate) fn is_type_allowed(allowed_types: &[String], filename: &str, declared: Option<&mime_guess::Mime>) -> bool {
+    if allowed_types.is_empty() {
+        return true;
+    }
+
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    let mime = mime_guess::from_path(filename).first().or_else(|| declared.cloned());
+
+    allowed_types.iter().any(|allowed| {
+        let allowed = allowed.trim().to_ascii_lowercase();
+        if let Some(allowed_ext) = allowed.strip_prefix('.') {
+            return extension.as_deref() == Some(allowed_ext);
+        }
+        match (&mime, allowed.split_once('/')) {
+            (Some(mime), Some((kind, "*"))) => mime.type_().as_str() == kind,
+            (Some(mime), Some(_)) => mime.essence_str() == allowed,
+            _ => false,
+        }
+    })
+}
+
+// Where a file that passed validation ended up
+pub(crate) enum HostedFile {
+    // On Telegram, either just sent or found to be the same as an earlier upload
+    Sent { record: UploadRecord, file_path: String, flags: UploadFlags },
+    // Telegram is unavailable, the file waits in the outbox
+    Queued { entry: OutboxEntry, flags: UploadFlags },
+}
+
+// Where an upload goes and whose it is
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Destination {
+    // Chat picked by the client or set for its API key, instead of the configured ones
+    pub(crate) chat: Option<ChatId>,
+    // Name of the API key the upload was made with
+    pub(crate) tenant: Option<String>,
+    // Path under /dav/ for uploads through WebDAV
+    pub(crate) dav_path: Option<String>,
+}
+
+// Process a saved file and send it to Telegram, the part of an upload shared by every way
+// files come in. The temp file is gone afterwards.
+pub(crate) async fn host_file(
+    data: &UploadData,
+    saved: &SavedFile,
+    options: &SendOptions,
+    destination: &Destination,
+    circuit_open: bool,
+) -> Result<HostedFile, Error> {
+    let path = Path::new(&saved.file_path);
+    let chat_ids = destination.chat.map_or_else(|| data.chat_ids.clone(), |chat_id| vec![chat_id]);
+    let fail = |error: Error| {
+        notify_webhooks(data, &WebhookPayload::failed(saved.id, Some(&saved.file_name), &error.to_string()));
+        error
+    };
+
+    // Infected files go no further, not even to be matched against earlier uploads
+    let settings = data.settings();
+    if let Some(clamav) = &settings.clamav {
+        let scanned = scan_upload(clamav, &data.metrics, path).instrument(tracing::info_span!("clamav_scan")).await;
+        if let Err(e) = scanned {
+            data.metrics.upload_failed(if matches!(e, Error::Infected(_)) { "infected" } else { "scan" });
+            remove_temp_file(path);
+            return Err(fail(e));
+        }
+    }
+
+    // The same bytes were sent to the same chats as the same kind of message before: post the
+    // file Telegram already stores instead of processing and uploading it again. The upload still
+    // gets a message, record and deletion token of its own. Uploads that expire or have a WebDAV
+    // path are kept apart, and so are those of other tenants.
+    let matches = |record: &UploadRecord| {
+        record.expires_at.is_none() && record.sent_as == options.mode && chat_ids.contains(&ChatId(record.chat_id))
+    };
+    let existing = (settings.deduplicate && options.ttl_secs.is_none() && destination.dav_path.is_none())
+        .then(|| data.registry.find_by_hash(&saved.content_hash, destination.tenant.as_deref(), matches))
+        .flatten();
+    if let Some(existing) = existing {
+        let (bot, chat_id) = (data.bots.pooled(existing.bot_id), ChatId(existing.chat_id));
+        let media = Media::FileId(&existing.file_id);
+        match send_to_chats(media, bot, &[chat_id], data.chat_mode, options, &data.metrics, &data.retry).await {
+            Ok((sent, mirrors)) => {
+                info!("Upload matches existing upload {}, posted its file again", existing.id);
+                let file_path = sent.file_path.clone();
+                let meta = UploadMeta {
+                    content_hash: Some(saved.content_hash.clone()),
+                    deletion_token: generate_deletion_token(),
+                    ttl_secs: None,
+                    moderation: existing.moderation,
+                    tenant: destination.tenant.clone(),
+                    dav_path: None,
+                    file_name: Some(saved.file_name.clone()),
+                };
+                let record = finish_upload(data, saved.id, path, (sent, mirrors), meta).await;
+                notify_webhooks(data, &WebhookPayload::succeeded(data, &record, Some(&saved.file_name)));
+                remove_temp_file(path);
+                let flags = UploadFlags { deduplicated: true, ..UploadFlags::default() };
+                return Ok(HostedFile::Sent { record, file_path, flags });
+            }
+            Err(e) => error!("Failed to post the file of existing upload {}, uploading again: {:?}", existing.id, e),
+        }
+    }
+
+    // A failed backup shouldn't keep the file from being hosted
+    let archived = match &data.archive_dir {
+        Some(archive_dir) => match archive_upload(archive_dir, saved).await {
+            Ok(archived) => Some(archived),
+            Err(e) => {
+                error!("Failed to archive upload {}: {:?}", saved.id, e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Images sent in any form are oriented, stripped and watermarked; only photos are converted
+    // and recompressed, as Telegram compresses them anyway
+    let processing = run_image_processing(path, &settings.image_options, options.mode == UploadMode::Photo);
+    let mut processed = match processing.instrument(tracing::info_span!("image_processing")).await {
+        Ok(processed) => processed,
+        Err(e) => {
+            error!("Failed to process image: {:?}", e);
+            data.metrics.upload_failed("processing");
+            remove_temp_file(path);
+            archived.iter().for_each(ArchivedUpload::discard);
+            return Err(fail(e));
+        }
+    };
+
+    // Score what would be posted, before it reaches the chat
+    if let Some(moderation) = &settings.moderation {
+        let content_type = mime_guess::from_path(&processed.file_path).first().map(|mime| mime.to_string());
+        let content_type = content_type.or_else(|| saved.content_type.clone());
+        let moderated = moderate(moderation, &data.http_client, &processed.file_path, content_type.as_deref())
+            .instrument(tracing::info_span!("moderation"))
+            .await;
+        match moderated {
+            Ok(verdict) => processed.moderation = verdict,
+            Err(e) => {
+                data.metrics.upload_failed("moderation");
+                remove_temp_file(&processed.file_path);
+                archived.iter().for_each(ArchivedUpload::discard);
+                return Err(fail(e));
+            }
+        }
+    }
+    let path = processed.file_path.as_path();
+    let document;
+    let options = match processed.as_document {
+        true => {
+            document = SendOptions { mode: UploadMode::Document, ..options.clone() };
+            &document
+        }
+        false => options,
+    };
+    let flags = UploadFlags { converted: processed.converted, recompressed: processed.recompressed, deduplicated: false };
+
+    // Telegram is down: straight into the outbox rather than failing after another timeout
+    if circuit_open {
+        if let Some(outbox) = &data.outbox {
+            let entry = queue_upload(data, outbox, saved, &processed, options, destination, None)
+                .map_err(fail)?;
+            return Ok(HostedFile::Queued { entry, flags });
+        }
+    }
+
+    // Semaphore to limit concurrent uploads
+    let permit = match acquire_upload_slot(data).await {
+        Ok(permit) => permit,
+        Err(e) => {
+            error!("Turned away upload {}: {}", saved.id, e);
+            data.metrics.upload_failed("overloaded");
+            remove_temp_file(path);
+            archived.iter().for_each(ArchivedUpload::discard);
+            return Err(fail(e));
+        }
+    };
+    let chat_mode = data.chat_mode;
+    let result = send_to_chats(Media::File(path), data.bots.next(), &chat_ids, chat_mode, options, &data.metrics, &data.retry).await;
+
+    drop(permit); // Release semaphore permit
+
+    // Only outages count against the circuit, not Telegram rejecting this particular file
+    let transient = matches!(&result, Err(e) if e.is_transient());
+    if transient {
+        data.circuit_breaker.record_failure(&data.metrics);
+    } else {
+        data.circuit_breaker.record_success(&data.metrics);
+    }
+
+    let (sent, mirrors) = match result {
+        Ok(sent) => sent,
+        Err(e) => {
+            error!("Failed to upload image to Telegram: {:?}", e);
+            if let (true, Some(outbox)) = (transient, &data.outbox) {
+                let last_error = Some(e.to_string());
+                let entry = queue_upload(data, outbox, saved, &processed, options, destination, last_error)
+                    .map_err(fail)?;
+                return Ok(HostedFile::Queued { entry, flags });
+            }
+            remove_temp_file(path);
+            archived.iter().for_each(ArchivedUpload::discard);
+            data.metrics.upload_failed("telegram");
+            return Err(fail(e));
+        }
+    };
+
+    let file_path = sent.file_path.clone();
+    let meta = UploadMeta {
+        content_hash: Some(saved.content_hash.clone()),
+        deletion_token: generate_deletion_token(),
+        ttl_secs: options.ttl_secs,
+        moderation: processed.moderation,
+        tenant: destination.tenant.clone(),
+        dav_path: destination.dav_path.clone(),
+        file_name: Some(saved.file_name.clone()),
+    };
+    let record = finish_upload(data, saved.id, path, (sent, mirrors), meta).await;
+    notify_webhooks(data, &WebhookPayload::succeeded(data, &record, Some(&saved.file_name)));
+
+    // Remove the temporary file
+    remove_temp_file(path);
+
+    Ok(HostedFile::Sent { record, file_path, flags })
+}
+
+// What the registry keeps about an upload besides what Telegram returned
+pub(crate) struct UploadMeta {
+    pub(crate) content_hash: Option<String>,
+    pub(crate) deletion_token: String,
+    pub(crate) ttl_secs: Option<u64>,
+    pub(crate) moderation: Option<ModerationVerdict>,
+    pub(crate) tenant: Option<String>,
+    pub(crate) dav_path: Option<String>,
+    pub(crate) file_name: Option<String>,
+}
+
+// Wait for one of the max_concurrent_uploads slots, unless too many uploads are waiting
+// already or the wait runs out
+pub(crate) async fn acquire_upload_slot(data: &UploadData) -> Result<SemaphorePermit<'_>, Error> {
+    if let Ok(permit) = data.semaphore.try_acquire() {
+        return Ok(permit);
+    }
+    let overloaded = Error::Overloaded { retry_after: data.upload_queue.retry_after_secs.max(1) };
+    if data.queue_full() {
+        return Err(overloaded);
+    }
+
+    let _waiting = InFlight::new(&data.metrics.uploads_waiting);
+    let wait = data.metrics.semaphore_wait.start_timer();
+    let acquire = data.semaphore.acquire().instrument(tracing::info_span!("semaphore_wait"));
+    let permit = match data.upload_queue.max_wait_secs {
+        0 => acquire.await,
+        secs => tokio::time::timeout(Duration::from_secs(secs), acquire).await.map_err(|_| overloaded)?,
+    };
+    wait.observe_duration();
+    Ok(permit.unwrap())
+}
+
+// Thumbnail and registry record for a file that made it to Telegram
+pub(crate) async fn finish_upload(
+    data: &UploadData,
+    id: Uuid,
+    path: &Path,
+    (sent, mirrors): (SentFile, Vec<MirroredMessage>),
+    meta: UploadMeta,
+) -> UploadRecord {
+    let size = std::fs::metadata(path).ok().map(|metadata| metadata.len());
+    let dimensions = image::image_dimensions(path).ok();
+    data.metrics.bytes_uploaded.inc_by(size.unwrap_or(0));
+
+    // Only images get thumbnails
+    if let (Some(thumbnail_dir), Some(_)) = (&data.thumbnail_dir, dimensions) {
+        let file_path = path.to_path_buf();
+        let thumbnail_path = thumbnail_path(thumbnail_dir, &id);
+        let (size, quality) = (data.thumbnail_size, data.settings().image_options.jpeg_quality);
+        let span = tracing::Span::current();
+        let generated = tokio::task::spawn_blocking(move || {
+            span.in_scope(|| generate_thumbnail(&file_path, &thumbnail_path, size, quality))
+        })
+        .await;
+
+        match generated {
+            Ok(Ok(())) => debug!("Generated thumbnail for upload {}", id),
+            Ok(Err(e)) => error!("Failed to generate thumbnail for upload {}: {:?}", id, e),
+            Err(e) => error!("Failed to generate thumbnail for upload {}: {:?}", id, e),
+        }
+    }
+
+    let uploaded_at = Utc::now();
+    let record = UploadRecord {
+        id,
+        bot_id: Some(sent.bot_id),
+        file_id: sent.file_id,
+        file_unique_id: sent.file_unique_id,
+        chat_id: sent.chat_id.0,
+        message_id: sent.message_id.0,
+        uploaded_at,
+        content_hash: meta.content_hash,
+        deletion_token: meta.deletion_token,
+        size,
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        photo_sizes: sent.photo_sizes,
+        mirrors,
+        sent_as: sent.sent_as,
+        moderation: meta.moderation,
+        tenant: meta.tenant,
+        dav_path: meta.dav_path,
+        file_name: meta.file_name,
+        expires_at: meta.ttl_secs.map(|ttl| uploaded_at + chrono::Duration::seconds(ttl.min(i64::MAX as u64) as i64)),
+    };
+    // An upload put at a path replaces what was there only now that it is on Telegram, which
+    // for queued ones can be long after the put
+    let replaced = match &record.dav_path {
+        Some(_) => data.registry.replace(record.clone()).await,
+        None => data.registry.insert(record.clone()).await.map(|()| Vec::new()),
+    };
+    match replaced {
+        Ok(replaced) => {
+            for replaced in replaced {
+                let mut entry = AuditEntry {
+                    api_key: replaced.tenant.clone(),
+                    ..AuditEntry::of(AuditAction::Delete, AuditSource::Http, &replaced)
+                };
+                match remove_upload(data, &replaced).await {
+                    Ok(()) => info!("Deleted upload {}, replaced by upload {}", replaced.id, id),
+                    Err(e) => {
+                        error!("Failed to remove upload {} replaced by upload {}: {:?}", replaced.id, id, e);
+                        (entry.success, entry.error) = (false, Some(e.to_string()));
+                    }
+                }
+                data.audit(entry);
+            }
+        }
+        Err(e) => error!("Failed to save upload {} to the registry: {:?}", id, e),
+    }
+    record
+}
+
+// Park an upload in the outbox until Telegram takes it
+pub(crate) fn queue_upload(
+    data: &UploadData,
+    outbox: &Outbox,
+    saved: &SavedFile,
+    processed: &ProcessedImage,
+    options: &SendOptions,
+    destination: &Destination,
+    last_error: Option<String>,
+) -> Result<OutboxEntry, Error> {
+    let id = saved.id;
+    let entry = OutboxEntry {
+        id,
+        file_name: String::new(),
+        content_hash: saved.content_hash.clone(),
+        options: options.clone(),
+        chat_id: destination.chat.map(|chat_id| chat_id.0),
+        deletion_token: generate_deletion_token(),
+        queued_at: Utc::now(),
+        attempts: u32::from(last_error.is_some()),
+        last_error,
+        failed: false,
+        moderation: processed.moderation,
+        tenant: destination.tenant.clone(),
+        dav_path: destination.dav_path.clone(),
+    };
+    match outbox.queue(entry, &processed.file_path) {
+        Ok(entry) => {
+            info!("Queued upload {} in the outbox", id);
+            Ok(entry)
+        }
+        Err(e) => {
+            error!("Failed to queue upload {} in the outbox: {:?}", id, e);
+            remove_temp_file(&processed.file_path);
+            data.metrics.upload_failed("outbox");
+            Err(Error::Internal("Failed to queue upload".to_string()))
+        }
+    }
+}
+
+// How often uploads are checked for having outlived their TTL
+pub(crate) const EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// Delete uploads once their TTL is up
+pub(crate) async fn run_expiry(data: web::Data<UploadData>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let expired = data.registry.records().into_iter().filter(|record| record.expires_at.is_some_and(|at| at <= now));
+        for record in expired {
+            let mut entry = AuditEntry::of(AuditAction::Delete, AuditSource::Expiry, &record);
+            match remove_upload(&data, &record).await {
+                Ok(()) => info!("Deleted upload {}, its TTL is up", record.id),
+                Err(e) => {
+                    error!("Failed to remove expired upload {} from the registry: {:?}", record.id, e);
+                    (entry.success, entry.error) = (false, Some(e.to_string()));
+                }
+            }
+            data.audit(entry);
+        }
+    }
+}
+
+// Delete an upload's Telegram messages and everything kept about it here
+pub(crate) async fn remove_upload(data: &UploadData, record: &UploadRecord) -> std::io::Result<()> {
+    let mirrors = record.mirrors.iter().map(|mirror| (mirror.chat_id, mirror.message_id));
+    for (chat_id, message_id) in std::iter::once((record.chat_id, record.message_id)).chain(mirrors) {
+        let deleted = data.bots.get(record.bot_id).delete_message(ChatId(chat_id), MessageId(message_id));
+        if let Err(e) = data.metrics.time_telegram("delete_message", deleted).await {
+            error!("Failed to delete Telegram message of upload {} in chat {}: {:?}", record.id, chat_id, e);
+        }
+    }
+
+    if let Some(thumbnail_dir) = &data.thumbnail_dir {
+        let _ = std::fs::remove_file(thumbnail_path(thumbnail_dir, &record.id));
+    }
+    if let Some(proxy_cache) = &data.proxy_cache {
+        proxy_cache.remove(&record.id);
+    }
+
+    data.registry.remove(&record.id).await.map(|_| ())
+}
+
+// What happened to an upload on its way to Telegram, reported back to the client
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct UploadFlags {
+    pub(crate) converted: bool,
+    pub(crate) recompressed: bool,
+    pub(crate) deduplicated: bool,
+}
+
+// How long a webhook endpoint gets to answer
+pub(crate) const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Deliveries tried per webhook before the event is dropped
+pub(crate) const WEBHOOK_ATTEMPTS: u32 = 3;
+
+// Body POSTed to webhooks
+#[derive(Debug, Serialize)]
+pub(crate) struct WebhookPayload<'a> {
+    pub(crate) event: WebhookEvent,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) upload_id: Uuid,
+    // Name of the file on the client
+    pub(crate) file_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) upload: Option<WebhookUpload<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<&'a str>,
+}
+
+// Metadata of a finished upload. Leaves out the deletion token and Telegram's file URL,
+// which contains the bot token.
+#[derive(Debug, Serialize)]
+pub(crate) struct WebhookUpload<'a> {
+    // Proxy URL of the upload, only known with public_url set
+    pub(crate) url: Option<String>,
+    pub(crate) content_hash: Option<&'a str>,
+    pub(crate) size: Option<u64>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) chat_id: i64,
+    pub(crate) message_id: i32,
+    pub(crate) sent_as: UploadMode,
+    pub(crate) uploaded_at: DateTime<Utc>,
+}
+
+impl<'a> WebhookPayload<'a> {
+    pub(crate) fn succeeded(data: &UploadData, record: &'a UploadRecord, file_name: Option<&'a str>) -> WebhookPayload<'a> {
+        let settings = data.settings();
+        let url = settings.public_url.as_ref().map(|public_url| settings.file_url(public_url.trim_end_matches('/'), &record.id));
+        WebhookPayload {
+            event: WebhookEvent::UploadSucceeded,
+            timestamp: Utc::now(),
+            upload_id: record.id,
+            file_name,
+            upload: Some(WebhookUpload {
+                url,
+                content_hash: record.content_hash.as_deref(),
+                size: record.size,
+                width: record.width,
+                height: record.height,
+                chat_id: record.chat_id,
+                message_id: record.message_id,
+                sent_as: record.sent_as,
+                uploaded_at: record.uploaded_at,
+            }),
+            error: None,
+        }
+    }
+
+    pub(crate) fn failed(id: Uuid, file_name: Option<&'a str>, error: &'a str) -> WebhookPayload<'a> {
+        WebhookPayload {
+            event: WebhookEvent::UploadFailed,
+            timestamp: Utc::now(),
+            upload_id: id,
+            file_name,
+            upload: None,
+            error: Some(error),
+        }
+    }
+}
+
+// Hex-encoded HMAC-SHA256 of a webhook body
+pub(crate) fn webhook_signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    to_hex(&mac.finalize().into_bytes())
+}
+
+// Send an event to every webhook subscribed to it, in the background
+pub(crate) fn notify_webhooks(data: &UploadData, payload: &WebhookPayload) {
+    let webhooks: Vec<WebhookConfig> =
+        data.settings().webhooks.iter().filter(|webhook| webhook.subscribes(payload.event)).cloned().collect();
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let body = match serde_json::to_vec(payload) {
+        Ok(body) => web::Bytes::from(body),
+        Err(e) => {
+            error!("Failed to encode webhook payload for upload {}: {:?}", payload.upload_id, e);
+            return;
+        }
+    };
+    for webhook in webhooks {
+        let span = tracing::info_span!("webhook", upload_id = %payload.upload_id, url = %webhook.url);
+        let delivery = deliver_webhook(data.http_client.clone(), webhook, payload.event, body.clone());
+        tokio::spawn(delivery.instrument(span));
+    }
+}
+
+pub(crate) async fn deliver_webhook(client: reqwest::Client, webhook: WebhookConfig, event: WebhookEvent, body: web::Bytes) {
+    let event = serde_json::to_value(event).ok().and_then(|event| event.as_str().map(str::to_string)).unwrap_or_default();
+    let signature = webhook.secret.as_ref().map(|secret| format!("sha256={}", webhook_signature(secret, &body)));
+
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let mut request = client
+            .post(webhook.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Event", &event)
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header("X-Webhook-Signature", signature);
+        }
+
+        let error = match request.send().await.and_then(|response| response.error_for_status()) {
+            Ok(_) => {
+                debug!("Delivered {} webhook to {}", event, webhook.url);
+                return;
+            }
+            Err(e) => e,
+        };
+        if attempt == WEBHOOK_ATTEMPTS {
+            error!("Failed to deliver {} webhook to {}, giving up: {:?}", event, webhook.url, error);
+        } else {
+            warn!("Failed to deliver {} webhook to {} (attempt {}): {:?}", event, webhook.url, attempt, error);
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+        }
+    }
+}
+
+// Keep sending queued uploads to Telegram in the background
+pub(crate) async fn run_outbox(data: web::Data<UploadData>) {
+    let Some(outbox) = &data.outbox else {
+        return;
+    };
+
+    loop {
+        tokio::time::sleep(outbox.retry_interval).await;
+
+        for mut entry in outbox.pending() {
+            // Still down, wait for the circuit to let a probe through
+            if data.circuit_breaker.check().is_err() {
+                break;
+            }
+
+            let span = tracing::info_span!("outbox_delivery", upload_id = %entry.id, attempt = entry.attempts + 1);
+            let path = outbox.image_path(&entry);
+            let permit = data.semaphore.acquire().await.unwrap();
+            let chat_ids = entry.chat_id.map_or_else(|| data.chat_ids.clone(), |chat_id| vec![ChatId(chat_id)]);
+            let (bot, chat_mode) = (data.bots.next(), data.chat_mode);
+            let result = send_to_chats(Media::File(&path), bot, &chat_ids, chat_mode, &entry.options, &data.metrics, &data.retry)
+                .instrument(span.clone())
+                .await;
+            drop(permit);
+
+            let error = match result {
+                Ok((sent, mirrors)) => {
+                    data.circuit_breaker.record_success(&data.metrics);
+                    let meta = UploadMeta {
+                        content_hash: Some(entry.content_hash.clone()),
+                        deletion_token: entry.deletion_token.clone(),
+                        ttl_secs: entry.options.ttl_secs,
+                        moderation: entry.moderation,
+                        tenant: entry.tenant.clone(),
+                        dav_path: entry.dav_path.clone(),
+                        file_name: entry.options.file_name.clone(),
+                    };
+                    let record = finish_upload(&data, entry.id, &path, (sent, mirrors), meta)
+                        .instrument(span)
+                        .await;
+                    info!("Delivered queued upload {} after {} attempts", record.id, entry.attempts + 1);
+                    notify_webhooks(&data, &WebhookPayload::succeeded(&data, &record, entry.options.file_name.as_deref()));
+                    outbox.remove(&entry);
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            let transient = error.is_transient();
+            entry.attempts += 1;
+            entry.last_error = Some(error.to_string());
+            if transient {
+                data.circuit_breaker.record_failure(&data.metrics);
+            } else {
+                data.circuit_breaker.record_success(&data.metrics);
+            }
+            if transient && !outbox.out_of_attempts(&entry) {
+                error!("Failed to deliver queued upload {}, will retry: {:?}", entry.id, error);
+            } else {
+                match transient {
+                    true => error!("Failed to deliver queued upload {} {} times, giving up: {:?}", entry.id, entry.attempts, error),
+                    false => error!("Telegram rejected queued upload {}, giving up: {:?}", entry.id, error),
+                }
+                data.metrics.upload_failed("telegram");
+                entry.failed = true;
+                let _ = std::fs::remove_file(&path);
+                let message = error.to_string();
+                notify_webhooks(&data, &WebhookPayload::failed(entry.id, entry.options.file_name.as_deref(), &message));
+            }
+
+            let entry_id = entry.id;
+            if let Err(e) = outbox.update(entry) {
+                error!("Failed to update outbox entry {}: {:?}", entry_id, e);
+            }
+            // Don't hammer Telegram with the rest of the queue while it's failing
+            if transient {
+                break;
+            }
+        }
+    }
+}
+
+// Struct to hold shared data for the upload handler
+pub(crate) struct UploadData {
+    pub(crate) bots: BotPool,
+    pub(crate) chat_ids: Vec<ChatId>,
+    pub(crate) chat_mode: ChatMode,
+    pub(crate) semaphore: Semaphore,
+    pub(crate) max_concurrent_uploads: usize,
+    pub(crate) upload_queue: UploadQueueConfig,
+    pub(crate) payload_read_timeout: Option<Duration>,
+    pub(crate) replay_guard: ReplayGuard,
+    pub(crate) thumbnail_dir: Option<PathBuf>,
+    pub(crate) thumbnail_size: u32,
+    pub(crate) archive_dir: Option<PathBuf>,
+    pub(crate) registry: Registry,
+    pub(crate) temp_dir: PathBuf,
+    pub(crate) zip_upload: ZipUploadConfig,
+    pub(crate) metrics: Metrics,
+    // Set once get_me succeeded with the configured token
+    pub(crate) bot_validated: AtomicBool,
+    pub(crate) readiness_check_telegram: bool,
+    pub(crate) resize_cache: Mutex<LruCache<ResizeKey, ProxiedFile>>,
+    // What a config reload can change
+    pub(crate) settings: RwLock<Arc<Settings>>,
+    pub(crate) cache_max_age_secs: u64,
+    // Uploads by Idempotency-Key, None if that's turned off
+    pub(crate) idempotency: Option<IdempotencyStore>,
+    // Uploads followed through /progress/{id}
+    pub(crate) progress: ProgressTracker,
+    pub(crate) bandwidth: Bandwidth,
+    pub(crate) retry: RetryConfig,
+    pub(crate) circuit_breaker: CircuitBreaker,
+    pub(crate) outbox: Option<Outbox>,
+    pub(crate) temp_quota: TempQuota,
+    pub(crate) proxy_cache: Option<ProxyCache>,
+    pub(crate) audit: Option<AuditLog>,
+    pub(crate) http_client: reqwest::Client,
+    // File a config reload reads, if the config came from one
+    pub(crate) config_file: Option<PathBuf>,
+}
+
+// Options taking effect without a restart when the config is reloaded
+pub(crate) struct Settings {
+    pub(crate) api_keys: Vec<ApiKeyConfig>,
+    pub(crate) allowed_types: Vec<String>,
+    pub(crate) send_options: SendOptions,
+    pub(crate) image_options: ImageOptions,
+    pub(crate) deduplicate: bool,
+    pub(crate) public_url: Option<String>,
+    pub(crate) trusted_proxies: Vec<IpNet>,
+    pub(crate) ip_filter: IpFilter,
+    pub(crate) webhooks: Vec<WebhookConfig>,
+    pub(crate) gallery: GalleryConfig,
+    pub(crate) feed: Option<FeedConfig>,
+    pub(crate) signed_urls: Option<SignedUrlConfig>,
+    pub(crate) moderation: Option<ModerationConfig>,
+    pub(crate) clamav: Option<ClamavConfig>,
+}
+
+// Networks let in and turned away by the ip_filter config
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IpFilter {
+    pub(crate) allow: Vec<IpNet>,
+    pub(crate) deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub(crate) fn allows(&self, address: &IpAddr) -> bool {
+        !contains_address(&self.deny, address) && (self.allow.is_empty() || contains_address(&self.allow, address))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+// Whether an address is in one of the networks. Clients connecting over IPv4 to a socket
+// listening on IPv6 show up as IPv4-mapped addresses (::ffff:192.0.2.1), which are matched
+// against IPv4 networks too.
+pub(crate) fn contains_address(networks: &[IpNet], address: &IpAddr) -> bool {
+    let canonical = address.to_canonical();
+    networks.iter().any(|network| network.contains(address) || network.contains(&canonical))
+}
+
+// Parse a list of addresses and CIDR ranges from the config option named
+pub(crate) fn parse_networks(networks: &[String], option: &str) -> Result<Vec<IpNet>, String> {
+    networks
+        .iter()
+        .map(|network| parse_network(network).map_err(|e| format!("Invalid {} entry: {}", option, e)))
+        .collect()
+}
+
+impl Settings {
+    pub(crate) fn from_config(config: &Config) -> Result<Settings, String> {
+        // The gallery can delete uploads, so it must not be open to everyone
+        if config.gallery.enabled && config.api_keys.is_empty() {
+            return Err("The gallery needs api_keys to be set in the config".to_string());
+        }
+        if config.signed_urls.as_ref().is_some_and(|signed_urls| signed_urls.secret.is_empty()) {
+            return Err("signed_urls needs a secret".to_string());
+        }
+        if let Some(moderation) = &config.moderation {
+            if moderation.url.is_some() == moderation.command.is_some() {
+                return Err("moderation needs exactly one of url and command".to_string());
+            }
+            if !(0.0..=1.0).contains(&moderation.block_above) {
+                return Err("moderation.block_above must be between 0 and 1".to_string());
+            }
+        }
+        let watermark = match &config.watermark {
+            Some(watermark) => {
+                Some(Arc::new(load_watermark(watermark).map_err(|e| format!("Failed to load watermark: {:?}", e))?))
+            }
+            None => None,
+        };
+
+        Ok(Settings {
+            api_keys: config.api_keys.clone(),
+            allowed_types: config.allowed_types.clone(),
+            send_options: SendOptions {
+                message_thread_id: config.message_thread_id,
+                disable_notification: config.disable_notification,
+                protect_content: config.protect_content,
+                pin: config.pin_uploads,
+                mode: config.upload_mode,
+                ttl_secs: (config.default_ttl_secs > 0).then_some(config.default_ttl_secs),
+                ..SendOptions::default()
+            },
+            image_options: ImageOptions {
+                auto_orient: config.auto_orient,
+                strip_exif: config.strip_exif,
+                max_dimension: config.max_dimension,
+                jpeg_quality: config.jpeg_quality,
+                convert_command: config.convert_command.clone(),
+                convert_timeout: Duration::from_secs(config.convert_timeout_secs.max(1)),
+                watermark,
+            },
+            deduplicate: config.deduplicate,
+            public_url: config.public_url.clone(),
+            trusted_proxies: parse_networks(&config.trusted_proxies, "trusted_proxies")?,
+            ip_filter: IpFilter {
+                allow: parse_networks(&config.ip_filter.allow, "ip_filter.allow")?,
+                deny: parse_networks(&config.ip_filter.deny, "ip_filter.deny")?,
+            },
+            webhooks: config.webhooks.clone(),
+            gallery: config.gallery.clone(),
+            feed: config.feed.clone(),
+            signed_urls: config.signed_urls.clone(),
+            moderation: config.moderation.clone(),
+            clamav: config.clamav.clone(),
+        })
+    }
+
+    // Link to an upload through this server, signed to expire when signed_urls is set
+    pub(crate) fn file_url(&self, base_url: &str, id: &Uuid) -> String {
+        self.signed(format!("{}/f/{}", base_url, id), id)
+    }
+
+    // Link to an upload's thumbnail, signed like file_url
+    pub(crate) fn thumbnail_url(&self, base_url: &str, id: &Uuid) -> String {
+        self.signed(format!("{}/t/{}", base_url, id), id)
+    }
+
+    fn signed(&self, url: String, id: &Uuid) -> String {
+        match &self.signed_urls {
+            Some(signed_urls) => {
+                let expires = Utc::now().timestamp().saturating_add_unsigned(signed_urls.expiry_secs);
+                format!("{}?exp={}&sig={}", url, expires, url_signature(&signed_urls.secret, id, expires))
+            }
+            None => url,
+        }
+    }
+}
+
+// Hex-encoded HMAC-SHA256 of an upload ID and the Unix time its link expires at
+pub(crate) fn url_signature(secret: &str, id: &Uuid, expires: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}", id, expires).as_bytes());
+    to_hex(&mac.finalize().into_bytes())
+}
+
+impl UploadData {
+    pub(crate) fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    pub(crate) fn audit(&self, entry: AuditEntry) {
+        if let Some(audit) = &self.audit {
+            audit.record(&entry);
+        }
+    }
+
+    // Whether an upload would be turned away for lack of a slot right now
+    pub(crate) fn queue_full(&self) -> bool {
+        let max_waiting = self.upload_queue.max_waiting;
+        max_waiting > 0 && self.semaphore.available_permits() == 0 && self.metrics.uploads_waiting.get() >= max_waiting as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip_filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let networks = |networks: &[&str]| {
+            let networks: Vec<String> = networks.iter().map(|network| network.to_string()).collect();
+            parse_networks(&networks, "ip_filter").unwrap()
+        };
+        IpFilter { allow: networks(allow), deny: networks(deny) }
+    }
+
+    fn allows(ip_filter: &IpFilter, address: &str) -> bool {
+        ip_filter.allows(&address.parse().unwrap())
+    }
+
+    #[test]
+    fn empty_allow_list_lets_everyone_in() {
+        let filter = ip_filter(&[], &[]);
+        assert!(filter.is_empty());
+        assert!(allows(&filter, "192.0.2.1"));
+        assert!(allows(&filter, "2001:db8::1"));
+
+        let filter = ip_filter(&[], &["192.0.2.0/24"]);
+        assert!(!allows(&filter, "192.0.2.1"));
+        assert!(allows(&filter, "198.51.100.1"));
+    }
+
+    #[test]
+    fn allow_list_turns_away_everyone_else() {
+        let filter = ip_filter(&["10.0.0.0/8", "2001:db8::/32"], &[]);
+        assert!(allows(&filter, "10.1.2.3"));
+        assert!(allows(&filter, "2001:db8::1"));
+        assert!(!allows(&filter, "192.0.2.1"));
+        assert!(!allows(&filter, "2001:db9::1"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = ip_filter(&["10.0.0.0/8"], &["10.0.0.0/24", "10.9.9.9"]);
+        assert!(allows(&filter, "10.0.1.1"));
+        assert!(!allows(&filter, "10.0.0.1"));
+        assert!(!allows(&filter, "10.9.9.9"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_networks() {
+        let filter = ip_filter(&["10.0.0.0/8"], &["10.0.0.1"]);
+        assert!(allows(&filter, "::ffff:10.1.2.3"));
+        assert!(!allows(&filter, "::ffff:10.0.0.1"));
+        assert!(!allows(&filter, "::ffff:192.0.2.1"));
+
+        // Networks written as mapped addresses still match as written
+        let filter = ip_filter(&[], &["::ffff:192.0.2.0/120"]);
+        assert!(!allows(&filter, "::ffff:192.0.2.1"));
+    }
+
+    #[test]
+    fn signs_webhooks_with_hmac_sha256() {
+        // Test case 2 of RFC 4231
+        assert_eq!(
+            webhook_signature("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(webhook_signature("", b""), "b613679a0814d9ec772f95d778c35fc5ff1697c493715653c6c712144292c5ad");
+    }
+}